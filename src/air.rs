@@ -1,50 +1,269 @@
-use miette::{bail, Result, Severity};
+use fxhash::FxHashMap;
+use miette::{Report, Result};
 
 use crate::{
     debugger::Breakpoints,
-    symbol::{Flag, Label, Register, Span},
+    error::{self, Diagnostics},
+    expr::Expr,
+    features::Features,
+    parser::Bits,
+    runtime::MEMORY_MAX,
+    source::Source,
+    symbol::{Flag, Label, Register, Span, SymbolTable},
 };
 
-/// Assembly intermediate representation, contains starting address and list of instructions
-#[derive(Clone)]
+/// Origin of a program which does not set one with `.orig`.
+pub const DEFAULT_ORIG: u16 = 0x3000;
+
+/// First word of an object file which contains several segments.
+///
+/// A file with a single segment is written as its origin followed by its words, like other LC3
+/// assemblers. Otherwise, this word is followed by the origin, length and words of each segment.
+/// It cannot be mistaken for an origin, as no words would fit in memory after it.
+pub const SEGMENTS_MAGIC: u16 = 0xFFFF;
+
+/// Most statements which are rewritten when relaxing. Any statement which still does not fit is
+/// reported when backpatching.
+const MAX_RELAX_PASSES: usize = 4096;
+
+/// Assembly intermediate representation, contains the segments of the program and list of
+/// instructions
 pub struct Air {
-    /// Memory address to start program at
-    orig: Option<u16>,
+    /// Each `.orig` section of the program, in source order
+    segments: Vec<Segment>,
     /// AIR
     pub ast: Vec<AsmLine>,
+    /// Operands which refer to labels, by index of their statement
+    deferred: FxHashMap<usize, Deferred>,
+    /// Every operand which refers to labels, by index of their statement, including those which
+    /// were filled by the parser
+    refs: FxHashMap<usize, Expr>,
+    /// Prefix labels, with the span where each was declared
+    labels: Vec<(String, Span)>,
+    /// Labels exported with `.global`
+    globals: Vec<(String, Span)>,
+    /// `.blkw` directives which reserve no words, so do not have a statement
+    empty_blocks: Vec<Span>,
+    /// Whether the program is assembled into an object file, to be linked with other modules
+    relocatable: bool,
+    /// Operands which must be filled by the linker
+    relocations: Vec<Relocation>,
+    /// Whether references which are too far from their target are rewritten, rather than
+    /// reported as errors
+    relax: bool,
 
     pub breakpoints: Breakpoints,
 
-    pub src: &'static str,
+    pub source: Source,
+    /// Labels and constants of this program
+    pub symbols: SymbolTable,
+    /// Extensions which the program was assembled with
+    pub features: Features,
+
+    /// Errors and warnings from every stage of assembly so far
+    pub diagnostics: Diagnostics,
+}
+
+/// Statements which are placed at consecutive addresses, starting at an origin.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Segment {
+    pub orig: u16,
+    /// Whether the origin was set with `.orig`, rather than being chosen by the linker
+    pub fixed: bool,
+    /// Index of the first statement in the segment
+    start: usize,
+    /// Span of the `.orig` directive, or of the first statement if the program does not set an
+    /// origin
+    span: Span,
 }
 
 impl Air {
-    pub fn new(src: &'static str) -> Self {
+    pub fn new(source: Source, features: Features) -> Self {
         Air {
-            orig: None,
+            segments: Vec::new(),
             ast: Vec::new(),
+            deferred: FxHashMap::default(),
+            refs: FxHashMap::default(),
+            labels: Vec::new(),
+            globals: Vec::new(),
+            empty_blocks: Vec::new(),
+            relocatable: false,
+            relocations: Vec::new(),
+            relax: false,
             breakpoints: Breakpoints::new(),
-            src,
+            source,
+            symbols: SymbolTable::new(features),
+            features,
+            diagnostics: Diagnostics::new(),
         }
     }
 
-    /// Set the .orig offset for the program. Error if set twice.
-    pub fn set_orig(&mut self, val: u16) -> Result<()> {
-        if self.orig.is_some() {
-            bail!("Origin set twice.")
-        } else {
-            self.orig = Some(val);
-            Ok(())
+    /// Start a new segment at `orig`. Following statements are placed after it.
+    pub fn set_orig(&mut self, orig: u16, span: Span) {
+        // A segment without any statements is replaced
+        if self
+            .segments
+            .last()
+            .is_some_and(|seg| seg.start == self.ast.len())
+        {
+            self.segments.pop();
         }
+        self.segments.push(Segment {
+            orig,
+            fixed: true,
+            start: self.ast.len(),
+            span,
+        });
     }
 
+    /// Origin of the first segment, where the program starts.
     pub fn orig(&self) -> Option<u16> {
-        self.orig
+        self.segments.first().map(|seg| seg.orig)
+    }
+
+    /// Each segment, with the statements within it.
+    pub fn segments(&self) -> impl Iterator<Item = (&Segment, &[AsmLine])> {
+        self.segments.iter().enumerate().map(|(i, seg)| {
+            let end = match self.segments.get(i + 1) {
+                Some(next) => next.start,
+                None => self.ast.len(),
+            };
+            (seg, &self.ast[seg.start..end])
+        })
+    }
+
+    /// Index of the segment which contains the statement at `idx`.
+    pub fn segment_of_stmt(&self, idx: usize) -> usize {
+        self.segments
+            .iter()
+            .rposition(|seg| seg.start <= idx)
+            .expect("Statement should belong to a segment")
+    }
+
+    /// Index of the segment which contains `addr`, or ends at it.
+    ///
+    /// Returns `None` if the address is outside of the program.
+    pub fn segment_of_addr(&self, addr: u16) -> Option<usize> {
+        let ranges: Vec<_> = self
+            .segments()
+            .map(|(seg, stmts)| seg.orig as usize..seg.orig as usize + stmts.len())
+            .collect();
+        let addr = addr as usize;
+        ranges
+            .iter()
+            .position(|range| range.contains(&addr))
+            .or_else(|| ranges.iter().position(|range| range.end == addr))
+    }
+
+    /// Address of the next statement to be added.
+    pub fn next_addr(&self) -> u16 {
+        match self.segments.last() {
+            Some(seg) => seg.orig.wrapping_add((self.ast.len() - seg.start) as u16),
+            None => DEFAULT_ORIG,
+        }
     }
 
     pub fn add_stmt(&mut self, stmt: AirStmt, span: Span) {
-        self.ast
-            .push(AsmLine::new((self.ast.len() + 1) as u16, stmt, span))
+        if self.segments.is_empty() {
+            self.segments.push(Segment {
+                orig: DEFAULT_ORIG,
+                fixed: false,
+                start: 0,
+                span,
+            });
+        }
+        self.ast.push(AsmLine::new(self.next_addr(), stmt, span))
+    }
+
+    /// Evaluate an operand of the most recently added statement when backpatching.
+    pub fn defer(&mut self, deferred: Deferred) {
+        self.add_ref(deferred.expr.clone());
+        self.deferred.insert(self.ast.len() - 1, deferred);
+    }
+
+    /// Point the label operand of a statement at an address, such as a value in a literal pool.
+    pub fn fill_label(&mut self, idx: usize, addr: u16) {
+        match &mut self.ast[idx].stmt {
+            AirStmt::Branch { dest_label, .. }
+            | AirStmt::JumbSub { dest_label }
+            | AirStmt::Store { dest_label, .. }
+            | AirStmt::StoreInd { dest_label, .. }
+            | AirStmt::Call { dest_label } => *dest_label = Label::Ref(addr),
+            AirStmt::Load { src_label, .. }
+            | AirStmt::LoadInd { src_label, .. }
+            | AirStmt::LoadEAddr { src_label, .. } => *src_label = Label::Ref(addr),
+            _ => panic!("statement has no label operand"),
+        }
+    }
+
+    /// Record an operand of the most recently added statement which refers to labels.
+    pub fn add_ref(&mut self, expr: Expr) {
+        self.refs.insert(self.ast.len() - 1, expr);
+    }
+
+    /// Declare a prefix label at the address of the next statement. Errors on duplicates.
+    pub fn add_label(&mut self, name: &str, span: Span) -> Result<()> {
+        self.symbols.insert_label(name, self.next_addr())?;
+        self.labels.push((name.to_string(), span));
+        Ok(())
+    }
+
+    /// Export a label, so that other modules can refer to it.
+    pub fn add_global(&mut self, name: &str, span: Span) {
+        self.globals.push((name.to_string(), span));
+    }
+
+    /// Prefix labels, with the span where each was declared.
+    pub fn labels(&self) -> &[(String, Span)] {
+        &self.labels
+    }
+
+    /// Address of every prefix label, in order of address.
+    pub fn label_addresses(&self) -> Vec<(String, u16)> {
+        let mut labels: Vec<_> = self
+            .labels
+            .iter()
+            .filter_map(|(name, _)| Some((name.clone(), self.symbols.label(name)?)))
+            .collect();
+        labels.sort_by(|(a_name, a_addr), (b_name, b_addr)| {
+            a_addr.cmp(b_addr).then_with(|| a_name.cmp(b_name))
+        });
+        labels
+    }
+
+    /// Record a `.blkw` directive which reserves no words.
+    pub fn add_empty_block(&mut self, span: Span) {
+        self.empty_blocks.push(span);
+    }
+
+    pub fn empty_blocks(&self) -> &[Span] {
+        &self.empty_blocks
+    }
+
+    /// Every operand which refers to labels.
+    pub(crate) fn refs(&self) -> impl Iterator<Item = (usize, &Expr)> {
+        self.refs.iter().map(|(i, expr)| (*i, expr))
+    }
+
+    pub fn is_global(&self, name: &str) -> bool {
+        self.globals.iter().any(|(global, _)| global == name)
+    }
+
+    /// Assemble into an object file, so that labels declared with `.extern` are filled by the
+    /// linker. Must be called before backpatching.
+    pub fn set_relocatable(&mut self) {
+        self.relocatable = true;
+    }
+
+    /// Rewrite references which are too far from their target to fit in the offset of their
+    /// instruction. Must be called before backpatching.
+    pub fn set_relax(&mut self) {
+        self.relax = true;
+    }
+
+    /// Operands which must be filled by the linker, once the program is relocatable.
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
     }
 
     pub fn get(&self, idx: usize) -> &AsmLine {
@@ -59,12 +278,466 @@ impl Air {
         self.ast.len() == 0
     }
 
-    pub fn backpatch(&mut self) -> Result<()> {
-        for stmt in self.ast.iter_mut() {
-            stmt.backpatch()?;
+    /// Fill label references and check that every statement can be emitted.
+    ///
+    /// Each failure is recorded in [`Air::diagnostics`], so that all of them can be reported at
+    /// once.
+    pub fn backpatch(&mut self) {
+        if self.relax {
+            self.relax();
+        }
+        self.check_segments();
+        self.check_globals();
+        if self.relocatable {
+            self.relocate();
+        }
+        // Statements which `--relax` would rewrite, if their label is too far away
+        let relaxable: Vec<bool> = (0..self.ast.len())
+            .map(|i| {
+                let stmt = &self.ast[i];
+                !self.relax
+                    && stmt.is_relaxable()
+                    && (!matches!(stmt.stmt, AirStmt::Branch { .. })
+                        || self
+                            .target(i)
+                            .and_then(|target| self.segment_of_addr(target))
+                            == Some(self.segment_of_stmt(i)))
+            })
+            .collect();
+        for (i, stmt) in self.ast.iter_mut().enumerate() {
+            let deferred = match self.deferred.remove(&i) {
+                Some(deferred) => stmt.fill_deferred(deferred, &self.symbols),
+                None => Ok(()),
+            };
+            // Emitting requires a filled label
+            if let Err(err) = deferred
+                .and_then(|_| stmt.backpatch(&self.symbols))
+                .and_then(|_| match relaxable[i] {
+                    true => stmt.check_offset_relaxable(),
+                    false => Ok(()),
+                })
+                .and_then(|_| stmt.emit())
+            {
+                self.diagnostics
+                    .push(err.with_source_code(self.source.clone()));
+            }
+        }
+    }
+
+    /// Check that every segment fits in memory, and that no two segments overlap.
+    fn check_segments(&mut self) {
+        let ranges: Vec<_> = self
+            .segments()
+            .map(|(seg, stmts)| seg.orig as usize..seg.orig as usize + stmts.len())
+            .collect();
+        for (i, seg) in self.segments.iter().enumerate() {
+            let range = &ranges[i];
+            let err = if range.end > MEMORY_MAX {
+                error::asm_segment_overflow(seg.span, range.len())
+            } else if let Some(other) = ranges[..i]
+                .iter()
+                .position(|other| other.start < range.end && range.start < other.end)
+            {
+                error::asm_segment_overlap(seg.span, self.segments[other].span)
+            } else {
+                continue;
+            };
+            self.diagnostics
+                .push(err.with_source_code(self.source.clone()));
+        }
+    }
+
+    /// Check that every label exported with `.global` is declared in this module.
+    fn check_globals(&mut self) {
+        for (name, span) in &self.globals {
+            if self.symbols.label(name).is_none() {
+                self.diagnostics.push(
+                    error::asm_missing_label(*span, name).with_source_code(self.source.clone()),
+                );
+            }
+        }
+    }
+
+    /// Find operands which the linker must fill, as they refer to a label in another module, or
+    /// to a label whose address is only known once the program is linked.
+    ///
+    /// Each of these operands is replaced with a placeholder value.
+    fn relocate(&mut self) {
+        let mut refs: Vec<_> = self
+            .refs
+            .iter()
+            .map(|(i, expr)| (*i, expr.clone()))
+            .collect();
+        refs.sort_unstable_by_key(|(i, _)| *i);
+
+        for (i, expr) in refs {
+            let kind = match self.ast[i].offset_bits() {
+                Some(bits) => Some(RelocationKind::Offset(bits)),
+                None if matches!(self.ast[i].stmt, AirStmt::RawWord { .. }) => {
+                    Some(RelocationKind::Word)
+                }
+                None => None,
+            };
+            let (Some(kind), Some((label, addend))) = (kind, expr.label_offset()) else {
+                // Other operands are evaluated as if the program was not moved
+                if let Some(label) = expr
+                    .labels()
+                    .into_iter()
+                    .find(|(name, _)| self.symbols.is_extern(name))
+                {
+                    self.diagnostics.push(
+                        error::asm_extern_expr(label.1, expr.span())
+                            .with_source_code(self.source.clone()),
+                    );
+                }
+                continue;
+            };
+
+            let needed = self.symbols.is_extern(label) || {
+                let target = self
+                    .symbols
+                    .label(label)
+                    .and_then(|addr| self.segment_of_addr(addr));
+                let moves = |seg: usize| !self.segments[seg].fixed;
+                match (target, kind) {
+                    // Missing labels are reported when backpatching
+                    (None, _) => false,
+                    // Offset only changes if the segments are moved separately
+                    (Some(target), RelocationKind::Offset(_)) => {
+                        let seg = self.segment_of_stmt(i);
+                        seg != target && (moves(seg) || moves(target))
+                    }
+                    (Some(target), RelocationKind::Word) => moves(target),
+                }
+            };
+            if !needed {
+                continue;
+            }
+
+            // Replace operand with a placeholder, which is filled by the linker
+            self.deferred.remove(&i);
+            let stmt = &mut self.ast[i];
+            let next = stmt.addr.wrapping_add(1);
+            match stmt.label_mut() {
+                Some(dest) => *dest = Label::Ref(next),
+                None => stmt.stmt = AirStmt::RawWord { val: RawWord(0) },
+            }
+            self.relocations.push(Relocation {
+                index: i,
+                kind,
+                label: label.to_string(),
+                addend,
+                span: expr.span(),
+            });
         }
-        Ok(())
     }
+
+    /// Rewrite statements whose target is too far away to fit in their offset, until every
+    /// statement fits or no more can be rewritten.
+    ///
+    /// Branches jump through a trampoline between them and their target, and other statements
+    /// use the address of their target from a pointer word placed after them. Rewritten code is
+    /// laid out again, so other statements may then need to be rewritten. Each rewrite is reported
+    /// as a note, and statements which cannot be rewritten are reported when backpatching.
+    fn relax(&mut self) {
+        // Statements which hold the address of a target, which must move with it
+        let mut pointers = Vec::new();
+        for _ in 0..MAX_RELAX_PASSES {
+            let rewritten = (0..self.ast.len()).any(|i| {
+                let (Some(bits), Some(target)) = (self.ast[i].offset_bits(), self.target(i)) else {
+                    return false;
+                };
+                !offset_fits(self.ast[i].addr, target, bits) && self.rewrite(i, &mut pointers)
+            });
+            if !rewritten {
+                break;
+            }
+        }
+    }
+
+    /// Address which the label operand of a statement refers to, if it is known yet.
+    fn target(&self, i: usize) -> Option<u16> {
+        if let Some(deferred) = self.deferred.get(&i) {
+            let val = deferred
+                .expr
+                .eval(&mut |name, span| match self.symbols.label(name) {
+                    Some(addr) => Ok(addr as i32),
+                    None => Err(missing_label(span, name, &self.symbols)),
+                });
+            return val.ok().map(|val| val as u16);
+        }
+        match self.ast[i].label()? {
+            Label::Ref(addr) => Some(*addr),
+            Label::Unfilled(name) => self.symbols.label(name),
+        }
+    }
+
+    /// Rewrite a statement whose target is too far away. Returns `false` if it cannot be
+    /// rewritten.
+    fn rewrite(&mut self, i: usize, pointers: &mut Vec<usize>) -> bool {
+        let seg = self.segment_of_stmt(i);
+        let span = self.ast[i].span;
+        let skip = |addr: u16| AirStmt::Branch {
+            flag: Flag::Nzp,
+            dest_label: Label::Ref(addr),
+        };
+
+        if let AirStmt::Branch { .. } = self.ast[i].stmt {
+            let target = self.target(i).expect("Branch target should be known");
+            if self.segment_of_addr(target) != Some(seg) {
+                return false;
+            }
+            let Some((j, guard)) = self.trampoline_position(i, target) else {
+                return false;
+            };
+            let len = 1 + guard as usize;
+            self.insert_words(seg, j, len, span, pointers);
+            let i = if j <= i { i + len } else { i };
+            // Target may have moved
+            let target = self.target(i).expect("Branch target should be known");
+            let hop = self.ast[j + len - 1].addr;
+            if guard {
+                self.ast[j].stmt = skip(hop.wrapping_add(1));
+            }
+            self.ast[j + len - 1].stmt = skip(target);
+            *self.ast[i].label_mut().expect("Branch should have a label") = Label::Ref(hop);
+            self.deferred.remove(&i);
+            // Trampoline now refers to the label
+            if let Some(expr) = self.refs.remove(&i) {
+                self.refs.insert(j + len - 1, expr);
+            }
+            self.diagnostics.push(
+                error::asm_relaxed(span, "to jump through a trampoline")
+                    .with_source_code(self.source.clone()),
+            );
+            return true;
+        }
+
+        // Replaced statement, which uses the pointer at the given offset from itself
+        let replace: fn(&AirStmt, u16) -> Option<Vec<AirStmt>> = |stmt, addr| {
+            let ptr = |offs| Label::Ref(addr.wrapping_add(offs));
+            Some(match *stmt {
+                AirStmt::Load { dest, .. } => vec![AirStmt::LoadInd {
+                    dest,
+                    src_label: ptr(2),
+                }],
+                AirStmt::LoadEAddr { dest, .. } => vec![AirStmt::Load {
+                    dest,
+                    src_label: ptr(2),
+                }],
+                AirStmt::Store { src_reg, .. } => vec![AirStmt::StoreInd {
+                    src_reg,
+                    dest_label: ptr(2),
+                }],
+                AirStmt::LoadInd { dest, .. } => vec![
+                    AirStmt::LoadInd {
+                        dest,
+                        src_label: ptr(3),
+                    },
+                    AirStmt::LoadOffs {
+                        dest,
+                        src_reg: dest,
+                        offset: 0,
+                    },
+                ],
+                _ => return None,
+            })
+        };
+        let addr = self.ast[i].addr;
+        let Some(stmts) = replace(&self.ast[i].stmt, addr) else {
+            return false;
+        };
+        // Skip over the pointer, and the pointer itself
+        let len = stmts.len() + 1;
+        self.insert_words(seg, i + 1, len, span, pointers);
+        let target = self.target(i).expect("Target should be known");
+        let ptr = i + len;
+        let stmts = replace(&self.ast[i].stmt, addr).expect("Statement should be replaced");
+        for (k, stmt) in stmts.into_iter().enumerate() {
+            self.ast[i + k].stmt = stmt;
+        }
+        self.ast[ptr - 1].stmt = skip(self.ast[ptr].addr.wrapping_add(1));
+        self.ast[ptr].stmt = AirStmt::RawWord {
+            val: RawWord(target),
+        };
+        pointers.push(ptr);
+        self.deferred.remove(&i);
+        if let Some(expr) = self.refs.remove(&i) {
+            self.refs.insert(ptr, expr);
+        }
+        self.diagnostics.push(
+            error::asm_relaxed(span, "to use a pointer to it")
+                .with_source_code(self.source.clone()),
+        );
+        true
+    }
+
+    /// Where a trampoline can be placed for the branch at `i`, as the index of the statement to
+    /// place it before, and whether it must be guarded by a branch over it.
+    ///
+    /// The trampoline is placed as close to the target as possible. It is only placed after an
+    /// unconditional jump, or between two instructions, so that it is not placed within data.
+    /// The trampoline must be closer to the target than any branch which cannot reach it,
+    /// including the branch at `i`, so that a chain of trampolines cannot grow without getting
+    /// any closer.
+    fn trampoline_position(&self, i: usize, target: u16) -> Option<(usize, bool)> {
+        let seg = self.segment_of_stmt(i);
+        let (start, end) = self
+            .segments()
+            .nth(seg)
+            .map(|(seg, stmts)| (seg.start, seg.start + stmts.len()))
+            .expect("Segment should exist");
+        let addr = self.ast[i].addr;
+        let forward = target > addr;
+        // Includes the branch itself, so every trampoline is closer than the branch which uses it
+        let closest = (0..self.ast.len())
+            .filter(|&k| matches!(self.ast[k].stmt, AirStmt::Branch { .. }))
+            .filter(|&k| self.target(k) == Some(target))
+            .map(|k| self.ast[k].addr)
+            .filter(|&other| !offset_fits(other, target, 9))
+            .map(|other| distance(other, target))
+            .min()
+            .unwrap_or(u16::MAX);
+
+        let mut positions = (start..=end).filter_map(|j| {
+            let prev = j.checked_sub(1).filter(|prev| *prev >= start)?;
+            let prev = &self.ast[prev];
+            let next = self.ast.get(j).filter(|_| j < end);
+            if next.is_some_and(|next| next.span == prev.span) {
+                return None;
+            }
+            if prev.is_unconditional() {
+                return Some((j, false));
+            }
+            let is_instr = |line: &AsmLine| !matches!(line.stmt, AirStmt::RawWord { .. });
+            (is_instr(prev) && next.is_some_and(is_instr)).then_some((j, true))
+        });
+        let in_range = |&(j, guard): &(usize, bool)| {
+            let len = 1 + guard as u16;
+            let place = self.addr_in_segment(seg, j);
+            let hop = place.wrapping_add(len - 1);
+            // Target is moved by a trampoline before it
+            let remaining = distance(hop, if forward { target + len } else { target });
+            // At least one statement is skipped, so that a chain of trampolines reaches the target
+            remaining < closest
+                && if forward {
+                    j > i + 1 && hop < target && offset_fits(addr, hop, 9)
+                } else {
+                    // Branch is moved by the trampoline
+                    j < i && place > target && offset_fits(addr.wrapping_add(len), hop, 9)
+                }
+        };
+        if forward {
+            positions.rfind(in_range)
+        } else {
+            positions.find(in_range)
+        }
+    }
+
+    /// Address of the statement at index `j` in a segment, or of the end of the segment.
+    fn addr_in_segment(&self, seg: usize, j: usize) -> u16 {
+        let seg = &self.segments[seg];
+        seg.orig.wrapping_add((j - seg.start) as u16)
+    }
+
+    /// Insert placeholder words before the statement at index `j` in a segment, and move every
+    /// following statement, with the labels and addresses which refer to them.
+    fn insert_words(
+        &mut self,
+        seg: usize,
+        j: usize,
+        len: usize,
+        span: Span,
+        pointers: &mut [usize],
+    ) {
+        let from = self.addr_in_segment(seg, j);
+        let end = match self.segments.get(seg + 1) {
+            Some(next) => next.start,
+            None => self.ast.len(),
+        };
+        let to = self.addr_in_segment(seg, end);
+        let moved = |addr: &mut u16| {
+            if (from..=to).contains(addr) {
+                *addr = addr.wrapping_add(len as u16);
+            }
+        };
+
+        for (name, _) in &self.labels {
+            if let Some(addr) = self.symbols.label_mut(name) {
+                moved(addr);
+            }
+        }
+        for line in &mut self.ast {
+            if let Some(Label::Ref(addr)) = line.label_mut() {
+                moved(addr);
+            }
+        }
+        for ptr in pointers.iter_mut() {
+            if let AirStmt::RawWord { val } = &mut self.ast[*ptr].stmt {
+                moved(&mut val.0);
+            }
+            if *ptr >= j {
+                *ptr += len;
+            }
+        }
+        for breakpoint in self.breakpoints.iter_mut() {
+            moved(&mut breakpoint.address);
+        }
+
+        let placeholder = AsmLine::new(from, AirStmt::RawWord { val: RawWord(0) }, span);
+        self.ast.splice(j..j, std::iter::repeat_n(placeholder, len));
+        for k in j..end + len {
+            self.ast[k].addr = self.addr_in_segment(seg, k);
+        }
+        for next in &mut self.segments[seg + 1..] {
+            next.start += len;
+        }
+        shift_keys(&mut self.deferred, j, len);
+        shift_keys(&mut self.refs, j, len);
+    }
+
+    /// Return binary representation of the program, as written to an object file.
+    ///
+    /// See [`SEGMENTS_MAGIC`] for the format.
+    pub fn emit(&self) -> Result<Vec<u16>> {
+        let mut segments = Vec::with_capacity(self.segments.len());
+        for (seg, stmts) in self.segments() {
+            let words = stmts.iter().map(AsmLine::emit).collect::<Result<_>>()?;
+            segments.push((seg.orig, words));
+        }
+        Ok(image(&segments))
+    }
+}
+
+/// Binary representation of a program, with the origin and words of each segment.
+///
+/// Empty segments are skipped. See [`SEGMENTS_MAGIC`] for the format.
+pub fn image(segments: &[(u16, Vec<u16>)]) -> Vec<u16> {
+    let segments: Vec<_> = segments
+        .iter()
+        .filter(|(_, words)| !words.is_empty())
+        .collect();
+    let len = segments
+        .iter()
+        .map(|(_, words)| words.len() + 2)
+        .sum::<usize>();
+    let mut image = Vec::with_capacity(len + 1);
+    match segments.as_slice() {
+        [] => image.push(DEFAULT_ORIG),
+        [(orig, words)] => {
+            image.push(*orig);
+            image.extend(words);
+        }
+        _ => {
+            image.push(SEGMENTS_MAGIC);
+            for (orig, words) in segments {
+                image.push(*orig);
+                image.push(words.len() as u16);
+                image.extend(words);
+            }
+        }
+    }
+    image
 }
 
 impl<'a> IntoIterator for &'a Air {
@@ -165,6 +838,52 @@ impl ImmediateOrReg {
     }
 }
 
+/// Operand which refers to labels, so cannot be evaluated until every label is known.
+#[derive(Clone, Debug)]
+pub struct Deferred {
+    pub expr: Expr,
+    pub kind: DeferredKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum DeferredKind {
+    /// Immediate value of an instruction
+    Imm(Bits),
+    /// Value of a `.fill` directive
+    Word,
+    /// Address, which is converted to an offset from the program counter
+    Address,
+}
+
+/// Error for a label which is not declared in this module.
+fn missing_label(span: Span, label: &str, symbols: &SymbolTable) -> Report {
+    if symbols.is_extern(label) {
+        error::asm_extern_unlinked(span, label)
+    } else {
+        error::asm_missing_label(span, label)
+    }
+}
+
+/// Operand which refers to a label that is only known once the program is linked.
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    /// Index of the statement
+    pub index: usize,
+    pub kind: RelocationKind,
+    pub label: String,
+    /// Value added to the address of the label
+    pub addend: i32,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RelocationKind {
+    /// Offset from the program counter, in the lowest bits of an instruction
+    Offset(u32),
+    /// Address of the label, as the value of a `.fill` directive
+    Word,
+}
+
 /// Newtype to represent 16 bits (word size of LC3) as a raw value.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct RawWord(pub u16);
@@ -172,43 +891,163 @@ pub struct RawWord(pub u16);
 /// A line (16 bits) of assembly.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct AsmLine {
-    pub line: u16,
+    /// Memory address of the statement
+    pub addr: u16,
     pub stmt: AirStmt,
     pub span: Span,
 }
 
 impl AsmLine {
-    pub fn new(line: u16, stmt: AirStmt, span: Span) -> Self {
-        AsmLine { line, stmt, span }
+    pub fn new(addr: u16, stmt: AirStmt, span: Span) -> Self {
+        AsmLine { addr, stmt, span }
     }
 
     /// Fill label references using values from symbol table
-    pub fn backpatch(&mut self) -> Result<()> {
-        let inner_label = match self.stmt {
+    pub fn backpatch(&mut self, symbols: &SymbolTable) -> Result<()> {
+        let span = self.span;
+        let Some(inner_label) = self.label_mut() else {
+            return Ok(());
+        };
+        if let Label::Unfilled(label) = inner_label {
+            let addr = symbols
+                .label(label)
+                .ok_or_else(|| missing_label(span, label, symbols))?;
+            *inner_label = Label::Ref(addr);
+        }
+        Ok(())
+    }
+
+    /// Evaluate an operand which refers to labels, and replace its placeholder value.
+    pub fn fill_deferred(&mut self, deferred: Deferred, symbols: &SymbolTable) -> Result<()> {
+        let val = deferred
+            .expr
+            .eval(&mut |name, span| match symbols.label(name) {
+                Some(addr) => Ok(addr as i32),
+                None => Err(missing_label(span, name, symbols)),
+            })?;
+        match deferred.kind {
+            DeferredKind::Imm(bits) => {
+                if !bits.contains(val) {
+                    return Err(error::expr_range(deferred.expr.span(), bits, val));
+                }
+                let val = val as u16;
+                match &mut self.stmt {
+                    AirStmt::Add { src_reg_imm, .. } | AirStmt::And { src_reg_imm, .. } => {
+                        *src_reg_imm = ImmediateOrReg::Imm5(val as u8)
+                    }
+                    AirStmt::LoadOffs { offset, .. } | AirStmt::StoreOffs { offset, .. } => {
+                        *offset = val as u8
+                    }
+                    AirStmt::Trap { trap_vect } => *trap_vect = val as u8,
+                    _ => unreachable!("Statement does not have an immediate operand"),
+                }
+            }
+            DeferredKind::Word => {
+                self.stmt = AirStmt::RawWord {
+                    val: RawWord(val as u16),
+                }
+            }
+            DeferredKind::Address => {
+                let label = self
+                    .label_mut()
+                    .expect("Statement should have a label operand");
+                *label = Label::Ref(val as u16);
+            }
+        }
+        Ok(())
+    }
+
+    /// Amount of bits in the offset of a statement with a label operand.
+    pub fn offset_bits(&self) -> Option<u32> {
+        match self.stmt {
+            AirStmt::Branch { .. }
+            | AirStmt::Load { .. }
+            | AirStmt::LoadInd { .. }
+            | AirStmt::LoadEAddr { .. }
+            | AirStmt::Store { .. }
+            | AirStmt::StoreInd { .. } => Some(9),
+            AirStmt::Call { .. } => Some(10),
+            AirStmt::JumbSub { .. } => Some(11),
+            _ => None,
+        }
+    }
+
+    /// Whether the statement is rewritten by relaxing, if its label is too far away.
+    pub fn is_relaxable(&self) -> bool {
+        matches!(
+            self.stmt,
+            AirStmt::Branch { .. }
+                | AirStmt::Load { .. }
+                | AirStmt::LoadInd { .. }
+                | AirStmt::LoadEAddr { .. }
+                | AirStmt::Store { .. }
+        )
+    }
+
+    /// Check that the filled label operand fits in the offset, suggesting `--relax` if it does
+    /// not.
+    fn check_offset_relaxable(&self) -> Result<()> {
+        if let (Some(bits), Some(Label::Ref(target))) = (self.offset_bits(), self.label()) {
+            if !offset_fits(self.addr, *target, bits) {
+                return Err(error::asm_offset_range(
+                    self.span, self.addr, *target, bits, true,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get label operand of the statement, if it has one.
+    pub(crate) fn label(&self) -> Option<&Label> {
+        match &self.stmt {
+            AirStmt::Branch { dest_label, .. }
+            | AirStmt::JumbSub { dest_label }
+            | AirStmt::Store { dest_label, .. }
+            | AirStmt::StoreInd { dest_label, .. }
+            | AirStmt::Call { dest_label } => Some(dest_label),
+            AirStmt::Load { src_label, .. }
+            | AirStmt::LoadInd { src_label, .. }
+            | AirStmt::LoadEAddr { src_label, .. } => Some(src_label),
+            _ => None,
+        }
+    }
+
+    /// Whether execution never continues to the next statement.
+    pub(crate) fn is_unconditional(&self) -> bool {
+        match self.stmt {
+            AirStmt::Branch { flag, .. } => flag == Flag::Nzp,
+            AirStmt::Jump { .. } | AirStmt::Return | AirStmt::Interrupt | AirStmt::Rets => true,
+            // Halt
+            AirStmt::Trap { trap_vect } => trap_vect == 0x25,
+            _ => false,
+        }
+    }
+
+    /// Get label operand of the statement, if it has one.
+    fn label_mut(&mut self) -> Option<&mut Label> {
+        match self.stmt {
             AirStmt::Branch {
                 ref mut dest_label, ..
-            } => dest_label,
-            AirStmt::JumbSub { ref mut dest_label } => dest_label,
+            } => Some(dest_label),
+            AirStmt::JumbSub { ref mut dest_label } => Some(dest_label),
             AirStmt::Load {
                 ref mut src_label, ..
-            } => src_label,
+            } => Some(src_label),
             AirStmt::LoadInd {
                 ref mut src_label, ..
-            } => src_label,
+            } => Some(src_label),
             AirStmt::LoadEAddr {
                 ref mut src_label, ..
-            } => src_label,
+            } => Some(src_label),
             AirStmt::Store {
                 ref mut dest_label, ..
-            } => dest_label,
+            } => Some(dest_label),
             AirStmt::StoreInd {
                 ref mut dest_label, ..
-            } => dest_label,
-            AirStmt::Call { ref mut dest_label } => dest_label,
-            _ => return Ok(()),
-        };
-        *inner_label = inner_label.clone().filled()?;
-        Ok(())
+            } => Some(dest_label),
+            AirStmt::Call { ref mut dest_label } => Some(dest_label),
+            _ => None,
+        }
     }
 
     /// Return binary representation of a statement
@@ -283,7 +1122,7 @@ impl AsmLine {
                 let mut raw = 0x6000;
                 raw |= (*dest as u16) << 9;
                 raw |= (*src_reg as u16) << 6;
-                raw |= *offset as u16;
+                raw |= (*offset as u16) & 0b111111;
                 Ok(raw)
             }
             AirStmt::LoadEAddr { dest, src_label } => {
@@ -328,7 +1167,7 @@ impl AsmLine {
                 let mut raw = 0x7000;
                 raw |= (*src_reg as u16) << 9;
                 raw |= (*dest_reg as u16) << 6;
-                raw |= *offset as u16;
+                raw |= (*offset as u16) & 0b111111;
                 Ok(raw)
             }
             // In order to be able to do push, pop, call and rets with the same instruction, a new format
@@ -376,28 +1215,43 @@ impl AsmLine {
         }
     }
 
-    /// Find offset between label reference and current line while checking bounds
+    /// Find offset between label reference and current address while checking bounds
     fn bit_offs(&self, ref_label: &Label, bits: u32) -> Result<u16> {
         let label_pos = match ref_label {
             Label::Ref(val) => val,
             Label::Unfilled(_) => panic!("Tried to offset unfilled label"),
         };
-        let (offset, _) = label_pos.overflowing_sub(self.line);
-        let offset = (offset as i16) - 1;
         // Must fit in specified offset bits
-        if offset.abs() > 2i16.pow(bits - 1) - if offset > 0 { 1 } else { 0 } {
-            bail!(
-                severity = Severity::Error,
-                r#"Difference between label and label reference is too large: at line {}, referencing line {}
-                Please note that this could be because of a long .stringz literal or large .blkw allocation."#,
-                self.line,
-                label_pos
-            )
+        if !offset_fits(self.addr, *label_pos, bits) {
+            return Err(error::asm_offset_range(
+                self.span, self.addr, *label_pos, bits, false,
+            ));
         }
-        Ok((offset as u16) & (2u16.pow(bits) - 1))
+        let offset = label_pos.wrapping_sub(self.addr).wrapping_sub(1);
+        Ok(offset & (2u16.pow(bits) - 1))
     }
 }
 
+/// Move entries for statements from index `j` onwards, after `len` statements are inserted.
+fn shift_keys<V>(map: &mut FxHashMap<usize, V>, j: usize, len: usize) {
+    *map = std::mem::take(map)
+        .into_iter()
+        .map(|(k, v)| (if k >= j { k + len } else { k }, v))
+        .collect();
+}
+
+/// Number of words which a branch at `addr` must jump over to reach `target`.
+fn distance(addr: u16, target: u16) -> u16 {
+    (target.wrapping_sub(addr.wrapping_add(1)) as i16).unsigned_abs()
+}
+
+/// Whether the offset from the statement after `addr` to `target` fits in `bits`.
+fn offset_fits(addr: u16, target: u16, bits: u32) -> bool {
+    let offset = (target.wrapping_sub(addr) as i16).wrapping_sub(1);
+    let max = 2i16.pow(bits - 1);
+    (-max..max).contains(&offset)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -416,19 +1270,18 @@ mod test {
         label jmp r0
         "#,
         )
-        .unwrap()
-        .parse()
-        .unwrap();
-        air.backpatch().unwrap();
+        .parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(air.len(), 2);
 
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
-                    dest_label: Label::Ref(2)
+                    dest_label: Label::Ref(0x3001)
                 },
                 span: Span::new(
                     SrcOffset(
@@ -444,15 +1297,302 @@ mod test {
 
     #[test]
     fn backpatch_missing() {
-        let mut air = AsmParser::new("br label").unwrap().parse().unwrap();
-        assert!(air.backpatch().is_err());
+        let mut air = AsmParser::new("br label").parse();
+        air.backpatch();
+        assert!(air.diagnostics.has_errors());
+    }
+
+    #[test]
+    fn backpatch_expressions() {
+        let mut air = AsmParser::new(
+            r#"
+        .orig x3000
+        ld r0 table+2
+        .fill table
+        .fill end-table
+        table .blkw #4
+        end add r0 r0 table-end
+        "#,
+        )
+        .parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
+        // Offset from the next statement
+        assert_eq!(air.get(0).emit().unwrap(), 0x2004);
+        assert_eq!(air.get(1).emit().unwrap(), 0x3003);
+        assert_eq!(air.get(2).emit().unwrap(), 4);
+        assert_eq!(air.get(7).emit().unwrap(), 0x103C);
+    }
+
+    #[test]
+    fn backpatch_expression_range() {
+        let mut air = AsmParser::new(
+            "trap label
+label .fill label*x10",
+        )
+        .parse();
+        air.backpatch();
+        // Address does not fit in 8 bits, and is too large to multiply
+        assert_eq!(air.diagnostics.error_count(), 2);
+    }
+
+    #[test]
+    fn backpatch_segments() {
+        let mut air = AsmParser::new(
+            r#"
+        .orig x3000
+        ldi r0 ptr
+        halt
+        ptr .fill value
+        .orig x4000
+        value .fill #7
+        .orig x3010
+        br ptr
+        "#,
+        )
+        .parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
+        // Labels are absolute addresses
+        assert_eq!(air.get(2).emit().unwrap(), 0x4000);
+        assert_eq!(air.get(4).addr, 0x3010);
+        assert_eq!(air.get(4).emit().unwrap(), 0x0FF1);
+        assert_eq!(
+            air.emit().unwrap(),
+            vec![
+                SEGMENTS_MAGIC,
+                0x3000,
+                3,
+                0xA001,
+                0xF025,
+                0x4000,
+                0x4000,
+                1,
+                7,
+                0x3010,
+                1,
+                0x0FF1,
+            ]
+        );
+    }
+
+    #[test]
+    fn backpatch_local_labels() {
+        let mut air = AsmParser::new(
+            r#"
+        first
+        .loop add r0 r0 #-1
+            brp .loop
+            br second.loop
+        second
+        .loop add r1 r1 #-1
+            brp .loop
+        .done .fill .done
+        "#,
+        )
+        .parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
+        // Each local label is scoped to the preceding global label
+        assert_eq!(air.get(1).emit().unwrap(), 0x03FE);
+        assert_eq!(air.get(2).emit().unwrap(), 0x0E00);
+        assert_eq!(air.get(4).emit().unwrap(), 0x03FE);
+        assert_eq!(air.get(5).emit().unwrap(), 0x3005);
+        assert_eq!(air.symbols.label("second.done"), Some(0x3005));
+    }
+
+    #[test]
+    fn backpatch_literal_pool() {
+        let mut air = AsmParser::with_features(
+            r#"
+        ldi16 r0 x1234
+        ldi16 r1 value
+        .pool
+        ldi16 r2 #4660
+        halt
+        value .fill #0
+        "#,
+            "sugar".parse().unwrap(),
+        )
+        .parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
+        assert_eq!(air.get(0).emit().unwrap(), 0x2001);
+        assert_eq!(air.get(1).emit().unwrap(), 0x2201);
+        assert_eq!(air.get(2).emit().unwrap(), 0x1234);
+        assert_eq!(air.get(3).emit().unwrap(), 0x3006);
+        // Pool at the end of the section
+        assert_eq!(air.get(4).emit().unwrap(), 0x2402);
+        assert_eq!(air.get(7).emit().unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn backpatch_relax() {
+        let mut air = AsmParser::new(
+            r#"
+        ld r0 value
+        brz done
+        halt
+        .blkw #250
+        halt
+        .blkw #10
+        done halt
+        value .fill #7
+        "#,
+        )
+        .parse();
+        air.set_relax();
+        air.backpatch();
+        assert!(!air.diagnostics.has_errors());
+        assert_eq!(air.diagnostics.iter().count(), 2);
+        // Load through a pointer word, which is skipped over
+        assert_eq!(air.get(0).emit().unwrap(), 0xA001);
+        assert_eq!(air.get(1).emit().unwrap(), 0x0E01);
+        assert_eq!(air.get(2).emit().unwrap(), 0x3000 + 268);
+        // Branch through a trampoline after the second halt
+        assert_eq!(air.get(3).emit().unwrap(), 0x04FC);
+        assert_eq!(air.get(255).stmt, AirStmt::Trap { trap_vect: 0x25 });
+        assert_eq!(air.get(256).emit().unwrap(), 0x0E0A);
+        assert_eq!(air.get(268).emit().unwrap(), 7);
+        assert_eq!(air.symbols.label("done"), Some(0x3000 + 267));
+    }
+
+    #[test]
+    fn backpatch_relax_no_progress() {
+        // A guarded trampoline before the branch is no closer to the target than the branch
+        for src in [
+            "A .fill #0\n.blkw #600\nand r0 r0 #0\nB add r6 r6 #-1\nbrnp A\n",
+            ".orig x3000\nA halt\n.blkw #600\nand r0 r0 #0\nadd r6 r6 #-1\nbrnp A\nhalt\n",
+        ] {
+            let mut air = AsmParser::new(src).parse();
+            air.set_relax();
+            air.backpatch();
+            let codes: Vec<_> = air
+                .diagnostics
+                .iter()
+                .filter_map(|report| Some(report.code()?.to_string()))
+                .collect();
+            assert_eq!(codes, ["asm::offset_range"], "{src}");
+            // Already relaxing
+            let help = air.diagnostics.iter().next().unwrap().help().unwrap();
+            assert!(!help.to_string().contains("--relax"));
+        }
+    }
+
+    #[test]
+    fn backpatch_offset_range_suggests_relax() {
+        // Only statements which relaxing rewrites are suggested it
+        for (instr, suggested) in [("ld r0 far", true), ("sti r0 far", false)] {
+            let src = format!("{instr}\n.blkw #300\nfar .fill #0\n");
+            let mut air = AsmParser::new(&src).parse();
+            air.backpatch();
+            assert_eq!(air.diagnostics.error_count(), 1, "{instr}");
+            let help = air.diagnostics.iter().next().unwrap().help().unwrap();
+            assert_eq!(help.to_string().contains("--relax"), suggested, "{instr}");
+        }
+    }
+
+    #[test]
+    fn backpatch_relax_shared_target() {
+        // Trampolines for both branches can only be placed before the data, so neither reaches
+        let mut air = AsmParser::new(
+            r#"
+        .orig x3000
+        brz far
+        add r0 r0 #1
+        br far
+        .blkw #260
+        far halt
+        "#,
+        )
+        .parse();
+        air.set_relax();
+        air.backpatch();
+        assert!(air.diagnostics.has_errors());
+
+        // Each branch gets a trampoline, which is closer than the one before it
+        let mut air = AsmParser::new(
+            r#"
+        brz far
+        add r0 r0 #1
+        brp far
+        halt
+        .blkw #200
+        halt
+        .blkw #100
+        far halt
+        "#,
+        )
+        .parse();
+        air.set_relax();
+        air.backpatch();
+        assert!(!air.diagnostics.has_errors());
+        assert_eq!(air.diagnostics.iter().count(), 2);
+        // Both branches jump through trampolines after the second halt
+        assert_eq!(air.get(204).stmt, AirStmt::Trap { trap_vect: 0x25 });
+        assert_eq!(air.get(0).emit().unwrap(), 0x04CC);
+        assert_eq!(air.get(2).emit().unwrap(), 0x02CB);
+        assert_eq!(air.get(205).emit().unwrap(), 0x0E65);
+        assert_eq!(air.get(206).emit().unwrap(), 0x0E64);
+    }
+
+    #[test]
+    fn backpatch_segment_errors() {
+        let mut air = AsmParser::new(
+            r#"
+        .orig x3000
+        ld r0 value
+        .blkw #4
+        .orig x3002
+        value .fill #1
+        "#,
+        )
+        .parse();
+        air.backpatch();
+        // Sections overlap
+        assert_eq!(air.diagnostics.error_count(), 1);
+
+        // Label is too far away
+        let mut air = AsmParser::new(
+            ".orig x3000
+ld r0 value
+.orig x4000
+value .fill #1",
+        )
+        .parse();
+        air.backpatch();
+        assert_eq!(air.diagnostics.error_count(), 1);
+    }
+
+    #[test]
+    fn emit_single_segment() {
+        let mut air = AsmParser::new(
+            "add r0 r0 #1
+.orig x4000
+.orig x5000
+halt",
+        )
+        .parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
+        assert_eq!(
+            air.emit().unwrap(),
+            vec![SEGMENTS_MAGIC, 0x3000, 1, 0x1021, 0x5000, 1, 0xF025]
+        );
+
+        let air = AsmParser::new(
+            ".orig x4000
+halt",
+        )
+        .parse();
+        assert_eq!(air.emit().unwrap(), vec![0x4000, 0xF025]);
     }
 
     // Code emission tests
     #[test]
     fn emit_add_reg() {
         let asm = AsmLine {
-            line: 0,
+            addr: 0,
             stmt: AirStmt::Add {
                 dest: Register::R1,
                 src_reg: Register::R2,
@@ -466,7 +1606,7 @@ mod test {
     #[test]
     fn emit_add_imm() {
         let asm = AsmLine {
-            line: 0,
+            addr: 0,
             stmt: AirStmt::Add {
                 dest: Register::R1,
                 src_reg: Register::R2,
@@ -480,7 +1620,7 @@ mod test {
     #[test]
     fn emit_label() {
         let asm = AsmLine {
-            line: 1,
+            addr: 1,
             stmt: AirStmt::Branch {
                 flag: Flag::Nzp,
                 dest_label: Label::Ref(4),
@@ -493,7 +1633,7 @@ mod test {
     #[test]
     fn emit_label_neg() {
         let asm = AsmLine {
-            line: 4,
+            addr: 4,
             stmt: AirStmt::Branch {
                 flag: Flag::Nzp,
                 dest_label: Label::Ref(1),
@@ -506,7 +1646,7 @@ mod test {
     #[test]
     fn emit_label_bad_range() {
         let asm = AsmLine {
-            line: 1,
+            addr: 1,
             stmt: AirStmt::Branch {
                 flag: Flag::Nzp,
                 dest_label: Label::Ref(258),
@@ -515,7 +1655,7 @@ mod test {
         };
         assert!(asm.emit().is_err());
         let asm = AsmLine {
-            line: 257,
+            addr: 257,
             stmt: AirStmt::Branch {
                 flag: Flag::Nzp,
                 dest_label: Label::Ref(1),
@@ -529,7 +1669,7 @@ mod test {
     #[test]
     fn emit_neg_imm() {
         let asm = AsmLine {
-            line: 1,
+            addr: 1,
             stmt: AirStmt::Add {
                 dest: Register::R4,
                 src_reg: Register::R4,
@@ -539,4 +1679,19 @@ mod test {
         };
         assert_eq!(asm.emit().unwrap(), 0x193f);
     }
+
+    // Regression
+    #[test]
+    fn emit_neg_offset() {
+        let asm = AsmLine {
+            addr: 1,
+            stmt: AirStmt::LoadOffs {
+                dest: Register::R0,
+                src_reg: Register::R1,
+                offset: (-1i8) as u8,
+            },
+            span: Span::dummy(),
+        };
+        assert_eq!(asm.emit().unwrap(), 0x607F);
+    }
 }
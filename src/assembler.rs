@@ -0,0 +1,135 @@
+//! Entry point for assembling programs as a library.
+//!
+//! ```
+//! use lace::{Assembler, Source};
+//!
+//! let air = Assembler::default().assemble(&Source::new("main add r0 r0 #1\nhalt\n"));
+//! assert!(!air.diagnostics.has_errors());
+//! assert_eq!(air.symbols.label("main"), Some(0x3000));
+//! ```
+
+use std::path::Path;
+
+use miette::Result;
+
+use crate::air::Air;
+use crate::features::Features;
+use crate::lint::{self, Lints};
+use crate::parser::{AsmParser, Define};
+use crate::source::Source;
+
+/// Settings which programs are assembled with.
+///
+/// Nothing is shared between programs: each is assembled with its own symbol table, which is kept
+/// in the resulting [`Air`] along with its source. Programs may be assembled one after another,
+/// or on several threads at once.
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+    features: Features,
+    defines: Vec<Define>,
+    relocatable: bool,
+    relax: bool,
+    lints: Lints,
+}
+
+impl Assembler {
+    pub fn new(features: Features) -> Self {
+        Assembler {
+            features,
+            ..Default::default()
+        }
+    }
+
+    /// Define constants before assembling, like `-D NAME[=VALUE]`.
+    pub fn defines(mut self, defines: &[Define]) -> Self {
+        self.defines.extend_from_slice(defines);
+        self
+    }
+
+    /// Leave labels declared with `.extern` for the linker to fill, so that the program can be
+    /// written as an object file.
+    pub fn relocatable(mut self, relocatable: bool) -> Self {
+        self.relocatable = relocatable;
+        self
+    }
+
+    /// Rewrite branches and loads whose label is too far away, instead of failing.
+    pub fn relax(mut self, relax: bool) -> Self {
+        self.relax = relax;
+        self
+    }
+
+    /// Check the program for each lint in `lints` once it is assembled without errors.
+    pub fn lints(mut self, lints: Lints) -> Self {
+        self.lints = lints;
+        self
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Parse and backpatch a program.
+    ///
+    /// Errors do not stop assembly, and are recorded in [`Air::diagnostics`] instead.
+    pub fn assemble(&self, source: &Source) -> Air {
+        let mut air = AsmParser::with_source(source, self.features, &self.defines).parse();
+        if self.relocatable {
+            air.set_relocatable();
+        }
+        if self.relax {
+            air.set_relax();
+        }
+        air.backpatch();
+        if !self.lints.is_empty() && !air.diagnostics.has_errors() {
+            lint::lint(&mut air, self.lints);
+        }
+        air
+    }
+
+    /// Read a file, and every file which it includes, then assemble it.
+    pub fn assemble_file(&self, path: &Path) -> Result<Air> {
+        Ok(self.assemble(&Source::load(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_independently() {
+        let assembler = Assembler::new("stack".parse().unwrap());
+        let first = assembler.assemble(&Source::new("main push r0\nhalt\nvalue .equ #1\n"));
+        // Same names again, which would be duplicates if the symbol table was shared
+        let second = assembler.assemble(&Source::new("halt\nmain .fill #2\nvalue .equ #3\n"));
+        assert!(!first.diagnostics.has_errors());
+        assert!(!second.diagnostics.has_errors());
+        assert_eq!(first.symbols.label("main"), Some(0x3000));
+        assert_eq!(second.symbols.label("main"), Some(0x3001));
+        assert_eq!(first.symbols.get_const("value"), Some(1));
+        assert_eq!(second.symbols.get_const("value"), Some(3));
+
+        // Features only apply to the assembler which they were given to
+        let air = Assembler::default().assemble(&Source::new("push r0\n"));
+        assert!(air.diagnostics.has_errors());
+    }
+
+    #[test]
+    fn assembles_on_threads() {
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let define = format!("OFFSET={i}").parse().unwrap();
+                    let air = Assembler::default()
+                        .defines(&[define])
+                        .assemble(&Source::new(".orig x3000 + OFFSET\nmain halt\n"));
+                    air.symbols.label("main")
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), Some(0x3000 + i as u16));
+        }
+    }
+}
@@ -1,24 +1,20 @@
 use std::ops::Range;
 
 use crate::air::AsmLine;
+use crate::source::Source;
 use crate::{dprint, DIAGNOSTIC_CONTEXT_LINES};
 
 /// Reference to assembly source code.
 ///
 /// Used by "assembly" and "break list" commands.
 pub struct AsmSource {
-    orig: u16,
     ast: Vec<AsmLine>,
-    src: &'static str,
+    source: Source,
 }
 
 impl AsmSource {
-    pub fn from(orig: u16, ast: Vec<AsmLine>, src: &'static str) -> Self {
-        Self { orig, ast, src }
-    }
-
-    pub fn orig(&self) -> u16 {
-        self.orig
+    pub fn from(ast: Vec<AsmLine>, source: Source) -> Self {
+        Self { ast, source }
     }
 
     /// Show lines surrounding instruction/directive corresponding to `address`.
@@ -32,7 +28,7 @@ impl AsmSource {
             )],
             "",
         )
-        .with_source_code(self.src);
+        .with_source_code(self.source.clone());
         eprintln!("{:?}", report);
         Some(stmt)
     }
@@ -43,7 +39,7 @@ impl AsmSource {
             return;
         };
         let range: Range<usize> = stmt.span.into();
-        let line = &self.src[range];
+        let line = &self.source.text()[range];
         dprint!(Always, Normal, "{}", line);
     }
 
@@ -51,7 +47,7 @@ impl AsmSource {
     pub fn get_single_line(&self, address: u16) -> Option<&str> {
         let stmt = self.get_source_statement(address)?;
         let range: Range<usize> = stmt.span.into();
-        let line = &self.src[range];
+        let line = &self.source.text()[range];
         Some(line)
     }
 
@@ -59,14 +55,7 @@ impl AsmSource {
     ///
     /// Used to access source code span.
     fn get_source_statement(&self, address: u16) -> Option<&AsmLine> {
-        if address < self.orig || (address - self.orig) as usize >= self.ast.len() {
-            return None;
-        };
-        let stmt = self
-            .ast
-            .get((address - self.orig) as usize)
-            .expect("index was checked to be within bounds above");
-        Some(stmt)
+        self.ast.iter().find(|stmt| stmt.addr == address)
     }
 
     /// Get memory addresses of first and last line shown in source context.
@@ -77,8 +66,10 @@ impl AsmSource {
         // Split source into characters before and after span
         // Neither string contains characters in the span, but may contain characters in the same
         // line as the instruction
-        let source_above = &self.src[..stmt_start];
-        let source_below = &self.src[stmt_end..];
+        // Context does not continue into other files
+        let file = self.source.file_range(stmt_start);
+        let source_above = &self.source.text()[file.start..stmt_start];
+        let source_below = &self.source.text()[stmt_end..file.end];
 
         let start = stmt_start - count_chars_in_lines(source_above.chars().rev());
         let end = stmt_end + count_chars_in_lines(source_below.chars());
@@ -88,26 +79,25 @@ impl AsmSource {
 
         // Get address of earliest statement, whose span is (at least partially) within `start..`
         let start_addr = {
-            let mut line = stmt.line;
+            let mut addr = stmt.addr;
             for stmt in self.ast.iter().rev() {
                 if stmt.span.end() < start {
                     break;
                 }
-                line = stmt.line;
+                addr = stmt.addr;
             }
-            // -1 applied to addresses, to account for the `line` field counting from 1, not 0
-            line + self.orig - 1
+            addr
         };
         // Get address of latest statement, whose span is (at least partially) within `..end`
         let end_addr = {
-            let mut line = stmt.line;
+            let mut addr = stmt.addr;
             for stmt in self.ast.iter() {
                 if stmt.span.offs() >= end {
                     break;
                 }
-                line = stmt.line;
+                addr = stmt.addr;
             }
-            line + self.orig - 1
+            addr
         };
 
         (start_addr, end_addr)
@@ -142,7 +132,7 @@ mod tests {
     use super::*;
     use crate::air::{AirStmt, AsmLine, ImmediateOrReg};
     use crate::symbol::{Register, Span, SrcOffset};
-    use crate::{features, AsmParser};
+    use crate::AsmParser;
 
     #[test]
     fn get_context_lines() {
@@ -191,7 +181,7 @@ fib_inner:
         let stmt_start = src.find(target).expect("target line not found in source");
 
         let stmt = AsmLine {
-            line: 7,
+            addr: 0x3006,
             span: Span::new(SrcOffset(stmt_start), target.len()),
             stmt: AirStmt::And {
                 dest: Register::R1,
@@ -200,17 +190,17 @@ fib_inner:
             },
         };
 
-        features::init("stack".parse().unwrap());
-        let parser = AsmParser::new(src).unwrap();
-        let mut air = parser.parse().unwrap();
-        air.backpatch().unwrap();
+        let parser = AsmParser::with_features(src, "stack".parse().unwrap());
+        let mut air = parser.parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
         let ast = air.ast;
 
         let orig = 0x3000;
 
-        assert_eq!(ast.get(stmt.line as usize - 1), Some(&stmt));
+        assert_eq!(ast.get((stmt.addr - orig) as usize), Some(&stmt));
 
-        let asm_source = AsmSource::from(orig, ast.clone(), src);
+        let asm_source = AsmSource::from(ast.clone(), Source::new(src));
 
         let (start, end) = asm_source.get_context_range(&stmt);
 
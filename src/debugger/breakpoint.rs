@@ -74,16 +74,6 @@ impl Breakpoints {
         initial_len != self.0.len()
     }
 
-    /// Add the `orig` address to each [`Breakpoint`] item.
-    ///
-    /// Should only be called once per program run.
-    pub fn with_orig(mut self, orig: u16) -> Self {
-        for breakpoint in &mut self.0 {
-            breakpoint.address += orig;
-        }
-        self
-    }
-
     pub fn len(&self) -> usize {
         self.0.len()
     }
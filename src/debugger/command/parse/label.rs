@@ -2,7 +2,7 @@ use super::{error, integer::Integer, CharIter, Label, TryParse};
 
 /// Returns `true` if the given character can appear at the start of a label.
 pub fn can_start_with(ch: char) -> bool {
-    matches!(ch, 'a'..='z' | 'A'..='Z' | '_')
+    matches!(ch, 'a'..='z' | 'A'..='Z' | '_' | '.')
 }
 /// Returns `true` if the given character can appear as a subsequent character of a label.
 pub fn can_contain(ch: char) -> bool {
@@ -18,8 +18,12 @@ impl<'a> TryParse<'a> for Label<'a> {
         if !chars.next().is_some_and(can_start_with) {
             return Ok(None);
         };
-        // Take characters until non-alphanumeric
-        while chars.peek().copied().is_some_and(can_contain) {
+        // Take characters until non-alphanumeric, including the `.` of a local label
+        while chars
+            .peek()
+            .copied()
+            .is_some_and(|ch| can_contain(ch) || ch == '.')
+        {
             chars.next();
         }
 
@@ -132,5 +136,7 @@ mod tests {
         expect_label("Foo-0o4", Ok(Some(Label::new("Foo", -4))));
         expect_label("Foo-#24", Ok(Some(Label::new("Foo", -24))));
         expect_label("Foo+#024", Ok(Some(Label::new("Foo", 24))));
+        expect_label("Foo.loop", Ok(Some(Label::new("Foo.loop", 0))));
+        expect_label(".loop-1", Ok(Some(Label::new(".loop", -1))));
     }
 }
@@ -2,23 +2,19 @@ use miette::Result;
 
 use crate::air::{AirStmt, AsmLine};
 use crate::runtime::RunState;
-use crate::symbol::Span;
+use crate::symbol::{Span, SymbolTable};
 use crate::{dprintln, AsmParser};
 
-pub fn eval(state: &mut RunState, line: &str) {
-    // Required to make temporarily 'static
-    // SAFETY: `line` is not used after being dropped (i.e. not returned or used in a greater
-    // scope)
-    let line_static = unsafe { &*(line as *const str) };
-    if let Err(err) = eval_inner(state, line_static) {
+pub fn eval(state: &mut RunState, line: &str, symbols: &SymbolTable) {
+    if let Err(err) = eval_inner(state, line, symbols) {
         eprintln!("{:?}", err);
     }
 }
 
 /// Wrapper to group errors into one location
-fn eval_inner(state: &mut RunState, line: &'static str) -> Result<()> {
+fn eval_inner(state: &mut RunState, line: &str, symbols: &SymbolTable) -> Result<()> {
     // Parse
-    let stmt = AsmParser::new_simple(line)?.parse_simple()?;
+    let stmt = AsmParser::new_simple(line, state.features())?.parse_simple()?;
 
     match stmt {
         // Don't allow any branch instructions
@@ -36,7 +32,7 @@ fn eval_inner(state: &mut RunState, line: &'static str) -> Result<()> {
         }
 
         // Don't allow `RTI` (interrupt) instruction
-        // Since it can only be used in supervisor mode, and it is unimplemented regardless
+        // Since it can only be used in supervisor mode, and would return to the saved PC
         AirStmt::Interrupt => {
             dprintln!(
                 Alternate,
@@ -83,13 +79,26 @@ fn eval_inner(state: &mut RunState, line: &'static str) -> Result<()> {
     }
 
     // Check labels
-    let line = state.pc() - state.orig();
-    let mut asm = AsmLine::new(line, stmt, Span::dummy());
-    asm.backpatch()?;
+    // Offsets are relative to the address after the instruction, which is the current PC
+    let addr = state.pc().wrapping_sub(1);
+    let mut asm = AsmLine::new(addr, stmt, Span::dummy());
+    asm.backpatch(symbols)?;
 
     // Compile and execute
     let instr = asm.emit()?;
-    state.execute(instr);
+    let result = match asm.stmt {
+        // Rather than jumping to its routine in OS mode
+        AirStmt::Trap { trap_vect } => state.native_trap(trap_vect as u16),
+        _ => state.execute(instr),
+    };
+    if let Err(fault) = result {
+        dprintln!(
+            Alternate,
+            Error,
+            "Fault",
+            ["Exception: {}. Instruction was not completed.", fault],
+        );
+    }
 
     Ok(())
 }
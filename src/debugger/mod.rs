@@ -8,10 +8,12 @@ use std::cmp::Ordering;
 use self::asm::AsmSource;
 use self::command::{Command, CommandReader, Label, Location, MemoryLocation};
 use crate::air::AsmLine;
+use crate::devices::Interrupt;
+use crate::dprintln;
 use crate::output::{Condition, Output};
-use crate::runtime::{RunState, HALT_ADDRESS, USER_MEMORY_END};
-use crate::symbol::with_symbol_table;
-use crate::{dprintln, features};
+use crate::runtime::{RunState, RuntimeError, HALT_ADDRESS, USER_MEMORY_END};
+use crate::source::Source;
+use crate::symbol::{Symbol, SymbolTable};
 
 pub use self::breakpoint::{Breakpoint, Breakpoints};
 
@@ -26,6 +28,8 @@ pub(super) struct Debugger {
     initial_state: RunState,
     /// Must not be mutated.
     asm_source: AsmSource,
+    /// Labels which can be referred to by commands
+    symbols: SymbolTable,
 
     command_reader: CommandReader,
     status: Status,
@@ -119,12 +123,13 @@ impl Debugger {
         initial_state: RunState,
         breakpoints: impl Into<Breakpoints>,
         ast: Vec<AsmLine>,
-        src: &'static str,
+        source: Source,
+        symbols: SymbolTable,
     ) -> Self {
-        let orig = initial_state.pc();
         Self {
             initial_state,
-            asm_source: AsmSource::from(orig, ast, src),
+            asm_source: AsmSource::from(ast, source),
+            symbols,
 
             command_reader: CommandReader::from(opts.command),
             status: Status::default(),
@@ -137,13 +142,9 @@ impl Debugger {
         }
     }
 
+    /// Lowest address of the program.
     pub(super) fn orig(&self) -> u16 {
-        debug_assert_eq!(
-            self.asm_source.orig(),
-            self.initial_state.pc(),
-            "orig values do not match between assembly and initial state",
-        );
-        self.asm_source.orig()
+        self.initial_state.orig()
     }
 
     pub(super) fn increment_instruction_count(&mut self) {
@@ -293,6 +294,45 @@ impl Debugger {
         self.current_breakpoint = None;
     }
 
+    /// Report a device interrupt, which was taken after the last instruction.
+    ///
+    /// Execution is not paused, so that the handler runs as it would without the debugger.
+    pub(super) fn interrupt_taken(&mut self, interrupt: Interrupt, handler: u16) {
+        Output::Debugger(Condition::Always, Default::default()).start_new_line();
+        match interrupt {
+            Interrupt::Keyboard => dprintln!(
+                Alternate,
+                Warning,
+                "Interrupt::Keyboard",
+                [
+                    "Interrupt taken: keyboard. Jumped to handler at 0x{:04x}.",
+                    handler
+                ],
+            ),
+            Interrupt::Timer => dprintln!(
+                Alternate,
+                Warning,
+                "Interrupt::Timer",
+                [
+                    "Interrupt taken: timer. Jumped to handler at 0x{:04x}.",
+                    handler
+                ],
+            ),
+        }
+    }
+
+    /// Report a fault of the last instruction, and wait for a command instead of exiting.
+    pub(super) fn fault(&mut self, error: &RuntimeError) {
+        Output::Debugger(Condition::Always, Default::default()).start_new_line();
+        dprintln!(
+            Alternate,
+            Error,
+            "Fault",
+            ["Exception: {}. Pausing execution.", error],
+        );
+        self.status = Status::WaitForAction;
+    }
+
     /// Read and execute the next [`Command`], returning an [`Action`] if it is raised.
     fn run_command(&mut self, state: &mut RunState) -> Option<Action> {
         assert!(
@@ -362,7 +402,7 @@ impl Debugger {
             }
 
             Command::StepOut => {
-                if !features::stack() {
+                if !state.features().stack() {
                     dprintln!(
                         Alternate,
                         Error,
@@ -439,7 +479,7 @@ impl Debugger {
             }
 
             Command::Eval { instruction } => {
-                eval::eval(state, instruction);
+                eval::eval(state, instruction, &self.symbols);
                 self.should_echo_pc = true;
             }
 
@@ -510,8 +550,7 @@ impl Debugger {
                         Output::Debugger(Condition::Always, Default::default())
                             .print_breakpoint_table(|i| {
                                 let address = self.breakpoints.nth(i)?.address;
-                                let label =
-                                    resolve_symbol_name(address - self.orig()).unwrap_or("");
+                                let label = self.symbols.label_at(address).unwrap_or("");
                                 let line = self.asm_source.get_single_line(address).unwrap_or("");
                                 Some((address, label, line))
                             });
@@ -601,9 +640,9 @@ impl Debugger {
 
     /// Returns `None` if `label` is out of bounds or an invalid label.
     fn resolve_label(&self, label: &Label) -> Option<u16> {
-        let address = resolve_symbol_address(label.name)?;
+        let address = resolve_symbol_address(&self.symbols, label.name)?;
 
-        let Some(address) = self.add_address_offset(address + self.orig(), label.offset) else {
+        let Some(address) = self.add_address_offset(address, label.offset) else {
             dprintln!(
                 Alternate,
                 Error,
@@ -659,46 +698,40 @@ impl Debugger {
 ///
 /// Label names are case-sensitive.
 /// Prints a warning if the given name only has a case-insensitive match.
-fn resolve_symbol_address(label: &str) -> Option<u16> {
-    with_symbol_table(|sym| {
-        if let Some(addr) = sym.get(label) {
-            // -1 to account for PC being incremented before instruction is executed
-            return Some(addr - 1);
-        }
+fn resolve_symbol_address(symbols: &SymbolTable, label: &str) -> Option<u16> {
+    if let Some(addr) = symbols.label(label) {
+        return Some(addr);
+    }
 
-        dprintln!(
-            Alternate,
-            Error,
-            "Labels::NotFound",
-            ["Label not found named `{}`.", label],
-        );
-        // Check for case-*insensitive* match
-        for key in sym.keys() {
-            if key.eq_ignore_ascii_case(label) {
-                dprintln!(Sometimes, Warning, "Hint: Similar label named `{}`", key);
-                break;
-            }
-        }
-        None
-    })
+    dprintln!(
+        Alternate,
+        Error,
+        "Labels::NotFound",
+        ["Label not found named `{}`.", label],
+    );
+    // Check for case-*insensitive* match, or a local label with the same name
+    let mut similar: Vec<&str> = symbols
+        .iter()
+        .filter(|(_, symbol)| matches!(symbol, Symbol::Label(_)))
+        .map(|(key, _)| key)
+        .filter(|key| is_similar_label(key, label))
+        .collect();
+    similar.sort_unstable();
+    for key in similar {
+        dprintln!(Sometimes, Warning, "Hint: Similar label named `{}`", key);
+    }
+    None
 }
 
-/// Get name of symbol with given address.
-///
-/// Returns `None` if no symbol exists at `address`.
-fn resolve_symbol_name(address: u16) -> Option<&'static str> {
-    with_symbol_table(|sym| {
-        for (label, symbol_address) in sym {
-            // +1 to account for PC being incremented before instruction is executed
-            if *symbol_address == address + 1 {
-                // SAFETY: Symbol table is statically allocated, and all keys will last until the
-                // end of the program lifetime
-                let label_static = unsafe { &*(label.as_str() as *const str) };
-                return Some(label_static);
-            }
-        }
-        None
-    })
+/// Returns `true` if `key` only differs from `label` by case, or if `key` is a local label with
+/// the same name as `label`, such as `print.loop` for `loop` or `.loop`.
+fn is_similar_label(key: &str, label: &str) -> bool {
+    if key.eq_ignore_ascii_case(label) {
+        return true;
+    }
+    let local = label.rsplit('.').next().unwrap_or(label);
+    key.rsplit_once('.')
+        .is_some_and(|(_, name)| name.eq_ignore_ascii_case(local))
 }
 
 /// Print debugger information for `help` command or `--print-help` argument.
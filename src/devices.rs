@@ -0,0 +1,268 @@
+use std::io::{stdout, Write};
+
+use crate::output::Output;
+use crate::runtime::poll_char;
+
+/// First address of device registers, which are not part of RAM.
+///
+/// Addresses from here which are not device registers are ordinary memory.
+pub const DEVICE_MEMORY: u16 = 0xFE00;
+
+/// Keyboard status register. Bit 15 is set when a character can be read from [`KBDR`], and bit 14
+/// enables keyboard interrupts.
+pub const KBSR: u16 = 0xFE00;
+/// Keyboard data register. Reading it takes the character which was typed.
+pub const KBDR: u16 = 0xFE02;
+/// Display status register. Bit 15 is set when a character can be written to [`DDR`].
+pub const DSR: u16 = 0xFE04;
+/// Display data register. Writing it prints a character.
+pub const DDR: u16 = 0xFE06;
+/// Timer status register. Bit 15 is set when the interval has elapsed, and is cleared by reading
+/// it. Bit 14 enables timer interrupts.
+pub const TMR: u16 = 0xFE08;
+/// Timer interval register. The number of instructions between each time the timer elapses, or 0
+/// to stop the timer.
+pub const TMI: u16 = 0xFE0A;
+/// Machine control register. Clearing bit 15 stops the clock, which halts the machine.
+pub const MCR: u16 = 0xFFFE;
+
+const READY: u16 = 0x8000;
+const INTERRUPT_ENABLE: u16 = 0x4000;
+const CLOCK_ENABLE: u16 = 0x8000;
+
+/// Device interrupt, which is taken through the interrupt vector table in OS mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Interrupt {
+    /// A key was typed, while `KBSR` interrupts are enabled
+    Keyboard,
+    /// The timer interval elapsed, while `TMR` interrupts are enabled
+    Timer,
+}
+
+impl Interrupt {
+    /// Entry in the interrupt vector table.
+    pub fn vector(&self) -> u16 {
+        match self {
+            Interrupt::Keyboard => 0x80,
+            Interrupt::Timer => 0x81,
+        }
+    }
+
+    /// Priority level, which must be higher than the priority of the running program for the
+    /// interrupt to be taken.
+    pub fn priority(&self) -> u16 {
+        match self {
+            Interrupt::Keyboard => 4,
+            Interrupt::Timer => 5,
+        }
+    }
+}
+
+/// Models of the devices which are mapped to memory from [`DEVICE_MEMORY`].
+///
+/// The keyboard reads from the same input as the `GETC` and `IN` traps, and the display prints
+/// to the same output as the `OUT` trap. The timer is optional, and its registers are ordinary
+/// memory unless it is enabled.
+#[derive(Clone, Debug)]
+pub(crate) struct Devices {
+    /// Character which has been typed, but not yet read from `KBDR`
+    key: Option<char>,
+    /// Last character read from `KBDR`
+    kbdr: u16,
+    kbsr_ie: bool,
+    dsr_ie: bool,
+    timer: Option<Timer>,
+    mcr: u16,
+}
+
+/// Counts instructions, to elapse after each interval.
+#[derive(Clone, Debug, Default)]
+struct Timer {
+    interval: u16,
+    count: u16,
+    elapsed: bool,
+    ie: bool,
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Devices {
+            key: None,
+            kbdr: 0,
+            kbsr_ie: false,
+            dsr_ie: false,
+            timer: None,
+            mcr: CLOCK_ENABLE,
+        }
+    }
+}
+
+impl Devices {
+    /// Value of a device register, or `None` if `addr` is not a device register.
+    pub fn read(&mut self, addr: u16) -> Option<u16> {
+        Some(match (addr, &mut self.timer) {
+            (KBSR, _) => {
+                if self.key.is_none() {
+                    self.key = poll_char();
+                }
+                bit(self.key.is_some(), READY) | bit(self.kbsr_ie, INTERRUPT_ENABLE)
+            }
+            (KBDR, _) => {
+                if let Some(key) = self.key.take() {
+                    self.kbdr = key as u16;
+                }
+                self.kbdr
+            }
+            // Output is never delayed
+            (DSR, _) => READY | bit(self.dsr_ie, INTERRUPT_ENABLE),
+            (TMR, Some(timer)) => {
+                let elapsed = std::mem::take(&mut timer.elapsed);
+                bit(elapsed, READY) | bit(timer.ie, INTERRUPT_ENABLE)
+            }
+            (TMI, Some(timer)) => timer.interval,
+            (MCR, _) => self.mcr,
+            _ => return None,
+        })
+    }
+
+    /// Write to a device register, ignoring read-only bits.
+    ///
+    /// Returns `false` if `addr` is not a device register.
+    pub fn write(&mut self, addr: u16, val: u16) -> bool {
+        match (addr, &mut self.timer) {
+            (KBSR, _) => self.kbsr_ie = val & INTERRUPT_ENABLE != 0,
+            (DSR, _) => self.dsr_ie = val & INTERRUPT_ENABLE != 0,
+            (DDR, _) => {
+                Output::Normal.print((val & 0xFF) as u8 as char);
+                stdout().flush().unwrap();
+            }
+            (TMR, Some(timer)) => timer.ie = val & INTERRUPT_ENABLE != 0,
+            (TMI, Some(timer)) => {
+                timer.interval = val;
+                timer.count = 0;
+            }
+            (MCR, _) => self.mcr = val,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Take the character which was typed while polling `KBSR`, if it has not been read from
+    /// `KBDR`, so that it is not skipped by traps which read input.
+    pub fn take_key(&mut self) -> Option<char> {
+        self.key.take()
+    }
+
+    /// Add the timer device, which is stopped until an interval is set.
+    pub fn enable_timer(&mut self) {
+        self.timer = Some(Timer::default());
+    }
+
+    /// Count an instruction towards the timer interval.
+    pub fn tick(&mut self) {
+        let Some(timer) = &mut self.timer else {
+            return;
+        };
+        if timer.interval == 0 {
+            return;
+        }
+        timer.count += 1;
+        if timer.count >= timer.interval {
+            timer.count = 0;
+            timer.elapsed = true;
+        }
+    }
+
+    /// Interrupt with the highest priority, of any device which is requesting one with a higher
+    /// priority than `priority`.
+    ///
+    /// A device keeps requesting its interrupt until it is acknowledged, by reading `KBDR` or
+    /// `TMR`. The keyboard is not polled unless it could interrupt.
+    pub fn pending(&mut self, priority: u16) -> Option<Interrupt> {
+        if let Some(timer) = &self.timer {
+            if timer.elapsed && timer.ie && Interrupt::Timer.priority() > priority {
+                return Some(Interrupt::Timer);
+            }
+        }
+        if self.kbsr_ie && Interrupt::Keyboard.priority() > priority {
+            // Input which ends does not interrupt
+            if self.key.is_none() {
+                self.key = poll_char();
+            }
+            if self.key.is_some() {
+                return Some(Interrupt::Keyboard);
+            }
+        }
+        None
+    }
+
+    /// Whether the clock of the machine is running.
+    pub fn clock_enabled(&self) -> bool {
+        self.mcr & CLOCK_ENABLE != 0
+    }
+}
+
+fn bit(set: bool, mask: u16) -> u16 {
+    if set {
+        mask
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut devices = Devices {
+            key: Some('a'),
+            ..Default::default()
+        };
+        assert_eq!(devices.read(KBSR), Some(READY));
+        assert_eq!(devices.read(KBDR), Some('a' as u16));
+        // Character is only read once, but stays in the data register
+        assert_eq!(devices.read(KBDR), Some('a' as u16));
+        assert_eq!(devices.take_key(), None);
+
+        assert!(devices.write(KBSR, 0xFFFF));
+        devices.key = Some('b');
+        assert_eq!(devices.read(KBSR), Some(READY | INTERRUPT_ENABLE));
+        assert_eq!(devices.read(DSR), Some(READY));
+
+        assert!(devices.clock_enabled());
+        assert!(devices.write(MCR, 0x7FFF));
+        assert!(!devices.clock_enabled());
+
+        assert_eq!(devices.read(TMR), None);
+        assert!(!devices.write(0xFFFF, 0));
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut devices = Devices::default();
+        devices.enable_timer();
+        assert!(devices.write(TMI, 2));
+        devices.tick();
+        assert_eq!(devices.pending(0), None);
+        devices.tick();
+        // Interrupts are not enabled
+        assert_eq!(devices.pending(0), None);
+        devices.write(TMR, INTERRUPT_ENABLE);
+        assert_eq!(devices.pending(0), Some(Interrupt::Timer));
+
+        devices.write(KBSR, INTERRUPT_ENABLE);
+        devices.key = Some('a');
+        // Timer has a higher priority, until it is acknowledged
+        assert_eq!(devices.pending(0), Some(Interrupt::Timer));
+        assert_eq!(devices.read(TMR), Some(READY | INTERRUPT_ENABLE));
+        assert_eq!(devices.read(TMR), Some(INTERRUPT_ENABLE));
+        assert_eq!(devices.pending(0), Some(Interrupt::Keyboard));
+        // Keyboard is not polled while its priority is too low
+        assert_eq!(devices.pending(4), None);
+        devices.read(KBDR);
+        devices.write(KBSR, 0);
+        assert_eq!(devices.pending(0), None);
+    }
+}
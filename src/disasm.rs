@@ -0,0 +1,485 @@
+//! Disassembler for binary programs, used by `lace disasm`.
+//!
+//! Every word is decoded, and words which are guessed to be data are written as directives:
+//! strings as `.stringz`, runs of zeros as `.blkw` and anything else as `.fill`. Words are data if
+//! they are only ever read, or if they follow an unconditional jump, such as `halt` or `ret`, up to
+//! the next address which is jumped to. Labels are taken
+//! from the symbol table if a symbol file was loaded, or otherwise made up for each address which
+//! an instruction refers to. Words which have no exact instruction form, such as an `add` with
+//! its unused bits set, are also written with `.fill`, so that the output assembles back into the
+//! same image.
+
+use std::fmt::Write;
+
+use fxhash::FxHashMap;
+use miette::Result;
+
+use crate::air::SEGMENTS_MAGIC;
+use crate::emit::Format;
+use crate::error;
+use crate::features::Features;
+use crate::symbol::{Symbol, SymbolTable};
+
+/// Shortest run of printable characters, ending with a null word, which is written as a string.
+const MIN_STRING_LEN: usize = 3;
+
+/// Operands are written after this many columns, excluding labels.
+const MNEMONIC_WIDTH: usize = 6;
+
+/// Address comments are written after this many columns, excluding labels.
+const COMMENT_COLUMN: usize = 32;
+
+/// Most times that labels are recomputed, after words which refer to them are found to be data.
+const MAX_PASSES: usize = 8;
+
+/// Decode a program, as the words of a [`Format::Lc3`] file, into assembly source.
+///
+/// Labels are named after any in `symbols`, such as from a symbol file. Instructions of the
+/// 'stack' extension are only decoded if the feature is enabled.
+pub fn disassemble(raw: &[u16], symbols: &SymbolTable, features: Features) -> Result<String> {
+    let segments = segments(raw)?;
+    let in_image = |addr: u16| {
+        segments.iter().any(|(orig, words)| {
+            (*orig as usize..*orig as usize + words.len()).contains(&(addr as usize))
+        })
+    };
+    let decoded: Vec<Vec<_>> = segments
+        .iter()
+        .map(|(orig, words)| {
+            (words.iter().enumerate())
+                .map(|(i, word)| decode(orig.wrapping_add(i as u16), *word, features))
+                .collect()
+        })
+        .collect();
+
+    // Every word is first assumed to be an instruction. Then only the words which are written as
+    // instructions refer to an address, which may change which words are data
+    let mut refs = collect_refs(decoded.iter().flatten().flatten(), in_image);
+    let mut labels = label_names(symbols, &refs, in_image);
+    let mut lines = layout(&segments, &decoded, &refs, &labels);
+    for _ in 0..MAX_PASSES {
+        let used = collect_refs(lines.iter().flatten().filter_map(Line::instr), in_image);
+        if used == refs {
+            break;
+        }
+        refs = used;
+        labels = label_names(symbols, &refs, in_image);
+        lines = layout(&segments, &decoded, &refs, &labels);
+    }
+
+    let label_width = labels
+        .values()
+        .map(|name| (name.len() + 1).next_multiple_of(4))
+        .max()
+        .unwrap_or(0)
+        .max(8);
+    let mut out = String::from("; Disassembled by lace\n");
+    for ((orig, _), lines) in segments.iter().zip(lines) {
+        writeln!(out, "\n{:label_width$}.orig x{orig:04X}", "").unwrap();
+        for line in lines {
+            let label = labels.get(&line.addr).map_or("", String::as_str);
+            let code = format!("{label:label_width$}{}", line.text(&labels));
+            let width = label_width + COMMENT_COLUMN;
+            writeln!(out, "{code:<width$} ; x{:04X}", line.addr).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+/// Statement of the disassembled source, starting at an address.
+struct Line<'a> {
+    addr: u16,
+    stmt: Stmt<'a>,
+}
+
+enum Stmt<'a> {
+    Instr(&'a Instr),
+    /// Characters of a string, without the null word
+    String(&'a [u16]),
+    Zeros(usize),
+    Fill(u16),
+}
+
+impl<'a> Line<'a> {
+    fn instr(&self) -> Option<&'a Instr> {
+        match self.stmt {
+            Stmt::Instr(instr) => Some(instr),
+            _ => None,
+        }
+    }
+
+    /// Source text of the statement, with addresses written as labels where possible.
+    fn text(&self, labels: &FxHashMap<u16, String>) -> String {
+        match self.stmt {
+            Stmt::Instr(instr) => instr.text(labels),
+            Stmt::String(chars) => {
+                let mut text = String::from(".stringz \"");
+                for ch in chars {
+                    match *ch as u8 as char {
+                        '\n' => text.push_str("\\n"),
+                        '\t' => text.push_str("\\t"),
+                        '"' => text.push_str("\\\""),
+                        '\\' => text.push_str("\\\\"),
+                        ch => text.push(ch),
+                    }
+                }
+                text.push('"');
+                text
+            }
+            Stmt::Zeros(len) => format!(".blkw #{len}"),
+            // Likely to be a pointer
+            Stmt::Fill(word) => match labels.get(&word) {
+                Some(label) => format!(".fill {label}"),
+                None => format!(".fill x{word:04X}"),
+            },
+        }
+    }
+}
+
+/// Split each segment into statements.
+fn layout<'a>(
+    segments: &[(u16, &'a [u16])],
+    decoded: &'a [Vec<Option<Instr>>],
+    refs: &FxHashMap<u16, RefKind>,
+    labels: &FxHashMap<u16, String>,
+) -> Vec<Vec<Line<'a>>> {
+    let mut layout = Vec::with_capacity(segments.len());
+    for ((orig, words), instrs) in segments.iter().zip(decoded) {
+        let mut lines = Vec::new();
+        let mut reached = true;
+        let mut i = 0;
+        while i < words.len() {
+            let addr = orig.wrapping_add(i as u16);
+            reached |= refs.get(&addr) == Some(&RefKind::Code);
+            // Directives do not continue past a label
+            let end = (i + 1..words.len())
+                .find(|j| labels.contains_key(&orig.wrapping_add(*j as u16)))
+                .unwrap_or(words.len());
+            let is_data = !reached || refs.get(&addr) == Some(&RefKind::Data);
+
+            let (stmt, len) = if let Some(len) = string_len(&words[i..end]) {
+                (Stmt::String(&words[i..i + len]), len + 1)
+            } else if let Some(len) = zeros_len(&words[i..end]) {
+                (Stmt::Zeros(len), len)
+            } else if let Some(instr) =
+                (instrs[i].as_ref()).filter(|instr| !is_data && instr.is_writable(labels))
+            {
+                reached = !instr.is_unconditional();
+                (Stmt::Instr(instr), 1)
+            } else {
+                (Stmt::Fill(words[i]), 1)
+            };
+            lines.push(Line { addr, stmt });
+            i += len;
+        }
+        layout.push(lines);
+    }
+    layout
+}
+
+/// Addresses within the program which instructions refer to.
+fn collect_refs<'a>(
+    instrs: impl Iterator<Item = &'a Instr>,
+    in_image: impl Fn(u16) -> bool,
+) -> FxHashMap<u16, RefKind> {
+    let mut refs = FxHashMap::default();
+    for (target, kind) in instrs.filter_map(|instr| instr.target) {
+        if in_image(target) {
+            let entry = refs.entry(target).or_insert(kind);
+            // A word which is jumped to is code, even if it is also read
+            if kind == RefKind::Code {
+                *entry = kind;
+            }
+        }
+    }
+    refs
+}
+
+/// How an address is referred to by an instruction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RefKind {
+    /// Jumped to with a branch or subroutine call
+    Code,
+    /// Read, written or loaded with `lea`
+    Data,
+}
+
+/// Instruction which re-encodes to the same word.
+struct Instr {
+    mnemonic: &'static str,
+    operands: Vec<String>,
+    /// Address of a PC-relative operand, which is written last
+    target: Option<(u16, RefKind)>,
+    /// Address of the instruction
+    addr: u16,
+}
+
+impl Instr {
+    fn new(addr: u16, mnemonic: &'static str, operands: Vec<String>) -> Self {
+        Instr {
+            mnemonic,
+            operands,
+            target: None,
+            addr,
+        }
+    }
+
+    fn with_target(mut self, offset: u16, bits: u32, kind: RefKind) -> Self {
+        let target = self
+            .addr
+            .wrapping_add(1)
+            .wrapping_add(sign_extend(offset, bits));
+        self.target = Some((target, kind));
+        self
+    }
+
+    /// Whether the next word is never executed after this instruction, unless it is jumped to.
+    fn is_unconditional(&self) -> bool {
+        matches!(
+            self.mnemonic,
+            "brnzp" | "jmp" | "ret" | "rti" | "halt" | "rets"
+        )
+    }
+
+    /// Whether the instruction can be written, as `call` only takes a label and not an offset.
+    fn is_writable(&self, labels: &FxHashMap<u16, String>) -> bool {
+        match self.target {
+            Some((target, _)) if self.mnemonic == "call" => labels.contains_key(&target),
+            _ => true,
+        }
+    }
+
+    /// Source text of the instruction, with its target written as a label if it has one.
+    fn text(&self, labels: &FxHashMap<u16, String>) -> String {
+        let mut operands = self.operands.clone();
+        if let Some((target, _)) = self.target {
+            operands.push(match labels.get(&target) {
+                Some(label) => label.clone(),
+                None => {
+                    let offset = target.wrapping_sub(self.addr).wrapping_sub(1) as i16;
+                    format!("#{offset}")
+                }
+            });
+        }
+        match operands.is_empty() {
+            true => self.mnemonic.to_string(),
+            false => format!("{:MNEMONIC_WIDTH$}{}", self.mnemonic, operands.join(" ")),
+        }
+    }
+}
+
+/// Decode a word as an instruction, if it has one exact form.
+fn decode(addr: u16, word: u16, features: Features) -> Option<Instr> {
+    let reg = |shift: u16| format!("r{}", (word >> shift) & 0b111);
+    let bits = |mask: u16| word & mask;
+    let instr = |mnemonic, operands| Some(Instr::new(addr, mnemonic, operands));
+
+    match word >> 12 {
+        0x0 => {
+            let mnemonic = match bits(0x0E00) >> 9 {
+                0b100 => "brn",
+                0b010 => "brz",
+                0b001 => "brp",
+                0b110 => "brnz",
+                0b011 => "brzp",
+                0b101 => "brnp",
+                0b111 => "brnzp",
+                // Never branches, and cannot be written
+                _ => return None,
+            };
+            instr(mnemonic, vec![]).map(|i| i.with_target(bits(0x1FF), 9, RefKind::Code))
+        }
+        op @ (0x1 | 0x5) => {
+            let mnemonic = if op == 0x1 { "add" } else { "and" };
+            let last = if bits(0x20) != 0 {
+                format!("#{}", sign_extend(bits(0x1F), 5) as i16)
+            } else if bits(0x18) == 0 {
+                reg(0)
+            } else {
+                return None;
+            };
+            instr(mnemonic, vec![reg(9), reg(6), last])
+        }
+        op @ (0x2 | 0x3 | 0xA | 0xB | 0xE) => {
+            let mnemonic = match op {
+                0x2 => "ld",
+                0x3 => "st",
+                0xA => "ldi",
+                0xB => "sti",
+                _ => "lea",
+            };
+            instr(mnemonic, vec![reg(9)]).map(|i| i.with_target(bits(0x1FF), 9, RefKind::Data))
+        }
+        0x4 if bits(0x0800) != 0 => {
+            instr("jsr", vec![]).map(|i| i.with_target(bits(0x7FF), 11, RefKind::Code))
+        }
+        0x4 if bits(0x0E3F) == 0 => instr("jsrr", vec![reg(6)]),
+        op @ (0x6 | 0x7) => {
+            let mnemonic = if op == 0x6 { "ldr" } else { "str" };
+            let offset = format!("#{}", sign_extend(bits(0x3F), 6) as i16);
+            instr(mnemonic, vec![reg(9), reg(6), offset])
+        }
+        0x8 if word == 0x8000 => instr("rti", vec![]),
+        0x9 if bits(0x3F) == 0x3F => instr("not", vec![reg(9), reg(6)]),
+        0xC if word == 0xC1C0 => instr("ret", vec![]),
+        0xC if bits(0x0E3F) == 0 => instr("jmp", vec![reg(6)]),
+        0xD if features.stack() => match bits(0x0C00) >> 10 {
+            0b00 if bits(0x03F) == 0 => instr("pop", vec![reg(6)]),
+            0b01 if bits(0x03F) == 0 => instr("push", vec![reg(6)]),
+            0b10 if bits(0x3FF) == 0 => instr("rets", vec![]),
+            0b11 => instr("call", vec![]).map(|i| i.with_target(bits(0x3FF), 10, RefKind::Code)),
+            _ => None,
+        },
+        0xF if bits(0x0F00) == 0 => match bits(0xFF) {
+            0x20 => instr("getc", vec![]),
+            0x21 => instr("out", vec![]),
+            0x22 => instr("puts", vec![]),
+            0x23 => instr("in", vec![]),
+            0x24 => instr("putsp", vec![]),
+            0x25 => instr("halt", vec![]),
+            0x26 => instr("putn", vec![]),
+            0x27 => instr("reg", vec![]),
+            vect => instr("trap", vec![format!("x{vect:02X}")]),
+        },
+        _ => None,
+    }
+}
+
+fn sign_extend(val: u16, bits: u32) -> u16 {
+    let shift = 16 - bits;
+    (((val << shift) as i16) >> shift) as u16
+}
+
+/// Name of each address which is referred to, or which has a label in the symbol table.
+fn label_names(
+    symbols: &SymbolTable,
+    refs: &FxHashMap<u16, RefKind>,
+    in_image: impl Fn(u16) -> bool,
+) -> FxHashMap<u16, String> {
+    let mut labels: FxHashMap<u16, String> = FxHashMap::default();
+    for (name, symbol) in symbols.iter() {
+        if let Symbol::Label(addr) = symbol {
+            // Only one name is kept for each address, chosen consistently
+            let entry = labels.entry(*addr).or_insert_with(|| name.to_string());
+            if name < entry.as_str() {
+                *entry = name.to_string();
+            }
+        }
+    }
+    labels.retain(|addr, _| in_image(*addr));
+    for (addr, kind) in refs {
+        labels.entry(*addr).or_insert_with(|| match kind {
+            RefKind::Code => format!("L{addr:04X}"),
+            RefKind::Data => format!("D{addr:04X}"),
+        });
+    }
+    labels
+}
+
+/// Length of a run of printable characters, which is followed by a null word.
+fn string_len(words: &[u16]) -> Option<usize> {
+    let len = words
+        .iter()
+        .take_while(|word| matches!(**word, 0x20..=0x7E | 0x09 | 0x0A))
+        .count();
+    (len >= MIN_STRING_LEN && words.get(len) == Some(&0)).then_some(len)
+}
+
+/// Length of a run of more than one zero word.
+fn zeros_len(words: &[u16]) -> Option<usize> {
+    let len = words.iter().take_while(|word| **word == 0).count();
+    (len > 1).then_some(len)
+}
+
+/// Origin and words of each segment. See [`SEGMENTS_MAGIC`] for the format.
+fn segments(raw: &[u16]) -> Result<Vec<(u16, &[u16])>> {
+    let malformed = || error::load_malformed(Format::Lc3, "segment table");
+    match raw {
+        [SEGMENTS_MAGIC, rest @ ..] if !rest.is_empty() => {
+            let mut rest = rest;
+            let mut segments = Vec::new();
+            while let [orig, len, words @ ..] = rest {
+                let len = *len as usize;
+                let words = words.get(..len).ok_or_else(malformed)?;
+                segments.push((*orig, words));
+                rest = &rest[2 + len..];
+            }
+            match rest.is_empty() {
+                true => Ok(segments),
+                false => Err(malformed()),
+            }
+        }
+        [orig, words @ ..] => Ok(vec![(*orig, words)]),
+        [] => Err(malformed()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, Source};
+
+    /// Disassemble a program, then check that the output assembles to the same image.
+    fn round_trip(src: &str, features: Features) -> String {
+        let assembler = Assembler::new(features);
+        let air = assembler.assemble(&Source::new(src));
+        assert!(air.diagnostics.is_empty());
+        let image = air.emit().unwrap();
+
+        let text = disassemble(&image, &SymbolTable::default(), features).unwrap();
+        let air = assembler.assemble(&Source::new(text.as_str()));
+        for report in air.diagnostics.iter() {
+            eprintln!("{report:?}");
+        }
+        assert!(air.diagnostics.is_empty(), "{text}");
+        assert_eq!(air.emit().unwrap(), image, "{text}");
+        text
+    }
+
+    #[test]
+    fn disassemble_round_trip() {
+        let text = round_trip(
+            ".orig x3000\n\
+             lea r0 msg\nputs\nld r1 value\nadd r1 r1 #-3\nbrp #-20\nand r2 r2 r3\n\
+             not r4 r5\nldr r0 r6 #-1\nstr r0 r6 #31\njsr sub\ntrap x30\nbrn int\njmp r2\n\
+             sub ret\nint rti\nvalue .fill #5\nptr .fill value\nmsg .stringz \"Hi \\\"you\\\"\\n\"\n.blkw #4\n\
+             .fill x9000\n.fill x1018\n.orig x4000\nsti r0 ptr2\nptr2 .fill xFFFF\n",
+            Features::default(),
+        );
+        assert!(text.contains("        .orig x3000"));
+        assert!(text.contains("\nD300F   .fill x0005"));
+        // Not reached after `ret`
+        assert!(text.contains("        .fill D300F"));
+        assert!(text.contains("lea   r0 D3011"));
+        assert!(text.contains("brp   #-20"));
+        assert!(text.contains("jsr   L300D"));
+        assert!(text.contains("brn   L300E"));
+        assert!(text.contains(".stringz \"Hi \\\"you\\\"\\n\""));
+        assert!(text.contains(".blkw #4"));
+        // Cannot be written as instructions
+        assert!(text.contains(".fill x9000"));
+        assert!(text.contains(".fill x1018"));
+        assert!(text.contains("trap  x30"));
+        assert!(text.contains("        .orig x4000"));
+    }
+
+    #[test]
+    fn disassemble_stack() {
+        let text = round_trip(
+            "push r1\npop r2\ncall sub\n.fill xDC10\nhalt\nsub rets\n",
+            "stack".parse().unwrap(),
+        );
+        assert!(text.contains("push  r1"));
+        assert!(text.contains("call  L3005"));
+        // Target is outside of the image, so has no label
+        assert!(text.contains(".fill xDC10"));
+    }
+
+    #[test]
+    fn disassemble_symbols() {
+        let symbols = crate::read_symbols("//\t----  ----\n//\tloop  3000\n").unwrap();
+        let text = disassemble(&[0x3000, 0x1021, 0x0FFE], &symbols, Features::default()).unwrap();
+        assert!(text.contains("\nloop    add   r0 r0 #1"));
+        assert!(text.contains("brnzp loop"));
+    }
+}
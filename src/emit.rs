@@ -0,0 +1,402 @@
+//! Formats which an assembled program can be written in, with `lace compile --emit`.
+//!
+//! Every format can also be loaded by `lace run`, as text formats are expected by some autograders
+//! and lab boards, and lc3tools has its own object format.
+
+use std::fmt::{self, Display, Write};
+use std::str::FromStr;
+
+use miette::Result;
+
+use crate::air::{self, Air, AsmLine};
+use crate::error;
+
+/// First bytes of an lc3tools object file, followed by the version.
+const LC3TOOLS_MAGIC: &[u8] = b"\x1c\x30\x15\xc0\x01\x01\x01";
+
+/// Maximum amount of words in each Intel HEX data record.
+const IHEX_RECORD_WORDS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Format {
+    /// Big-endian words, starting with the origin. See [`air::SEGMENTS_MAGIC`]
+    #[default]
+    Lc3,
+    /// Same words as [`Format::Lc3`], written in hex with one word per line
+    Hex,
+    /// Same words as [`Format::Lc3`], written in binary with one word per line, like lc3tools
+    Bin,
+    /// Intel HEX records, with two bytes at each address
+    Ihex,
+    /// Object file read by the lc3tools simulator
+    Lc3toolsObj,
+}
+
+impl Format {
+    /// Extension of a file written in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Lc3 => "lc3",
+            Format::Hex => "hex",
+            Format::Bin => "bin",
+            Format::Ihex => "ihex",
+            Format::Lc3toolsObj => "obj",
+        }
+    }
+
+    /// Format of a program file, from its header, or otherwise its extension.
+    ///
+    /// Returns `None` if the extension is not known. Files from other LC3 assemblers use `.obj`
+    /// for the same layout as [`Format::Lc3`].
+    pub fn detect(extension: &str, bytes: &[u8]) -> Option<Format> {
+        if bytes.starts_with(LC3TOOLS_MAGIC) {
+            return Some(Format::Lc3toolsObj);
+        }
+        match extension {
+            "lc3" | "obj" => Some(Format::Lc3),
+            "hex" if bytes.starts_with(b":") => Some(Format::Ihex),
+            "hex" => Some(Format::Hex),
+            "bin" => Some(Format::Bin),
+            "ihex" => Some(Format::Ihex),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Ok(match string {
+            "lc3" => Format::Lc3,
+            "hex" => Format::Hex,
+            "bin" => Format::Bin,
+            "ihex" => Format::Ihex,
+            "lc3tools-obj" => Format::Lc3toolsObj,
+            _ => {
+                return Err(format!(
+                    "Unknown format '{string}', expected lc3, hex, bin, ihex or lc3tools-obj"
+                ))
+            }
+        })
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Lc3 => "lc3",
+            Format::Hex => "hex",
+            Format::Bin => "bin",
+            Format::Ihex => "ihex",
+            Format::Lc3toolsObj => "lc3tools-obj",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Write a program in a format.
+///
+/// Fails if a statement cannot be emitted, so the program must be backpatched first.
+pub fn encode(air: &Air, format: Format) -> Result<Vec<u8>> {
+    let bytes = match format {
+        Format::Lc3 => air.emit()?.iter().flat_map(|w| w.to_be_bytes()).collect(),
+        Format::Hex => text_words(&air.emit()?, |w| format!("{w:04X}")),
+        Format::Bin => text_words(&air.emit()?, |w| format!("{w:016b}")),
+        Format::Ihex => {
+            let mut segments = Vec::new();
+            for (orig, stmts) in nonempty_segments(air) {
+                let words = stmts.iter().map(AsmLine::emit).collect::<Result<_>>()?;
+                segments.push((orig, words));
+            }
+            ihex(&segments).into_bytes()
+        }
+        Format::Lc3toolsObj => lc3tools_obj(air)?,
+    };
+    Ok(bytes)
+}
+
+/// Read a program, as the words of a [`Format::Lc3`] file.
+pub fn decode(bytes: &[u8], format: Format) -> Result<Vec<u16>> {
+    match format {
+        Format::Lc3 => {
+            if !bytes.len().is_multiple_of(2) {
+                return Err(error::load_malformed(format, "end of file"));
+            }
+            Ok(bytes
+                .chunks_exact(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                .collect())
+        }
+        Format::Hex => read_text_words(bytes, format, 16),
+        Format::Bin => read_text_words(bytes, format, 2),
+        Format::Ihex => read_ihex(bytes).map(|segments| air::image(&segments)),
+        Format::Lc3toolsObj => read_lc3tools_obj(bytes).map(|segments| air::image(&segments)),
+    }
+}
+
+/// Origin and statements of each segment which contains any statements.
+fn nonempty_segments(air: &Air) -> impl Iterator<Item = (u16, &[AsmLine])> {
+    air.segments()
+        .filter(|(_, stmts)| !stmts.is_empty())
+        .map(|(seg, stmts)| (seg.orig, stmts))
+}
+
+/// Write each word on its own line.
+fn text_words(words: &[u16], format: impl Fn(u16) -> String) -> Vec<u8> {
+    let mut out = String::new();
+    for word in words {
+        writeln!(out, "{}", format(*word)).unwrap();
+    }
+    out.into_bytes()
+}
+
+/// Read a word from each non-empty line.
+fn read_text_words(bytes: &[u8], format: Format, radix: u32) -> Result<Vec<u16>> {
+    let text = std::str::from_utf8(bytes).map_err(|_| error::load_malformed(format, "line 1"))?;
+    let mut words = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let word = u16::from_str_radix(line, radix)
+            .map_err(|_| error::load_malformed(format, &format!("line {}", i + 1)))?;
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Write Intel HEX data records for each segment, with each word stored big-endian.
+///
+/// Memory is addressed by byte, so addresses from x8000 are written after an extended linear
+/// address record.
+fn ihex(segments: &[(u16, Vec<u16>)]) -> String {
+    let mut out = String::new();
+    let mut upper = 0;
+    for (orig, words) in segments {
+        let mut addr = *orig as u32 * 2;
+        // A record which crosses into the next 64K bytes is split in two
+        for chunk in words.chunks(IHEX_RECORD_WORDS) {
+            if addr >> 16 != upper {
+                upper = addr >> 16;
+                ihex_record(&mut out, 0, 0x04, &(upper as u16).to_be_bytes());
+            }
+            let data: Vec<u8> = chunk.iter().flat_map(|w| w.to_be_bytes()).collect();
+            let split = (0x10000 - (addr & 0xFFFF)) as usize;
+            if split < data.len() {
+                ihex_record(&mut out, addr as u16, 0x00, &data[..split]);
+                upper += 1;
+                ihex_record(&mut out, 0, 0x04, &(upper as u16).to_be_bytes());
+                ihex_record(&mut out, 0, 0x00, &data[split..]);
+            } else {
+                ihex_record(&mut out, addr as u16, 0x00, &data);
+            }
+            addr += data.len() as u32;
+        }
+    }
+    ihex_record(&mut out, 0, 0x01, &[]);
+    out
+}
+
+fn ihex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    out.push(':');
+    for byte in bytes.iter().chain([&checksum]) {
+        write!(out, "{byte:02X}").unwrap();
+    }
+    out.push('\n');
+}
+
+/// Read the segments of an Intel HEX file. Contiguous data records are joined into one segment.
+fn read_ihex(bytes: &[u8]) -> Result<Vec<(u16, Vec<u16>)>> {
+    let text =
+        std::str::from_utf8(bytes).map_err(|_| error::load_malformed(Format::Ihex, "line 1"))?;
+    let mut segments: Vec<(u16, Vec<u16>)> = Vec::new();
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let malformed = || error::load_malformed(Format::Ihex, &format!("line {}", i + 1));
+        let record = line
+            .strip_prefix(':')
+            .filter(|hex| hex.len() % 2 == 0 && hex.len() >= 10)
+            .and_then(|hex| {
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|j| u8::from_str_radix(&hex[j..j + 2], 16).ok())
+                    .collect::<Option<Vec<u8>>>()
+            })
+            .ok_or_else(malformed)?;
+        let len = record[0] as usize;
+        if record.len() != len + 5 || record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(malformed());
+        }
+        let addr = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..4 + len];
+        match record[3] {
+            0x00 => {
+                let addr = base + addr;
+                if !addr.is_multiple_of(2)
+                    || !len.is_multiple_of(2)
+                    || addr / 2 + len as u32 / 2 > 0x10000
+                {
+                    return Err(malformed());
+                }
+                let orig = (addr / 2) as u16;
+                let words = data
+                    .chunks_exact(2)
+                    .map(|w| u16::from_be_bytes([w[0], w[1]]));
+                match segments.last_mut() {
+                    Some((last, last_words))
+                        if *last as usize + last_words.len() == orig as usize =>
+                    {
+                        last_words.extend(words)
+                    }
+                    _ => segments.push((orig, words.collect())),
+                }
+            }
+            0x01 => break,
+            0x02 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start addresses are not used, as the program starts at the first segment
+            0x03 | 0x05 => {}
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(segments)
+}
+
+/// Write an lc3tools object file.
+///
+/// Each word is written little-endian, followed by whether it is an origin, and the source line
+/// it was assembled from.
+fn lc3tools_obj(air: &Air) -> Result<Vec<u8>> {
+    let mut out = LC3TOOLS_MAGIC.to_vec();
+    let mut entry = |word: u16, is_orig: bool, line: &str| {
+        out.extend(word.to_le_bytes());
+        out.push(is_orig as u8);
+        out.extend((line.len() as u32).to_le_bytes());
+        out.extend(line.as_bytes());
+    };
+    for (orig, stmts) in nonempty_segments(air) {
+        entry(orig, true, &format!(".orig x{orig:04X}"));
+        for (i, stmt) in stmts.iter().enumerate() {
+            // Source is only written for the first word of a statement
+            let line = match i == 0 || stmts[i - 1].span != stmt.span {
+                true => &air.source.text()[stmt.span.as_range()],
+                false => "",
+            };
+            entry(stmt.emit()?, false, line);
+        }
+    }
+    Ok(out)
+}
+
+/// Read the segments of an lc3tools object file. Each origin starts a new segment.
+fn read_lc3tools_obj(bytes: &[u8]) -> Result<Vec<(u16, Vec<u16>)>> {
+    let mut rest = bytes
+        .strip_prefix(LC3TOOLS_MAGIC)
+        .ok_or_else(|| error::load_malformed(Format::Lc3toolsObj, "header"))?;
+    let mut segments: Vec<(u16, Vec<u16>)> = Vec::new();
+    while !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        let malformed = || error::load_malformed(Format::Lc3toolsObj, &format!("byte {offset}"));
+        let [w0, w1, is_orig, l0, l1, l2, l3, after @ ..] = rest else {
+            return Err(malformed());
+        };
+        let len = u32::from_le_bytes([*l0, *l1, *l2, *l3]) as usize;
+        if after.len() < len {
+            return Err(malformed());
+        }
+        rest = &after[len..];
+
+        let word = u16::from_le_bytes([*w0, *w1]);
+        match (*is_orig != 0, segments.last_mut()) {
+            (true, _) => segments.push((word, Vec::new())),
+            (false, Some((_, words))) => words.push(word),
+            (false, None) => return Err(malformed()),
+        }
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AsmParser;
+
+    fn assemble(src: &'static str) -> Air {
+        let mut air = AsmParser::new(src).parse();
+        air.backpatch();
+        assert!(air.diagnostics.is_empty());
+        air
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let air = assemble(".orig x3000\nlea r0 msg\nputs\nhalt\nmsg .stringz \"hi\"\n.orig x7FFE\n.fill #1\n.fill #2\n.fill #3\n");
+        let image = air.emit().unwrap();
+        for format in [
+            Format::Lc3,
+            Format::Hex,
+            Format::Bin,
+            Format::Ihex,
+            Format::Lc3toolsObj,
+        ] {
+            let bytes = encode(&air, format).unwrap();
+            assert_eq!(decode(&bytes, format).unwrap(), image, "{format}");
+            let detected = Format::detect(format.extension(), &bytes);
+            assert_eq!(detected, Some(format));
+        }
+    }
+
+    #[test]
+    fn text_formats() {
+        let air = assemble("add r0 r0 #1\n");
+        let hex = encode(&air, Format::Hex).unwrap();
+        assert_eq!(String::from_utf8(hex).unwrap(), "3000\n1021\n");
+        let bin = encode(&air, Format::Bin).unwrap();
+        assert_eq!(
+            String::from_utf8(bin).unwrap(),
+            "0011000000000000\n0001000000100001\n"
+        );
+        let ihex = encode(&air, Format::Ihex).unwrap();
+        assert_eq!(
+            String::from_utf8(ihex).unwrap(),
+            ":0260000010216D\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn ihex_crosses_64k() {
+        let segments = vec![(0x7FFE, (0..4).collect())];
+        let text = ihex(&segments);
+        assert_eq!(
+            text,
+            ":04FFFC000000000100\n:020000040001F9\n:0400000000020003F7\n:00000001FF\n"
+        );
+        assert_eq!(read_ihex(text.as_bytes()).unwrap(), segments);
+    }
+
+    #[test]
+    fn decode_malformed() {
+        assert!(decode(b"\x30", Format::Lc3).is_err());
+        assert!(decode(b"3000\nxyz\n", Format::Hex).is_err());
+        assert!(decode(b"0011000000000002\n", Format::Bin).is_err());
+        assert!(decode(b":02600000102100\n", Format::Ihex).is_err());
+        assert!(decode(
+            b"\x1c\x30\x15\xc0\x01\x01\x01\x00\x30\x00\x05",
+            Format::Lc3toolsObj
+        )
+        .is_err());
+    }
+}
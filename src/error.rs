@@ -1,60 +1,222 @@
+use std::fmt;
 use std::num::ParseIntError;
 
-use miette::{miette, LabeledSpan, Report, Severity};
+use miette::{miette, Diagnostic, LabeledSpan, Report, Severity, SourceCode};
 
 use crate::{
+    emit::Format,
     lexer::{Token, TokenKind},
     parser::Bits,
+    runtime::MEMORY_MAX,
+    source::{IncludeError, Source},
     symbol::Span,
 };
 
+/// Every diagnostic collected while assembling a source file.
+///
+/// The lexer, parser and backpatcher recover from errors instead of stopping, so that all problems
+/// in a file are reported together.
+#[derive(Debug, Default)]
+pub struct Diagnostics(Vec<Report>);
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, report: Report) {
+        self.0.push(report);
+    }
+
+    /// Amount of diagnostics which should prevent the program from being assembled.
+    pub fn error_count(&self) -> usize {
+        self.iter().filter(|report| is_error(report)).count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.iter()
+            .filter(|report| report.severity() == Some(Severity::Warning))
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.iter().any(is_error)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Report> {
+        self.0.iter()
+    }
+
+    /// Replace the source code of every diagnostic, so that they show which file they belong to.
+    pub fn set_source(&mut self, source: &Source) {
+        self.0 = std::mem::take(&mut self.0)
+            .into_iter()
+            .map(|report| {
+                Report::new(WithSource {
+                    report,
+                    source: source.clone(),
+                })
+            })
+            .collect();
+    }
+}
+
+/// Diagnostic with its source code replaced.
+///
+/// Unlike [`Report::with_source_code`], this takes precedence over any existing source code.
+struct WithSource {
+    report: Report,
+    source: Source,
+}
+
+impl fmt::Debug for WithSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.report, f)
+    }
+}
+
+impl fmt::Display for WithSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.report, f)
+    }
+}
+
+impl std::error::Error for WithSource {}
+
+impl Diagnostic for WithSource {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.report.code()
+    }
+
+    fn severity(&self) -> Option<Severity> {
+        self.report.severity()
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.report.help()
+    }
+
+    fn url<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.report.url()
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.source)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        self.report.labels()
+    }
+}
+
+/// Reports without a severity are treated as errors by miette.
+fn is_error(report: &Report) -> bool {
+    matches!(report.severity(), None | Some(Severity::Error))
+}
+
+/// Summary of diagnostic counts, such as `2 errors, 1 warning`.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        let errors = self.error_count();
+        let warnings = self.warning_count();
+        write!(
+            f,
+            "{} error{}, {} warning{}",
+            errors,
+            plural(errors),
+            warnings,
+            plural(warnings)
+        )
+    }
+}
+
+/// Label a span, and the macro call which it was expanded from, if any.
+fn labels(span: Span, label: impl Into<String>) -> Vec<LabeledSpan> {
+    let mut labels = vec![LabeledSpan::at(span, label.into())];
+    if let Some(call) = span.call_site() {
+        // Arguments are already labelled at the call site
+        if !(call.offs() <= span.offs() && span.end() <= call.end()) {
+            labels.push(LabeledSpan::at(call, "in this macro expansion"));
+        }
+    }
+    labels
+}
+
 // Lexer errors
 
-pub fn lex_invalid_dir(span: Span, src: &'static str) -> Report {
+pub fn lex_invalid_dir(span: Span, src: &str) -> Report {
     miette!(
         severity = Severity::Error,
         code = "lex::dir",
         help = "check the list of available directives in the documentation.",
-        labels = vec![LabeledSpan::at(span, "incorrect directive")],
+        labels = labels(span, "incorrect directive"),
         "Encountered an invalid directive.",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn lex_unclosed_str(span: Span, src: &'static str) -> Report {
+pub fn lex_unclosed_str(span: Span, src: &str) -> Report {
     miette!(
         severity = Severity::Error,
         code = "lex::str_lit",
         help = "make sure to close string literals with a \" character.",
-        labels = vec![LabeledSpan::at(span, "incorrect literal")],
+        labels = labels(span, "incorrect literal"),
         "Encountered an unterminated string literal.",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
+}
+
+pub fn lex_invalid_char(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "lex::char_lit",
+        help =
+            "character literals contain a single character or escape sequence, like 'A' or '\\n'",
+        labels = labels(span, "incorrect literal"),
+        "Encountered an invalid character literal",
+    )
+    .with_source_code(src.to_string())
 }
 
-pub fn lex_invalid_lit(span: Span, src: &'static str, e: ParseIntError) -> Report {
+pub fn lex_invalid_escape(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "lex::escape",
+        help = "available escape sequences are \\n, \\t, \\r, \\0, \\e, \\\\, \\\", \\' and \\x followed by two hex digits",
+        labels = labels(span, "invalid escape sequence"),
+        "Encountered an invalid escape sequence",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn lex_invalid_lit(span: Span, src: &str, e: ParseIntError) -> Report {
     miette!(
         severity = Severity::Error,
         code = "lex::bad_lit",
         help = "ranges from -32,768 to 32,767 or 0 to 65,535 are allowed",
-        labels = vec![LabeledSpan::at(span, "incorrect literal")],
+        labels = labels(span, "incorrect literal"),
         "Encountered an invalid literal: {e}",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn lex_unknown(span: Span, src: &'static str) -> Report {
+pub fn lex_unknown(span: Span, src: &str) -> Report {
     miette!(
         severity = Severity::Error,
         code = "lex::unknown",
         help = "make sure that your int literals start with #",
-        labels = vec![LabeledSpan::at(span, "unknown token")],
+        labels = labels(span, "unknown token"),
         "Encountered an unknown token",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn lex_stack_extension_not_enabled(instr: &str, span: Span, src: &'static str) -> Report {
+pub fn lex_stack_extension_not_enabled(instr: &str, span: Span, src: &str) -> Report {
     miette!(
         severity = Severity::Error,
         code = "lex::stack_extension_not_enabled",
@@ -63,16 +225,16 @@ pub fn lex_stack_extension_not_enabled(instr: &str, span: Span, src: &'static st
         run with `-f stack` to enable feature\n\
         note: this identifier cannot be used as a label\
         ",
-        labels = vec![LabeledSpan::at(span, "non-standard instruction")],
+        labels = labels(span, "non-standard instruction"),
         "Non-standard '{}' instruction used without 'stack' extension enabled",
         instr
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
 // Preprocessor errors
 
-pub fn preproc_bad_lit(span: Span, src: &'static str, is_present: bool) -> Report {
+pub fn preproc_bad_lit(span: Span, src: &str, is_present: bool) -> Report {
     let (help, label, severity) = if is_present {
         (
             "you may have meant to use a positive literal",
@@ -90,37 +252,279 @@ pub fn preproc_bad_lit(span: Span, src: &'static str, is_present: bool) -> Repor
         severity = severity,
         code = "preproc::bad_lit",
         help = help,
-        labels = vec![LabeledSpan::at(span, label)],
+        labels = labels(span, label),
         "Expected valid integer or hex literal",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn preproc_no_str(span: Span, src: &'static str) -> Report {
+pub fn preproc_no_str(span: Span, src: &str) -> Report {
     miette!(
         severity = Severity::Error,
         code = "preproc::stringz",
         help = ".stringz requires a valid string literal like \"hello\\n\"",
-        labels = vec![LabeledSpan::at(span, "not a string literal")],
+        labels = labels(span, "not a string literal"),
         "Expected a valid string literal",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_include_failed(span: Span, src: &str, path: &str, err: &IncludeError) -> Report {
+    let (help, label) = match err {
+        IncludeError::Read(reason) => (
+            format!("could not read file: {reason}"),
+            "file could not be included",
+        ),
+        IncludeError::Cycle => (
+            "files may not include themselves, directly or indirectly".to_string(),
+            "include cycle",
+        ),
+    };
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::include",
+        help = help,
+        labels = labels(span, label),
+        "Failed to include {path}",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_incbin_failed(span: Span, src: &str, path: &str, reason: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::incbin",
+        help = format!("could not read file: {reason}"),
+        labels = labels(span, "file could not be embedded"),
+        "Failed to embed {path}",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_incbin_odd(span: Span, src: &str, path: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::incbin_odd",
+        help = "binary files are embedded as big-endian words\nuse .incbin \"path\" text to embed each character of a text file",
+        labels = labels(span, "odd number of bytes"),
+        "File {path} is not aligned to 16 bits",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_include_unresolved(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::include_unresolved",
+        help = "write .include \"path\" on its own line, in a file which was read from disk",
+        labels = labels(span, "unresolved include"),
+        "Could not resolve included file",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_unterminated(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_unterminated",
+        help = "end the macro definition with .endm",
+        labels = labels(span, "macro defined here"),
+        "Macro definition is never terminated",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_unmatched_end(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_unmatched_end",
+        help = "start a macro definition with .macro NAME PARAMS...",
+        labels = labels(span, "unmatched directive"),
+        "Found .endm outside of a macro definition",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_position(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_position",
+        help = "write .macro NAME PARAMS... and .endm on their own lines, without a label",
+        labels = labels(span, "directive after start of line"),
+        "Macro directives must begin a line",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_nested(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_nested",
+        help = "define macros outside of other macros; they may still be called within one",
+        labels = labels(span, "nested definition"),
+        "Macro definitions cannot be nested",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_name(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_name",
+        help = "macro definitions look like: .macro NAME PARAMS...",
+        labels = labels(span, "expected a name"),
+        "Macro names and parameters must be labels",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_duplicate(span: Span, src: &str, name: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_duplicate",
+        help = "macros and their parameters must have unique names",
+        labels = labels(span, "duplicate name"),
+        "Name `{name}` is defined twice",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_args(span: Span, src: &str, expected: usize, found: usize) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_args",
+        help = "each argument must be a single register, literal or label",
+        labels = labels(span, format!("expected {expected} arguments")),
+        "Macro called with {found} arguments, but it takes {expected}",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_macro_recursion(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::macro_recursion",
+        help = "macros may call other macros, but not themselves",
+        labels = labels(span, "recursive call"),
+        "Macro calls itself, directly or indirectly",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_const_name(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::const_name",
+        help = "constants are defined like: NAME .equ #10",
+        labels = labels(span, "expected a name"),
+        "Constant definition requires a label as its name",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_const_duplicate(span: Span, src: &str, name: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::const_duplicate",
+        help = "only constants defined with .set may be defined again with .set",
+        labels = labels(span, "duplicate constant"),
+        "Constant `{name}` is already defined",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_cond_position(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::cond_position",
+        help = "write .if, .ifdef, .ifndef, .else and .endif on their own line, without a label",
+        labels = labels(span, "conditional directive"),
+        "Conditional directives must begin a line",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_cond_name(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::cond_name",
+        help = "conditions on a name look like: .ifdef NAME",
+        labels = labels(span, "expected a name"),
+        "Conditional directive requires the name of a constant",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_cond_unmatched(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::cond_unmatched",
+        help = "start a conditional block with .if, .ifdef or .ifndef",
+        labels = labels(span, "unmatched directive"),
+        "Found .else or .endif outside of a conditional block",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_cond_else(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::cond_else",
+        help = "nest another .if inside the .else block to test another condition",
+        labels = labels(span, "second .else"),
+        "Conditional block has more than one .else",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn preproc_cond_unterminated(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "preproc::cond_unterminated",
+        help = "end the conditional block with .endif",
+        labels = labels(span, "block starts here"),
+        "Conditional block is never terminated",
+    )
+    .with_source_code(src.to_string())
 }
 
 // Parser errors
 
-pub fn parse_duplicate_label(span: Span, src: &'static str) -> Report {
+pub fn parse_extern_duplicate(span: Span, src: &str, name: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "parse::extern_duplicate",
+        help = "a label declared with .extern must not be declared or defined in this module",
+        labels = labels(span, "duplicate symbol"),
+        "Symbol `{name}` is already defined",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn parse_invalid_dir(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "parse::dir",
+        help = "check the list of available directives in the documentation. Local labels \
+                must be followed by a statement or the end of the line.",
+        labels = labels(span, "incorrect directive"),
+        "Encountered an invalid directive.",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn parse_duplicate_label(span: Span, src: &str) -> Report {
     miette!(
         severity = Severity::Error,
         code = "parse::duplicate_label",
         help = "prefix labels are only allowed once per file",
-        labels = vec![LabeledSpan::at(span, "duplicate label")],
+        labels = labels(span, "duplicate label"),
         "Duplicate prefix label"
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn parse_generic_unexpected(src: &'static str, expected: &str, found: Token) -> Report {
+pub fn parse_generic_unexpected(src: &str, expected: &str, found: Token) -> Report {
     let mut help = "check the operands for this instruction".to_string();
     if found.kind == TokenKind::Label {
         let label = &src[found.span.offs()..found.span.offs() + found.span.len()];
@@ -132,17 +536,14 @@ pub fn parse_generic_unexpected(src: &'static str, expected: &str, found: Token)
         severity = Severity::Error,
         code = "parse::unexpected_token",
         help = help,
-        labels = vec![LabeledSpan::at(
-            found.span,
-            format!("unexpected {}", found.kind)
-        )],
+        labels = labels(found.span, format!("unexpected {}", found.kind)),
         "Expected token of type {expected}, found {}",
         found.kind
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn parse_eof(src: &'static str) -> Report {
+pub fn parse_eof(src: &str) -> Report {
     let offset = src.len().saturating_sub(1);
     miette!(
         severity = Severity::Error,
@@ -151,16 +552,425 @@ pub fn parse_eof(src: &'static str) -> Report {
         labels = vec![LabeledSpan::at_offset(offset, "here")],
         "Unexpected end of file",
     )
-    .with_source_code(src)
+    .with_source_code(src.to_string())
 }
 
-pub fn parse_lit_range(span: Span, src: &'static str, bits: Bits) -> Report {
+pub fn parse_sugar_simple(span: Span, src: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "parse::sugar_simple",
+        help = "only pseudo-instructions which expand to a single instruction may be used here",
+        labels = labels(span, "pseudo-instruction"),
+        "Pseudo-instruction expands to more than one word",
+    )
+    .with_source_code(src.to_string())
+}
+
+pub fn parse_lit_range(span: Span, src: &str, bits: Bits, val: i32) -> Report {
+    let range = bits.range();
     miette!(
         severity = Severity::Error,
         code = "parse::unexpected_token",
-        help = format!("this instruction expects literals that can be contained in {bits} bits",),
-        labels = vec![LabeledSpan::at(span, "out-of-range literal")],
-        "Found numeric literal of incorrect size"
+        help = format!(
+            "this instruction expects a {bits} literal, from {} to {}",
+            range.start(),
+            range.end()
+        ),
+        labels = labels(span, format!("{val} is out of range")),
+        "Found numeric literal of incorrect size: {val} is not a {bits} value"
+    )
+    .with_source_code(src.to_string())
+}
+
+// Expression errors
+// Source code is attached later, as expressions may be evaluated by the parser or backpatcher
+
+pub fn expr_overflow(span: Span, val: Option<i32>) -> Report {
+    let label = match val {
+        Some(val) => format!("evaluates to {val}"),
+        None => "overflows".to_string(),
+    };
+    miette!(
+        severity = Severity::Error,
+        code = "expr::overflow",
+        help = "every value in an expression must be between -32768 and 65535",
+        labels = labels(span, label),
+        "Expression does not fit in 16 bits",
+    )
+}
+
+pub fn expr_div_zero(span: Span) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "expr::div_zero",
+        labels = labels(span, "divisor is zero"),
+        "Division by zero in expression",
+    )
+}
+
+pub fn expr_not_const(span: Span) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "expr::not_const",
+        help = "this value is needed before the address of any label is known",
+        labels = labels(span, "label used here"),
+        "Expression cannot refer to labels",
+    )
+}
+
+pub fn expr_missing_operand(span: Span) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "expr::missing_operand",
+        help = "operators must be followed by a literal, label or parenthesized expression",
+        labels = labels(span, "expected an operand after this"),
+        "Incomplete expression",
+    )
+}
+
+pub fn expr_unclosed(span: Span) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "expr::unclosed",
+        labels = labels(span, "never closed"),
+        "Unclosed parenthesis in expression",
+    )
+}
+
+pub fn expr_range(span: Span, bits: Bits, val: i32) -> Report {
+    let range = bits.range();
+    miette!(
+        severity = Severity::Error,
+        code = "expr::range",
+        help = format!(
+            "this instruction expects a {bits} value, from {} to {}",
+            range.start(),
+            range.end()
+        ),
+        labels = labels(span, format!("evaluates to {val}")),
+        "Expression evaluates to a value of incorrect size: {val} is not a {bits} value",
+    )
+}
+
+// Backpatching errors
+// Source code is attached by `Air`, as a single `AsmLine` does not have access to it
+
+pub fn asm_missing_label(span: Span, label: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "asm::missing_label",
+        help = "labels are case-sensitive, and must be declared at the start of a line",
+        labels = labels(span, "unknown label"),
+        "Label `{label}` not found"
+    )
+}
+
+pub fn asm_extern_unlinked(span: Span, label: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "asm::extern_unlinked",
+        help =
+            "assemble each module with `lace compile --object`, then combine them with `lace link`",
+        labels = labels(span, "label in another module"),
+        "Label `{label}` is in another module, which must be linked",
+    )
+}
+
+pub fn asm_extern_expr(label: Span, expr: Span) -> Report {
+    let mut labels = labels(expr, "expression cannot be relocated");
+    labels.push(LabeledSpan::at(label, "label in another module"));
+    miette!(
+        severity = Severity::Error,
+        code = "asm::extern_expr",
+        help = "labels in another module can only be used as `label`, `label+n` or `label-n`, in a .fill or an operand which is an offset",
+        labels = labels,
+        "Expression refers to a label in another module",
+    )
+}
+
+/// `suggest_relax` is whether assembling with `--relax` would rewrite the statement.
+pub fn asm_offset_range(
+    span: Span,
+    addr: u16,
+    label_pos: u16,
+    bits: u32,
+    suggest_relax: bool,
+) -> Report {
+    let help = "this could be because of a long .stringz literal, a large .blkw allocation, or a label in another .orig section";
+    let help = match suggest_relax {
+        true => {
+            format!("{help}\nassemble with `--relax` to rewrite references which are too far away")
+        }
+        false => help.to_string(),
+    };
+    miette!(
+        severity = Severity::Error,
+        code = "asm::offset_range",
+        help = help,
+        labels = labels(span, format!("offset does not fit in {bits} bits")),
+        "Difference between label and label reference is too large: at address x{addr:04X}, referencing address x{label_pos:04X}",
+    )
+}
+
+pub fn asm_relaxed(span: Span, rewrite: &str) -> Report {
+    miette!(
+        severity = Severity::Advice,
+        code = "asm::relaxed",
+        help = "the label was too far away for the offset of this instruction",
+        labels = labels(span, "rewritten"),
+        "Rewrote reference to a distant label {rewrite}",
+    )
+}
+
+pub fn asm_segment_overlap(span: Span, other: Span) -> Report {
+    let mut labels = labels(span, "this section");
+    labels.push(LabeledSpan::at(other, "overlaps this section"));
+    miette!(
+        severity = Severity::Error,
+        code = "asm::segment_overlap",
+        help = "move one of the sections to a different origin",
+        labels = labels,
+        "Sections of the program overlap in memory",
+    )
+}
+
+pub fn asm_segment_overflow(span: Span, len: usize) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "asm::segment_overflow",
+        help = "move the section to a lower origin",
+        labels = labels(span, format!("section of {len} words starts here")),
+        "Section of the program extends past the end of memory",
+    )
+}
+
+// Lints
+// Source code is attached by `lint`, like backpatching errors
+
+pub fn lint_fall_through(instr: Span, data: Span) -> Report {
+    let mut labels = labels(data, "executed as an instruction");
+    labels.push(LabeledSpan::at(
+        instr,
+        "execution continues past this instruction",
+    ));
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::fall_through",
+        help = "add a `halt` or `br` before the data, or move the data elsewhere",
+        labels = labels,
+        "Execution falls through into data",
+    )
+}
+
+pub fn lint_unreachable(span: Span, stop: Option<Span>) -> Report {
+    let mut labels = labels(span, "never executed");
+    if let Some(stop) = stop {
+        labels.push(LabeledSpan::at(stop, "execution stops here"));
+    }
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::unreachable",
+        help = "nothing branches to this code, or refers to it by label",
+        labels = labels,
+        "Unreachable code",
+    )
+}
+
+pub fn lint_missing_halt(span: Span, returns: bool) -> Report {
+    let label = if returns {
+        "returns, but the program was not called as a subroutine"
+    } else {
+        "execution continues past the end of the program"
+    };
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::missing_halt",
+        help = "end the program with `halt`",
+        labels = labels(span, label),
+        "Program does not halt on every path",
+    )
+}
+
+pub fn lint_unsaved_r7(call: Span, ret: Span) -> Report {
+    let mut labels = labels(call, "overwrites R7");
+    labels.push(LabeledSpan::at(ret, "returns to the address in R7"));
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::unsaved_r7",
+        help = "save R7 before calling another subroutine, and restore it before returning",
+        labels = labels,
+        "Subroutine calls another subroutine without saving R7",
+    )
+}
+
+pub fn lint_unused_label(span: Span, name: &str) -> Report {
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::unused_label",
+        help = "labels which are exported with `.global` are always used",
+        labels = labels(span, "never referred to"),
+        "Label `{name}` is never used",
+    )
+}
+
+pub fn lint_stale_flags(branch: Span, prev: Option<Span>) -> Report {
+    let mut labels = labels(branch, "tests the condition codes");
+    let help = match prev {
+        Some(prev) => {
+            labels.push(LabeledSpan::at(prev, "does not set the condition codes"));
+            "the condition codes are left over from an earlier instruction"
+        }
+        None => "no instruction has set the condition codes when the program starts",
+    };
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::stale_flags",
+        help = help,
+        labels = labels,
+        "Branch does not follow an instruction which sets the condition codes",
+    )
+}
+
+pub fn lint_empty_blkw(span: Span) -> Report {
+    miette!(
+        severity = Severity::Warning,
+        code = "lint::empty_blkw",
+        help = "a label on this line refers to the statement after it",
+        labels = labels(span, "reserves no words"),
+        "Block of zero words",
+    )
+}
+
+// Linker errors
+// These have no source code, as object files only record the location of each label
+
+pub fn link_bad_object(line: usize) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::bad_object",
+        help = "object files are written by `lace compile --object`",
+        "Malformed object file at line {line}",
+    )
+}
+
+pub fn link_duplicate_global(name: &str, location: &str, other: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::duplicate_global",
+        help = format!("declared at {location}, and again at {other}"),
+        "Label `{name}` is exported by more than one module",
+    )
+}
+
+pub fn link_undefined(name: &str, location: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::undefined",
+        help = format!("referenced at {location}. labels must be exported with .global to be used by another module"),
+        "Label `{name}` not found in any module",
+    )
+}
+
+pub fn link_offset_range(name: &str, bits: u32, location: &str, declared: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::offset_range",
+        help = format!("referenced at {location}, and declared at {declared}. A distant label can be reached through a pointer, with .fill"),
+        "Offset to label `{name}` does not fit in {bits} bits",
+    )
+}
+
+pub fn link_value_range(name: &str, location: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::value_range",
+        help = format!("referenced at {location}"),
+        "Address of label `{name}` with its offset does not fit in a word",
+    )
+}
+
+pub fn link_overlap(orig: u16, other: u16) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::overlap",
+        help = "move one of the sections to a different origin",
+        "Section at x{orig:04X} overlaps section at x{other:04X}",
+    )
+}
+
+pub fn link_no_space(len: usize) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "link::no_space",
+        help = "sections without an .orig are placed after x3000",
+        "No space in memory for a section of {len} words",
+    )
+}
+
+// Symbol files
+
+pub fn sym_bad_file(line: usize) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "sym::bad_file",
+        help = "each symbol is written as `//` followed by its name and address in hex",
+        "Malformed symbol file at line {line}",
+    )
+}
+
+pub fn sym_duplicate(name: &str, line: usize) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "sym::duplicate",
+        "Symbol `{name}` is declared more than once, again at line {line}",
+    )
+}
+
+// Program files
+
+pub fn load_malformed(format: Format, location: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "load::malformed",
+        help = "the format is detected from the header of the file, or otherwise its extension",
+        "Malformed {format} file at {location}",
+    )
+}
+
+pub fn load_overlap(start: u16, end: u16, other_start: u16, other_end: u16) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "load::overlap",
+        help = format!("x{other_start:04X}-x{other_end:04X} was loaded by an earlier image. each image must be placed at its own origin"),
+        "Image overlaps memory at x{start:04X}-x{end:04X}",
+    )
+}
+
+pub fn load_empty() -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "load::empty",
+        "Program file is empty",
+    )
+}
+
+pub fn load_too_long(orig: u16) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "load::too_long",
+        help = format!(
+            "the last address x{:04X} is left for a HALT after the program",
+            MEMORY_MAX - 1
+        ),
+        "Segment at x{orig:04X} does not fit in memory",
+    )
+}
+
+pub fn load_bad_segments(location: &str) -> Report {
+    miette!(
+        severity = Severity::Error,
+        code = "load::bad_segments",
+        help = "files with several segments are written by `lace compile` and `lace link`, for programs with several .orig",
+        "Malformed segment table at {location}",
     )
-    .with_source_code(src)
 }
@@ -0,0 +1,422 @@
+//! Expressions in operands, such as `#SIZE-1` or `table+2`.
+//!
+//! Expressions which only contain literals and constants are evaluated by the parser. Those which
+//! refer to labels are evaluated when backpatching, once the address of every label is known.
+
+use std::iter::Peekable;
+
+use miette::Result;
+
+use crate::{
+    error,
+    lexer::{LiteralKind, OpKind, Token, TokenKind},
+    parser::label_name,
+    symbol::Span,
+};
+
+/// Every value in an expression must fit in a word, either signed or unsigned.
+const MIN: i32 = i16::MIN as i32;
+const MAX: i32 = u16::MAX as i32;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Expr {
+    Num {
+        val: i32,
+        span: Span,
+    },
+    /// Address of a label, with its name made unique if it is local to a macro expansion
+    Label {
+        name: String,
+        span: Span,
+    },
+    Neg {
+        expr: Box<Expr>,
+        span: Span,
+    },
+    Paren {
+        expr: Box<Expr>,
+        span: Span,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Shl,
+    Shr,
+}
+
+impl BinOp {
+    fn from_op(op: OpKind) -> Option<Self> {
+        Some(match op {
+            OpKind::Add => BinOp::Add,
+            OpKind::Sub => BinOp::Sub,
+            OpKind::Mul => BinOp::Mul,
+            OpKind::Div => BinOp::Div,
+            OpKind::And => BinOp::And,
+            OpKind::Or => BinOp::Or,
+            OpKind::Shl => BinOp::Shl,
+            OpKind::Shr => BinOp::Shr,
+            OpKind::Open | OpKind::Close | OpKind::Hash => return None,
+        })
+    }
+
+    /// Operators with higher precedence bind more tightly, in the same order as C.
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Shl | BinOp::Shr => 3,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Mul | BinOp::Div => 5,
+        }
+    }
+
+    /// Returns `None` if the result cannot be calculated.
+    fn apply(self, lhs: i32, rhs: i32) -> Option<i32> {
+        match self {
+            BinOp::Add => lhs.checked_add(rhs),
+            BinOp::Sub => lhs.checked_sub(rhs),
+            BinOp::Mul => lhs.checked_mul(rhs),
+            BinOp::Div => lhs.checked_div(rhs),
+            BinOp::And => Some(lhs & rhs),
+            BinOp::Or => Some(lhs | rhs),
+            BinOp::Shl => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+            BinOp::Shr => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+        }
+    }
+}
+
+/// Whether a token can be the first token of an expression.
+pub fn is_start(kind: TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Label
+            | TokenKind::Lit(
+                LiteralKind::Dec(_)
+                    | LiteralKind::Hex(_)
+                    | LiteralKind::Bin(_)
+                    | LiteralKind::Oct(_)
+                    | LiteralKind::Char(_)
+            )
+            | TokenKind::Op(OpKind::Open | OpKind::Sub | OpKind::Hash)
+    )
+}
+
+impl Expr {
+    /// Parse an expression, consuming only the tokens which belong to it.
+    ///
+    /// Numbers without a prefix are decimal, like the `1` in `#SIZE-1`. An entire expression
+    /// cannot be an unprefixed number, as it is more likely to be a mistake.
+    pub fn parse(toks: &mut Peekable<impl Iterator<Item = Token>>, src: &str) -> Result<Self> {
+        let mut parser = ExprParser { toks, src };
+        let expr = parser.binary(0, None)?;
+        if let Expr::Num { span, .. } = expr {
+            if src[span.as_range()].chars().all(|c| c.is_ascii_digit()) {
+                let tok = Token::new(TokenKind::Label, span);
+                return Err(error::parse_generic_unexpected(src, "expression", tok));
+            }
+        }
+        Ok(expr)
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Num { span, .. }
+            | Expr::Label { span, .. }
+            | Expr::Neg { span, .. }
+            | Expr::Paren { span, .. } => *span,
+            Expr::Binary { lhs, rhs, .. } => lhs.span().join(rhs.span()),
+        }
+    }
+
+    /// Whether the expression can be evaluated without knowing the address of any label.
+    pub fn is_const(&self) -> bool {
+        match self {
+            Expr::Num { .. } => true,
+            Expr::Label { .. } => false,
+            Expr::Neg { expr, .. } | Expr::Paren { expr, .. } => expr.is_const(),
+            Expr::Binary { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+        }
+    }
+
+    /// Prefix every local label in the expression with the global label which it is scoped to.
+    pub fn scope_locals(&mut self, scope: &str) {
+        match self {
+            Expr::Num { .. } => (),
+            Expr::Label { name, .. } => {
+                if name.starts_with('.') {
+                    name.insert_str(0, scope);
+                }
+            }
+            Expr::Neg { expr, .. } | Expr::Paren { expr, .. } => expr.scope_locals(scope),
+            Expr::Binary { lhs, rhs, .. } => {
+                lhs.scope_locals(scope);
+                rhs.scope_locals(scope);
+            }
+        }
+    }
+
+    /// Every label in the expression, with its span.
+    pub fn labels(&self) -> Vec<(&str, Span)> {
+        match self {
+            Expr::Num { .. } => Vec::new(),
+            Expr::Label { name, span } => vec![(name, *span)],
+            Expr::Neg { expr, .. } | Expr::Paren { expr, .. } => expr.labels(),
+            Expr::Binary { lhs, rhs, .. } => {
+                let mut labels = lhs.labels();
+                labels.extend(rhs.labels());
+                labels
+            }
+        }
+    }
+
+    /// Get the label and constant offset of an expression like `label`, `label+2` or `label-1`.
+    ///
+    /// Returns `None` for any other expression, as it cannot be filled by the linker.
+    pub fn label_offset(&self) -> Option<(&str, i32)> {
+        match self {
+            Expr::Label { name, .. } => Some((name, 0)),
+            Expr::Paren { expr, .. } => expr.label_offset(),
+            Expr::Binary { op, lhs, rhs } if rhs.is_const() => {
+                let (name, offset) = lhs.label_offset()?;
+                let val = rhs.eval_const().ok()?;
+                match op {
+                    BinOp::Add => Some((name, offset.checked_add(val)?)),
+                    BinOp::Sub => Some((name, offset.checked_sub(val)?)),
+                    _ => None,
+                }
+            }
+            Expr::Binary {
+                op: BinOp::Add,
+                lhs,
+                rhs,
+            } if lhs.is_const() => {
+                let (name, offset) = rhs.label_offset()?;
+                Some((name, offset.checked_add(lhs.eval_const().ok()?)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Evaluate the expression, using `label` to get the address of each label.
+    ///
+    /// The result, and every intermediate value, must fit in a word.
+    pub fn eval(&self, label: &mut impl FnMut(&str, Span) -> Result<i32>) -> Result<i32> {
+        let val = match self {
+            Expr::Num { val, .. } => *val,
+            Expr::Label { name, span } => label(name, *span)?,
+            Expr::Neg { expr, .. } => -expr.eval(label)?,
+            Expr::Paren { expr, .. } => expr.eval(label)?,
+            Expr::Binary { op, lhs, rhs } => {
+                let (lhs, rhs) = (lhs.eval(label)?, rhs.eval(label)?);
+                match op.apply(lhs, rhs) {
+                    Some(val) => val,
+                    None if *op == BinOp::Div => return Err(error::expr_div_zero(self.span())),
+                    None => return Err(error::expr_overflow(self.span(), None)),
+                }
+            }
+        };
+        if !(MIN..=MAX).contains(&val) {
+            return Err(error::expr_overflow(self.span(), Some(val)));
+        }
+        Ok(val)
+    }
+
+    /// Evaluate an expression which must not refer to any labels.
+    pub fn eval_const(&self) -> Result<i32> {
+        self.eval(&mut |_, span| Err(error::expr_not_const(span)))
+    }
+}
+
+struct ExprParser<'a, I: Iterator<Item = Token>> {
+    toks: &'a mut Peekable<I>,
+    src: &'a str,
+}
+
+impl<I: Iterator<Item = Token>> ExprParser<'_, I> {
+    /// Parse operators which bind at least as tightly as `min_precedence`.
+    ///
+    /// `prev` is the operator before this operand, if any.
+    fn binary(&mut self, min_precedence: u8, prev: Option<Span>) -> Result<Expr> {
+        let mut lhs = self.unary(prev)?;
+        while let Some((op, span)) = self.peek_op(min_precedence, lhs.span()) {
+            self.toks.next();
+            // Operators of equal precedence are left-associative
+            let rhs = self.binary(op.precedence() + 1, Some(span))?;
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    /// Get the next binary operator, if it binds at least as tightly as `min_precedence`.
+    ///
+    /// An expression ends at the end of the line of `lhs`.
+    fn peek_op(&mut self, min_precedence: u8, lhs: Span) -> Option<(BinOp, Span)> {
+        let tok = self.toks.peek()?;
+        let TokenKind::Op(op) = tok.kind else {
+            return None;
+        };
+        if !lhs.same_line(tok.span, self.src) {
+            return None;
+        }
+        BinOp::from_op(op)
+            .filter(|op| op.precedence() >= min_precedence)
+            .map(|op| (op, tok.span))
+    }
+
+    fn unary(&mut self, prev: Option<Span>) -> Result<Expr> {
+        // Operand of an operator must be on the same line
+        let on_line = |tok: &Token| prev.is_none_or(|prev| prev.same_line(tok.span, self.src));
+        let Some(tok) = self.toks.next_if(|tok| is_start(tok.kind) && on_line(tok)) else {
+            return Err(match (self.toks.peek(), prev) {
+                (Some(tok), _) if on_line(tok) => {
+                    error::parse_generic_unexpected(self.src, "expression", *tok)
+                }
+                (_, prev) => error::expr_missing_operand(prev.unwrap_or_else(Span::dummy)),
+            });
+        };
+        Ok(match tok.kind {
+            TokenKind::Lit(LiteralKind::Dec(val)) => Expr::Num {
+                val: val as i32,
+                span: tok.span,
+            },
+            TokenKind::Lit(
+                LiteralKind::Hex(val)
+                | LiteralKind::Bin(val)
+                | LiteralKind::Oct(val)
+                | LiteralKind::Char(val),
+            ) => Expr::Num {
+                val: val as i32,
+                span: tok.span,
+            },
+            TokenKind::Label => {
+                let name = label_name(self.src, tok);
+                match name.parse::<i32>() {
+                    Ok(val) => Expr::Num {
+                        val,
+                        span: tok.span,
+                    },
+                    Err(_) => Expr::Label {
+                        name: name.into_owned(),
+                        span: tok.span,
+                    },
+                }
+            }
+            TokenKind::Op(OpKind::Sub) => {
+                let expr = self.unary(Some(tok.span))?;
+                Expr::Neg {
+                    span: tok.span.join(expr.span()),
+                    expr: Box::new(expr),
+                }
+            }
+            TokenKind::Op(OpKind::Hash) => self.unary(Some(tok.span))?,
+            TokenKind::Op(OpKind::Open) => {
+                let expr = self.binary(0, Some(tok.span))?;
+                let Some(close) = self
+                    .toks
+                    .next_if(|tok| tok.kind == TokenKind::Op(OpKind::Close))
+                else {
+                    return Err(error::expr_unclosed(tok.span));
+                };
+                Expr::Paren {
+                    expr: Box::new(expr),
+                    span: tok.span.join(close.span),
+                }
+            }
+            _ => unreachable!("Found token which cannot start an expression"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::cursor::Cursor;
+
+    fn parse(src: &str) -> Result<Expr> {
+        let mut cur = Cursor::new(src);
+        let mut toks = Vec::new();
+        loop {
+            let tok = cur.advance_real().unwrap();
+            if tok.kind == TokenKind::Eof {
+                break;
+            }
+            toks.push(tok);
+        }
+        let mut toks = toks.into_iter().peekable();
+        let expr = Expr::parse(&mut toks, src)?;
+        assert!(toks.next().is_none(), "expression should use every token");
+        Ok(expr)
+    }
+
+    fn eval(src: &str) -> Result<i32> {
+        parse(src)?.eval(&mut |name, _| match name {
+            "table" => Ok(0x3010),
+            _ => Err(miette::miette!("unknown label")),
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("#1+2*3").unwrap(), 7);
+        assert_eq!(eval("(#1+#2)*#3").unwrap(), 9);
+        assert_eq!(eval("#10-2-3").unwrap(), 5);
+        assert_eq!(eval("#1<<4|x3&x2").unwrap(), 18);
+        assert_eq!(eval("x20>>#2 - #1").unwrap(), 16);
+        assert_eq!(eval("#-(x3)/2").unwrap(), -1);
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(eval("table+2").unwrap(), 0x3012);
+        assert_eq!(eval("#table-x3000+1").unwrap(), 0x11);
+        assert!(!parse("table").unwrap().is_const());
+        assert!(parse("#3*4").unwrap().is_const());
+        assert!(parse("x10 + table").unwrap().eval_const().is_err());
+        assert_eq!(parse("table").unwrap().label_offset(), Some(("table", 0)));
+        assert_eq!(
+            parse("(table - #2) + 1").unwrap().label_offset(),
+            Some(("table", -1))
+        );
+        assert_eq!(
+            parse("x10 + table").unwrap().label_offset(),
+            Some(("table", 16))
+        );
+        assert_eq!(parse("table*2").unwrap().label_offset(), None);
+        assert_eq!(parse("#1 - table").unwrap().label_offset(), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(eval("xFFFF+#1").is_err());
+        assert!(
+            eval("x8000*x2-x8000").is_err(),
+            "intermediate value overflows"
+        );
+        assert!(eval("#1/0").is_err());
+        assert!(eval("#1<<-1").is_err());
+        assert!(parse("(#1+2").is_err());
+        assert!(parse("#1+").is_err());
+        // Operand of an operator cannot be on the next line
+        assert!(parse("#1+\n2").is_err());
+        // Numbers need a prefix, unless they are part of a larger expression
+        assert!(parse("12").is_err());
+        assert!(parse("(12)").is_ok());
+        assert!(parse("0x12").is_ok());
+    }
+}
@@ -1,43 +1,33 @@
-use std::cell::RefCell;
 use std::fmt;
 use std::str::FromStr;
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Features {
     stack: bool,
+    sugar: bool,
 }
 
-thread_local! {
-    /// Must only be mutated within `set_features`
-    static FEATURES: RefCell<Option<Features>> = const { RefCell::new(None) };
-}
+impl Features {
+    pub fn stack(&self) -> bool {
+        self.stack
+    }
 
-pub fn stack() -> bool {
-    with_features(|features| features.stack)
-}
+    pub fn sugar(&self) -> bool {
+        self.sugar
+    }
 
-pub fn init(value: Features) {
-    FEATURES.with(|features| {
-        let mut features = features.borrow_mut();
-        assert!(
-            features.is_none(),
-            "tried to initialize features state multiple times"
-        );
-        *features = Some(value);
-    });
-}
+    /// Value of the constant which is predefined for an enabled feature, such as `FEATURE_STACK`.
+    pub fn constant(&self, name: &str) -> Option<i32> {
+        let feature = name.strip_prefix("FEATURE_")?.to_ascii_lowercase();
+        self.enabled().any(|name| name == feature).then_some(1)
+    }
 
-fn with_features<F, R>(callback: F) -> R
-where
-    F: Fn(&Features) -> R,
-{
-    FEATURES.with(|features| {
-        let features = features.borrow();
-        let features = features.unwrap_or_else(|| {
-            panic!("tried to access features state before initialization");
-        });
-        callback(&features)
-    })
+    fn enabled(&self) -> impl Iterator<Item = &'static str> {
+        [("stack", self.stack), ("sugar", self.sugar)]
+            .into_iter()
+            .filter(|(_, value)| *value)
+            .map(|(name, _)| name)
+    }
 }
 
 impl FromStr for Features {
@@ -48,6 +38,7 @@ impl FromStr for Features {
             let value = match word {
                 "" => continue,
                 "stack" => &mut features.stack,
+                "sugar" => &mut features.sugar,
                 _ => return Err(format!("Unknown feature '{}'", word)),
             };
             if *value {
@@ -61,18 +52,7 @@ impl FromStr for Features {
 
 impl fmt::Display for Features {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let features = [("stack", self.stack)];
-        let mut has_any_feature = false;
-        for (name, value) in features {
-            if !value {
-                continue;
-            }
-            if has_any_feature {
-                write!(f, ",")?;
-            }
-            write!(f, "{}", name)?;
-            has_any_feature = true;
-        }
-        Ok(())
+        let names: Vec<_> = self.enabled().collect();
+        write!(f, "{}", names.join(","))
     }
 }
@@ -0,0 +1,435 @@
+//! Source formatter, used by `lace fmt`.
+//!
+//! Each line is laid out in columns: labels, then mnemonics (instructions, traps and directives),
+//! then operands, then trailing comments. Mnemonics, registers and directives are written in
+//! lowercase, and literals are written in a consistent style. Comments and blank lines are kept.
+
+use fxhash::FxHashSet;
+
+use crate::error::Diagnostics;
+use crate::features::Features;
+use crate::lexer::{LiteralKind, OpKind, Token, TokenKind};
+use crate::symbol::DirKind;
+use crate::syntax::{SyntaxLine, SyntaxTree};
+
+/// Columns are aligned to a multiple of this width.
+const TAB_WIDTH: usize = 4;
+
+/// Format entire source file.
+///
+/// Fails if the source could not be lexed. Keywords of enabled `features` are formatted as
+/// instructions.
+pub fn format(src: &str, features: Features) -> Result<String, Diagnostics> {
+    let tree = SyntaxTree::parse(src, features)?;
+    Ok(Formatter::new(&tree).format())
+}
+
+/// A line, split into the columns which are aligned by the formatter.
+enum Row {
+    Blank,
+    /// Comment on its own line. Lines which are not indented stay that way.
+    Comment {
+        text: String,
+        indented: bool,
+    },
+    Code {
+        label: Option<String>,
+        mnemonic: Option<String>,
+        operands: Vec<String>,
+        comment: Option<String>,
+    },
+    /// Line which does not follow `LABEL? MNEMONIC OPERANDS*`, kept in order but re-spaced.
+    Other {
+        text: String,
+        comment: Option<String>,
+    },
+}
+
+struct Formatter<'a> {
+    tree: &'a SyntaxTree<'a>,
+    rows: Vec<Row>,
+    /// Column which mnemonics start at.
+    indent: usize,
+    /// Width of the mnemonic column, including padding.
+    mnemonic_width: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(tree: &'a SyntaxTree<'a>) -> Self {
+        // Macro calls are aligned like instructions
+        let macros: FxHashSet<&str> = tree
+            .lines
+            .iter()
+            .filter_map(|line| {
+                let mut toks = line.significant();
+                match (toks.next(), toks.next()) {
+                    (Some(dir), Some(name)) if dir.kind == TokenKind::Dir(DirKind::Macro) => {
+                        Some(tree.text(name))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        let rows: Vec<Row> = tree
+            .lines
+            .iter()
+            .map(|line| Self::row(tree, line, &macros))
+            .collect();
+
+        let mut label_width = 0;
+        let mut mnemonic_width = 0;
+        for row in &rows {
+            if let Row::Code {
+                label,
+                mnemonic,
+                operands,
+                ..
+            } = row
+            {
+                label_width = label_width.max(label.as_ref().map_or(0, String::len));
+                // Mnemonics without operands are never padded
+                if !operands.is_empty() {
+                    mnemonic_width = mnemonic_width.max(mnemonic.as_ref().map_or(0, String::len));
+                }
+            }
+        }
+
+        Formatter {
+            tree,
+            rows,
+            indent: round_up(label_width + 1).max(TAB_WIDTH),
+            mnemonic_width: mnemonic_width + 1,
+        }
+    }
+
+    fn row(tree: &SyntaxTree, line: &SyntaxLine, macros: &FxHashSet<&str>) -> Row {
+        let comment = line
+            .comment()
+            .map(|tok| tree.text(tok).trim_end().to_string());
+        let mut toks = line.significant().peekable();
+
+        if toks.peek().is_none() {
+            return match comment {
+                Some(text) => Row::Comment {
+                    text,
+                    indented: line.is_indented(),
+                },
+                None => Row::Blank,
+            };
+        }
+
+        let is_macro =
+            |tok: &Token| tok.kind == TokenKind::Label && macros.contains(tree.text(tok));
+        let label = toks
+            .next_if(|tok| tok.kind == TokenKind::Label && !is_macro(tok))
+            .map(|tok| tree.text(tok).to_string());
+        let mnemonic = toks.next_if(|tok| {
+            is_macro(tok)
+                || matches!(
+                    tok.kind,
+                    TokenKind::Instr(_) | TokenKind::Trap(_) | TokenKind::Dir(_)
+                )
+        });
+        let mut operands: Vec<String> = Vec::new();
+        let mut prev: Option<&Token> = None;
+        for tok in toks {
+            let text = operand(tree, tok);
+            // Operands separated by a comma are never joined, like `.word #1, -2`
+            let joined = prev.is_some_and(|prev| {
+                !tree.between(prev, tok).contains(',') && continues_expr(prev, tok)
+            });
+            match operands.last_mut() {
+                Some(last) if joined => last.push_str(&text),
+                _ => operands.push(text),
+            }
+            prev = Some(tok);
+        }
+        // Parameters are separated from the macro name, like operands from a mnemonic
+        if mnemonic.is_some_and(|tok| tok.kind == TokenKind::Dir(DirKind::Macro))
+            && operands.len() > 1
+        {
+            let params = operands.split_off(1).join(", ");
+            operands[0] = format!("{} {}", operands[0], params);
+        }
+        let mnemonic = mnemonic.map(|tok| match tok.kind {
+            TokenKind::Label => tree.text(tok).to_string(),
+            _ => tree.text(tok).to_ascii_lowercase(),
+        });
+
+        // Operands can only follow a mnemonic
+        if mnemonic.is_none() && !operands.is_empty() {
+            let text = line
+                .significant()
+                .map(|tok| operand(tree, tok))
+                .collect::<Vec<_>>()
+                .join(" ");
+            return Row::Other { text, comment };
+        }
+
+        Row::Code {
+            label,
+            mnemonic,
+            operands,
+            comment,
+        }
+    }
+
+    fn format(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+
+        // Trailing comments are aligned within each block of lines between blank lines
+        for block in self.rows.split(|row| matches!(row, Row::Blank)) {
+            if block.is_empty() {
+                continue;
+            }
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+
+            let codes: Vec<Option<String>> = block.iter().map(|row| self.code(row)).collect();
+            let comment_col = block
+                .iter()
+                .zip(&codes)
+                .filter(|(row, _)| self.trailing_comment(row).is_some())
+                .filter_map(|(_, code)| code.as_ref().map(|code| code.len() + 1))
+                .max()
+                .unwrap_or(0);
+
+            for (row, code) in block.iter().zip(codes) {
+                let line = match (row, code) {
+                    (Row::Comment { text, indented }, _) => {
+                        let indent = if *indented { self.indent } else { 0 };
+                        format!("{}{}", " ".repeat(indent), text)
+                    }
+                    (row, Some(code)) => match self.trailing_comment(row) {
+                        Some(comment) => format!("{:<comment_col$}{}", code, comment),
+                        None => code,
+                    },
+                    (Row::Blank, None) | (_, None) => unreachable!("row should have code"),
+                };
+                lines.push(line);
+            }
+        }
+
+        let mut res = lines.join("\n");
+        // Source after `.end` is kept as-is
+        if let Some(trailing) = self.tree.trailing() {
+            res.push_str(trailing.trim_end());
+        }
+        res.push('\n');
+        res
+    }
+
+    /// Get text of all columns before the trailing comment.
+    fn code(&self, row: &Row) -> Option<String> {
+        match row {
+            Row::Blank | Row::Comment { .. } => None,
+            Row::Other { text, .. } => Some(format!("{}{}", " ".repeat(self.indent), text)),
+            Row::Code {
+                label,
+                mnemonic,
+                operands,
+                ..
+            } => {
+                let mut code = label.clone().unwrap_or_default();
+                if let Some(mnemonic) = mnemonic {
+                    code = format!("{:<indent$}{}", code, mnemonic, indent = self.indent);
+                    if !operands.is_empty() {
+                        code = format!(
+                            "{:<width$}{}",
+                            code,
+                            operands.join(", "),
+                            width = self.indent + self.mnemonic_width,
+                        );
+                    }
+                }
+                Some(code)
+            }
+        }
+    }
+
+    fn trailing_comment<'r>(&self, row: &'r Row) -> Option<&'r str> {
+        match row {
+            Row::Code { comment, .. } | Row::Other { comment, .. } => comment.as_deref(),
+            Row::Blank | Row::Comment { .. } => None,
+        }
+    }
+}
+
+/// Normalize case and literal style of an operand.
+fn operand(tree: &SyntaxTree, tok: &Token) -> String {
+    let text = tree.text(tok);
+    match tok.kind {
+        // Hex digits are uppercase, with a lowercase `x` prefix
+        TokenKind::Lit(LiteralKind::Hex(_)) => {
+            let digits = text
+                .strip_prefix("0x")
+                .or_else(|| text.strip_prefix("0X"))
+                .unwrap_or(&text[1..]);
+            format!("x{}", digits.to_ascii_uppercase())
+        }
+        // Lowercase prefix, without a leading zero
+        TokenKind::Lit(LiteralKind::Bin(_) | LiteralKind::Oct(_)) => {
+            let text = text.strip_prefix('0').unwrap_or(text);
+            text.to_ascii_lowercase()
+        }
+        // No redundant sign or leading zeros
+        TokenKind::Lit(LiteralKind::Dec(_)) => {
+            let digits = &text[1..];
+            let (sign, digits) = match digits.strip_prefix('-') {
+                Some(digits) => ("-", digits),
+                None => ("", digits.strip_prefix('+').unwrap_or(digits)),
+            };
+            let digits = digits.trim_start_matches('0');
+            if digits.is_empty() {
+                "#0".to_string()
+            } else {
+                format!("#{sign}{digits}")
+            }
+        }
+        TokenKind::Instr(_) | TokenKind::Trap(_) | TokenKind::Dir(_) | TokenKind::Reg(_) => {
+            text.to_ascii_lowercase()
+        }
+        _ => text.to_string(),
+    }
+}
+
+/// Whether a token belongs to the same expression as the token before it.
+///
+/// Expressions are written without spaces, so that they are not mistaken for several operands.
+fn continues_expr(prev: &Token, tok: &Token) -> bool {
+    match (prev.kind, tok.kind) {
+        (TokenKind::Op(op), _) if op != OpKind::Close => true,
+        // Operator after a register is unary, like `add r0 r0 -1`
+        (TokenKind::Reg(_), _) => false,
+        (_, TokenKind::Op(op)) => !matches!(op, OpKind::Open | OpKind::Hash),
+        _ => false,
+    }
+}
+
+fn round_up(width: usize) -> usize {
+    width.div_ceil(TAB_WIDTH) * TAB_WIDTH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(src: &str) -> String {
+        format(src, Features::default()).unwrap()
+    }
+
+    #[test]
+    fn columns() {
+        let src = "\
+.ORIG 0X3000
+  LEA R0,HW ; load
+PUTS
+HALT;stop
+hw: .STRINGZ \"Hello\"
+.END";
+        assert_eq!(
+            fmt(src),
+            "    .orig    x3000
+    lea      r0, HW ; load
+    puts
+    halt            ;stop
+hw  .stringz \"Hello\"
+    .end
+"
+        );
+    }
+
+    #[test]
+    fn literals() {
+        let src = "add r0 r0 #+005\nand r1 r1 #-00\nld r2 0xff\n.fill x-1a\n.fill #0010";
+        assert_eq!(
+            fmt(src),
+            "    add   r0, r0, #5
+    and   r1, r1, #0
+    ld    r2, xFF
+    .fill x-1A
+    .fill #10
+"
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let src = "
+
+; heading
+  ; indented
+main add r0 r0 #1 ; a
+     not r0 r0      ; b
+
+
+long_label_name
+    halt ; c
+";
+        assert_eq!(
+            fmt(src),
+            "; heading
+                ; indented
+main            add r0, r0, #1 ; a
+                not r0, r0     ; b
+
+long_label_name
+                halt ; c
+"
+        );
+    }
+
+    #[test]
+    fn keeps_source_after_end() {
+        let src = "halt\n.end ; done\n\nnotes @ not lexed\n\n";
+        assert_eq!(fmt(src), "    halt\n    .end ; done\n\nnotes @ not lexed\n");
+    }
+
+    #[test]
+    fn macros() {
+        let src = ".MACRO incr dst,n\nADD dst,dst,n\n.ENDM\nstart incr R1,#2\nincr r2 #3";
+        assert_eq!(
+            fmt(src),
+            "        .macro incr dst, n
+        add    dst, dst, n
+        .endm
+start   incr   r1, #2
+        incr   r2, #3
+"
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let src = "ldr r0 r1 # SIZE - 1\nadd r0 r0 -1\n.fill ( table + x2 ) * 2\n.fill x-1A";
+        assert_eq!(
+            fmt(src),
+            "    ldr   r0, r1, #SIZE-1
+    add   r0, r0, -1
+    .fill (table+x2)*2
+    .fill x-1A
+"
+        );
+    }
+
+    #[test]
+    fn comma_separates_operands() {
+        assert_eq!(
+            fmt(".word #1, x10, -2\n.word x10 -2"),
+            "    .word #1, x10, -2\n    .word x10-2\n"
+        );
+    }
+
+    #[test]
+    fn malformed_line_kept() {
+        assert_eq!(fmt("x3000 r0 foo ; c"), "    x3000 r0 foo ; c\n");
+    }
+
+    #[test]
+    fn idempotent() {
+        let src = "main: ld r0 n ; x\n\tPUTS\n\n;c\nn .FILL #23\n.end";
+        let once = fmt(src);
+        assert_eq!(fmt(&once), once);
+    }
+}
@@ -3,6 +3,8 @@
 
 use std::{ops::Range, str::Chars};
 
+use crate::features::Features;
+
 #[derive(Clone)]
 /// Peekable iterator over a char sequence.
 pub struct Cursor<'sess> {
@@ -10,27 +12,59 @@ pub struct Cursor<'sess> {
     orig_size: usize,
     /// Iterator over chars in a &str
     chars: Chars<'sess>,
-    src: &'static str,
+    src: &'sess str,
+    /// Extensions which add keywords
+    features: Features,
 }
 
 pub(crate) const NULL_CHAR: char = '\0';
 
 impl<'sess> Cursor<'sess> {
-    pub fn new(src: &'static str) -> Cursor<'sess> {
+    pub fn new(src: &'sess str) -> Cursor<'sess> {
         Cursor {
             len_remaining: src.len(),
             orig_size: src.len(),
             chars: src.chars(),
             src,
+            features: Features::default(),
         }
     }
 
+    /// Cursor over part of the source, such as a single included file.
+    ///
+    /// Offsets are still relative to the start of `src`.
+    pub fn with_range(src: &'sess str, range: Range<usize>) -> Cursor<'sess> {
+        Cursor {
+            len_remaining: range.len(),
+            orig_size: range.end,
+            chars: src[range].chars(),
+            src,
+            features: Features::default(),
+        }
+    }
+
+    /// Lex the keywords added by enabled features, such as `mov` for `sugar`.
+    pub fn with_features(self, features: Features) -> Cursor<'sess> {
+        Cursor { features, ..self }
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
     /// Returns next character without consuming it.
     pub fn first(&self) -> char {
         self.chars.clone().next().unwrap_or(NULL_CHAR)
     }
 
-    pub fn src(&self) -> &'static str {
+    /// Returns the character after the next one, without consuming either.
+    pub fn second(&self) -> char {
+        let mut chars = self.chars.clone();
+        chars.next();
+        chars.next().unwrap_or(NULL_CHAR)
+    }
+
+    pub fn src(&self) -> &'sess str {
         self.src
     }
 
@@ -62,6 +96,14 @@ impl<'sess> Cursor<'sess> {
         }
     }
 
+    /// Consume the rest of the current line, not including the newline.
+    ///
+    /// Used to recover after an error, so that lexing resumes on the next line.
+    pub(crate) fn skip_line(&mut self) {
+        self.take_while(|c| c != '\n');
+        self.reset_pos();
+    }
+
     pub(crate) fn abs_pos(&self) -> usize {
         self.orig_size - self.len_remaining + self.pos_in_token()
     }
@@ -1,12 +1,13 @@
+use std::borrow::Cow;
 use std::fmt::Display;
 use std::num::IntErrorKind;
 use std::str::FromStr;
 
 use miette::Result;
 
+use crate::error;
 use crate::lexer::cursor::Cursor;
 use crate::symbol::{DirKind, Flag, InstrKind, Register, Span, SrcOffset, TrapKind};
-use crate::{error, features};
 
 pub mod cursor;
 
@@ -49,10 +50,33 @@ pub enum LiteralKind {
     Hex(u16),
     /// #-1, #32456
     Dec(i16),
+    /// b0101, 0b1111
+    Bin(u16),
+    /// o17, 0o777
+    Oct(u16),
+    /// 'A', '\n', '\x1B'
+    Char(u16),
     /// "str with \" escaped chars"
     Str,
 }
 
+/// Operators and parentheses, used in expressions.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OpKind {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Shl,
+    Shr,
+    Open,
+    Close,
+    /// `#` before an expression which does not start with a decimal literal, like `#SIZE-1`
+    Hash,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum TokenKind {
     Label,
@@ -61,6 +85,7 @@ pub enum TokenKind {
     Lit(LiteralKind),
     Dir(DirKind),
     Reg(Register),
+    Op(OpKind),
     /// Preprocessor raw values
     Byte(u16),
     Breakpoint,
@@ -78,6 +103,7 @@ impl Display for TokenKind {
             TokenKind::Lit(_) => "literal",
             TokenKind::Dir(_) => "preprocessor directive",
             TokenKind::Reg(_) => "register",
+            TokenKind::Op(_) => "operator",
             TokenKind::Whitespace
             | TokenKind::Comment
             | TokenKind::Eof
@@ -101,6 +127,96 @@ pub(crate) fn is_reg_num(c: char) -> bool {
     matches!(c, '0'..='7')
 }
 
+/// Replace escape sequences in a string or character literal, without the quotes.
+///
+/// Unknown escape sequences are kept as they are written. The lexer rejects literals which contain
+/// them, see [`invalid_escape`].
+pub(crate) fn unescape(s: &str) -> Cow<'_, str> {
+    if s.find('\\').is_none() {
+        return Cow::Borrowed(s);
+    }
+    let mut result = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('x') => {
+                    let digits = chars.as_str().get(..2).unwrap_or_default();
+                    match hex_escape(digits) {
+                        Some(val) => {
+                            result.push(val);
+                            chars.nth(1);
+                        }
+                        None => result.push_str("\\x"),
+                    }
+                }
+                Some(c) => match escaped(c) {
+                    Some(c) => result.push(c),
+                    None => {
+                        result.push('\\');
+                        result.push(c);
+                    }
+                },
+                None => {
+                    // Trailing \
+                    result.push('\\');
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Byte offset and length of the first escape sequence in `s` which is unknown, or which is
+/// missing its hex digits.
+fn invalid_escape(s: &str) -> Option<(usize, usize)> {
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+        match chars.next() {
+            Some((_, 'x')) => match s.get(i + 2..i + 4).and_then(hex_escape) {
+                Some(_) => {
+                    chars.nth(1);
+                }
+                None => return Some((i, 2)),
+            },
+            Some((_, c)) if escaped(c).is_some() => {}
+            Some((_, c)) => return Some((i, 1 + c.len_utf8())),
+            None => return Some((i, 1)),
+        }
+    }
+    None
+}
+
+/// Character written by an escape sequence of a single character, such as `n` for `\n`.
+fn escaped(c: char) -> Option<char> {
+    Some(match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        // Escape character, which starts ANSI escape codes
+        'e' => '\x1B',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        _ => return None,
+    })
+}
+
+/// Character written by the two hex digits of a `\x` escape sequence.
+fn hex_escape(digits: &str) -> Option<char> {
+    if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(digits, 16).ok().map(char::from)
+}
+
 /// Test if a character is considered an LC3 identifier character.
 pub(crate) fn is_id(c: char) -> bool {
     // Non-prefixed numerical literals are considered identifiers.
@@ -136,11 +252,22 @@ impl Cursor<'_> {
             }
             // Hex literals
             'x' | 'X' => self.hex()?,
+            // Binary and octal literals, otherwise identifiers such as `br`
+            'b' | 'B' if self.is_radix(0, 2) => self.radix(2)?,
+            'o' | 'O' if self.is_radix(0, 8) => self.radix(8)?,
             '0' => match self.first() {
                 'x' | 'X' => {
                     self.bump();
                     self.hex()?
                 }
+                'b' | 'B' if self.is_radix(1, 2) => {
+                    self.bump();
+                    self.radix(2)?
+                }
+                'o' | 'O' if self.is_radix(1, 8) => {
+                    self.bump();
+                    self.radix(8)?
+                }
                 _ => self.ident()?,
             },
             // Register literals
@@ -164,12 +291,35 @@ impl Cursor<'_> {
             },
             // Check only after other identifier-likes
             c if is_id(c) => self.ident()?,
-            // Decimal literal
-            '#' => self.dec()?,
+            // Decimal literal, or the start of a decimal expression
+            '#' => match (self.first(), self.second()) {
+                (c, _) if c.is_ascii_digit() => self.dec()?,
+                ('-' | '+', c) if c.is_ascii_digit() => self.dec()?,
+                _ => TokenKind::Op(OpKind::Hash),
+            },
+            // Operators
+            '+' => TokenKind::Op(OpKind::Add),
+            '-' => TokenKind::Op(OpKind::Sub),
+            '*' => TokenKind::Op(OpKind::Mul),
+            '/' => TokenKind::Op(OpKind::Div),
+            '&' => TokenKind::Op(OpKind::And),
+            '|' => TokenKind::Op(OpKind::Or),
+            '(' => TokenKind::Op(OpKind::Open),
+            ')' => TokenKind::Op(OpKind::Close),
+            '<' if self.first() == '<' => {
+                self.bump();
+                TokenKind::Op(OpKind::Shl)
+            }
+            '>' if self.first() == '>' => {
+                self.bump();
+                TokenKind::Op(OpKind::Shr)
+            }
             // Directive
             '.' => self.dir()?,
             // String literal
             '"' => self.str()?,
+            // Character literal
+            '\'' => self.char()?,
             // Unknown starting characters
             _ => {
                 let start = self.abs_pos() - 1;
@@ -191,7 +341,8 @@ impl Cursor<'_> {
     fn hex(&mut self) -> Result<TokenKind> {
         let start = self.abs_pos();
         let prefix = self.pos_in_token();
-        self.take_while(|c| !is_whitespace(c) && c != ';');
+        self.take_sign();
+        self.take_while(is_id);
         let str_val = self.get_range(start..self.abs_pos());
         let value = match i16::from_str_radix(str_val, 16) {
             Ok(value) => value as u16,
@@ -216,7 +367,8 @@ impl Cursor<'_> {
     fn dec(&mut self) -> Result<TokenKind> {
         let start = self.abs_pos();
         let prefix = self.pos_in_token();
-        self.take_while(|c| !is_whitespace(c) && c != ';');
+        self.take_sign();
+        self.take_while(is_id);
         let str_val = self.get_range(start..self.abs_pos());
 
         // i16 to handle negative values
@@ -237,6 +389,44 @@ impl Cursor<'_> {
         Ok(TokenKind::Lit(LiteralKind::Dec(value)))
     }
 
+    /// Whether the identifier after the next `skip` characters only contains digits of `radix`.
+    fn is_radix(&self, skip: usize, radix: u32) -> bool {
+        let mut chars = self.clone();
+        for _ in 0..skip {
+            chars.bump();
+        }
+        let mut digits = 0;
+        while is_id(chars.first()) && !chars.is_eof() {
+            if !chars.first().is_digit(radix) {
+                return false;
+            }
+            chars.bump();
+            digits += 1;
+        }
+        digits > 0
+    }
+
+    fn radix(&mut self, radix: u32) -> Result<TokenKind> {
+        let start = self.abs_pos();
+        let prefix = self.pos_in_token();
+        self.take_while(is_id);
+        let str_val = self.get_range(start..self.abs_pos());
+        let value = u16::from_str_radix(str_val, radix).map_err(|e| {
+            error::lex_invalid_lit((start - prefix..self.abs_pos()).into(), self.src(), e)
+        })?;
+        Ok(TokenKind::Lit(match radix {
+            2 => LiteralKind::Bin(value),
+            _ => LiteralKind::Oct(value),
+        }))
+    }
+
+    /// Consume the sign of a literal, which is not treated as an operator.
+    fn take_sign(&mut self) {
+        if matches!(self.first(), '-' | '+') && is_id(self.second()) {
+            self.bump();
+        }
+    }
+
     fn str(&mut self) -> Result<TokenKind> {
         let start = self.abs_pos() - 1;
         let mut terminated = false;
@@ -258,33 +448,79 @@ impl Cursor<'_> {
                 self.src(),
             ));
         }
+        self.check_escapes(start + 1)?;
         Ok(TokenKind::Lit(LiteralKind::Str))
     }
 
+    fn char(&mut self) -> Result<TokenKind> {
+        let start = self.abs_pos() - 1;
+        let mut terminated = false;
+        while let Some(c) = self.bump() {
+            if c == '\n' {
+                break;
+            };
+            if c == '\'' {
+                terminated = true;
+                break;
+            }
+            if c == '\\' {
+                self.bump();
+            }
+        }
+        let span = (start..self.abs_pos()).into();
+        if !terminated {
+            return Err(error::lex_invalid_char(span, self.src()));
+        }
+        self.check_escapes(start + 1)?;
+        let value = unescape(self.get_range(start + 1..self.abs_pos() - 1));
+        let mut chars = value.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if (c as u32) <= u16::MAX as u32 => {
+                Ok(TokenKind::Lit(LiteralKind::Char(c as u16)))
+            }
+            _ => Err(error::lex_invalid_char(span, self.src())),
+        }
+    }
+
+    /// Check escape sequences of a string or character literal which has just been lexed, from
+    /// `start` up to the closing quote.
+    fn check_escapes(&self, start: usize) -> Result<()> {
+        let text = self.get_range(start..self.abs_pos() - 1);
+        match invalid_escape(text) {
+            Some((offs, len)) => Err(error::lex_invalid_escape(
+                Span::new(SrcOffset(start + offs), len),
+                self.src(),
+            )),
+            None => Ok(()),
+        }
+    }
+
     fn dir(&mut self) -> Result<TokenKind> {
         // Starting .
         let start = self.abs_pos() - 1;
         self.take_while(is_id);
-        let dir = self.get_range(start..self.abs_pos()).to_ascii_lowercase();
-
-        if let Some(token_kind) = self.check_directive(&dir) {
-            Ok(token_kind)
-        } else {
-            Err(error::lex_invalid_dir(
+        if self.abs_pos() == start + 1 {
+            return Err(error::lex_invalid_dir(
                 (start..self.abs_pos()).into(),
                 self.src(),
-            ))
+            ));
         }
+        let dir = self.get_range(start..self.abs_pos()).to_ascii_lowercase();
+
+        // Any other name is a local label, scoped to the preceding global label. The parser reports
+        // it as an invalid directive where a statement is expected
+        Ok(self.check_directive(&dir).unwrap_or(TokenKind::Label))
     }
 
     fn ident(&mut self) -> Result<TokenKind> {
         let ident_start = self.abs_pos() - 1;
-        self.take_while(is_id);
+        // Local labels can be referred to by their full name, such as `parent.local`
+        self.take_while(|c| is_id(c) || c == '.');
         let ident = self
             .get_range(ident_start..self.abs_pos())
             .to_ascii_lowercase();
 
-        let mut token_kind = self.check_instruction(&ident, ident_start)?;
+        let mut token_kind = self.check_instruction(&ident);
         if token_kind == TokenKind::Label {
             token_kind = self.check_trap(&ident);
         }
@@ -302,24 +538,37 @@ impl Cursor<'_> {
             ".blkw" => Some(Dir(Blkw)),
             ".fill" => Some(Dir(Fill)),
             ".break" => Some(Dir(Break)),
+            ".macro" => Some(Dir(Macro)),
+            ".endm" => Some(Dir(Endm)),
+            ".include" => Some(Dir(Include)),
+            ".equ" => Some(Dir(Equ)),
+            ".set" => Some(Dir(Set)),
+            ".global" => Some(Dir(Global)),
+            ".extern" => Some(Dir(Extern)),
+            ".if" => Some(Dir(If)),
+            ".ifdef" => Some(Dir(Ifdef)),
+            ".ifndef" => Some(Dir(Ifndef)),
+            ".else" => Some(Dir(Else)),
+            ".endif" => Some(Dir(Endif)),
+            ".pool" => Some(Dir(Pool)),
+            ".word" => Some(Dir(Word)),
+            ".stringp" => Some(Dir(Stringp)),
+            ".incbin" => Some(Dir(Incbin)),
             _ => None,
         }
     }
 
     /// Expects lowercase
-    fn check_instruction(&self, ident: &str, start_pos: usize) -> Result<TokenKind> {
+    ///
+    /// Instructions from extensions are checked by the parser, so that they may be used in a
+    /// block of conditional assembly which is only kept if the extension is enabled.
+    /// Pseudo-instructions are only reserved if the 'sugar' extension is enabled, as their names
+    /// are common labels.
+    fn check_instruction(&self, ident: &str) -> TokenKind {
         use InstrKind::*;
         use TokenKind::Instr;
 
-        if matches!(ident, "pop" | "push" | "call" | "rets") && !features::stack() {
-            return Err(error::lex_stack_extension_not_enabled(
-                ident,
-                Span::new(SrcOffset(start_pos), self.pos_in_token()),
-                self.src(),
-            ));
-        }
-
-        Ok(match ident {
+        match ident {
             "add" => Instr(Add),
             "and" => Instr(And),
             "br" => Instr(Br(Flag::Nzp)),
@@ -347,8 +596,15 @@ impl Cursor<'_> {
             "push" => Instr(Push),
             "call" => Instr(Call),
             "rets" => Instr(Rets),
+            "mov" if self.features().sugar() => Instr(Mov),
+            "clr" if self.features().sugar() => Instr(Clr),
+            "inc" if self.features().sugar() => Instr(Inc),
+            "dec" if self.features().sugar() => Instr(Dec),
+            "neg" if self.features().sugar() => Instr(Neg),
+            "sub" if self.features().sugar() => Instr(Sub),
+            "ldi16" if self.features().sugar() => Instr(Ldi16),
             _ => TokenKind::Label,
-        })
+        }
     }
 
     /// Expects lowercase
@@ -374,7 +630,7 @@ impl Cursor<'_> {
 mod test {
     use crate::{
         lexer::{LiteralKind, TokenKind},
-        symbol::Register,
+        symbol::{DirKind, Flag, InstrKind, Register},
     };
 
     use super::cursor::Cursor;
@@ -466,6 +722,56 @@ mod test {
         assert!(res.kind == TokenKind::Lit(LiteralKind::Dec(-300)))
     }
 
+    // BIN AND OCT LIT TESTS
+
+    #[test]
+    fn bin_oct_values() {
+        let mut lex = Cursor::new("b0101 0B1111 o17 0o777 br b2");
+        let mut kinds = Vec::new();
+        while let Ok(tok) = lex.advance_real() {
+            if tok.kind == TokenKind::Eof {
+                break;
+            }
+            kinds.push(tok.kind);
+        }
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Lit(LiteralKind::Bin(0b0101)),
+                TokenKind::Lit(LiteralKind::Bin(0b1111)),
+                TokenKind::Lit(LiteralKind::Oct(0o17)),
+                TokenKind::Lit(LiteralKind::Oct(0o777)),
+                TokenKind::Instr(InstrKind::Br(Flag::Nzp)),
+                TokenKind::Label,
+            ]
+        );
+        let mut lex = Cursor::new("b11111111111111111");
+        assert!(lex.advance_token().is_err());
+    }
+
+    // CHAR LIT TESTS
+
+    #[test]
+    fn char_values() {
+        for (src, val) in [
+            ("'A'", b'A' as u16),
+            ("';'", b';' as u16),
+            (r"'\n'", b'\n' as u16),
+            (r"'\''", b'\'' as u16),
+            (r"'\0'", 0),
+            (r"'\e'", 0x1B),
+            (r"'\x7f'", 0x7F),
+        ] {
+            let mut lex = Cursor::new(src);
+            let res = lex.advance_token().unwrap();
+            assert_eq!(res.kind, TokenKind::Lit(LiteralKind::Char(val)), "{src}");
+        }
+        for src in ["''", "'ab'", "'A", r"'\q'", r"'\xZZ'"] {
+            let mut lex = Cursor::new(src);
+            assert!(lex.advance_token().is_err(), "{src}");
+        }
+    }
+
     // STR LIT TESTS
 
     #[test]
@@ -480,6 +786,17 @@ mod test {
         assert!(lex.advance_token().is_ok())
     }
 
+    #[test]
+    fn str_invalid_escape() {
+        for src in [r#""\xZZ""#, r#""\q""#, r#""ok \x4""#] {
+            let mut lex = Cursor::new(src);
+            assert!(lex.advance_token().is_err(), "{src}");
+        }
+        let mut lex = Cursor::new(r#""\x41\e[0m\"""#);
+        assert!(lex.advance_token().is_ok());
+        assert_eq!(super::unescape(r"\x41\e[0m\q"), "A\x1B[0m\\q");
+    }
+
     #[test]
     fn str_comment() {
         let mut lex = Cursor::new(r#""there is an escaped \" in this str\n;""#);
@@ -516,6 +833,20 @@ mod test {
         );
     }
 
+    #[test]
+    fn local_label() {
+        let mut lex = Cursor::new(".loop .fill print.loop .");
+        assert_eq!(lex.advance_real().unwrap().kind, TokenKind::Label);
+        assert_eq!(
+            lex.advance_real().unwrap().kind,
+            TokenKind::Dir(DirKind::Fill)
+        );
+        let tok = lex.advance_real().unwrap();
+        assert_eq!(tok.kind, TokenKind::Label);
+        assert_eq!(tok.span.len(), "print.loop".len());
+        assert!(lex.advance_real().is_err());
+    }
+
     #[test]
     fn register_comment() {
         let mut lex = Cursor::new("R0;");
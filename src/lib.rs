@@ -1,22 +1,46 @@
 // Parsing
+mod assembler;
+pub use assembler::Assembler;
 mod parser;
-pub use parser::AsmParser;
+pub use parser::{AsmParser, Define};
 mod air;
 pub use air::Air;
+mod expr;
+mod object;
+pub use object::{link, Object};
+mod disasm;
+pub use disasm::disassemble;
+mod emit;
+pub use emit::{decode, encode, Format};
+mod symfile;
+pub use symfile::{read_symbols, write_symbols};
+mod lint;
+pub use lint::{lint, Lint, Lints};
+
+// Formatting
+mod formatter;
+mod syntax;
+pub use formatter::format;
+mod listing;
+pub use listing::listing;
 
 // Running
 mod runtime;
-pub use runtime::RunEnvironment;
+pub use runtime::{Fault, RunEnvironment, RunOutcome, RuntimeError};
 #[macro_use]
 pub mod debugger;
+mod devices;
 mod output;
 mod term;
 
-// Reset global state for watch
+// Program context
 mod symbol;
-pub use symbol::{reset_state, StaticSource};
+pub use symbol::{Symbol, SymbolTable};
+mod source;
+pub use source::Source;
 
 mod error;
+pub use error::Diagnostics;
 mod lexer;
 
 pub mod features;
@@ -0,0 +1,574 @@
+//! Warnings about programs which assemble, but are unlikely to do what was intended.
+//!
+//! Lints are checked after backpatching, by following every path that execution can take from
+//! the start of the program and from each subroutine.
+
+use std::fmt;
+use std::str::FromStr;
+
+use fxhash::{FxHashMap, FxHashSet};
+use miette::Report;
+
+use crate::air::{AirStmt, AsmLine};
+use crate::error;
+use crate::symbol::{Flag, Label, Register};
+use crate::Air;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lint {
+    /// Execution continues from an instruction into data
+    FallThrough,
+    /// Instructions which execution never reaches
+    Unreachable,
+    /// Execution leaves the program without halting
+    MissingHalt,
+    /// A subroutine calls another with `jsr` without saving its own return address
+    UnsavedR7,
+    /// A label which nothing refers to
+    UnusedLabel,
+    /// A conditional branch after an instruction which does not set the condition codes
+    StaleFlags,
+    /// A `.blkw` directive which reserves no words
+    EmptyBlkw,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 7] = [
+        Lint::FallThrough,
+        Lint::Unreachable,
+        Lint::MissingHalt,
+        Lint::UnsavedR7,
+        Lint::UnusedLabel,
+        Lint::StaleFlags,
+        Lint::EmptyBlkw,
+    ];
+
+    /// Name which the lint is enabled or allowed by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::FallThrough => "fall-through",
+            Lint::Unreachable => "unreachable",
+            Lint::MissingHalt => "missing-halt",
+            Lint::UnsavedR7 => "unsaved-r7",
+            Lint::UnusedLabel => "unused-label",
+            Lint::StaleFlags => "stale-flags",
+            Lint::EmptyBlkw => "empty-blkw",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// Set of lints, such as those which are enabled.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Lints(u8);
+
+impl Lints {
+    pub fn all() -> Self {
+        Lints(Lint::ALL.iter().fold(0, |bits, lint| bits | lint.bit()))
+    }
+
+    pub fn contains(&self, lint: Lint) -> bool {
+        self.0 & lint.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Lints in either set.
+    pub fn with(self, other: Lints) -> Self {
+        Lints(self.0 | other.0)
+    }
+
+    /// Lints in this set, but not in `other`.
+    pub fn without(self, other: Lints) -> Self {
+        Lints(self.0 & !other.0)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = Lint> + '_ {
+        Lint::ALL.into_iter().filter(|lint| self.contains(*lint))
+    }
+}
+
+impl FromStr for Lints {
+    type Err = String;
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut lints = Self::default();
+        for word in string.split(',') {
+            if word.is_empty() {
+                continue;
+            }
+            if word == "all" {
+                lints = Lints::all();
+                continue;
+            }
+            let Some(lint) = Lint::ALL.into_iter().find(|lint| lint.name() == word) else {
+                return Err(format!("Unknown lint '{}'", word));
+            };
+            lints = lints.with(Lints(lint.bit()));
+        }
+        Ok(lints)
+    }
+}
+
+impl fmt::Display for Lints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.iter().map(|lint| lint.name()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Check a backpatched program for each lint in `lints`.
+///
+/// Every warning is recorded in [`Air::diagnostics`], in order of where it is in the source.
+pub fn lint(air: &mut Air, lints: Lints) {
+    let mut warnings = Vec::new();
+    let flow = Flow::new(air);
+    let reachable = flow.reachable(air);
+
+    if lints.contains(Lint::FallThrough) {
+        for (i, line) in air.ast.iter().enumerate() {
+            if let Some(next) = flow.next(i) {
+                let is_data = |i: usize| matches!(air.ast[i].stmt, AirStmt::RawWord { .. });
+                if reachable[i] && !is_data(i) && is_data(next) && !line.is_unconditional() {
+                    warnings.push(error::lint_fall_through(line.span, air.ast[next].span));
+                }
+            }
+        }
+    }
+
+    if lints.contains(Lint::Unreachable) {
+        for (i, line) in air.ast.iter().enumerate() {
+            let is_dead =
+                |i: usize| !reachable[i] && !matches!(air.ast[i].stmt, AirStmt::RawWord { .. });
+            // Only the first statement of each unreachable sequence is reported
+            let prev = flow.prev(i);
+            if !is_dead(i) || prev.is_some_and(is_dead) {
+                continue;
+            }
+            let stop = prev
+                .filter(|prev| air.ast[*prev].is_unconditional())
+                .map(|prev| air.ast[prev].span);
+            warnings.push(error::lint_unreachable(line.span, stop));
+        }
+    }
+
+    if lints.contains(Lint::MissingHalt) && !air.ast.is_empty() {
+        for i in flow.body(air, 0) {
+            let line = &air.ast[i];
+            match line.stmt {
+                AirStmt::Return | AirStmt::Rets => {
+                    warnings.push(error::lint_missing_halt(line.span, true));
+                }
+                AirStmt::RawWord { .. } => (),
+                _ if !line.is_unconditional() && flow.next(i).is_none() => {
+                    warnings.push(error::lint_missing_halt(line.span, false));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    if lints.contains(Lint::UnsavedR7) {
+        for &root in &flow.subroutines {
+            let body = flow.body(air, root);
+            let saves = body.iter().any(|i| saves_r7(&air.ast[*i].stmt));
+            let call = body.iter().find(|i| {
+                matches!(
+                    air.ast[**i].stmt,
+                    AirStmt::JumbSub { .. } | AirStmt::JumpSubReg { .. }
+                )
+            });
+            let ret = body.iter().find(|i| {
+                matches!(
+                    air.ast[**i].stmt,
+                    AirStmt::Return
+                        | AirStmt::Jump {
+                            src_reg: Register::R7
+                        }
+                )
+            });
+            if let (false, Some(call), Some(ret)) = (saves, call, ret) {
+                warnings.push(error::lint_unsaved_r7(
+                    air.ast[*call].span,
+                    air.ast[*ret].span,
+                ));
+            }
+        }
+    }
+
+    if lints.contains(Lint::UnusedLabel) {
+        let used: FxHashSet<&str> = air
+            .refs()
+            .flat_map(|(_, expr)| expr.labels())
+            .map(|(name, _)| name)
+            .collect();
+        let entry = air.ast.first().map(|line| line.addr);
+        for (name, span) in air.labels() {
+            // Labels which are local to a macro expansion may only be used by some expansions
+            if used.contains(name.as_str())
+                || air.is_global(name)
+                || span.local_label().is_some()
+                || air.symbols.label(name) == entry
+            {
+                continue;
+            }
+            warnings.push(error::lint_unused_label(*span, name));
+        }
+    }
+
+    if lints.contains(Lint::StaleFlags) {
+        for (i, line) in air.ast.iter().enumerate() {
+            let AirStmt::Branch { flag, .. } = line.stmt else {
+                continue;
+            };
+            if flag == Flag::Nzp {
+                continue;
+            }
+            // Find the instruction which set the condition codes, skipping other branches, which
+            // leave them unchanged
+            let mut j = i;
+            let setter = loop {
+                if flow.targets.contains(&j) {
+                    // Condition codes may be set before any of the jumps to here
+                    break Some(None);
+                }
+                match flow.prev(j) {
+                    Some(prev) if matches!(air.ast[prev].stmt, AirStmt::Branch { flag, .. } if flag != Flag::Nzp) =>
+                    {
+                        j = prev;
+                    }
+                    Some(prev) => break Some(Some(prev)),
+                    // Start of the program
+                    None if j == 0 => break None,
+                    None => break Some(None),
+                }
+            };
+            match setter {
+                Some(Some(prev)) if !sets_flags(&air.ast[prev].stmt) => {
+                    warnings.push(error::lint_stale_flags(line.span, Some(air.ast[prev].span)));
+                }
+                None => warnings.push(error::lint_stale_flags(line.span, None)),
+                Some(_) => (),
+            }
+        }
+    }
+
+    if lints.contains(Lint::EmptyBlkw) {
+        for span in air.empty_blocks() {
+            warnings.push(error::lint_empty_blkw(*span));
+        }
+    }
+
+    // Warnings are sorted by the first span of each
+    let offset = |report: &Report| {
+        report
+            .labels()
+            .and_then(|mut labels| labels.next())
+            .map_or(0, |label| label.offset())
+    };
+    warnings.sort_by_key(offset);
+    for warning in warnings {
+        air.diagnostics
+            .push(warning.with_source_code(air.source.clone()));
+    }
+}
+
+/// Paths which execution can take between statements.
+struct Flow {
+    /// Index of the statement after each statement in its segment, if any
+    next: Vec<Option<usize>>,
+    /// Index of each address in the program
+    index: FxHashMap<u16, usize>,
+    /// Index of the branch and subroutine target of each statement, if it has one
+    target: Vec<Option<usize>>,
+    /// Statements which are branched to, called, or referred to by a label
+    targets: FxHashSet<usize>,
+    /// First statement of each subroutine which is called with `jsr` or `call`
+    subroutines: Vec<usize>,
+}
+
+impl Flow {
+    fn new(air: &Air) -> Self {
+        let mut next = Vec::with_capacity(air.len());
+        for (_, stmts) in air.segments() {
+            let start = next.len();
+            next.extend((1..=stmts.len()).map(|k| (k < stmts.len()).then_some(start + k)));
+        }
+        let index: FxHashMap<u16, usize> = air
+            .ast
+            .iter()
+            .enumerate()
+            .map(|(i, line)| (line.addr, i))
+            .collect();
+        let target: Vec<_> = air
+            .ast
+            .iter()
+            .map(|line| match line.label() {
+                Some(Label::Ref(addr)) => index.get(addr).copied(),
+                _ => None,
+            })
+            .collect();
+
+        let mut targets: FxHashSet<usize> = target.iter().flatten().copied().collect();
+        targets.extend(
+            air.label_addresses()
+                .iter()
+                .filter_map(|(_, addr)| index.get(addr)),
+        );
+        let mut subroutines: Vec<usize> = air
+            .ast
+            .iter()
+            .zip(&target)
+            .filter(|(line, _)| matches!(line.stmt, AirStmt::JumbSub { .. } | AirStmt::Call { .. }))
+            .filter_map(|(_, target)| *target)
+            .collect();
+        subroutines.sort_unstable();
+        subroutines.dedup();
+
+        Flow {
+            next,
+            index,
+            target,
+            targets,
+            subroutines,
+        }
+    }
+
+    fn next(&self, i: usize) -> Option<usize> {
+        self.next[i]
+    }
+
+    /// Index of the statement before `i` in its segment, if any.
+    fn prev(&self, i: usize) -> Option<usize> {
+        i.checked_sub(1).filter(|prev| self.next[*prev] == Some(i))
+    }
+
+    /// Statements which can be executed after `i`, without entering a subroutine.
+    fn successors(&self, line: &AsmLine, i: usize) -> Vec<usize> {
+        let mut successors = Vec::new();
+        match line.stmt {
+            AirStmt::RawWord { .. } => return successors,
+            AirStmt::Branch { .. } => successors.extend(self.target[i]),
+            _ => (),
+        }
+        if !line.is_unconditional() {
+            successors.extend(self.next(i));
+        }
+        successors
+    }
+
+    /// Statements which can be executed from `root`, without entering a subroutine, in order of
+    /// index.
+    fn body(&self, air: &Air, root: usize) -> Vec<usize> {
+        let seen = self.walk(air, vec![root], false);
+        (0..seen.len()).filter(|i| seen[*i]).collect()
+    }
+
+    /// Whether each statement can be executed.
+    ///
+    /// Execution starts at the first statement, and at each statement which is referred to by a
+    /// label in an operand other than a branch, such as a pointer to be called with `jsrr`, or
+    /// which is exported with `.global`. Subroutines are reachable if they are called from
+    /// reachable code.
+    fn reachable(&self, air: &Air) -> Vec<bool> {
+        let mut roots = Vec::new();
+        if !air.is_empty() {
+            roots.push(0);
+        }
+        for (i, expr) in air.refs() {
+            if matches!(
+                air.get(i).stmt,
+                AirStmt::Branch { .. } | AirStmt::JumbSub { .. } | AirStmt::Call { .. }
+            ) {
+                continue;
+            }
+            roots.extend(expr.labels().into_iter().filter_map(|(name, _)| {
+                let addr = air.symbols.label(name)?;
+                self.index.get(&addr).copied()
+            }));
+        }
+        for (name, _) in air.labels() {
+            if air.is_global(name) {
+                let addr = air.symbols.label(name);
+                roots.extend(addr.and_then(|addr| self.index.get(&addr)));
+            }
+        }
+        self.walk(air, roots, true)
+    }
+
+    /// Whether each statement can be executed from any of `roots`, optionally entering the
+    /// subroutines which are called.
+    fn walk(&self, air: &Air, roots: Vec<usize>, calls: bool) -> Vec<bool> {
+        let mut seen = vec![false; air.len()];
+        let mut stack = roots;
+        while let Some(i) = stack.pop() {
+            if std::mem::replace(&mut seen[i], true) {
+                continue;
+            }
+            let line = air.get(i);
+            stack.extend(self.successors(line, i));
+            if calls && matches!(line.stmt, AirStmt::JumbSub { .. } | AirStmt::Call { .. }) {
+                stack.extend(self.target[i]);
+            }
+        }
+        seen
+    }
+}
+
+/// Whether a statement keeps a copy of R7, so that it can be restored before returning.
+fn saves_r7(stmt: &AirStmt) -> bool {
+    match *stmt {
+        AirStmt::Store { src_reg, .. }
+        | AirStmt::StoreInd { src_reg, .. }
+        | AirStmt::StoreOffs { src_reg, .. }
+        | AirStmt::Push { src_reg } => src_reg == Register::R7,
+        AirStmt::Add { dest, src_reg, .. } | AirStmt::And { dest, src_reg, .. } => {
+            src_reg == Register::R7 && dest != Register::R7
+        }
+        _ => false,
+    }
+}
+
+/// Whether a statement sets the condition codes, or may do so as it calls a subroutine.
+fn sets_flags(stmt: &AirStmt) -> bool {
+    !matches!(
+        stmt,
+        AirStmt::Store { .. }
+            | AirStmt::StoreInd { .. }
+            | AirStmt::StoreOffs { .. }
+            | AirStmt::Push { .. }
+            | AirStmt::Pop { .. }
+            | AirStmt::Trap { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, Source};
+
+    /// Code of each warning from linting `src` with every lint.
+    fn lint_codes(src: &str) -> Vec<String> {
+        let air = Assembler::default()
+            .lints(Lints::all())
+            .assemble(&Source::new(src));
+        assert!(!air.diagnostics.has_errors());
+        air.diagnostics
+            .iter()
+            .filter_map(|report| Some(report.code()?.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let src = "main    jsr print\n        halt\n\
+                   print   st r7 saved\n        lea r0 msg\n        puts\n        jsr newline\n\
+                   \x20       ld r7 saved\n        ret\n\
+                   newline ld r0 nl\n        out\n        ret\n\
+                   saved   .blkw #1\nnl      .fill '\\n'\nmsg     .stringz \"hi\"\n";
+        assert_eq!(lint_codes(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn fall_through() {
+        assert_eq!(
+            lint_codes("lea r0 msg\nputs\nmsg .stringz \"x\"\n"),
+            ["lint::fall_through"]
+        );
+        // Only if the branch is taken
+        assert_eq!(
+            lint_codes("add r0 r0 #0\nbrz done\nnot r0 r0\ndone halt\n.fill #1\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(
+            lint_codes("loop br loop\nadd r0 r0 #1\nnot r0 r0\nhalt\n"),
+            ["lint::unreachable"]
+        );
+        // Referred to by a pointer, so may be called with `jsrr`
+        assert_eq!(
+            lint_codes("ld r0 ptr\njsrr r0\nhalt\nptr .fill sub\nsub ret\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn missing_halt() {
+        assert_eq!(
+            lint_codes("add r0 r0 #1\nnot r0 r0\n"),
+            ["lint::missing_halt"]
+        );
+        // Falls into a subroutine, then returns
+        assert_eq!(
+            lint_codes("main jsr sub\nsub add r0 r0 #1\nret\n"),
+            ["lint::missing_halt"]
+        );
+    }
+
+    #[test]
+    fn unsaved_r7() {
+        assert_eq!(
+            lint_codes("jsr outer\nhalt\nouter jsr inner\nret\ninner ret\n"),
+            ["lint::unsaved_r7"]
+        );
+        assert_eq!(
+            lint_codes("jsr outer\nhalt\nouter add r6 r7 #0\njsr inner\njmp r6\ninner ret\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn unused_label() {
+        assert_eq!(
+            lint_codes("main halt\nunused .fill #1\n"),
+            ["lint::unused_label"]
+        );
+        assert_eq!(
+            lint_codes(".global lib\nhalt\nlib .fill #1\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn stale_flags() {
+        assert_eq!(
+            lint_codes("getc\nbrz done\nout\ndone halt\n"),
+            ["lint::stale_flags"]
+        );
+        // Condition codes are set before the first branch
+        assert_eq!(
+            lint_codes("add r0 r0 #0\nbrz done\nbrn done\nout\ndone halt\n"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            lint_codes("brz done\nout\ndone halt\n"),
+            ["lint::stale_flags"]
+        );
+    }
+
+    #[test]
+    fn empty_blkw() {
+        assert_eq!(
+            lint_codes("lea r0 buf\nhalt\nbuf .blkw #0\n.fill #1\n"),
+            ["lint::empty_blkw"]
+        );
+    }
+
+    #[test]
+    fn lints_from_str() {
+        assert_eq!("all".parse::<Lints>(), Ok(Lints::all()));
+        let lints: Lints = "unreachable,empty-blkw".parse().unwrap();
+        assert!(lints.contains(Lint::Unreachable));
+        assert!(!lints.contains(Lint::FallThrough));
+        assert_eq!(lints.to_string(), "unreachable,empty-blkw");
+        assert!("unknown".parse::<Lints>().is_err());
+        assert_eq!(Lints::all().without(lints).with(lints), Lints::all());
+    }
+}
@@ -0,0 +1,164 @@
+//! Listing of an assembled program, written by `lace compile --listing`.
+//!
+//! Each word is listed with its address, its value in hex and binary, the label declared at it,
+//! and the source line which it was assembled from. Long runs of the same word from a single
+//! directive, such as `.blkw`, are collapsed. Statements from another file than the one before
+//! them, such as an included file, follow a row with the name of that file. The symbol table is
+//! listed after the program.
+
+use std::fmt::Write;
+
+use fxhash::FxHashMap;
+use miette::Result;
+
+use crate::air::{Air, AirStmt, AsmLine};
+use crate::symbol::Span;
+
+/// Runs of at least this many identical words from one directive are collapsed.
+const COLLAPSE_LEN: usize = 3;
+
+/// Write a listing of an assembled program.
+///
+/// Fails if a statement cannot be emitted, so the program must be backpatched first.
+pub fn listing(air: &Air) -> Result<String> {
+    let labels = air.label_addresses();
+    let mut at_addr: FxHashMap<u16, Vec<&str>> = FxHashMap::default();
+    for (name, addr) in &labels {
+        at_addr.entry(*addr).or_default().push(name);
+    }
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<6} {:<5} {:<19} {:>5}  {:<16} Source",
+        "Addr", "Hex", "Binary", "Line", "Label"
+    )
+    .unwrap();
+
+    let lines: Vec<&AsmLine> = air.into_iter().collect();
+    let mut file = air.source.main_range();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let word = line.emit()?;
+        // Source is only shown for the first word of a statement
+        let first = i == 0 || lines[i - 1].span != line.span;
+        let label = at_addr
+            .get(&line.addr)
+            .map(|names| names.join(" "))
+            .unwrap_or_default();
+        let (number, text) = match first {
+            true => source_line(air, line.span),
+            false => (String::new(), ""),
+        };
+        let offs = line.span.call_site().unwrap_or(line.span).offs();
+        if first && !file.contains(&offs) {
+            file = air.source.file_range(offs);
+            let name = air.source.file_name(offs).unwrap_or("<source>");
+            writeln!(out, "--- {name}").unwrap();
+        }
+        let row = format!(
+            "x{:04X}  x{:04X} {} {:>5}  {:<16} {}",
+            line.addr,
+            word,
+            binary(word),
+            number,
+            label,
+            text
+        );
+        writeln!(out, "{}", row.trim_end()).unwrap();
+
+        let run = run_len(&lines[i..]);
+        if run >= COLLAPSE_LEN {
+            let last = lines[i + run - 1].addr;
+            writeln!(
+                out,
+                "{:<6} {:<5} {:<19} {:>5}  {:<16} ({} more words, to x{:04X})",
+                "...",
+                "",
+                "",
+                "",
+                "",
+                run - 1,
+                last
+            )
+            .unwrap();
+            i += run;
+        } else {
+            i += 1;
+        }
+    }
+
+    writeln!(out, "\nSymbol table").unwrap();
+    writeln!(out, "{:<16} Address", "Label").unwrap();
+    for (name, addr) in labels {
+        writeln!(out, "{name:<16} x{addr:04X}").unwrap();
+    }
+    Ok(out)
+}
+
+/// Amount of raw words at the start of `lines` with the same value and span.
+fn run_len(lines: &[&AsmLine]) -> usize {
+    let first = lines[0];
+    if !matches!(first.stmt, AirStmt::RawWord { .. }) {
+        return 1;
+    }
+    lines
+        .iter()
+        .take_while(|line| line.span == first.span && line.stmt == first.stmt)
+        .count()
+}
+
+/// Number and text of the line containing the start of a span, within its file.
+///
+/// Statements produced by a macro are listed with the line of the macro call.
+fn source_line(air: &Air, span: Span) -> (String, &str) {
+    let span = span.call_site().unwrap_or(span);
+    let text = air.source.text();
+    let file = air.source.file_range(span.offs());
+    let start = text[file.start..span.offs()]
+        .rfind('\n')
+        .map_or(file.start, |i| file.start + i + 1);
+    let end = text[span.offs()..file.end]
+        .find('\n')
+        .map_or(file.end, |i| span.offs() + i);
+    let number = text[file.start..start].matches('\n').count() + 1;
+    (number.to_string(), text[start..end].trim_end())
+}
+
+/// Binary representation of a word, in groups of 4 bits.
+fn binary(word: u16) -> String {
+    let bits = format!("{word:016b}");
+    let groups: Vec<_> = (0..4).map(|i| &bits[i * 4..i * 4 + 4]).collect();
+    groups.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AsmParser;
+
+    #[test]
+    fn lists_program() {
+        let mut air =
+            AsmParser::new("main and r0 r0 #0\n  brz main\nbuf .blkw #4\nend .stringz \"ab\"\n")
+                .parse();
+        air.backpatch();
+        let listing = listing(&air).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(
+            lines[1],
+            "x3000  x5020 0101 0000 0010 0000     1  main             main and r0 r0 #0"
+        );
+        assert!(lines[2].starts_with("x3001  x05FE 0000 0101 1111 1110     2"));
+        // Block is collapsed
+        assert!(lines[3].contains("buf"));
+        assert!(lines[4].starts_with("..."));
+        assert!(lines[4].ends_with("(3 more words, to x3005)"));
+        // Source is only shown for the first word of a statement
+        assert!(lines[5].contains("end .stringz \"ab\""));
+        // Rows without source have no trailing whitespace
+        assert_eq!(lines[6], "x3007  x0062 0000 0000 0110 0010");
+        assert!(listing.contains("\nbuf              x3002\n"));
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::Duration;
@@ -11,11 +11,11 @@ use hotwatch::{
     blocking::{Flow, Hotwatch},
     EventKind,
 };
-use miette::{bail, IntoDiagnostic, Result};
+use miette::{bail, IntoDiagnostic, Result, WrapErr};
 
+use lace::debugger;
 use lace::features::Features;
-use lace::{debugger, reset_state};
-use lace::{Air, RunEnvironment, StaticSource};
+use lace::{Air, Assembler, Define, Fault, Format, Lints, Object, RunEnvironment, SymbolTable};
 
 /// Lace is a complete & convenient assembler toolchain for the LC3 assembly language.
 #[derive(Parser)]
@@ -31,26 +31,34 @@ struct Args {
     minimal: bool,
     #[command(flatten)]
     run_options: RunOptions,
+    #[command(flatten)]
+    asm_options: AsmOptions,
+    #[command(flatten)]
+    machine_options: MachineOptions,
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Run text `.asm` or binary `.lc3` file directly and output to terminal
     Run {
-        /// `.asm` or `.lc3` file to run
+        /// `.asm` or binary file to run, such as `.lc3` or any format from `compile --emit`
         name: PathBuf,
         /// Produce minimal output, suited for blackbox tests
         #[arg(short, long)]
         minimal: bool,
         #[command(flatten)]
         run_options: RunOptions,
+        #[command(flatten)]
+        asm_options: AsmOptions,
+        #[command(flatten)]
+        machine_options: MachineOptions,
     },
-    /// Run and debug text `.asm` file directly
+    /// Run and debug text `.asm` or binary `.lc3` file directly
     ///
     /// For information on commands, run `lace debug --print-help` or type `help` in the debugger prompt
     #[clap(group(ArgGroup::new("name_or_help").required(true)))]
     Debug {
-        /// `.asm` file to run and debug
+        /// `.asm` or binary file to run and debug
         #[arg(group("name_or_help"))]
         name: Option<PathBuf>,
         /// Read debugger commands from argument
@@ -61,6 +69,10 @@ enum Command {
         minimal: bool,
         #[command(flatten)]
         run_options: RunOptions,
+        #[command(flatten)]
+        asm_options: AsmOptions,
+        #[command(flatten)]
+        machine_options: MachineOptions,
         /// Print information on debugger commands (without reading any file)
         ///
         /// Similar to `lace debug <file> --command 'help'`
@@ -71,8 +83,48 @@ enum Command {
     Compile {
         /// `.asm` file to compile
         name: PathBuf,
-        /// Destination to output .lc3 file
+        /// Destination to output .lc3 file, or a file of the format given by `--emit`
         dest: Option<PathBuf>,
+        /// Create a relocatable `.lobj` object file instead, to be combined with other modules by
+        /// `lace link`
+        #[arg(long)]
+        object: bool,
+        /// Format of the output file, which also sets its extension
+        ///
+        /// Available formats: 'lc3', 'hex', 'bin', 'ihex', 'lc3tools-obj'
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(Format),
+            default_value_t = Default::default(),
+            conflicts_with = "object",
+        )]
+        emit: Format,
+        /// Also write a `.lst` listing of each address, word, label and source line
+        #[arg(long)]
+        listing: bool,
+        #[command(flatten)]
+        run_options: RunOptions,
+        #[command(flatten)]
+        asm_options: AsmOptions,
+    },
+    /// Combine `.lobj` object files into a binary `.lc3` file
+    Link {
+        /// Object files to link. The program starts at the first section of the first file
+        #[arg(required = true)]
+        names: Vec<PathBuf>,
+        /// Destination to output .lc3 file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Decode a binary file into `.asm` source, which assembles back to the same binary
+    ///
+    /// Labels are read from a `.sym` file beside the binary, if there is one
+    Disasm {
+        /// Binary file to decode, such as `.lc3` or any format from `compile --emit`
+        name: PathBuf,
+        /// Destination to output .asm file, instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
         #[command(flatten)]
         run_options: RunOptions,
     },
@@ -80,6 +132,32 @@ enum Command {
     Check {
         /// File to check
         name: PathBuf,
+        /// Also warn about code which is likely to be a mistake, such as unreachable code
+        #[arg(long)]
+        lint: bool,
+        /// Warn about a comma-separated list of lints, even if they are allowed by `-A`
+        ///
+        /// Available lints: 'fall-through', 'unreachable', 'missing-halt', 'unsaved-r7',
+        /// 'unused-label', 'stale-flags', 'empty-blkw', or 'all'
+        #[arg(
+            short = 'W',
+            value_name = "LINTS",
+            value_parser = clap::value_parser!(Lints),
+            requires = "lint",
+        )]
+        warn: Vec<Lints>,
+        /// Do not warn about a comma-separated list of lints
+        #[arg(
+            short = 'A',
+            value_name = "LINTS",
+            value_parser = clap::value_parser!(Lints),
+            requires = "lint",
+        )]
+        allow: Vec<Lints>,
+        #[command(flatten)]
+        run_options: RunOptions,
+        #[command(flatten)]
+        asm_options: AsmOptions,
     },
     /// Remove compilation artifacts for specified source
     Clean {
@@ -90,11 +168,23 @@ enum Command {
     Watch {
         /// `.asm` file to watch
         name: PathBuf,
+        #[command(flatten)]
+        run_options: RunOptions,
+        #[command(flatten)]
+        asm_options: AsmOptions,
     },
     /// Format `.asm` file to adhere to recommended style
     Fmt {
         /// `.asm` file to format
         name: PathBuf,
+        /// Exit with an error if the file is not already formatted, without changing it
+        #[arg(long)]
+        check: bool,
+        /// Print formatted source to stdout, instead of overwriting the file
+        #[arg(long, conflicts_with = "check")]
+        stdout: bool,
+        #[command(flatten)]
+        run_options: RunOptions,
     },
 }
 
@@ -102,7 +192,7 @@ enum Command {
 struct RunOptions {
     /// Feature flags to enable non-standard extensions to the LC3 specification
     ///
-    /// Available flags: 'stack'
+    /// Available flags: 'stack', 'sugar'
     #[arg(
         short,
         long,
@@ -112,6 +202,55 @@ struct RunOptions {
     features: Features,
 }
 
+#[derive(clap::Args)]
+struct AsmOptions {
+    /// Define a constant before assembling, which is 1 if no value is given
+    ///
+    /// Enabled features are also defined, such as 'FEATURE_STACK' for 'stack'
+    #[arg(
+        short = 'D',
+        value_name = "NAME[=VALUE]",
+        value_parser = clap::value_parser!(Define),
+    )]
+    defines: Vec<Define>,
+    /// Rewrite branches and loads whose label is too far away, instead of failing
+    #[arg(long)]
+    relax: bool,
+}
+
+#[derive(clap::Args)]
+struct MachineOptions {
+    /// Dispatch traps and exceptions through the vector tables at x0000 and x0100, in supervisor
+    /// mode, so that programs can provide their own trap routines and handlers
+    ///
+    /// Traps without a routine of the program are performed by the default OS image
+    #[arg(long)]
+    os: bool,
+    /// Add a programmable timer device, with registers at xFE08 (status) and xFE0A (interval)
+    ///
+    /// With `--os`, the timer interrupts through vector x81 each time its interval elapses
+    #[arg(long)]
+    timer: bool,
+    /// Load a binary file into memory before the program, such as an OS image or data
+    ///
+    /// Each file is placed at its own origin, and must not overlap any other file
+    #[arg(long, value_name = "FILE")]
+    load: Vec<PathBuf>,
+    /// Address to start running at, instead of the first segment of the program
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_address)]
+    entry: Option<u16>,
+}
+
+/// Address written like a hex literal (`x3000` or `0x3000`), or a decimal literal.
+fn parse_address(string: &str) -> Result<u16, String> {
+    let lower = string.to_ascii_lowercase();
+    let value = match lower.strip_prefix("0x").or(lower.strip_prefix('x')) {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => lower.strip_prefix('#').unwrap_or(&lower).parse(),
+    };
+    value.map_err(|_| format!("Invalid address '{}'", string))
+}
+
 fn main() -> miette::Result<()> {
     use MsgColor::*;
     let args = Args::parse();
@@ -127,8 +266,15 @@ fn main() -> miette::Result<()> {
     match args.command {
         None => {
             if let Some(path) = args.path {
-                lace::features::init(args.run_options.features);
-                run(&path, None, args.minimal)?;
+                let features = args.run_options.features;
+                run(
+                    &path,
+                    None,
+                    args.minimal,
+                    features,
+                    &args.asm_options,
+                    &args.machine_options,
+                )?;
                 Ok(())
             } else {
                 println!("\n~ lace v{VERSION} - Copyright (c) 2024 Artemis Rosman ~");
@@ -141,20 +287,35 @@ fn main() -> miette::Result<()> {
             name,
             minimal,
             run_options: RunOptions { features },
-        }) => {
-            lace::features::init(features);
-            run(&name, None, minimal)
-        }
+            asm_options,
+            machine_options,
+        }) => run(
+            &name,
+            None,
+            minimal,
+            features,
+            &asm_options,
+            &machine_options,
+        ),
         Some(Command::Debug {
             name,
             command,
             minimal,
             run_options: RunOptions { features },
+            asm_options,
+            machine_options,
             print_help,
         }) => match (name, print_help) {
             (Some(name), false) => {
-                lace::features::init(features);
-                run(&name, Some(debugger::Options { command }), minimal)
+                let debugger_opts = Some(debugger::Options { command });
+                run(
+                    &name,
+                    debugger_opts,
+                    minimal,
+                    features,
+                    &asm_options,
+                    &machine_options,
+                )
             }
             (None, true) => {
                 lace::set_minimal(minimal);
@@ -167,42 +328,123 @@ fn main() -> miette::Result<()> {
         Some(Command::Compile {
             name,
             dest,
+            object,
+            emit,
+            listing,
             run_options: RunOptions { features },
+            asm_options,
         }) => {
-            lace::features::init(features);
             file_message(Green, "Assembling", &name);
-            let contents = StaticSource::new(fs::read_to_string(&name).into_diagnostic()?);
-            let air = assemble(&contents)?;
-
-            let out_file_name =
-                dest.unwrap_or(name.with_extension("lc3").file_name().unwrap().into());
-            let mut file = File::create(&out_file_name).unwrap();
-
-            // Deal with .orig
-            if let Some(orig) = air.orig() {
-                let _ = file.write(&orig.to_be_bytes());
+            let air = assemble(&name, features, object, &asm_options, Lints::default())?;
+
+            let out_file_name = if object {
+                let out_file_name =
+                    dest.unwrap_or(name.with_extension("lobj").file_name().unwrap().into());
+                let object = Object::from_air(&air)?;
+                fs::write(&out_file_name, object.to_string()).into_diagnostic()?;
+                message(Green, "Finished", "emit object");
+                out_file_name
             } else {
-                let _ = file.write(&0x3000u16.to_be_bytes());
+                let out_file_name = dest.unwrap_or(
+                    name.with_extension(emit.extension())
+                        .file_name()
+                        .unwrap()
+                        .into(),
+                );
+                fs::write(&out_file_name, lace::encode(&air, emit)?).into_diagnostic()?;
+
+                message(Green, "Finished", "emit binary");
+
+                // Symbol file is saved next to the binary, for other LC3 tools
+                let sym_file_name = out_file_name.with_extension("sym");
+                fs::write(&sym_file_name, lace::write_symbols(&air)).into_diagnostic()?;
+                file_message(Green, "Saved", &sym_file_name);
+                out_file_name
+            };
+            file_message(Green, "Saved", &out_file_name);
+
+            if listing {
+                // Listing is saved next to the output file
+                let listing_file_name = out_file_name.with_extension("lst");
+                fs::write(&listing_file_name, lace::listing(&air)?).into_diagnostic()?;
+                file_message(Green, "Saved", &listing_file_name);
             }
+            Ok(())
+        }
+        Some(Command::Link { names, output }) => {
+            let mut objects = Vec::with_capacity(names.len());
+            for name in &names {
+                file_message(Green, "Reading", name);
+                let text = fs::read_to_string(name).into_diagnostic()?;
+                let object = Object::parse(&text)
+                    .wrap_err_with(|| format!("Failed to read {}", name.display()))?;
+                objects.push(object);
+            }
+
+            let image = match lace::link(&objects) {
+                Ok(image) => image,
+                Err(diagnostics) => {
+                    for report in diagnostics.iter() {
+                        eprintln!("{:?}", report);
+                    }
+                    bail!("Failed to link with {}", diagnostics);
+                }
+            };
 
-            // Write lines
-            for stmt in &air {
-                let _ = file.write(&stmt.emit()?.to_be_bytes());
+            let out_file_name =
+                output.unwrap_or(names[0].with_extension("lc3").file_name().unwrap().into());
+            let mut file = File::create(&out_file_name).into_diagnostic()?;
+            for word in image {
+                let _ = file.write(&word.to_be_bytes());
             }
 
-            message(Green, "Finished", "emit binary");
+            message(Green, "Finished", "link binary");
             file_message(Green, "Saved", &out_file_name);
             Ok(())
         }
-        Some(Command::Check { name }) => {
+        Some(Command::Disasm {
+            name,
+            output,
+            run_options: RunOptions { features },
+        }) => {
+            let (raw, symbols) = read_binary(&name)?;
+            let text = lace::disassemble(&raw, &symbols, features)?;
+            match output {
+                Some(output) => {
+                    fs::write(&output, text).into_diagnostic()?;
+                    message(Green, "Finished", "disassembly");
+                    file_message(Green, "Saved", &output);
+                }
+                None => print!("{text}"),
+            }
+            Ok(())
+        }
+        Some(Command::Check {
+            name,
+            lint,
+            warn,
+            allow,
+            run_options: RunOptions { features },
+            asm_options,
+        }) => {
             file_message(Green, "Checking", &name);
-            let contents = StaticSource::new(fs::read_to_string(&name).into_diagnostic()?);
-            let _ = assemble(&contents)?;
+            let lints = if lint {
+                let allowed = allow.into_iter().fold(Lints::default(), Lints::with);
+                let warned = warn.into_iter().fold(Lints::default(), Lints::with);
+                Lints::all().without(allowed).with(warned)
+            } else {
+                Lints::default()
+            };
+            let _ = assemble(&name, features, false, &asm_options, lints)?;
             message(Green, "Success", "no errors found!");
             Ok(())
         }
         Some(Command::Clean { name: _ }) => todo!("There are no debug files implemented to clean!"),
-        Some(Command::Watch { name }) => {
+        Some(Command::Watch {
+            name,
+            run_options: RunOptions { features },
+            asm_options,
+        }) => {
             if !name.exists() {
                 bail!("File does not exist. Exiting...")
             }
@@ -233,14 +475,12 @@ fn main() -> miette::Result<()> {
                         // Now we are developing software (makes reruns more obvious)
                         sleep(Duration::from_millis(50));
 
-                        let mut contents = StaticSource::new(match fs::read_to_string(&name) {
-                            Ok(cts) => cts,
-                            Err(e) => {
-                                eprintln!("{e}. Exiting...");
-                                std::process::exit(1)
-                            }
-                        });
-                        match assemble(&contents) {
+                        if !name.exists() {
+                            eprintln!("File does not exist. Exiting...");
+                            std::process::exit(1)
+                        }
+                        // Each check has its own symbol table, so nothing is kept between them
+                        match assemble(&name, features, false, &asm_options, Lints::default()) {
                             Ok(_) => {
                                 message(Green, "Success", "no errors found!");
                             }
@@ -248,10 +488,6 @@ fn main() -> miette::Result<()> {
                                 println!("\n{:?}", e);
                             }
                         };
-
-                        reset_state();
-                        // To avoid leaking memory
-                        contents.reclaim();
                         Flow::Continue
                     }
                     _ => Flow::Continue,
@@ -260,7 +496,39 @@ fn main() -> miette::Result<()> {
             watcher.run();
             Ok(())
         }
-        Some(Command::Fmt { name: _ }) => todo!("Formatting is not currently implemented"),
+        Some(Command::Fmt {
+            name,
+            check,
+            stdout,
+            run_options: RunOptions { features },
+        }) => {
+            let contents = fs::read_to_string(&name).into_diagnostic()?;
+            let formatted = match lace::format(&contents, features) {
+                Ok(formatted) => formatted,
+                Err(diagnostics) => {
+                    for report in diagnostics.iter() {
+                        eprintln!("{:?}", report);
+                    }
+                    bail!("Failed to format with {}", diagnostics);
+                }
+            };
+
+            if stdout {
+                print!("{formatted}");
+            } else if check {
+                if formatted != contents {
+                    file_message(Red, "Unformatted", &name);
+                    bail!("File is not formatted. Run `lace fmt` to fix");
+                }
+                file_message(Green, "Formatted", &name);
+            } else if formatted == contents {
+                file_message(Green, "Unchanged", &name);
+            } else {
+                fs::write(&name, formatted).into_diagnostic()?;
+                file_message(Green, "Formatted", &name);
+            }
+            Ok(())
+        }
     }
 }
 
@@ -288,38 +556,46 @@ where
     println!("{left:>12} {right}");
 }
 
-fn run(name: &PathBuf, debugger_opts: Option<debugger::Options>, minimal: bool) -> Result<()> {
+fn run(
+    name: &Path,
+    debugger_opts: Option<debugger::Options>,
+    minimal: bool,
+    features: Features,
+    asm_options: &AsmOptions,
+    machine_options: &MachineOptions,
+) -> Result<()> {
     file_message(MsgColor::Green, "Assembling", name);
-    let mut program = if let Some(ext) = name.extension() {
+    let mut program = RunEnvironment::new(features, machine_options.os);
+    if machine_options.timer {
+        program.enable_timer();
+    }
+    if let Some(entry) = machine_options.entry {
+        program.set_entry(entry);
+    }
+    // Program is loaded last, as the debugger is attached to the machine once it is loaded
+    for path in &machine_options.load {
+        file_message(MsgColor::Green, "Loading", path);
+        let (raw, _) = read_binary(path)?;
+        program
+            .load(&raw)
+            .wrap_err_with(|| format!("Failed to load {}", path.display()))?;
+    }
+    if let Some(ext) = name.extension() {
         match ext.to_str().unwrap() {
-            "lc3" | "obj" => {
-                if debugger_opts.is_some() {
-                    bail!("Cannot use debugger on non-assembly file");
-                }
-
-                // Read to byte buffer
-                let mut file = File::open(name).into_diagnostic()?;
-                let f_size = file.metadata().unwrap().len();
-                let mut buffer = Vec::with_capacity(f_size as usize);
-                file.read_to_end(&mut buffer).into_diagnostic()?;
-
-                if buffer.len() % 2 != 0 {
-                    bail!("File is not aligned to 16 bits")
-                }
-
-                let u16_buf: Vec<u16> = buffer
-                    .chunks_exact(2)
-                    .map(|word| u16::from_be_bytes([word[0], word[1]]))
-                    .collect();
-                RunEnvironment::from_raw(&u16_buf)?
-            }
             "asm" => {
-                let contents = StaticSource::new(fs::read_to_string(name).into_diagnostic()?);
-                let air = assemble(&contents)?;
-                RunEnvironment::try_from(air, debugger_opts)?
+                let air = assemble(name, features, false, asm_options, Lints::default())?;
+                program
+                    .load_air(air, debugger_opts)
+                    .wrap_err_with(|| format!("Failed to load {}", name.display()))?;
             }
             _ => {
-                bail!("File has unknown extension. Exiting...")
+                let (raw, symbols) = read_binary(name)?;
+                program
+                    .load(&raw)
+                    .wrap_err_with(|| format!("Failed to load {}", name.display()))?;
+                if let Some(debugger_opts) = debugger_opts {
+                    program.attach_debugger(debugger_opts, symbols);
+                }
             }
         }
     } else {
@@ -329,17 +605,87 @@ fn run(name: &PathBuf, debugger_opts: Option<debugger::Options>, minimal: bool)
     lace::set_minimal(minimal);
 
     message(MsgColor::Green, "Running", "emitted binary");
-    program.run();
+    if let Err(error) = program.run() {
+        match error.fault {
+            // An error with the emulator, not the CPU
+            Fault::EndOfInput => {
+                eprintln!("{}.", error.fault);
+                std::process::exit(1);
+            }
+            Fault::IllegalOpcode => {
+                eprintln!(
+                    "\
+                    You called a reserved instruction.\n\
+                    Note: Run with `-f stack` to enable stack extension feature.\n\
+                    Halting...\
+                    "
+                );
+                std::process::exit(1);
+            }
+            _ => {
+                eprintln!("exception: {}, exiting", error.fault);
+                std::process::exit(0xEE);
+            }
+        }
+    }
 
     file_message(MsgColor::Green, "Completed", name);
     Ok(())
 }
 
+/// Read the words of a binary file in any format, and the labels in its symbol file, if any.
+fn read_binary(name: &Path) -> Result<(Vec<u16>, SymbolTable)> {
+    let bytes = fs::read(name).into_diagnostic()?;
+    let ext = name.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let Some(format) = Format::detect(ext, &bytes) else {
+        bail!("File has unknown extension. Exiting...")
+    };
+    let raw = lace::decode(&bytes, format)?;
+
+    // Labels are only known if a symbol file was written beside the binary
+    let sym_path = name.with_extension("sym");
+    if !sym_path.exists() {
+        return Ok((raw, SymbolTable::default()));
+    }
+    let text = fs::read_to_string(&sym_path).into_diagnostic()?;
+    let symbols = lace::read_symbols(&text)
+        .wrap_err_with(|| format!("Failed to read {}", sym_path.display()))?;
+    Ok((raw, symbols))
+}
+
 /// Return assembly intermediate representation of source file for further processing
-fn assemble(contents: &StaticSource) -> Result<Air> {
-    let parser = lace::AsmParser::new(contents.src())?;
-    let mut air = parser.parse()?;
-    air.backpatch()?;
+///
+/// If `relocatable`, labels declared with `.extern` are left for the linker to fill. Each define
+/// in `options` is a constant which is defined before assembling. Each of `lints` is checked once
+/// the program assembles without errors.
+/// Every diagnostic is printed, followed by a summary of how many there were.
+fn assemble(
+    name: &Path,
+    features: Features,
+    relocatable: bool,
+    options: &AsmOptions,
+    lints: Lints,
+) -> Result<Air> {
+    let air = Assembler::new(features)
+        .defines(&options.defines)
+        .relocatable(relocatable)
+        .relax(options.relax)
+        .lints(lints)
+        .assemble_file(name)?;
+
+    for report in air.diagnostics.iter() {
+        eprintln!("{:?}", report);
+    }
+    if air.diagnostics.has_errors() {
+        bail!("Failed to assemble with {}", air.diagnostics);
+    }
+    if !air.diagnostics.is_empty() {
+        message(
+            MsgColor::Cyan,
+            "Assembled",
+            &format!("with {}", air.diagnostics),
+        );
+    }
     Ok(air)
 }
 
@@ -0,0 +1,481 @@
+//! Relocatable object files, and the linker which combines them into a program.
+//!
+//! Each module is assembled into an object file with `lace compile --object`. Sections which set
+//! their origin with `.orig` stay at that address, and every other section is placed by the
+//! linker. Operands which refer to a label in another module, or in a section which was moved, are
+//! then filled using the relocations recorded in each object file.
+
+use std::collections::hash_map::Entry;
+use std::fmt::{self, Display};
+use std::ops::Range;
+
+use fxhash::FxHashMap;
+use miette::Result;
+
+use crate::{
+    air::{self, Air, AsmLine, RelocationKind, DEFAULT_ORIG},
+    error::{self, Diagnostics},
+    runtime::MEMORY_MAX,
+};
+
+/// First line of every object file.
+const HEADER: &str = "lace object 1";
+
+/// Maximum amount of words written on each line.
+const WORDS_PER_LINE: usize = 8;
+
+/// Module which has been assembled, but not yet placed in memory.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Object {
+    sections: Vec<Section>,
+    symbols: Vec<ObjSymbol>,
+    relocations: Vec<ObjRelocation>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Section {
+    /// Address which the section was assembled at
+    orig: u16,
+    /// Whether the section must stay at its origin
+    fixed: bool,
+    words: Vec<u16>,
+}
+
+/// Prefix label of a module.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct ObjSymbol {
+    name: String,
+    /// Whether other modules may refer to the label
+    global: bool,
+    section: usize,
+    /// Offset from the start of the section
+    offset: u16,
+    /// Where the label was declared, such as `lib.asm:4:1`
+    location: String,
+}
+
+/// Operand which must be filled by the linker.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct ObjRelocation {
+    section: usize,
+    /// Offset of the statement from the start of the section
+    offset: u16,
+    kind: RelocationKind,
+    label: String,
+    /// Value added to the address of the label
+    addend: i32,
+    /// Where the label was referenced
+    location: String,
+}
+
+impl Object {
+    /// Object file for a program which was assembled with [`Air::set_relocatable`].
+    pub fn from_air(air: &Air) -> Result<Object> {
+        let mut sections = Vec::new();
+        for (seg, stmts) in air.segments() {
+            sections.push(Section {
+                orig: seg.orig,
+                fixed: seg.fixed,
+                words: stmts.iter().map(AsmLine::emit).collect::<Result<_>>()?,
+            });
+        }
+
+        let mut symbols = Vec::new();
+        for (name, span) in air.labels() {
+            let addr = air.symbols.label(name);
+            // Labels outside of the program cannot be referred to by another module
+            let Some((addr, section)) =
+                addr.and_then(|addr| air.segment_of_addr(addr).map(|section| (addr, section)))
+            else {
+                continue;
+            };
+            symbols.push(ObjSymbol {
+                name: name.clone(),
+                global: air.is_global(name),
+                section,
+                offset: addr.wrapping_sub(sections[section].orig),
+                location: air.source.location(*span),
+            });
+        }
+
+        let relocations = air
+            .relocations()
+            .iter()
+            .map(|relocation| {
+                let section = air.segment_of_stmt(relocation.index);
+                ObjRelocation {
+                    section,
+                    offset: air
+                        .get(relocation.index)
+                        .addr
+                        .wrapping_sub(sections[section].orig),
+                    kind: relocation.kind,
+                    label: relocation.label.clone(),
+                    addend: relocation.addend,
+                    location: air.source.location(relocation.span),
+                }
+            })
+            .collect();
+
+        Ok(Object {
+            sections,
+            symbols,
+            relocations,
+        })
+    }
+
+    /// Read an object file, which was written by [`Object::to_string`].
+    pub fn parse(text: &str) -> Result<Object> {
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(error::link_bad_object(1));
+        }
+        let mut object = Object {
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+        };
+        for (i, line) in lines.enumerate() {
+            // Header is line 1
+            object
+                .parse_line(line)
+                .ok_or_else(|| error::link_bad_object(i + 2))?;
+        }
+        Ok(object)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<()> {
+        let (kind, rest) = line.split_once(' ')?;
+        match kind {
+            "section" => {
+                let (orig, fixed) = rest.split_once(' ')?;
+                let fixed = match fixed {
+                    "fixed" => true,
+                    "relocatable" => false,
+                    _ => return None,
+                };
+                self.sections.push(Section {
+                    orig: parse_hex(orig)?,
+                    fixed,
+                    words: Vec::new(),
+                });
+            }
+            "words" => {
+                let section = self.sections.last_mut()?;
+                for word in rest.split(' ') {
+                    section.words.push(parse_hex(word)?);
+                }
+            }
+            "symbol" => {
+                let mut fields = rest.splitn(5, ' ');
+                let name = fields.next()?.to_string();
+                let global = match fields.next()? {
+                    "global" => true,
+                    "local" => false,
+                    _ => return None,
+                };
+                let section: usize = fields.next()?.parse().ok()?;
+                let offset = parse_hex(fields.next()?)?;
+                // Labels may be declared at the end of a section
+                if offset as usize > self.sections.get(section)?.words.len() {
+                    return None;
+                }
+                self.symbols.push(ObjSymbol {
+                    name,
+                    global,
+                    section,
+                    offset,
+                    location: fields.next()?.to_string(),
+                });
+            }
+            "reloc" => {
+                let mut fields = rest.splitn(6, ' ');
+                let section: usize = fields.next()?.parse().ok()?;
+                let offset = parse_hex(fields.next()?)?;
+                if offset as usize >= self.sections.get(section)?.words.len() {
+                    return None;
+                }
+                let kind = match fields.next()? {
+                    "word" => RelocationKind::Word,
+                    kind => RelocationKind::Offset(
+                        kind.strip_prefix("offset")?
+                            .parse()
+                            .ok()
+                            .filter(|bits| (1..16).contains(bits))?,
+                    ),
+                };
+                self.relocations.push(ObjRelocation {
+                    section,
+                    offset,
+                    kind,
+                    label: fields.next()?.to_string(),
+                    addend: fields.next()?.parse().ok()?,
+                    location: fields.next()?.to_string(),
+                });
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+fn parse_hex(val: &str) -> Option<u16> {
+    u16::from_str_radix(val.strip_prefix('x')?, 16).ok()
+}
+
+/// Text of an object file, with one record on each line.
+impl Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        for section in &self.sections {
+            let placement = if section.fixed {
+                "fixed"
+            } else {
+                "relocatable"
+            };
+            writeln!(f, "section x{:04X} {placement}", section.orig)?;
+            for words in section.words.chunks(WORDS_PER_LINE) {
+                let words: Vec<_> = words.iter().map(|word| format!("x{word:04X}")).collect();
+                writeln!(f, "words {}", words.join(" "))?;
+            }
+        }
+        for symbol in &self.symbols {
+            let scope = if symbol.global { "global" } else { "local" };
+            writeln!(
+                f,
+                "symbol {} {scope} {} x{:04X} {}",
+                symbol.name, symbol.section, symbol.offset, symbol.location
+            )?;
+        }
+        for relocation in &self.relocations {
+            let kind = match relocation.kind {
+                RelocationKind::Offset(bits) => format!("offset{bits}"),
+                RelocationKind::Word => "word".to_string(),
+            };
+            writeln!(
+                f,
+                "reloc {} x{:04X} {kind} {} {} {}",
+                relocation.section,
+                relocation.offset,
+                relocation.label,
+                relocation.addend,
+                relocation.location
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Combine modules into a program, which starts at the first section of the first module.
+///
+/// Returns the binary representation of the program, or every error which was found.
+pub fn link(objects: &[Object]) -> Result<Vec<u16>, Diagnostics> {
+    let mut diagnostics = Diagnostics::new();
+
+    // Labels which each module exports
+    let mut globals: FxHashMap<&str, (usize, &ObjSymbol)> = FxHashMap::default();
+    for (module, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            match globals.entry(&symbol.name) {
+                Entry::Occupied(other) => diagnostics.push(error::link_duplicate_global(
+                    &symbol.name,
+                    &other.get().1.location,
+                    &symbol.location,
+                )),
+                Entry::Vacant(entry) => {
+                    entry.insert((module, symbol));
+                }
+            }
+        }
+    }
+
+    let placed = place(objects, &mut diagnostics);
+    let mut words: Vec<Vec<Vec<u16>>> = objects
+        .iter()
+        .map(|object| {
+            object
+                .sections
+                .iter()
+                .map(|section| section.words.clone())
+                .collect()
+        })
+        .collect();
+
+    for (module, object) in objects.iter().enumerate() {
+        for relocation in &object.relocations {
+            // Labels in the same module take precedence
+            let target = object
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == relocation.label)
+                .map(|symbol| (module, symbol))
+                .or_else(|| globals.get(relocation.label.as_str()).copied());
+            let Some((target_module, symbol)) = target else {
+                diagnostics.push(error::link_undefined(
+                    &relocation.label,
+                    &relocation.location,
+                ));
+                continue;
+            };
+
+            let target = placed[target_module][symbol.section] as i32
+                + symbol.offset as i32
+                + relocation.addend;
+            let addr = placed[module][relocation.section].wrapping_add(relocation.offset);
+            let word = &mut words[module][relocation.section][relocation.offset as usize];
+            match relocation.kind {
+                RelocationKind::Offset(bits) => {
+                    // Program counter is incremented before the instruction is executed
+                    let offset = target - (addr as i32 + 1);
+                    let range = 1 << (bits - 1);
+                    if !(-range..range).contains(&offset) {
+                        diagnostics.push(error::link_offset_range(
+                            &relocation.label,
+                            bits,
+                            &relocation.location,
+                            &symbol.location,
+                        ));
+                        continue;
+                    }
+                    let mask = (1u16 << bits) - 1;
+                    *word = (*word & !mask) | (offset as u16 & mask);
+                }
+                RelocationKind::Word => {
+                    if !(i16::MIN as i32..=u16::MAX as i32).contains(&target) {
+                        diagnostics.push(error::link_value_range(
+                            &relocation.label,
+                            &relocation.location,
+                        ));
+                        continue;
+                    }
+                    *word = target as u16;
+                }
+            }
+        }
+    }
+
+    if diagnostics.has_errors() {
+        return Err(diagnostics);
+    }
+    let segments: Vec<_> = placed
+        .into_iter()
+        .flatten()
+        .zip(words.into_iter().flatten())
+        .collect();
+    Ok(air::image(&segments))
+}
+
+/// Choose the address of every section, by module.
+///
+/// Sections without a fixed origin are placed in order, from the default origin, skipping any
+/// memory which is already used.
+fn place(objects: &[Object], diagnostics: &mut Diagnostics) -> Vec<Vec<u16>> {
+    let overlaps = |a: &Range<usize>, b: &Range<usize>| a.start < b.end && b.start < a.end;
+
+    let mut used: Vec<Range<usize>> = Vec::new();
+    for object in objects {
+        for section in object.sections.iter().filter(|section| section.fixed) {
+            let range = section.orig as usize..section.orig as usize + section.words.len();
+            if let Some(other) = used.iter().find(|other| overlaps(other, &range)) {
+                diagnostics.push(error::link_overlap(section.orig, other.start as u16));
+            }
+            used.push(range);
+        }
+    }
+
+    let mut next = DEFAULT_ORIG as usize;
+    objects
+        .iter()
+        .map(|object| {
+            object
+                .sections
+                .iter()
+                .map(|section| {
+                    if section.fixed {
+                        return section.orig;
+                    }
+                    let len = section.words.len();
+                    let mut range = next..next + len;
+                    while let Some(other) = used.iter().find(|other| overlaps(other, &range)) {
+                        range = other.end..other.end + len;
+                    }
+                    if range.end > MEMORY_MAX {
+                        diagnostics.push(error::link_no_space(len));
+                        return section.orig;
+                    }
+                    next = range.end;
+                    used.push(range.clone());
+                    range.start as u16
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, Source};
+
+    fn assemble(src: &str) -> Object {
+        let air = Assembler::default()
+            .relocatable(true)
+            .assemble(&Source::new(src));
+        assert!(air.diagnostics.is_empty(), "{:?}", air.diagnostics);
+        Object::from_air(&air).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let object = assemble(
+            ".extern print\n.global main\nmain lea r0 msg\njsr print\n.fill print+1\n.fill msg\nhalt\nmsg .fill x41",
+        );
+        // Address of `msg` changes when the module is placed, but its offset does not
+        assert_eq!(object.relocations.len(), 3);
+        assert_eq!(Object::parse(&object.to_string()).unwrap(), object);
+        assert!(Object::parse("lace object 1\nwords x0001").is_err());
+        assert!(Object::parse("section x3000 fixed").is_err());
+    }
+
+    #[test]
+    fn links_modules() {
+        let main = assemble(".extern print\njsr print\nld r0 ptr\nhalt\nptr .fill print");
+        let lib = assemble(".global print\nprint puts\nret");
+        let data = assemble(".orig x3005\nvalue .fill #7");
+
+        let image = link(&[main, lib, data]).unwrap();
+        // Second module is placed after the data, which is fixed at x3005
+        assert_eq!(
+            image,
+            vec![
+                air::SEGMENTS_MAGIC,
+                0x3000,
+                4,
+                0x4805,
+                0x2001,
+                0xF025,
+                0x3006,
+                0x3006,
+                2,
+                0xF022,
+                0xC1C0,
+                0x3005,
+                1,
+                7,
+            ]
+        );
+    }
+
+    #[test]
+    fn link_errors() {
+        let main = assemble(".extern far\n.extern missing\nbr far\nld r0 missing\nhalt");
+        let far = assemble(".global far\n.blkw x200\nfar halt");
+        let errors = link(&[main, far]).unwrap_err();
+        // Offset to `far` is too large, and `missing` is not exported
+        assert_eq!(errors.error_count(), 2);
+
+        let a = assemble(".global dup\ndup halt");
+        let b = assemble(".global dup\ndup halt");
+        assert_eq!(link(&[a, b]).unwrap_err().error_count(), 1);
+    }
+}
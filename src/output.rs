@@ -2,6 +2,7 @@ use std::cell::RefCell;
 use std::fmt::{self, Write as _};
 
 use crate::runtime::{RunFlag, RunState};
+use crate::term;
 
 /// Colors used by [`Output::Debugger`].
 ///
@@ -459,6 +460,9 @@ struct NormalWriter {
 impl fmt::Write for NormalWriter {
     /// Must never fail.
     fn write_str(&mut self, string: &str) -> fmt::Result {
+        // Newlines do not return the cursor while the keyboard is polled in raw mode
+        let raw = term::is_raw_mode_held().then(|| string.replace('\n', "\r\n"));
+        let string = raw.as_deref().unwrap_or(string);
         if self.minimal {
             print!("{}", Decolored::new(string));
         } else {
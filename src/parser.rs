@@ -1,67 +1,481 @@
-use std::{borrow::Cow, fmt::Display, iter::Peekable, vec::IntoIter};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    fs,
+    iter::Peekable,
+    ops::{Range, RangeInclusive},
+    str::FromStr,
+    vec::IntoIter,
+};
 
+use fxhash::{FxHashMap, FxHashSet};
 use miette::Result;
 
 use crate::{
-    air::{Air, AirStmt, ImmediateOrReg, RawWord},
+    air::{Air, AirStmt, Deferred, DeferredKind, ImmediateOrReg, RawWord},
     debugger::Breakpoint,
-    error,
-    lexer::{cursor::Cursor, LiteralKind, Token, TokenKind},
-    symbol::{DirKind, InstrKind, Label, Register, Span, SrcOffset, TrapKind},
+    error::{self, Diagnostics},
+    expr::{self, Expr},
+    features::Features,
+    lexer::{cursor::Cursor, is_id, unescape, LiteralKind, Token, TokenKind},
+    source::Source,
+    symbol::{DirKind, Expansion, InstrKind, Label, Register, Span, SymbolTable, TrapKind},
 };
 
 /// Replaces raw value directives .fill, .blkw, .stringz with equivalent raw bytes
 /// Returns a 'final' vector of tokens. This is easier than working with an iterator that can
 /// either return a single token or a Vec of tokens.
-pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
+///
+/// Macros are expanded before raw value directives, so that macro bodies may contain them.
+/// Constants are replaced with their value as each line is processed, so they must be defined
+/// before they are used.
+/// Conditional blocks are also evaluated as each line is processed, after macros are expanded.
+/// Lines in a block whose condition is false are discarded before their constants are resolved.
+///
+/// Errors are recorded in `diagnostics`, and the line containing them is discarded.
+pub fn preprocess(
+    source: &Source,
+    features: Features,
+    symbols: &mut SymbolTable,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Token> {
+    let src = source.text();
+    let mut lines = Vec::new();
+    lex_lines(
+        source,
+        features,
+        source.main_range(),
+        &mut lines,
+        diagnostics,
+    );
+    let lines = MacroExpander::new(src).expand(lines, diagnostics);
+
     let mut res: Vec<Token> = Vec::new();
-    let mut cur = Cursor::new(src);
+    let mut conditions: Vec<Condition> = Vec::new();
+    for line in lines {
+        if let TokenKind::Dir(
+            dir @ (DirKind::If | DirKind::Ifdef | DirKind::Ifndef | DirKind::Else | DirKind::Endif),
+        ) = line[0].kind
+        {
+            if let Err(err) = conditional(src, symbols, dir, line, &mut conditions) {
+                diagnostics.push(err);
+            }
+            continue;
+        }
+        // Lines in a block whose condition is false are discarded
+        if !conditions.iter().all(Condition::is_active) {
+            continue;
+        }
+        let line_start = res.len();
+        let line = match resolve_constants(src, symbols, line) {
+            Ok(Some(line)) => line,
+            Ok(None) => continue,
+            Err(err) => {
+                diagnostics.push(err);
+                continue;
+            }
+        };
+        if let Err(err) = preprocess_line(source, &line, &mut res, diagnostics) {
+            diagnostics.push(err);
+            // Avoid passing a partial statement to the parser
+            res.truncate(line_start);
+        }
+    }
+    for condition in conditions {
+        diagnostics.push(error::preproc_cond_unterminated(condition.span, src));
+    }
+    res
+}
+
+/// Split a file into lines of tokens, without whitespace or comments.
+///
+/// Included files are lexed in place of their `.include` directive. Lines which could not be
+/// lexed are discarded. Empty lines are skipped.
+fn lex_lines(
+    source: &Source,
+    features: Features,
+    range: Range<usize>,
+    lines: &mut Vec<Vec<Token>>,
+    diagnostics: &mut Diagnostics,
+) {
+    let mut line: Vec<Token> = Vec::new();
+    let mut cur = Cursor::with_range(source.text(), range).with_features(features);
 
     loop {
-        let dir = cur.advance_real()?;
-        match dir.kind {
-            // Into raw word with the next literal as value
-            TokenKind::Dir(DirKind::Fill) => {
-                let val = cur.advance_real()?;
-                // Span entire directive name and integer literal
-                let span = dir.span.join(val.span);
-                match val.kind {
-                    TokenKind::Lit(LiteralKind::Hex(lit)) => {
-                        res.push(Token::byte(lit, span));
+        let tok = match cur.advance_token() {
+            Ok(tok) => tok,
+            Err(err) => {
+                diagnostics.push(err);
+                // Block is still matched with its `.else` and `.endif`, with an invalid condition
+                if let Some(dir) = line.first().filter(|tok| {
+                    matches!(
+                        tok.kind,
+                        TokenKind::Dir(DirKind::If | DirKind::Ifdef | DirKind::Ifndef)
+                    )
+                }) {
+                    lines.push(vec![*dir, Token::new(TokenKind::Eof, dir.span)]);
+                }
+                line.clear();
+                cur.skip_line();
+                continue;
+            }
+        };
+        match tok.kind {
+            TokenKind::Whitespace if cur.get_range(tok.span.into()).contains('\n') => {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+            }
+            TokenKind::Comment | TokenKind::Whitespace => (),
+            TokenKind::Dir(DirKind::Include) => {
+                let included = include_range(source, &mut cur, tok);
+                match included {
+                    Ok(included) if line.is_empty() => {
+                        lex_lines(source, features, included, lines, diagnostics)
                     }
-                    TokenKind::Lit(LiteralKind::Dec(lit)) => {
-                        res.push(Token::byte(lit as u16, span));
+                    // Loader only resolves directives at the start of a line
+                    Ok(_) => {
+                        diagnostics.push(error::preproc_include_unresolved(tok.span, source.text()))
                     }
-                    _ => return Err(error::preproc_bad_lit(val.span, src, false)),
+                    Err(err) => diagnostics.push(err),
                 }
             }
-            // Into a series of raw null words
+            TokenKind::Eof | TokenKind::Dir(DirKind::End) => break,
+            _ => line.push(tok),
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+}
+
+/// Get range of the file included by an `.include` directive, which was resolved by the loader.
+fn include_range(source: &Source, cur: &mut Cursor, dir: Token) -> Result<Range<usize>> {
+    let src = source.text();
+    let path = cur.advance_real()?;
+    if path.kind != TokenKind::Lit(LiteralKind::Str) {
+        return Err(error::preproc_no_str(path.span, src));
+    }
+    let span = dir.span.join(path.span);
+    match source.include(dir.span.offs()) {
+        Some(Ok(range)) => Ok(range),
+        Some(Err(err)) => {
+            let path = &src[path.span.offs() + 1..path.span.end() - 1];
+            Err(error::preproc_include_failed(span, src, path, &err))
+        }
+        None => Err(error::preproc_include_unresolved(span, src)),
+    }
+}
+
+/// Define a constant with `.equ` or `.set`, or replace constants in a line with their value.
+///
+/// Returns `None` if the line defined a constant. Prefix labels are never replaced, so that
+/// a label which shares its name with a constant is reported by the parser.
+fn resolve_constants(
+    src: &str,
+    symbols: &mut SymbolTable,
+    mut line: Vec<Token>,
+) -> Result<Option<Vec<Token>>> {
+    substitute_constants(src, symbols, &mut line);
+
+    let (name, dir) = match line.as_slice() {
+        [dir, ..] if matches!(dir.kind, TokenKind::Dir(DirKind::Equ | DirKind::Set)) => {
+            return Err(error::preproc_const_name(dir.span, src));
+        }
+        [name, dir, ..] if matches!(dir.kind, TokenKind::Dir(DirKind::Equ | DirKind::Set)) => {
+            (*name, *dir)
+        }
+        _ => return Ok(Some(line)),
+    };
+    if name.kind != TokenKind::Label {
+        return Err(error::preproc_const_name(name.span, src));
+    }
+    let mut toks = line[2..].iter().copied().peekable();
+    let value = match toks.peek() {
+        Some(tok) if expr::is_start(tok.kind) => Expr::parse(&mut toks, src)?.eval_const()?,
+        val => return Err(error::preproc_bad_lit(val.unwrap_or(&dir).span, src, false)),
+    };
+    if let Some(extra) = toks.next() {
+        return Err(error::parse_generic_unexpected(src, "end of line", extra));
+    }
+    let redefinable = dir.kind == TokenKind::Dir(DirKind::Set);
+    let name_str = label_name(src, name);
+    symbols
+        .define_const(&name_str, value, redefinable)
+        .map_err(|_| error::preproc_const_duplicate(name.span, src, &name_str))?;
+    Ok(None)
+}
+
+/// Replace constants in a line with their value, except for a prefix label.
+fn substitute_constants(src: &str, symbols: &SymbolTable, line: &mut [Token]) {
+    for tok in line.iter_mut().skip(1) {
+        if tok.kind != TokenKind::Label {
+            continue;
+        }
+        if let Some(value) = symbols.get_const(&label_name(src, *tok)) {
+            // Substituted value is checked like any other literal
+            let lit = match i16::try_from(value) {
+                Ok(value) if value < 0 => LiteralKind::Dec(value),
+                _ => LiteralKind::Hex(value as u16),
+            };
+            *tok = Token::new(TokenKind::Lit(lit), tok.span);
+        }
+    }
+}
+
+/// Block of lines started by `.if`, `.ifdef` or `.ifndef`, up to the matching `.endif`.
+struct Condition {
+    /// Directive which started the block
+    span: Span,
+    /// Whether the condition is true. Conditions within a discarded block are always false
+    value: bool,
+    /// Whether the block has reached its `.else`
+    is_else: bool,
+}
+
+impl Condition {
+    /// Whether lines in this part of the block are kept, assuming every enclosing block is.
+    fn is_active(&self) -> bool {
+        self.value != self.is_else
+    }
+}
+
+/// Start, switch or end a conditional block with `.if`, `.ifdef`, `.ifndef`, `.else` or
+/// `.endif`.
+fn conditional(
+    src: &str,
+    symbols: &SymbolTable,
+    dir: DirKind,
+    line: Vec<Token>,
+    conditions: &mut Vec<Condition>,
+) -> Result<()> {
+    let span = line[0].span;
+    match dir {
+        DirKind::Else => {
+            expect_end_of_line(src, line.get(1))?;
+            let Some(condition) = conditions.last_mut() else {
+                return Err(error::preproc_cond_unmatched(span, src));
+            };
+            if condition.is_else {
+                return Err(error::preproc_cond_else(span, src));
+            }
+            condition.is_else = true;
+            Ok(())
+        }
+        DirKind::Endif => {
+            expect_end_of_line(src, line.get(1))?;
+            match conditions.pop() {
+                Some(_) => Ok(()),
+                None => Err(error::preproc_cond_unmatched(span, src)),
+            }
+        }
+        _ => {
+            // Conditions within a discarded block are not evaluated, and a condition which
+            // failed to lex has already been reported
+            let invalid = line.last().is_some_and(|tok| tok.kind == TokenKind::Eof);
+            let value = match conditions.iter().all(Condition::is_active) && !invalid {
+                true => condition_value(src, symbols, dir, line),
+                false => Ok(false),
+            };
+            // Block is still matched with its `.endif` if the condition is invalid
+            conditions.push(Condition {
+                span,
+                value: *value.as_ref().unwrap_or(&false),
+                is_else: false,
+            });
+            value.map(|_| ())
+        }
+    }
+}
+
+/// Evaluate the condition of an `.if`, `.ifdef` or `.ifndef` directive.
+///
+/// Conditions may only refer to constants, as labels do not have an address yet.
+fn condition_value(
+    src: &str,
+    symbols: &SymbolTable,
+    dir: DirKind,
+    mut line: Vec<Token>,
+) -> Result<bool> {
+    let dir_tok = line[0];
+    if dir == DirKind::If {
+        substitute_constants(src, symbols, &mut line);
+        let mut toks = line[1..].iter().copied().peekable();
+        let value = match toks.peek() {
+            Some(tok) if expr::is_start(tok.kind) => Expr::parse(&mut toks, src)?.eval_const()?,
+            val => {
+                return Err(error::preproc_bad_lit(
+                    val.unwrap_or(&dir_tok).span,
+                    src,
+                    false,
+                ))
+            }
+        };
+        expect_end_of_line(src, toks.next().as_ref())?;
+        return Ok(value != 0);
+    }
+
+    let name = match line.get(1) {
+        Some(name) if name.kind == TokenKind::Label => *name,
+        tok => return Err(error::preproc_cond_name(tok.unwrap_or(&dir_tok).span, src)),
+    };
+    expect_end_of_line(src, line.get(2))?;
+    let defined = symbols.get_const(&label_name(src, name)).is_some();
+    Ok(defined == (dir == DirKind::Ifdef))
+}
+
+fn expect_end_of_line(src: &str, extra: Option<&Token>) -> Result<()> {
+    match extra {
+        Some(extra) => Err(error::parse_generic_unexpected(src, "end of line", *extra)),
+        None => Ok(()),
+    }
+}
+
+/// Parse a list of expressions, up to the end of the line.
+///
+/// Returns each expression with the tokens it was parsed from. Commas are lexed as whitespace, so
+/// they are found in the source between tokens, which keeps `a, -b` as two expressions rather
+/// than `a - b`.
+fn parse_list(src: &str, toks: impl Iterator<Item = Token>) -> Result<Vec<(Expr, Vec<Token>)>> {
+    let mut groups: Vec<Vec<Token>> = Vec::new();
+    let mut prev: Option<Token> = None;
+    for tok in toks {
+        let comma = prev.is_some_and(|prev| {
+            src.get(prev.span.end()..tok.span.offs())
+                .is_some_and(|between| between.contains(','))
+        });
+        match groups.last_mut() {
+            Some(group) if !comma => group.push(tok),
+            _ => groups.push(vec![tok]),
+        }
+        prev = Some(tok);
+    }
+
+    let mut items = Vec::new();
+    for group in groups {
+        let mut toks = group.iter().copied().peekable();
+        while let Some(tok) = toks.peek() {
+            if !expr::is_start(tok.kind) {
+                return Err(error::parse_generic_unexpected(src, "expression", *tok));
+            }
+            let start = group.len() - toks.len();
+            let expr = Expr::parse(&mut toks, src)?;
+            let used = group[start..group.len() - toks.len()].to_vec();
+            items.push((expr, used));
+        }
+    }
+    Ok(items)
+}
+
+/// Push a word with the value of an expression, or a `.fill` of it for the parser if it refers
+/// to labels.
+fn push_word(dir: Token, expr: &Expr, toks: &[Token], res: &mut Vec<Token>) -> Result<()> {
+    if expr.is_const() {
+        // Span entire directive name and expression
+        let span = dir.span.join(expr.span());
+        res.push(Token::byte(expr.eval_const()? as u16, span));
+    } else {
+        // Labels are resolved by the parser
+        res.push(Token::new(TokenKind::Dir(DirKind::Fill), dir.span));
+        res.extend(toks);
+    }
+    Ok(())
+}
+
+/// Read a file for `.incbin`, as big-endian words or as one word per character.
+fn incbin(source: &Source, dir: Token, path: Token, text: bool) -> Result<Vec<u16>> {
+    let src = source.text();
+    let span = dir.span.join(path.span);
+    let name = unescape(&src[path.span.offs() + 1..path.span.end() - 1]);
+    let full_path = source.dir_at(dir.span.offs()).join(name.as_ref());
+    let failed =
+        |err: std::io::Error| error::preproc_incbin_failed(span, src, &name, &err.to_string());
+    if text {
+        let text = fs::read_to_string(full_path).map_err(failed)?;
+        return Ok(text.chars().map(|c| c as u16).collect());
+    }
+    let bytes = fs::read(full_path).map_err(failed)?;
+    if bytes.len() % 2 != 0 {
+        return Err(error::preproc_incbin_odd(span, src, &name));
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect())
+}
+
+fn preprocess_line(
+    source: &Source,
+    line: &[Token],
+    res: &mut Vec<Token>,
+    diagnostics: &mut Diagnostics,
+) -> Result<()> {
+    let src = source.text();
+    let mut toks = line.iter().copied().peekable();
+    while let Some(dir) = toks.next() {
+        match dir.kind {
+            // Into raw word with the value of the next expression
+            TokenKind::Dir(DirKind::Fill) => {
+                if !toks.peek().is_some_and(|tok| expr::is_start(tok.kind)) {
+                    let val = toks.next().unwrap_or(dir);
+                    return Err(error::preproc_bad_lit(val.span, src, false));
+                }
+                let start = toks.clone();
+                let expr = Expr::parse(&mut toks, src)?;
+                let len = start.len() - toks.len();
+                let used: Vec<_> = start.take(len).collect();
+                push_word(dir, &expr, &used, res)?;
+            }
+            // Into raw words with the value of each expression
+            TokenKind::Dir(DirKind::Word) => {
+                if !toks.peek().is_some_and(|tok| expr::is_start(tok.kind)) {
+                    let val = toks.next().unwrap_or(dir);
+                    return Err(error::preproc_bad_lit(val.span, src, false));
+                }
+                for (expr, used) in parse_list(src, toks.by_ref())? {
+                    push_word(dir, &expr, &used, res)?;
+                }
+            }
+            // Into a series of raw words, which are null unless a value is given
             TokenKind::Dir(DirKind::Blkw) => {
-                let val = cur.advance_real()?;
-                let span = dir.span.join(val.span);
-                match val.kind {
-                    TokenKind::Lit(LiteralKind::Hex(lit)) => {
-                        for _ in 0..lit {
-                            res.push(Token::nullbyte(span));
+                if !toks.peek().is_some_and(|tok| expr::is_start(tok.kind)) {
+                    let val = toks.next().unwrap_or(dir);
+                    return Err(error::preproc_bad_lit(val.span, src, false));
+                }
+                let mut items = parse_list(src, toks.by_ref())?.into_iter();
+                let (expr, _) = items.next().expect("List should not be empty");
+                let span = dir.span.join(expr.span());
+                let len = expr.eval_const()?;
+                if len < 0 {
+                    diagnostics.push(error::preproc_bad_lit(expr.span(), src, true));
+                }
+                match items.next() {
+                    Some((value, used)) => {
+                        if let Some((_, extra)) = items.next() {
+                            let tok = extra[0];
+                            return Err(error::parse_generic_unexpected(src, "end of line", tok));
                         }
-                    }
-                    TokenKind::Lit(LiteralKind::Dec(lit)) => {
-                        if lit < 0 {
-                            println!("{:?}", error::preproc_bad_lit(val.span, src, true));
+                        for _ in 0..len.max(0) as u16 {
+                            push_word(dir, &value, &used, res)?;
                         }
-                        for _ in 0..lit as u16 {
+                    }
+                    None => {
+                        for _ in 0..len.max(0) as u16 {
                             res.push(Token::nullbyte(span));
                         }
                     }
-                    _ => return Err(error::preproc_bad_lit(val.span, src, false)),
+                }
+                // Kept so that the parser can record it for linting
+                if len == 0 {
+                    res.push(Token::new(TokenKind::Dir(DirKind::Blkw), span));
                 }
             }
             // str into a sequence of bytes corresponding to a literal + null terminator
             TokenKind::Dir(DirKind::Stringz) => {
-                let val = cur.advance_real()?;
+                let val = toks.next().unwrap_or(dir);
                 match val.kind {
                     TokenKind::Lit(LiteralKind::Str) => {
-                        let str_raw = cur.get_range(val.span.into());
+                        let str_raw = &src[val.span.as_range()];
                         let span = dir.span.join(val.span);
                         // Get rid of quotation marks
                         for c in unescape(&str_raw[1..str_raw.len() - 1]).chars() {
@@ -72,23 +486,280 @@ pub fn preprocess(src: &'static str) -> Result<Vec<Token>> {
                     _ => return Err(error::preproc_no_str(val.span, src)),
                 }
             }
+            // str into words of two characters each, for the PUTSP trap, with a null terminator
+            TokenKind::Dir(DirKind::Stringp) => {
+                let val = toks.next().unwrap_or(dir);
+                if val.kind != TokenKind::Lit(LiteralKind::Str) {
+                    return Err(error::preproc_no_str(val.span, src));
+                }
+                let str_raw = &src[val.span.as_range()];
+                let span = dir.span.join(val.span);
+                let chars: Vec<_> = unescape(&str_raw[1..str_raw.len() - 1])
+                    .chars()
+                    .map(|c| c as u16 & 0xFF)
+                    .collect();
+                // First character is in the low byte
+                for pair in chars.chunks(2) {
+                    let high = pair.get(1).copied().unwrap_or(0);
+                    res.push(Token::byte(pair[0] | high << 8, span));
+                }
+                res.push(Token::nullbyte(span));
+            }
+            // Contents of a file, as raw words
+            TokenKind::Dir(DirKind::Incbin) => {
+                let path = toks.next().unwrap_or(dir);
+                if path.kind != TokenKind::Lit(LiteralKind::Str) {
+                    return Err(error::preproc_no_str(path.span, src));
+                }
+                let text = match toks.next() {
+                    None => false,
+                    Some(mode)
+                        if mode.kind == TokenKind::Label
+                            && src[mode.span.as_range()].eq_ignore_ascii_case("text") =>
+                    {
+                        true
+                    }
+                    Some(mode) => return Err(error::parse_generic_unexpected(src, "text", mode)),
+                };
+                let span = dir.span.join(path.span);
+                for word in incbin(source, dir, path, text)? {
+                    res.push(Token::byte(word, span));
+                }
+            }
+            TokenKind::Dir(
+                DirKind::If | DirKind::Ifdef | DirKind::Ifndef | DirKind::Else | DirKind::Endif,
+            ) => return Err(error::preproc_cond_position(dir.span, src)),
             TokenKind::Dir(DirKind::Break) => {
                 // Note that this span will never be used
                 // Since breakpoints don't push bytes
                 res.push(Token::breakpoint(dir.span));
             }
-            // Eliminated during preprocessing
-            TokenKind::Comment | TokenKind::Whitespace => continue,
-            TokenKind::Eof | TokenKind::Dir(DirKind::End) => break,
             _ => res.push(dir),
         }
     }
-    Ok(res)
+    Ok(())
 }
 
-fn preprocess_simple(src: &'static str) -> Result<Vec<Token>> {
+/// Macro defined with `.macro NAME PARAMS...`, up to the matching `.endm`.
+struct Macro<'a> {
+    params: Vec<&'a str>,
+    body: Vec<Vec<Token>>,
+    /// Labels declared within the body, which are unique to each expansion
+    locals: FxHashSet<&'a str>,
+}
+
+/// Collects macro definitions and replaces macro calls with the body of the macro.
+///
+/// A macro is called by using its name in place of an instruction, followed by one token for
+/// each parameter.
+struct MacroExpander<'a> {
+    src: &'a str,
+    macros: FxHashMap<&'a str, Macro<'a>>,
+    /// Counter for [`Expansion`] lines
+    next_line: u32,
+    /// Counter for macro expansions, used to make local labels unique
+    next_id: u16,
+}
+
+impl<'a> MacroExpander<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            macros: FxHashMap::default(),
+            next_line: 0,
+            next_id: 0,
+        }
+    }
+
+    fn text(&self, tok: &Token) -> &'a str {
+        &self.src[tok.span.as_range()]
+    }
+
+    /// Span of a `.macro` or `.endm` directive which does not begin the line.
+    fn misplaced(&self, line: &[Token]) -> Option<Span> {
+        line.iter()
+            .skip(1)
+            .find(|tok| matches!(tok.kind, TokenKind::Dir(DirKind::Macro | DirKind::Endm)))
+            .map(|tok| tok.span)
+    }
+
+    fn expand(mut self, lines: Vec<Vec<Token>>, diagnostics: &mut Diagnostics) -> Vec<Vec<Token>> {
+        let mut res = Vec::new();
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            if let Some(span) = self.misplaced(&line) {
+                diagnostics.push(error::preproc_macro_position(span, self.src));
+                continue;
+            }
+            match line[0].kind {
+                TokenKind::Dir(DirKind::Macro) => {
+                    if let Err(err) = self.define(&line, &mut lines, diagnostics) {
+                        diagnostics.push(err);
+                    }
+                }
+                TokenKind::Dir(DirKind::Endm) => {
+                    diagnostics.push(error::preproc_macro_unmatched_end(line[0].span, self.src));
+                }
+                _ => self.expand_line(line, &mut res, diagnostics, &mut Vec::new()),
+            }
+        }
+        res
+    }
+
+    /// Parse a macro definition, consuming lines up to the matching `.endm`.
+    fn define(
+        &mut self,
+        line: &[Token],
+        lines: &mut impl Iterator<Item = Vec<Token>>,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<()> {
+        let mut body = Vec::new();
+        let mut terminated = false;
+        for line in lines.by_ref() {
+            if let Some(span) = self.misplaced(&line) {
+                diagnostics.push(error::preproc_macro_position(span, self.src));
+                continue;
+            }
+            match line[0].kind {
+                TokenKind::Dir(DirKind::Endm) => {
+                    terminated = true;
+                    break;
+                }
+                TokenKind::Dir(DirKind::Macro) => {
+                    diagnostics.push(error::preproc_macro_nested(line[0].span, self.src));
+                }
+                _ => body.push(line),
+            }
+        }
+        if !terminated {
+            return Err(error::preproc_macro_unterminated(line[0].span, self.src));
+        }
+
+        let name_tok = line.get(1).unwrap_or(&line[0]);
+        let mut names: Vec<&'a str> = Vec::new();
+        for tok in std::iter::once(name_tok).chain(line.iter().skip(2)) {
+            if tok.kind != TokenKind::Label {
+                return Err(error::preproc_macro_name(tok.span, self.src));
+            }
+            let text = self.text(tok);
+            if names.contains(&text) || self.macros.contains_key(text) {
+                return Err(error::preproc_macro_duplicate(tok.span, self.src, text));
+            }
+            names.push(text);
+        }
+        let name = names.remove(0);
+        let params = names;
+
+        let locals = body
+            .iter()
+            .map(|line| line[0])
+            .filter(|tok| tok.kind == TokenKind::Label)
+            .map(|tok| self.text(&tok))
+            .filter(|label| {
+                *label != name && !params.contains(label) && !self.macros.contains_key(label)
+            })
+            .collect();
+
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body,
+                locals,
+            },
+        );
+        Ok(())
+    }
+
+    /// Append a line to `res`, replacing any macro call with the expanded macro body.
+    fn expand_line(
+        &mut self,
+        line: Vec<Token>,
+        res: &mut Vec<Vec<Token>>,
+        diagnostics: &mut Diagnostics,
+        // Macros which are currently being expanded
+        stack: &mut Vec<&'a str>,
+    ) {
+        // Macro may be called after a prefix label
+        let call_index = line.iter().take(2).position(|tok| {
+            tok.kind == TokenKind::Label && self.macros.contains_key(self.text(tok))
+        });
+        let Some(call_index) = call_index else {
+            res.push(line);
+            return;
+        };
+
+        let call = line[call_index];
+        let args = &line[call_index + 1..];
+        // Only the outermost call is shown in diagnostics
+        let call_site = match call.span.call_site() {
+            Some(call_site) => call_site,
+            None => args
+                .last()
+                .map_or(call.span, |arg| call.span.join(arg.span)),
+        };
+
+        let name = self.text(&call);
+        let params = self.macros[name].params.len();
+        if args.len() != params {
+            diagnostics.push(error::preproc_macro_args(
+                call_site,
+                self.src,
+                params,
+                args.len(),
+            ));
+            return;
+        }
+        if stack.contains(&name) {
+            diagnostics.push(error::preproc_macro_recursion(call.span, self.src));
+            return;
+        }
+
+        if call_index > 0 {
+            res.push(vec![line[0]]);
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        let mac = &self.macros[name];
+        let mut expanded = Vec::new();
+        for body_line in &mac.body {
+            self.next_line += 1;
+            let expansion = |local| Expansion::new(call_site, self.next_line, local);
+            let body_line = body_line
+                .iter()
+                .map(|tok| {
+                    let text = &self.src[tok.span.as_range()];
+                    if tok.kind == TokenKind::Label {
+                        if let Some(i) = mac.params.iter().position(|param| *param == text) {
+                            // Arguments keep their own span, and whether they are local to the
+                            // expansion of another macro
+                            let arg = args[i];
+                            let span = arg.span.expanded(expansion(arg.span.local_label()));
+                            return Token::new(arg.kind, span);
+                        }
+                    }
+                    let is_local = tok.kind == TokenKind::Label && mac.locals.contains(text);
+                    Token::new(
+                        tok.kind,
+                        tok.span.expanded(expansion(is_local.then_some(id))),
+                    )
+                })
+                .collect();
+            expanded.push(body_line);
+        }
+
+        stack.push(name);
+        for line in expanded {
+            self.expand_line(line, res, diagnostics, stack);
+        }
+        stack.pop();
+    }
+}
+
+fn preprocess_simple(src: &str, features: Features) -> Result<Vec<Token>> {
     let mut res: Vec<Token> = Vec::new();
-    let mut cur = Cursor::new(src);
+    let mut cur = Cursor::new(src).with_features(features);
 
     loop {
         let token = cur.advance_real()?;
@@ -105,156 +776,375 @@ fn preprocess_simple(src: &'static str) -> Result<Vec<Token>> {
     Ok(res)
 }
 
-fn unescape(s: &str) -> Cow<'_, str> {
-    if s.find('\\').is_none() {
-        return Cow::Borrowed(s);
+/// Get name of a label, which is made unique if it is local to a macro expansion.
+pub(crate) fn label_name(src: &str, tok: Token) -> Cow<'_, str> {
+    let name = &src[tok.span.as_range()];
+    match tok.span.local_label() {
+        Some(id) => Cow::Owned(format!("{name}@{id}")),
+        None => Cow::Borrowed(name),
     }
-    let mut result = String::new();
-    let mut chars = s.chars();
+}
 
-    while let Some(c) = chars.next() {
-        if c == '\\' {
-            match chars.next() {
-                Some('n') => result.push('\n'),
-                Some('t') => result.push('\t'),
-                Some('r') => result.push('\r'),
-                Some('\\') => result.push('\\'),
-                Some('"') => result.push('"'),
-                Some(c) => {
-                    result.push('\\');
-                    result.push(c);
-                }
-                None => {
-                    // Trailing \
-                    result.push('\\');
-                }
-            }
-        } else {
-            result.push(c);
+/// Constant which is defined before assembling, with `-D NAME[=VALUE]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Define {
+    pub name: String,
+    pub value: i32,
+}
+
+impl FromStr for Define {
+    type Err = String;
+    /// Value defaults to 1, and may be written like a decimal or hex literal.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (name, value) = string.split_once('=').unwrap_or((string, "1"));
+        let mut chars = name.chars();
+        if !chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            || !chars.all(is_id)
+        {
+            return Err(format!("Invalid constant name '{}'", name));
         }
+
+        let lower = value.to_ascii_lowercase();
+        let digits = lower.strip_prefix('0').filter(|digits| !digits.is_empty());
+        let (digits, radix) = match digits.unwrap_or(&lower).split_at_checked(1) {
+            Some(("x", digits)) => (digits, 16),
+            Some(("b", digits)) => (digits, 2),
+            Some(("o", digits)) => (digits, 8),
+            _ => (lower.strip_prefix('#').unwrap_or(&lower), 10),
+        };
+        let value = i32::from_str_radix(digits, radix)
+            .ok()
+            .filter(|value| (i16::MIN as i32..=u16::MAX as i32).contains(value))
+            .ok_or_else(|| format!("Invalid value '{}' for constant '{}'", value, name))?;
+        Ok(Define {
+            name: name.to_string(),
+            value,
+        })
     }
-    Cow::Owned(result)
 }
 
 /// Transforms token stream into AIR
-pub struct AsmParser {
+pub struct AsmParser<'a> {
     /// Reference to the source file
-    src: &'static str,
+    src: &'a str,
     /// Peekable iterator over preprocessed tokens
     toks: Peekable<IntoIter<Token>>,
     /// Assembly intermediate representation
     air: Air,
-    /// Tracker for current line
-    line: u16,
 
     tok_end: usize,
+    /// Operand of the current statement which refers to labels
+    pending: Option<Deferred>,
+    /// Label operand of the current statement, which was filled by the parser
+    reference: Option<Expr>,
+    /// Name of the last global label, which local labels are scoped to
+    scope: Option<String>,
+    /// Statements which follow the current statement, if it is a pseudo-instruction
+    expanded: Vec<AirStmt>,
+    /// Values loaded by `ldi16`, which are placed at the next `.pool` or the end of the section
+    pool: Vec<PoolEntry>,
+}
+
+/// Value in a literal pool, which is loaded by one or more `ldi16` pseudo-instructions.
+struct PoolEntry {
+    expr: Expr,
+    /// Pseudo-instruction which first used the value
+    span: Span,
+    /// Index of each `ld` statement which loads the value
+    loads: Vec<usize>,
 }
 
-impl AsmParser {
+impl<'a> AsmParser<'a> {
     /// Preprocesses tokens, otherwise will go into unreachable code. Input should
     /// contain no whitespace or comments.
-    pub fn new(src: &'static str) -> Result<Self> {
-        let toks = preprocess(src)?;
-        Ok(AsmParser {
+    ///
+    /// Preprocessor errors are recorded in the diagnostics of the resulting [`Air`].
+    pub fn new(src: &'a str) -> Self {
+        Self::with_features(src, Features::default())
+    }
+
+    /// Parser for a single unnamed file, which may use the extensions of `features`.
+    pub fn with_features(src: &'a str, features: Features) -> Self {
+        Self::from_parts(src, Source::new(src), features, &[])
+    }
+
+    /// Parser for a program which may include several files, with constants which are defined
+    /// before preprocessing.
+    pub fn with_source(source: &'a Source, features: Features, defines: &[Define]) -> Self {
+        Self::from_parts(source.text(), source.clone(), features, defines)
+    }
+
+    /// `src` must be the text of `source`, which the parser borrows instead of `source`.
+    fn from_parts(src: &'a str, source: Source, features: Features, defines: &[Define]) -> Self {
+        let mut air = Air::new(source, features);
+        for define in defines {
+            air.symbols.predefine(&define.name, define.value);
+        }
+        let toks = preprocess(
+            &air.source,
+            features,
+            &mut air.symbols,
+            &mut air.diagnostics,
+        );
+        AsmParser {
             src,
             toks: toks.into_iter().peekable(),
-            air: Air::new(src),
-            line: 1,
+            air,
             tok_end: 0,
-        })
+            pending: None,
+            reference: None,
+            scope: None,
+            expanded: Vec::new(),
+            pool: Vec::new(),
+        }
     }
 
-    pub fn new_simple(src: &'static str) -> Result<Self> {
-        let toks = preprocess_simple(src)?;
+    /// Parser for a single statement. Labels are left unfilled, for the caller to backpatch.
+    pub fn new_simple(src: &'a str, features: Features) -> Result<Self> {
+        let toks = preprocess_simple(src, features)?;
         Ok(AsmParser {
             src,
             toks: toks.into_iter().peekable(),
-            air: Air::new(src),
-            line: 1,
+            air: Air::new(Source::new(src), features),
             tok_end: 0,
+            pending: None,
+            reference: None,
+            scope: None,
+            expanded: Vec::new(),
+            pool: Vec::new(),
         })
     }
 
-    fn get_span(&self, span: Span) -> &str {
-        &self.src[span.offs()..span.end()]
+    /// Source of the main file, without any included files.
+    fn main_src(&self) -> &'a str {
+        &self.src[self.air.source.main_range()]
+    }
+
+    /// Get name of a label, including the global label which it is scoped to if it is local.
+    fn label_name(&self, tok: Token) -> Cow<'a, str> {
+        let name = label_name(self.src, tok);
+        match &self.scope {
+            Some(scope) if name.starts_with('.') => Cow::Owned(format!("{scope}{name}")),
+            _ => name,
+        }
     }
 
     /// Create AIR out of token stream
-    pub fn parse(mut self) -> Result<Air> {
+    ///
+    /// Parsing recovers at the next line after an error. Every error is recorded in
+    /// [`Air::diagnostics`], and replaced with a placeholder word so that the addresses of
+    /// following statements are unaffected.
+    pub fn parse(mut self) -> Air {
         loop {
-            let mut labeled_line = false;
+            let line_label = self.optional_label();
             // Add prefix label to symbol table if exists
-            if let Some(label) = self.optional_label() {
-                labeled_line = true;
-                match Label::insert(self.get_span(label.span), self.line) {
-                    Ok(_) => (),
-                    Err(_) => return Err(error::parse_duplicate_label(label.span, self.src)),
+            if let Some(label) = line_label {
+                let name = self.label_name(label);
+                if self.air.add_label(&name, label.span).is_err() {
+                    self.air
+                        .diagnostics
+                        .push(error::parse_duplicate_label(label.span, self.src));
                 }
+                // Labels which are local to a macro expansion do not start a new scope
+                if !self.src[label.span.as_range()].starts_with('.')
+                    && label.span.local_label().is_none()
+                {
+                    self.scope = Some(name.into_owned());
+                }
+            }
+            // A label on its own line may be followed by another label
+            if line_label.is_some_and(|label| {
+                self.toks.peek().is_some_and(|tok| {
+                    tok.kind == TokenKind::Label && !label.span.same_line(tok.span, self.src)
+                })
+            }) {
+                continue;
             }
 
             // Parse line
-            if let Some(tok) = self.toks.next() {
-                let stmt = match tok.kind {
-                    // Lines should not start with these tokens
-                    TokenKind::Label | TokenKind::Lit(_) | TokenKind::Reg(_) => {
-                        return Err(error::parse_generic_unexpected(
+            let Some(tok) = self.toks.next() else {
+                if line_label.is_some() {
+                    self.air.diagnostics.push(error::parse_eof(self.main_src()));
+                }
+                break;
+            };
+            self.tok_end = tok.span.end();
+            let stmt = match tok.kind {
+                // Lines should not start with these tokens
+                TokenKind::Label | TokenKind::Lit(_) | TokenKind::Reg(_) | TokenKind::Op(_) => {
+                    match line_label {
+                        // Local labels cannot follow another label, so this is most likely a
+                        // misspelled directive, like `main .fil x10`
+                        Some(label)
+                            if tok.kind == TokenKind::Label
+                                && self.src[tok.span.as_range()].starts_with('.')
+                                && label.span.same_line(tok.span, self.src) =>
+                        {
+                            Err(error::parse_invalid_dir(tok.span, self.src))
+                        }
+                        // Most likely a misspelled directive, rather than a local label
+                        Some(label)
+                            if self.src[label.span.as_range()].starts_with('.')
+                                && label.span.same_line(tok.span, self.src) =>
+                        {
+                            Err(error::parse_invalid_dir(label.span, self.src))
+                        }
+                        _ => Err(error::parse_generic_unexpected(
                             self.src,
                             "directive/instruction/trap",
                             tok,
-                        ))
-                    }
-                    TokenKind::Dir(dir) => {
-                        assert!(dir == DirKind::Orig);
-                        let orig = self.expect_lit(Bits::Unsigned(16))?;
-                        self.air.set_orig(orig)?;
-                        continue;
+                        )),
                     }
-                    TokenKind::Breakpoint => {
-                        let addr = self.air.len() as u16;
-                        self.air.breakpoints.insert(Breakpoint {
-                            address: addr,
-                            is_predefined: true,
-                        });
-                        continue;
-                    }
-                    TokenKind::Instr(instr_kind) => self.parse_instr(instr_kind)?,
-                    TokenKind::Trap(trap_kind) => self.parse_trap(trap_kind)?,
-                    TokenKind::Byte(val) => self.parse_byte(val),
-                    // Does not exist in preprocessed token stream
-                    TokenKind::Whitespace | TokenKind::Comment | TokenKind::Eof => {
-                        unreachable!("Found whitespace/comment/eof in preprocessed stream")
+                }
+                TokenKind::Dir(DirKind::Fill) => self.parse_fill(),
+                TokenKind::Dir(dir) => {
+                    let res = match dir {
+                        DirKind::Orig => {
+                            self.flush_pool();
+                            self.parse_orig(tok)
+                        }
+                        DirKind::Pool => {
+                            self.flush_pool();
+                            Ok(())
+                        }
+                        DirKind::Global | DirKind::Extern => self.parse_linkage(dir),
+                        DirKind::Blkw => {
+                            self.air.add_empty_block(tok.span);
+                            Ok(())
+                        }
+                        _ => unreachable!("Found directive which should have been preprocessed"),
+                    };
+                    if let Err(err) = res {
+                        self.air.diagnostics.push(err);
+                        self.skip_line(tok.span);
                     }
-                };
+                    continue;
+                }
+                TokenKind::Breakpoint => {
+                    self.air.breakpoints.insert(Breakpoint {
+                        address: self.air.next_addr(),
+                        is_predefined: true,
+                    });
+                    continue;
+                }
+                TokenKind::Instr(instr_kind) => self
+                    .check_extension(tok, instr_kind)
+                    .and_then(|_| self.parse_instr(instr_kind)),
+                TokenKind::Trap(trap_kind) => self.parse_trap(trap_kind),
+                TokenKind::Byte(val) => Ok(self.parse_byte(val)),
+                // Does not exist in preprocessed token stream
+                TokenKind::Whitespace | TokenKind::Comment | TokenKind::Eof => {
+                    unreachable!("Found whitespace/comment/eof in preprocessed stream")
+                }
+            };
+            let stmt = match stmt {
+                Ok(stmt) => stmt,
+                Err(err) => {
+                    self.air.diagnostics.push(err);
+                    self.skip_line(tok.span);
+                    self.pending = None;
+                    self.reference = None;
+                    self.expanded.clear();
+                    self.parse_byte(0)
+                }
+            };
 
-                let len = if self.tok_end < tok.span.offs() {
-                    tok.span.len()
-                } else {
-                    self.tok_end - tok.span.offs()
-                };
-                let span = Span::new(SrcOffset(tok.span.offs()), len);
-                self.air.add_stmt(stmt, span);
-            } else {
-                if labeled_line {
-                    return Err(error::parse_eof(self.src));
+            // Operands substituted into a macro body are elsewhere in the source
+            let span = match self.src.get(tok.span.end()..self.tok_end) {
+                Some(between) if !between.contains('\n') => {
+                    tok.span.with_len(self.tok_end - tok.span.offs())
                 }
-                break;
+                _ => tok.span,
+            };
+            self.air.add_stmt(stmt, span);
+            if let Some(deferred) = self.pending.take() {
+                self.air.defer(deferred);
+            }
+            if let Some(expr) = self.reference.take() {
+                self.air.add_ref(expr);
+            }
+            // Every word of a pseudo-instruction maps back to its source
+            for stmt in std::mem::take(&mut self.expanded) {
+                self.air.add_stmt(stmt, span);
             }
+        }
+        self.flush_pool();
+        self.air.diagnostics.set_source(&self.air.source);
+        self.air
+    }
+
+    /// Place every value in the literal pool at the next address, and point each `ldi16` at
+    /// its value.
+    fn flush_pool(&mut self) {
+        for entry in std::mem::take(&mut self.pool) {
+            let addr = self.air.next_addr();
+            for idx in entry.loads {
+                self.air.fill_label(idx, addr);
+            }
+            let val = entry.expr.eval_const().ok();
+            let stmt = self.parse_byte(val.unwrap_or(0) as u16);
+            self.air.add_stmt(stmt, entry.span);
+            if val.is_none() {
+                self.air.defer(Deferred {
+                    expr: entry.expr,
+                    kind: DeferredKind::Word,
+                });
+            }
+        }
+    }
+
+    fn parse_orig(&mut self, tok: Token) -> Result<()> {
+        let expr = self.expect_expr()?;
+        let orig = self.check_lit(&expr, Bits::Unsigned(16))?;
+        self.air.set_orig(orig, tok.span);
+        Ok(())
+    }
+
+    /// Parse `.global NAME` or `.extern NAME`.
+    fn parse_linkage(&mut self, dir: DirKind) -> Result<()> {
+        let tok = self.expect(TokenKind::Label)?;
+        let name = self.label_name(tok);
+        if dir == DirKind::Global {
+            self.air.add_global(&name, tok.span);
+            return Ok(());
+        }
+        self.air
+            .symbols
+            .define_extern(&name)
+            .map_err(|_| error::parse_extern_duplicate(tok.span, self.src, &name))
+    }
 
-            self.line += 1;
+    /// Skip the remaining tokens on the same source line as `start`.
+    ///
+    /// Used to recover after an error, so that parsing resumes at the next statement.
+    fn skip_line(&mut self, start: Span) {
+        while let Some(tok) = self.toks.peek() {
+            if !start.same_line(tok.span, self.src) {
+                break;
+            }
+            self.toks.next();
         }
-        Ok(self.air)
     }
 
     pub fn parse_simple(&mut self) -> Result<AirStmt> {
         let Some(tok) = self.toks.next() else {
-            return Err(error::parse_eof(self.src));
+            return Err(error::parse_eof(self.main_src()));
         };
 
         let stmt = match tok.kind {
-            TokenKind::Instr(instr_kind) => self.parse_instr(instr_kind)?,
+            TokenKind::Instr(instr_kind) => {
+                self.check_extension(tok, instr_kind)?;
+                self.parse_instr(instr_kind)?
+            }
             TokenKind::Trap(trap_kind) => self.parse_trap(trap_kind)?,
 
-            TokenKind::Dir(_) | TokenKind::Label | TokenKind::Lit(_) | TokenKind::Reg(_) => {
+            TokenKind::Dir(_)
+            | TokenKind::Label
+            | TokenKind::Lit(_)
+            | TokenKind::Reg(_)
+            | TokenKind::Op(_) => {
                 return Err(error::parse_generic_unexpected(
                     self.src,
                     "instruction",
@@ -273,6 +1163,13 @@ impl AsmParser {
         };
 
         debug_assert!(self.toks.next().is_none(), "expected end of line");
+        if !self.expanded.is_empty() || !self.pool.is_empty() {
+            return Err(error::parse_sugar_simple(tok.span, self.src));
+        }
+        // Statement is not part of a program, so labels do not have an address
+        if let Some(deferred) = self.pending.take() {
+            return Err(error::expr_not_const(deferred.expr.span()));
+        }
 
         Ok(stmt)
     }
@@ -285,10 +1182,35 @@ impl AsmParser {
         }
     }
 
+    /// Check that the extension which an instruction belongs to is enabled.
+    fn check_extension(&self, tok: Token, kind: InstrKind) -> Result<()> {
+        use crate::symbol::InstrKind::*;
+        let features = self.air.features;
+        let sugar = matches!(kind, Push | Pop) && features.sugar();
+        if matches!(kind, Push | Pop | Call | Rets) && !features.stack() && !sugar {
+            let instr = self.src[tok.span.as_range()].to_ascii_lowercase();
+            return Err(error::lex_stack_extension_not_enabled(
+                &instr, tok.span, self.src,
+            ));
+        }
+        Ok(())
+    }
+
     /// Process several tokens to form valid AIR statement
     pub fn parse_instr(&mut self, kind: InstrKind) -> Result<AirStmt> {
         use crate::symbol::InstrKind;
         match kind {
+            InstrKind::Mov
+            | InstrKind::Clr
+            | InstrKind::Inc
+            | InstrKind::Dec
+            | InstrKind::Neg
+            | InstrKind::Sub
+            | InstrKind::Ldi16 => self.parse_sugar(kind),
+            // Stack without the 'stack' extension
+            InstrKind::Push | InstrKind::Pop if !self.air.features.stack() => {
+                self.parse_sugar(kind)
+            }
             InstrKind::Push => {
                 let src_reg = self.expect_reg()?;
                 Ok(AirStmt::Push { src_reg })
@@ -299,7 +1221,12 @@ impl AsmParser {
             }
             InstrKind::Call => {
                 let label_tok = self.expect(TokenKind::Label)?;
-                let dest_label = Label::try_fill(self.get_span(label_tok.span));
+                let name = self.label_name(label_tok);
+                let dest_label = self.air.symbols.try_fill(&name);
+                self.reference = Some(Expr::Label {
+                    name: name.into_owned(),
+                    span: label_tok.span,
+                });
                 Ok(AirStmt::Call { dest_label })
             }
             InstrKind::Rets => Ok(AirStmt::Rets),
@@ -400,6 +1327,142 @@ impl AsmParser {
         }
     }
 
+    /// Expand a pseudo-instruction from the 'sugar' extension into ordinary instructions.
+    ///
+    /// The first instruction is returned, and the rest are added after it with the same span.
+    fn parse_sugar(&mut self, kind: InstrKind) -> Result<AirStmt> {
+        use ImmediateOrReg::{Imm5, Reg};
+        let add = |dest, src_reg, src_reg_imm| AirStmt::Add {
+            dest,
+            src_reg,
+            src_reg_imm,
+        };
+        let clear = |dest| AirStmt::And {
+            dest,
+            src_reg: dest,
+            src_reg_imm: Imm5(0),
+        };
+        let negate = |dest, src_reg| [AirStmt::Not { dest, src_reg }, add(dest, dest, Imm5(1))];
+        let minus_one = Imm5(-1i16 as u8);
+
+        let mut stmts = match kind {
+            InstrKind::Mov => {
+                let dest = self.expect_reg()?;
+                let src = self.expect_lit_or_reg()?;
+                if let Some(deferred) = self.pending.take() {
+                    return Err(error::expr_not_const(deferred.expr.span()));
+                }
+                match src {
+                    Reg(src_reg) => vec![add(dest, src_reg, Imm5(0))],
+                    imm => vec![clear(dest), add(dest, dest, imm)],
+                }
+            }
+            InstrKind::Clr => vec![clear(self.expect_reg()?)],
+            InstrKind::Inc => {
+                let reg = self.expect_reg()?;
+                vec![add(reg, reg, Imm5(1))]
+            }
+            InstrKind::Dec => {
+                let reg = self.expect_reg()?;
+                vec![add(reg, reg, minus_one)]
+            }
+            InstrKind::Neg => {
+                let reg = self.expect_reg()?;
+                negate(reg, reg).to_vec()
+            }
+            InstrKind::Sub => {
+                let dest = self.expect_reg()?;
+                let lhs = self.expect_reg()?;
+                match self.toks.peek().map(|tok| tok.kind) {
+                    Some(TokenKind::Reg(_)) => {
+                        let rhs = self.expect_reg()?;
+                        if lhs == rhs {
+                            vec![clear(dest)]
+                        } else if dest != lhs {
+                            let mut stmts = negate(dest, rhs).to_vec();
+                            stmts.push(add(dest, lhs, Reg(dest)));
+                            stmts
+                        } else {
+                            // `lhs - rhs` is `!(!lhs + rhs)`, which leaves the subtrahend and
+                            // sets the flags from the difference
+                            let not = |reg| AirStmt::Not {
+                                dest: reg,
+                                src_reg: reg,
+                            };
+                            vec![not(dest), add(dest, dest, Reg(rhs)), not(dest)]
+                        }
+                    }
+                    _ => {
+                        let expr = self.expect_expr()?;
+                        let span = expr.span();
+                        let neg = Expr::Neg {
+                            expr: Box::new(expr),
+                            span,
+                        };
+                        let val = self.check_lit(&neg, Bits::Signed(5))?;
+                        vec![add(dest, lhs, Imm5(val as u8))]
+                    }
+                }
+            }
+            InstrKind::Push => {
+                let src_reg = self.expect_reg()?;
+                vec![
+                    add(Register::R6, Register::R6, minus_one),
+                    AirStmt::StoreOffs {
+                        src_reg,
+                        dest_reg: Register::R6,
+                        offset: 0,
+                    },
+                ]
+            }
+            InstrKind::Pop => {
+                let dest = self.expect_reg()?;
+                vec![
+                    AirStmt::LoadOffs {
+                        dest,
+                        src_reg: Register::R6,
+                        offset: 0,
+                    },
+                    add(Register::R6, Register::R6, Imm5(1)),
+                ]
+            }
+            InstrKind::Ldi16 => {
+                let dest = self.expect_reg()?;
+                let expr = self.expect_expr()?;
+                self.add_pool_entry(expr);
+                // Filled once the pool is placed
+                vec![AirStmt::Load {
+                    dest,
+                    src_label: Label::Ref(0),
+                }]
+            }
+            _ => unreachable!("Found instruction which is not a pseudo-instruction"),
+        };
+        let first = stmts.remove(0);
+        self.expanded = stmts;
+        Ok(first)
+    }
+
+    /// Add a value to the literal pool, which is loaded by the current statement.
+    ///
+    /// Constant values are only placed in the pool once.
+    fn add_pool_entry(&mut self, expr: Expr) {
+        let idx = self.air.len();
+        let val = expr.eval_const().ok();
+        let existing = self
+            .pool
+            .iter_mut()
+            .find(|entry| val.is_some() && entry.expr.eval_const().ok() == val);
+        match existing {
+            Some(entry) => entry.loads.push(idx),
+            None => self.pool.push(PoolEntry {
+                span: expr.span(),
+                expr,
+                loads: vec![idx],
+            }),
+        }
+    }
+
     /// Convert keyword trap to predetermined trap vector
     fn parse_trap(&mut self, kind: TrapKind) -> Result<AirStmt> {
         let trap_vect = match kind {
@@ -417,23 +1480,28 @@ impl AsmParser {
         Ok(AirStmt::Trap { trap_vect })
     }
 
+    /// `.fill` with a value which refers to labels, so was not replaced by the preprocessor.
+    fn parse_fill(&mut self) -> Result<AirStmt> {
+        let expr = self.expect_expr()?;
+        if expr.is_const() {
+            return Ok(self.parse_byte(expr.eval_const()? as u16));
+        }
+        self.pending = Some(Deferred {
+            expr,
+            kind: DeferredKind::Word,
+        });
+        Ok(self.parse_byte(0))
+    }
+
     fn parse_byte(&mut self, val: u16) -> AirStmt {
         AirStmt::RawWord { val: RawWord(val) }
     }
 
+    /// Consume the next token if it is of the expected kind.
+    ///
+    /// Unexpected tokens are not consumed, as they may begin the next statement.
     fn expect(&mut self, expected: TokenKind) -> Result<Token> {
-        match self.toks.next() {
-            Some(tok) if tok.kind == expected => {
-                self.tok_end = tok.span.offs() + tok.span.len();
-                Ok(tok)
-            }
-            Some(unexpected) => Err(error::parse_generic_unexpected(
-                self.src,
-                format!("{expected}").as_str(),
-                unexpected,
-            )),
-            None => Err(error::parse_eof(self.src)),
-        }
+        self.expect_where(|kind| *kind == expected, format!("{expected}").as_str())
     }
 
     fn expect_where(
@@ -441,50 +1509,51 @@ impl AsmParser {
         mut check: impl FnMut(&TokenKind) -> bool,
         expected: &str,
     ) -> Result<Token> {
-        match self.toks.next() {
-            Some(tok) if check(&tok.kind) => {
-                self.tok_end = tok.span.offs() + tok.span.len();
-                Ok(tok)
-            }
+        if let Some(tok) = self.toks.next_if(|tok| check(&tok.kind)) {
+            self.tok_end = tok.span.offs() + tok.span.len();
+            return Ok(tok);
+        }
+        match self.toks.peek() {
             Some(unexpected) => Err(error::parse_generic_unexpected(
-                self.src, expected, unexpected,
+                self.src,
+                expected,
+                *unexpected,
             )),
-            None => Err(error::parse_eof(self.src)),
+            None => Err(error::parse_eof(self.main_src())),
+        }
+    }
+
+    fn expect_expr(&mut self) -> Result<Expr> {
+        if self.toks.peek().is_none() {
+            return Err(error::parse_eof(self.main_src()));
         }
+        let mut expr = Expr::parse(&mut self.toks, self.src)?;
+        self.tok_end = expr.span().end();
+        if let Some(scope) = &self.scope {
+            expr.scope_locals(scope);
+        }
+        Ok(expr)
     }
 
+    /// Parse an immediate value, which is evaluated when backpatching if it refers to labels.
     fn expect_lit(&mut self, bits: Bits) -> Result<u16> {
-        let check_range = |val| -> bool {
-            match bits {
-                Bits::Signed(num_bits) => {
-                    let val = val as i16;
-                    let range = 2_i16.pow(num_bits as u32 - 1);
-                    (-range..range).contains(&val)
-                }
-                Bits::Unsigned(num_bits) => {
-                    let range = 2_u16.pow(num_bits as u32 - 1);
-                    (0..range).contains(&val)
-                }
-            }
-        };
+        let expr = self.expect_expr()?;
+        if !expr.is_const() {
+            self.pending = Some(Deferred {
+                expr,
+                kind: DeferredKind::Imm(bits),
+            });
+            return Ok(0);
+        }
+        self.check_lit(&expr, bits)
+    }
 
-        let tok = self.expect_where(
-            |kind| {
-                matches!(
-                    kind,
-                    TokenKind::Lit(LiteralKind::Dec(_) | LiteralKind::Hex(_))
-                )
-            },
-            "numeric literal",
-        )?;
-        let val = match tok.kind {
-            TokenKind::Lit(LiteralKind::Dec(val)) => val as u16,
-            TokenKind::Lit(LiteralKind::Hex(val)) => val,
-            _ => unreachable!("Found non-literal after checking for literal type"),
-        };
-        match check_range(val) {
-            true => Ok(val),
-            false => Err(error::parse_lit_range(tok.span, self.src, bits)),
+    /// Evaluate an expression which must not refer to labels, and check its range.
+    fn check_lit(&self, expr: &Expr, bits: Bits) -> Result<u16> {
+        let val = expr.eval_const()?;
+        match bits.contains(val) {
+            true => Ok(val as u16),
+            false => Err(error::parse_lit_range(expr.span(), self.src, bits, val)),
         }
     }
 
@@ -505,7 +1574,7 @@ impl AsmParser {
                     let reg = self.expect_reg()?;
                     Ok(ImmediateOrReg::Reg(reg))
                 }
-                TokenKind::Lit(_) => {
+                kind if expr::is_start(kind) => {
                     let val = self.expect_lit(Bits::Signed(5))?;
                     Ok(ImmediateOrReg::Imm5(val as u8))
                 }
@@ -515,48 +1584,81 @@ impl AsmParser {
                     *tok,
                 )),
             },
-            None => Err(error::parse_eof(self.src)),
+            None => Err(error::parse_eof(self.main_src())),
         }
     }
 
     fn expect_lit_or_label(&mut self, bits: u8) -> Result<Label> {
         match self.toks.peek() {
-            Some(tok) => match tok.kind {
-                TokenKind::Label => {
-                    let label_tok = self.expect(TokenKind::Label)?;
-                    let label = Label::try_fill(self.get_span(label_tok.span));
+            Some(tok) if expr::is_start(tok.kind) => match self.expect_expr()? {
+                Expr::Label { name, span } => {
+                    let label = self.air.symbols.try_fill(&name);
+                    self.reference = Some(Expr::Label { name, span });
                     Ok(label)
                 }
-                TokenKind::Lit(_) => {
-                    let val = self.expect_lit(Bits::Signed(bits))?;
-                    let label = Label::Ref(self.line + 1 + val);
-                    Ok(label)
+                // Offset from the program counter
+                expr if expr.is_const() => {
+                    let val = self.check_lit(&expr, Bits::Signed(bits))?;
+                    Ok(Label::Ref(
+                        self.air.next_addr().wrapping_add(1).wrapping_add(val),
+                    ))
+                }
+                // Address, which is converted to an offset when backpatching
+                expr => {
+                    self.pending = Some(Deferred {
+                        expr,
+                        kind: DeferredKind::Address,
+                    });
+                    Ok(Label::Ref(self.air.next_addr().wrapping_add(1)))
                 }
-                _ => Err(error::parse_generic_unexpected(
-                    self.src,
-                    "literal or label",
-                    *tok,
-                )),
             },
-            None => Err(error::parse_eof(self.src)),
+            Some(tok) => Err(error::parse_generic_unexpected(
+                self.src,
+                "literal or label",
+                *tok,
+            )),
+            None => Err(error::parse_eof(self.main_src())),
         }
     }
 }
 
 /// Convenient way to pass around bit limits
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Bits {
     Signed(u8),
     Unsigned(u8),
 }
 
+impl Bits {
+    /// Smallest and largest values which fit in this many bits.
+    pub fn range(&self) -> RangeInclusive<i32> {
+        match *self {
+            Bits::Signed(num_bits) => {
+                let range = 1 << (num_bits - 1);
+                -range..=range - 1
+            }
+            Bits::Unsigned(num_bits) => 0..=(1 << num_bits) - 1,
+        }
+    }
+
+    /// Whether a value fits in this many bits.
+    ///
+    /// Signed values may also be written as a 16-bit two's complement value, like `xFFFF` for -1.
+    pub fn contains(&self, val: i32) -> bool {
+        let range = self.range();
+        range.contains(&val)
+            || matches!(self, Bits::Signed(_))
+                && (0..=u16::MAX as i32).contains(&val)
+                && range.contains(&(val as u16 as i16 as i32))
+    }
+}
+
 impl Display for Bits {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let val = match self {
-            Bits::Signed(val) => val,
-            Bits::Unsigned(val) => val,
-        };
-        f.write_str(&val.to_string())
+        match self {
+            Bits::Signed(val) => write!(f, "{val}-bit signed"),
+            Bits::Unsigned(val) => write!(f, "{val}-bit unsigned"),
+        }
     }
 }
 
@@ -567,9 +1669,24 @@ mod test {
     use crate::{
         air::{AirStmt, AsmLine, ImmediateOrReg},
         lexer::TokenKind,
-        symbol::{Flag, Register},
+        symbol::{Flag, Register, SrcOffset},
     };
 
+    fn preprocess(src: &str) -> Result<Vec<Token>, Diagnostics> {
+        preprocess_with(src, &mut SymbolTable::default())
+    }
+
+    fn preprocess_with(src: &str, symbols: &mut SymbolTable) -> Result<Vec<Token>, Diagnostics> {
+        let mut diagnostics = Diagnostics::new();
+        let source = Source::new(src);
+        let toks = super::preprocess(&source, Features::default(), symbols, &mut diagnostics);
+        if diagnostics.has_errors() {
+            Err(diagnostics)
+        } else {
+            Ok(toks)
+        }
+    }
+
     // .FILL TEST
     #[test]
     fn preproc_fill() {
@@ -629,6 +1746,21 @@ mod test {
         assert!(preprocess("temp .blkw add").is_err())
     }
 
+    #[test]
+    fn preproc_recovers_next_line() {
+        let mut diagnostics = Diagnostics::new();
+        let res = super::preprocess(
+            &Source::new("add r0 @ r1\n.fill add\nhalt"),
+            Features::default(),
+            &mut SymbolTable::default(),
+            &mut diagnostics,
+        );
+        assert_eq!(diagnostics.error_count(), 2);
+        // Partial statements are discarded
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].kind, TokenKind::Trap(TrapKind::Halt));
+    }
+
     #[test]
     fn preproc_blkw_nolabel() {
         let res = preprocess(".blkw #1").unwrap();
@@ -680,6 +1812,40 @@ mod test {
         assert!(res[1].kind == TokenKind::Byte('k' as u16));
     }
 
+    // DATA DIRECTIVE TESTS
+    #[test]
+    fn preproc_data_directives() {
+        let kinds = |src| {
+            preprocess(src)
+                .unwrap()
+                .iter()
+                .map(|tok| tok.kind)
+                .collect::<Vec<_>>()
+        };
+        // Commas separate a negative item from the previous one
+        assert_eq!(
+            kinds(".word #1, -2 x3 (1 + 1)"),
+            [1, -2i16 as u16, 3, 2].map(TokenKind::Byte)
+        );
+        assert_eq!(
+            kinds(".stringp \"abc\""),
+            [0x6261, 0x63, 0].map(TokenKind::Byte)
+        );
+        assert_eq!(kinds(".blkw #2, 'x'"), [0x78, 0x78].map(TokenKind::Byte));
+        assert_eq!(kinds(".blkw #2"), [0, 0].map(TokenKind::Byte));
+        // Labels are left to the parser
+        assert_eq!(
+            kinds(".word #1, label"),
+            [
+                TokenKind::Byte(1),
+                TokenKind::Dir(DirKind::Fill),
+                TokenKind::Label
+            ]
+        );
+        assert!(preprocess(".blkw #2 #1 #0").is_err());
+        assert!(preprocess(".incbin \"missing.bin\"").is_err());
+    }
+
     // Regression
     #[test]
     fn preproc_empty_lines() {
@@ -695,15 +1861,540 @@ mod test {
         assert_eq!(toks[1].kind, TokenKind::Reg(Register::R1));
     }
 
+    // MACRO TEST
+    #[test]
+    fn preproc_macro_expands() {
+        let src = r#"
+        .macro incr dst amount
+            add dst dst amount
+        .endm
+        incr r1 #2
+        start incr r3 #-1
+        "#;
+        let res = preprocess(src).unwrap();
+        let kinds = res.iter().map(|tok| tok.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Instr(InstrKind::Add),
+                TokenKind::Reg(Register::R1),
+                TokenKind::Reg(Register::R1),
+                TokenKind::Lit(LiteralKind::Dec(2)),
+                TokenKind::Label,
+                TokenKind::Instr(InstrKind::Add),
+                TokenKind::Reg(Register::R3),
+                TokenKind::Reg(Register::R3),
+                TokenKind::Lit(LiteralKind::Dec(-1)),
+            ]
+        );
+        // Body tokens point into the macro, and arguments to the call
+        let call = src.find("incr r1 #2").unwrap();
+        assert_eq!(res[0].span.offs(), src.find("add").unwrap());
+        assert_eq!(res[1].span.offs(), call + "incr ".len());
+        assert_eq!(
+            res[0].span.call_site(),
+            Some(Span::new(SrcOffset(call), "incr r1 #2".len()))
+        );
+    }
+
+    #[test]
+    fn preproc_macro_nested_call() {
+        let src = r#"
+        .macro inner a
+            not a a
+        .endm
+        .macro outer a
+            inner a
+            .fill x1
+        .endm
+        outer r2
+        "#;
+        let res = preprocess(src).unwrap();
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[2].kind, TokenKind::Reg(Register::R2));
+        assert_eq!(res[3].kind, TokenKind::Byte(1));
+        let call = src.find("outer r2").unwrap();
+        assert_eq!(res[0].span.call_site().unwrap().offs(), call);
+    }
+
+    #[test]
+    fn preproc_macro_errors() {
+        let mut diagnostics = Diagnostics::new();
+        let res = super::preprocess(
+            &Source::new(
+                r#"
+            .macro two a b
+            .endm
+            two r0
+            .endm
+            .macro self
+                self
+            .endm
+            self
+            .macro
+            "#,
+            ),
+            Features::default(),
+            &mut SymbolTable::default(),
+            &mut diagnostics,
+        );
+        // Wrong argument count, unmatched end, recursion, unterminated
+        assert_eq!(diagnostics.error_count(), 4);
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn preproc_macro_position() {
+        for src in [
+            "x .macro m\n.endm\nhalt",
+            "x .endm\nhalt",
+            ".macro m\nadd r0 r0 #1 .endm\n.endm\nm",
+        ] {
+            let air = AsmParser::new(src).parse();
+            assert!(air.diagnostics.error_count() > 0, "{src}");
+        }
+        let air = AsmParser::new("x .endm\nhalt").parse();
+        assert_eq!(air.diagnostics.error_count(), 1);
+        assert_eq!(air.len(), 1);
+    }
+
+    #[test]
+    fn parse_local_labels() {
+        let air = AsmParser::new(
+            r#"
+        .start and r0 r0 #0
+        main
+        .loop brp .loop
+        ld r0 .start
+        .fil x30
+        "#,
+        )
+        .parse();
+        assert_eq!(
+            air.get(1).stmt,
+            AirStmt::Branch {
+                flag: Flag::P,
+                dest_label: Label::dummy(0x3001)
+            }
+        );
+        // Local labels before any global label are not in scope
+        assert_eq!(
+            air.get(2).stmt,
+            AirStmt::Load {
+                dest: Register::R0,
+                src_label: Label::empty("main.start")
+            }
+        );
+        // Misspelled directive
+        assert_eq!(air.diagnostics.error_count(), 1);
+    }
+
+    #[test]
+    fn parse_misspelled_directive_after_label() {
+        for (src, dir) in [("main .fil x10", ".fil"), (".start .fil x10", ".fil")] {
+            let air = AsmParser::new(src).parse();
+            let report = air.diagnostics.iter().next().unwrap();
+            assert_eq!(report.code().unwrap().to_string(), "parse::dir", "{src}");
+            let label = report.labels().unwrap().next().unwrap();
+            assert_eq!(&src[label.offset()..][..label.len()], dir, "{src}");
+        }
+    }
+
+    #[test]
+    fn parse_sugar() {
+        let air = AsmParser::with_features(
+            r#"
+        mov r0 #-3
+        sub r1 r1 r2
+        push r1
+        "#,
+            "sugar".parse().unwrap(),
+        )
+        .parse();
+        assert!(air.diagnostics.is_empty());
+        assert_eq!(air.len(), 7);
+        assert_eq!(
+            air.get(1).stmt,
+            AirStmt::Add {
+                dest: Register::R0,
+                src_reg: Register::R0,
+                src_reg_imm: ImmediateOrReg::Imm5(-3i16 as u8)
+            }
+        );
+        // Subtrahend is left unchanged, and the last instruction sets the flags from the result
+        assert_eq!(
+            air.get(3).stmt,
+            AirStmt::Add {
+                dest: Register::R1,
+                src_reg: Register::R1,
+                src_reg_imm: ImmediateOrReg::Reg(Register::R2)
+            }
+        );
+        assert_eq!(
+            air.get(4).stmt,
+            AirStmt::Not {
+                dest: Register::R1,
+                src_reg: Register::R1
+            }
+        );
+        assert_eq!(
+            air.get(6).stmt,
+            AirStmt::StoreOffs {
+                src_reg: Register::R1,
+                dest_reg: Register::R6,
+                offset: 0
+            }
+        );
+        // Every word maps back to the pseudo-instruction
+        assert_eq!(air.get(2).span, air.get(4).span);
+        assert_ne!(air.get(1).span, air.get(2).span);
+
+        let mut parser = AsmParser::new_simple("neg r3", Features::default()).unwrap();
+        assert!(parser.parse_simple().is_err());
+        let mut parser = AsmParser::new_simple("inc r3", "sugar".parse().unwrap()).unwrap();
+        assert!(parser.parse_simple().is_ok());
+    }
+
+    #[test]
+    fn parse_macro_local_labels() {
+        let air = AsmParser::new(
+            r#"
+        .macro wait count
+            ld r0 count
+        loop
+            add r0 r0 #-1
+            brp loop
+        .endm
+        wait delay
+        wait delay
+        delay .fill #5
+        "#,
+        )
+        .parse();
+        assert!(air.diagnostics.is_empty());
+        // Each expansion branches to its own copy of the label
+        assert_eq!(
+            air.get(2).stmt,
+            AirStmt::Branch {
+                flag: Flag::P,
+                dest_label: Label::dummy(0x3001)
+            }
+        );
+        assert_eq!(
+            air.get(5).stmt,
+            AirStmt::Branch {
+                flag: Flag::P,
+                dest_label: Label::dummy(0x3004)
+            }
+        );
+        assert_eq!(
+            air.get(3).stmt,
+            AirStmt::Load {
+                dest: Register::R0,
+                src_label: Label::empty("delay")
+            }
+        );
+    }
+
+    #[test]
+    fn parse_macro_recovers_in_expansion() {
+        let air = AsmParser::new(
+            r#"
+        .macro bad dst
+            not dst
+            add dst dst #1
+        .endm
+        bad r1
+        halt
+        "#,
+        )
+        .parse();
+        assert_eq!(air.diagnostics.error_count(), 1);
+        assert_eq!(air.len(), 3);
+        assert_eq!(air.get(2).stmt, AirStmt::Trap { trap_vect: 0x25 });
+    }
+
+    #[test]
+    fn preproc_constants() {
+        let res = preprocess(
+            r#"
+        SIZE .equ #3
+        VEC  .EQU SIZE
+        .blkw SIZE
+        .fill VEC
+        trap VEC
+        "#,
+        )
+        .unwrap()
+        .iter()
+        .map(|tok| tok.kind)
+        .collect::<Vec<TokenKind>>();
+        assert_eq!(
+            res,
+            vec![
+                TokenKind::Byte(0),
+                TokenKind::Byte(0),
+                TokenKind::Byte(0),
+                TokenKind::Byte(3),
+                TokenKind::Trap(TrapKind::Generic),
+                TokenKind::Lit(LiteralKind::Hex(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn preproc_constant_errors() {
+        let mut diagnostics = Diagnostics::new();
+        super::preprocess(
+            &Source::new(
+                r#"
+            A .equ #1
+            A .equ #2
+            B .set #1
+            B .set #2
+            A .set #3
+            .equ #4
+            C .equ r0
+            "#,
+            ),
+            Features::default(),
+            &mut SymbolTable::default(),
+            &mut diagnostics,
+        );
+        // Redefined with .equ, redefined with .set, no name, not a literal
+        assert_eq!(diagnostics.error_count(), 4);
+    }
+
+    #[test]
+    fn preproc_conditionals() {
+        let mut symbols = SymbolTable::default();
+        symbols.predefine("DEBUG", 1);
+        let res = preprocess_with(
+            r#"
+        LEVEL .equ #2
+        .ifdef DEBUG
+            .fill #1
+            .if LEVEL - 2
+                .fill #2
+            .else
+                .fill #3
+                .ifndef DEBUG
+                    .fill #4
+                .endif
+            .endif
+        .else
+            .fill #5
+        .endif
+        .if MISSING
+        .endif
+        .ifdef FEATURE_STACK
+            .fill #6
+        .endif
+        "#,
+            &mut symbols,
+        )
+        .unwrap_err();
+        // Condition refers to a label
+        assert_eq!(res.error_count(), 1);
+
+        let res = preprocess_with(
+            r#"
+        .ifdef DEBUG
+            .fill #1
+            .if #0
+                .fill #2
+                .if MISSING
+                .endif
+            .else
+                .fill #3
+            .endif
+        .else
+            .fill #4
+        .endif
+        "#,
+            &mut symbols,
+        )
+        .unwrap()
+        .iter()
+        .map(|tok| tok.kind)
+        .collect::<Vec<TokenKind>>();
+        assert_eq!(res, vec![TokenKind::Byte(1), TokenKind::Byte(3)]);
+    }
+
+    #[test]
+    fn preproc_conditional_errors() {
+        let mut diagnostics = Diagnostics::new();
+        super::preprocess(
+            &Source::new(
+                r#"
+            .endif
+            .if #1
+            .else
+            .else
+            .endif
+            label .ifdef A
+            .ifdef #1
+            .endif
+            .if
+            "#,
+            ),
+            Features::default(),
+            &mut SymbolTable::default(),
+            &mut diagnostics,
+        );
+        // Unmatched, second .else, label, not a name, no condition, unterminated
+        assert_eq!(diagnostics.error_count(), 6);
+
+        // Condition which fails to lex still opens a block, which is treated as false
+        let mut diagnostics = Diagnostics::new();
+        let res = super::preprocess(
+            &Source::new(
+                r#"
+            X .set #2
+            .if X == 2
+            .fill #1
+            .else
+            .fill #2
+            .endif
+            "#,
+            ),
+            Features::default(),
+            &mut SymbolTable::default(),
+            &mut diagnostics,
+        );
+        assert_eq!(diagnostics.error_count(), 1);
+        assert!(res.iter().any(|tok| tok.kind == TokenKind::Byte(2)));
+        assert!(!res.iter().any(|tok| tok.kind == TokenKind::Byte(1)));
+    }
+
+    #[test]
+    fn parse_defines() {
+        assert_eq!(
+            "DEBUG".parse(),
+            Ok(Define {
+                name: "DEBUG".to_string(),
+                value: 1
+            })
+        );
+        assert_eq!("LEVEL=x10".parse::<Define>().unwrap().value, 0x10);
+        assert_eq!("LEVEL=#-3".parse::<Define>().unwrap().value, -3);
+        assert_eq!("LEVEL=42".parse::<Define>().unwrap().value, 42);
+        assert!("2FAST".parse::<Define>().is_err());
+        assert!("LEVEL=".parse::<Define>().is_err());
+        assert!("LEVEL=x10000".parse::<Define>().is_err());
+    }
+
+    #[test]
+    fn parse_constants() {
+        let air = AsmParser::new(
+            r#"
+        ONE  .set #1
+        add r0 r0 ONE
+        ONE  .set #-1
+        add r0 r0 ONE
+        BIG  .equ #16
+        add r0 r0 BIG
+        BIG  halt
+        "#,
+        )
+        .parse();
+        assert_eq!(
+            air.get(1).stmt,
+            AirStmt::Add {
+                dest: Register::R0,
+                src_reg: Register::R0,
+                src_reg_imm: ImmediateOrReg::Imm5((-1i8) as u8)
+            }
+        );
+        // Out-of-range value, label with the name of a constant
+        assert_eq!(air.diagnostics.error_count(), 2);
+    }
+
+    #[test]
+    fn preproc_expressions() {
+        let res = preprocess("SIZE .equ #2*3\n.blkw SIZE-x4\n.fill #SIZE<<1\n.fill table+1")
+            .unwrap()
+            .iter()
+            .map(|tok| tok.kind)
+            .collect::<Vec<TokenKind>>();
+        assert_eq!(
+            res[..3],
+            [TokenKind::Byte(0), TokenKind::Byte(0), TokenKind::Byte(12)]
+        );
+        // Values which refer to labels are left to the parser
+        assert_eq!(res[3], TokenKind::Dir(DirKind::Fill));
+        assert_eq!(res.len(), 7);
+    }
+
+    #[test]
+    fn parse_expressions() {
+        let air = AsmParser::new(
+            r#"
+        SIZE .equ #4
+        ldr r0 r1 #SIZE-1
+        ldr r0 r1 (SIZE - x6) * 2
+        trap x20 | 5
+        br #SIZE/2
+        .orig table
+        "#,
+        )
+        .parse();
+        assert_eq!(
+            air.get(0).stmt,
+            AirStmt::LoadOffs {
+                dest: Register::R0,
+                src_reg: Register::R1,
+                offset: 3
+            }
+        );
+        assert_eq!(
+            air.get(1).stmt,
+            AirStmt::LoadOffs {
+                dest: Register::R0,
+                src_reg: Register::R1,
+                offset: (-4i8) as u8
+            }
+        );
+        assert_eq!(air.get(2).stmt, AirStmt::Trap { trap_vect: 0x25 });
+        assert_eq!(
+            air.get(3).stmt,
+            AirStmt::Branch {
+                flag: Flag::Nzp,
+                dest_label: Label::dummy(0x3006)
+            }
+        );
+        // Origin must be known before labels
+        assert_eq!(air.diagnostics.error_count(), 1);
+    }
+
+    #[test]
+    fn parse_expression_ends_at_line() {
+        for src in ["lea r0 #1+\nX add r0 r0 #1\nhalt", ".fill #1-\nhalt"] {
+            let air = AsmParser::new(src).parse();
+            let codes: Vec<_> = air
+                .diagnostics
+                .iter()
+                .map(|report| report.code().unwrap().to_string())
+                .collect();
+            assert_eq!(codes, ["expr::missing_operand"], "{src}");
+            // Following lines are still parsed
+            assert_eq!(
+                air.get(air.len() - 1).stmt,
+                AirStmt::Trap { trap_vect: 0x25 }
+            );
+        }
+    }
+
     // Parser tests
     #[test]
     fn parse_add_basic() {
-        let parser = AsmParser::new("add r0 r1 r2").unwrap();
-        let air = parser.parse().unwrap();
+        let parser = AsmParser::new("add r0 r1 r2");
+        let air = parser.parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::Add {
                     dest: Register::R0,
                     src_reg: Register::R1,
@@ -721,14 +2412,14 @@ mod test {
         add r0 r1 #15
         add r0 r1 #-16
         "#,
-        )
-        .unwrap();
-        let air = parser.parse().unwrap();
+        );
+        let air = parser.parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(air.len(), 2);
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::Add {
                     dest: Register::R0,
                     src_reg: Register::R1,
@@ -747,7 +2438,7 @@ mod test {
         assert_eq!(
             air.get(1),
             &AsmLine {
-                line: 2,
+                addr: 0x3001,
                 stmt: AirStmt::Add {
                     dest: Register::R0,
                     src_reg: Register::R1,
@@ -768,19 +2459,44 @@ mod test {
 
     #[test]
     fn parse_add_bad_range() {
-        let air = AsmParser::new("add r0 r1 #16").unwrap().parse();
-        assert!(air.is_err());
-        let air = AsmParser::new("add r0 r1 #-17").unwrap().parse();
-        assert!(air.is_err());
+        let air = AsmParser::new("add r0 r1 #16").parse();
+        assert!(air.diagnostics.has_errors());
+        let air = AsmParser::new("add r0 r1 #-17").parse();
+        assert!(air.diagnostics.has_errors());
+    }
+
+    #[test]
+    fn parse_recovers_next_line() {
+        let air = AsmParser::new(
+            r#"
+        add r0 r1 #16
+        not r0
+        ld r0
+        and r0 r1 r2
+        "#,
+        )
+        .parse();
+        assert_eq!(air.diagnostics.error_count(), 3);
+        // Placeholders keep the addresses of following statements intact
+        assert_eq!(air.len(), 4);
+        assert_eq!(
+            air.get(3).stmt,
+            AirStmt::And {
+                dest: Register::R0,
+                src_reg: Register::R1,
+                src_reg_imm: ImmediateOrReg::Reg(Register::R2),
+            }
+        );
     }
 
     #[test]
     fn parse_branch() {
-        let air = AsmParser::new("br label").unwrap().parse().unwrap();
+        let air = AsmParser::new("br label").parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
                     dest_label: Label::empty("label")
@@ -792,14 +2508,15 @@ mod test {
 
     #[test]
     fn parse_branch_lit() {
-        let air = AsmParser::new("br x2").unwrap().parse().unwrap();
+        let air = AsmParser::new("br x2").parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
-                    dest_label: Label::Ref(0x2 + 0x2)
+                    dest_label: Label::Ref(0x3001 + 0x2)
                 },
                 span: Span::new(SrcOffset(0), "br x2".len())
             }
@@ -808,11 +2525,12 @@ mod test {
 
     #[test]
     fn parse_fill() {
-        let air = AsmParser::new("label .fill x30").unwrap().parse().unwrap();
+        let air = AsmParser::new("label .fill x30").parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::RawWord { val: RawWord(0x30) },
                 span: Span::new(SrcOffset("label ".len()), ".fill x30".len())
             }
@@ -821,14 +2539,12 @@ mod test {
 
     #[test]
     fn parse_stringz() {
-        let air = AsmParser::new("label .stringz \"ab\"")
-            .unwrap()
-            .parse()
-            .unwrap();
+        let air = AsmParser::new("label .stringz \"ab\"").parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::RawWord {
                     val: RawWord('a' as u16)
                 },
@@ -838,7 +2554,7 @@ mod test {
         assert_eq!(
             air.get(1),
             &AsmLine {
-                line: 2,
+                addr: 0x3001,
                 stmt: AirStmt::RawWord {
                     val: RawWord('b' as u16)
                 },
@@ -848,7 +2564,7 @@ mod test {
         assert_eq!(
             air.get(2),
             &AsmLine {
-                line: 3,
+                addr: 0x3002,
                 stmt: AirStmt::RawWord {
                     val: RawWord('\0' as u16)
                 },
@@ -865,13 +2581,12 @@ mod test {
         .stringz "b"
         "#,
         )
-        .unwrap()
-        .parse()
-        .unwrap();
+        .parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::RawWord {
                     val: RawWord('a' as u16)
                 },
@@ -888,7 +2603,7 @@ mod test {
         assert_eq!(
             air.get(2),
             &AsmLine {
-                line: 3,
+                addr: 0x3002,
                 stmt: AirStmt::RawWord {
                     val: RawWord('b' as u16)
                 },
@@ -915,13 +2630,12 @@ mod test {
               br x30
         "#,
         )
-        .unwrap()
-        .parse()
-        .unwrap();
+        .parse();
+        assert!(air.diagnostics.is_empty());
         assert_eq!(
             air.get(0),
             &AsmLine {
-                line: 1,
+                addr: 0x3000,
                 stmt: AirStmt::Add {
                     dest: Register::R0,
                     src_reg: Register::R0,
@@ -940,10 +2654,10 @@ mod test {
         assert_eq!(
             air.get(1),
             &AsmLine {
-                line: 2,
+                addr: 0x3001,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
-                    dest_label: Label::dummy(1)
+                    dest_label: Label::dummy(0x3000)
                 },
                 span: Span::new(
                     SrcOffset(
@@ -959,7 +2673,7 @@ mod test {
         assert_eq!(
             air.get(2),
             &AsmLine {
-                line: 3,
+                addr: 0x3002,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
                     dest_label: Label::empty("not_existing")
@@ -979,10 +2693,10 @@ mod test {
         assert_eq!(
             air.get(3),
             &AsmLine {
-                line: 4,
+                addr: 0x3003,
                 stmt: AirStmt::Branch {
                     flag: Flag::Nzp,
-                    dest_label: Label::Ref(0x5 + 0x30),
+                    dest_label: Label::Ref(0x3004 + 0x30),
                 },
                 span: Span::new(
                     SrcOffset(
@@ -1,34 +1,45 @@
 use std::{
     cmp::Ordering,
+    fmt,
     io::{self, stdin, stdout, IsTerminal, Read, Write},
+    ops::Range,
 };
 
+use crate::term;
 use crate::{
-    debugger::{Action, Debugger, Options, SignificantInstr},
+    air::{DEFAULT_ORIG, SEGMENTS_MAGIC},
+    debugger::{Action, Breakpoints, Debugger, Options, SignificantInstr},
+    devices::{Devices, Interrupt, DEVICE_MEMORY},
     dprintln,
+    emit::{self, Format},
+    error,
+    features::Features,
     output::{Condition, Output},
+    source::Source,
+    symbol::SymbolTable,
     Air,
 };
-use crate::{features, term};
 use colored::Colorize;
-use miette::Result;
+use miette::{Diagnostic, Result, Severity};
 
-/// First address which is out of bounds of user memory.
-pub const USER_MEMORY_END: u16 = 0xFE00;
+/// First address which is out of bounds of user memory, where device registers start.
+pub const USER_MEMORY_END: u16 = DEVICE_MEMORY;
 /// Sentinel value, which the PC is set to when a `HALT` is encountered.
 pub const HALT_ADDRESS: u16 = 0xFFFF;
 
-/// CPU exception.
-/// A fatal error has occurred in the program, such as an invalid instruction.
-macro_rules! exception {
-    ( $fmt:literal $($tt:tt)* ) => {{
-        eprintln!(
-            concat!("exception: ", $fmt, ", exiting")
-            $($tt)*
-        );
-        std::process::exit(0xEE);
-    }};
-}
+/// Table of the address of each trap routine, by trap vector, in OS mode.
+pub const TRAP_VECTOR_TABLE: u16 = 0x0000;
+/// Table of the address of each exception and interrupt handler, by vector, in OS mode.
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+/// Address of the trap routines of the default OS image, after the vector tables.
+const OS_TRAP_ROUTINES: u16 = 0x0200;
+/// Initial value of the supervisor stack pointer, which grows down from below user memory.
+const INITIAL_SSP: u16 = 0x3000;
+
+/// PSR bit which is set in user mode, and clear in supervisor mode.
+const PSR_USER: u16 = 0x8000;
+/// PSR bits of the priority level of the running program.
+const PSR_PRIORITY: u16 = 0x0700;
 
 /// LC3 can address 128KB of memory.
 pub(crate) const MEMORY_MAX: usize = 0x10000;
@@ -36,6 +47,10 @@ pub(crate) const MEMORY_MAX: usize = 0x10000;
 pub struct RunEnvironment {
     state: RunState,
     debugger: Option<Debugger>,
+    /// Address of each segment which has been loaded, to find images which overlap
+    loaded: Vec<Range<usize>>,
+    /// Address to start at, instead of the first segment of the last image
+    entry: Option<u16>,
 }
 
 /// Represents complete program state during runtime.
@@ -50,10 +65,152 @@ pub(super) struct RunState {
     reg: [u16; 8],
     /// Condition code
     flag: RunFlag,
-    /// Processor status register
-    _psr: u16,
-    /// Origin address (usually 0x3000)
+    /// Privilege and priority bits of the processor status register
+    ///
+    /// The condition codes of the PSR are kept in `flag`.
+    psr: u16,
+    /// Stack pointer (R6) of whichever mode is not running
+    saved_ssp: u16,
+    saved_usp: u16,
+    /// Whether traps, exceptions and `RTI` use the vector tables and supervisor mode, rather than
+    /// being handled natively
+    os: bool,
+    /// Lowest origin of any segment (usually 0x3000)
     orig: u16,
+    /// Extensions which the program may use
+    features: Features,
+    /// Registers from [`DEVICE_MEMORY`], which are not part of `mem`
+    devices: Devices,
+}
+
+/// Fault which is dispatched through the interrupt vector table in OS mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Exception {
+    /// `RTI` in user mode
+    PrivilegeMode = 0x00,
+    /// Reserved opcode
+    IllegalOpcode = 0x01,
+}
+
+impl From<Exception> for Fault {
+    fn from(exception: Exception) -> Self {
+        match exception {
+            Exception::PrivilegeMode => Fault::PrivilegeMode,
+            Exception::IllegalOpcode => Fault::IllegalOpcode,
+        }
+    }
+}
+
+/// How a program stopped running, without a fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// `HALT` was executed, or the clock was stopped with the machine control register
+    Halted,
+    /// Program was exited from the debugger
+    Exited,
+}
+
+/// Fatal error in the program, such as an invalid instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// `RTI` in user mode, or outside of OS mode, without an exception handler
+    PrivilegeMode,
+    /// Reserved opcode, without an exception handler
+    IllegalOpcode,
+    /// Trap whose entry in the trap vector table is 0, in OS mode
+    MissingTrapRoutine(u8),
+    /// Trap which is not handled natively, outside of OS mode
+    UnknownTrap(u8),
+    /// PC is below the origin of the loaded program, or in device memory
+    ProtectedMemory {
+        /// Whether the PC is at or above the end of user memory
+        above: bool,
+        /// Origin, or end of user memory
+        bound: u16,
+    },
+    /// Input ended while a trap was reading it
+    EndOfInput,
+}
+
+impl Fault {
+    fn code(&self) -> &'static str {
+        match self {
+            Fault::PrivilegeMode => "run::privilege_mode",
+            Fault::IllegalOpcode => "run::illegal_opcode",
+            Fault::MissingTrapRoutine(_) => "run::missing_trap_routine",
+            Fault::UnknownTrap(_) => "run::unknown_trap",
+            Fault::ProtectedMemory { .. } => "run::protected_memory",
+            Fault::EndOfInput => "run::end_of_input",
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::PrivilegeMode => write!(f, "returned from interrupt in user mode"),
+            Fault::IllegalOpcode => write!(f, "called a reserved instruction"),
+            Fault::MissingTrapRoutine(trap_vect) => write!(
+                f,
+                "called a trap without a routine, with a vector of 0x{:02x}",
+                trap_vect
+            ),
+            Fault::UnknownTrap(trap_vect) => write!(
+                f,
+                "called a trap with an unknown vector of 0x{:02x}",
+                trap_vect
+            ),
+            Fault::ProtectedMemory { above, bound } => write!(
+                f,
+                "entered protected memory area {} 0x{:04x}",
+                if *above { ">=" } else { "<" },
+                bound
+            ),
+            Fault::EndOfInput => write!(f, "unexpected end of input file stream"),
+        }
+    }
+}
+
+/// Fault which stopped the program, with the instruction which caused it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub fault: Fault,
+    /// Address of the instruction
+    pub pc: u16,
+    /// Instruction word, or the word at the PC if it was not executed
+    pub instr: u16,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at 0x{:04x} (instruction 0x{:04x})",
+            self.fault, self.pc, self.instr
+        )
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+impl Diagnostic for RuntimeError {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(self.fault.code()))
+    }
+
+    fn severity(&self) -> Option<Severity> {
+        Some(Severity::Error)
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        match self.fault {
+            // Stack instructions are the only reserved opcode
+            Fault::IllegalOpcode => Some(Box::new(
+                "run with `-f stack` to enable stack extension feature",
+            )),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -65,68 +222,191 @@ pub(super) enum RunFlag {
 }
 
 impl RunEnvironment {
+    /// Machine with empty memory, to load programs into.
+    ///
+    /// In OS mode, traps and exceptions are dispatched through the vector tables in supervisor
+    /// mode, and the default OS image is loaded first. Its trap routines perform each trap natively,
+    /// and can be replaced by loading a program which sets the vectors of its own routines.
+    pub fn new(features: Features, os: bool) -> RunEnvironment {
+        let mut env = RunEnvironment {
+            state: RunState {
+                mem: Box::new([0; MEMORY_MAX]),
+                pc: DEFAULT_ORIG,
+                // Stack pointer (R7) initalized to last address in user memory
+                reg: [0, 0, 0, 0, 0, 0, 0, USER_MEMORY_END - 1],
+                flag: RunFlag::Uninit,
+                psr: if os { PSR_USER } else { 0 },
+                saved_ssp: INITIAL_SSP,
+                saved_usp: 0,
+                os,
+                orig: USER_MEMORY_END,
+                features,
+                devices: Devices::default(),
+            },
+            debugger: None,
+            loaded: Vec::new(),
+            entry: None,
+        };
+        if os {
+            env.load_os();
+        }
+        env
+    }
+
     // Not generic because of miette error
     pub fn try_from(air: Air, debugger_opts: Option<Options>) -> Result<RunEnvironment> {
-        // TODO(refactor): Use constant for default origin ?
-        let orig = air.orig().unwrap_or(0x3000);
-        let mut air_array: Vec<u16> = Vec::with_capacity(air.len() + 1);
-
-        air_array.push(orig);
-        for stmt in &air {
-            air_array.push(stmt.emit()?);
-        }
+        let mut env = RunEnvironment::new(air.features, false);
+        env.load_air(air, debugger_opts)?;
+        Ok(env)
+    }
 
-        let mut env = RunEnvironment::from_raw(air_array.as_slice())?;
+    /// Load an assembled program, which starts at its first segment.
+    ///
+    /// The debugger is attached once the program is loaded, so it should be loaded last.
+    pub fn load_air(&mut self, air: Air, debugger_opts: Option<Options>) -> Result<()> {
+        self.load(&air.emit()?)?;
 
         if let Some(debugger_opts) = debugger_opts {
-            env.debugger = Some(Debugger::new(
+            self.debugger = Some(Debugger::new(
                 debugger_opts,
-                env.state.clone(),
-                air.breakpoints.with_orig(env.state.pc), // Add orig to each breakpoint
+                self.state.clone(),
+                air.breakpoints,
                 air.ast,
-                air.src,
+                air.source,
+                air.symbols,
             ));
         }
+        Ok(())
+    }
+
+    /// Debug a program which was loaded without its source, such as from a `.lc3` file.
+    ///
+    /// Labels can still be used if they are in `symbols`, such as from a symbol file.
+    pub fn attach_debugger(&mut self, debugger_opts: Options, symbols: SymbolTable) {
+        self.debugger = Some(Debugger::new(
+            debugger_opts,
+            self.state.clone(),
+            Breakpoints::default(),
+            Vec::new(),
+            Source::new(""),
+            symbols,
+        ));
+    }
 
+    /// Load a program file which was written in any [`Format`].
+    pub fn from_bytes(bytes: &[u8], format: Format, features: Features) -> Result<RunEnvironment> {
+        RunEnvironment::from_raw(&emit::decode(bytes, format)?, features)
+    }
+
+    /// Load an object file, with one or more segments.
+    ///
+    /// See [`SEGMENTS_MAGIC`] for the format.
+    pub fn from_raw(raw: &[u16], features: Features) -> Result<RunEnvironment> {
+        let mut env = RunEnvironment::new(features, false);
+        env.load(raw)?;
         Ok(env)
     }
 
-    pub fn from_raw(raw: &[u16]) -> Result<RunEnvironment> {
+    /// Add the programmable timer device, whose registers are otherwise ordinary memory.
+    ///
+    /// Should be called before the debugger is attached, so that the timer is kept by `reset`.
+    pub fn enable_timer(&mut self) {
+        self.state.devices.enable_timer();
+    }
+
+    /// Start the program at `entry`, instead of the first segment of the last image to be loaded.
+    ///
+    /// Should be called before loading, so that the entry is kept by `reset` in the debugger.
+    pub fn set_entry(&mut self, entry: u16) {
+        self.entry = Some(entry);
+        self.state.pc = entry;
+    }
+
+    /// Place each segment of an object file in memory. The program starts at the first segment.
+    ///
+    /// Several images can be loaded, such as an OS image, data, and then the program, as long as
+    /// none overlap.
+    pub fn load(&mut self, raw: &[u16]) -> Result<()> {
         if raw.is_empty() {
-            exception!("provided file is empty");
+            return Err(error::load_empty());
         }
 
-        let orig = raw[0] as usize;
-        if orig + raw.len() > MEMORY_MAX {
-            exception!("assembly file is too long and cannot fit in memory");
+        let segments = match raw {
+            [SEGMENTS_MAGIC, rest @ ..] if !rest.is_empty() => read_segments(rest)?,
+            [orig, words @ ..] => vec![(*orig, words)],
+            [] => unreachable!("file was checked to be non-empty"),
+        };
+
+        let ranges: Vec<Range<usize>> = segments
+            .iter()
+            .map(|(orig, words)| *orig as usize..*orig as usize + words.len())
+            .collect();
+        for range in &ranges {
+            let overlap = self
+                .loaded
+                .iter()
+                .find(|other| range.start < other.end && other.start < range.end);
+            if let Some(other) = overlap {
+                return Err(error::load_overlap(
+                    range.start as u16,
+                    (range.end - 1) as u16,
+                    other.start as u16,
+                    (other.end - 1) as u16,
+                ));
+            }
         }
 
-        let mut mem = [0; MEMORY_MAX];
-        let raw = &raw[1..];
+        let mem = &mut self.state.mem;
+        for (orig, words) in &segments {
+            let orig = *orig as usize;
+            // Leave room for `HALT`
+            if orig + words.len() >= MEMORY_MAX {
+                return Err(error::load_too_long(orig as u16));
+            }
+            mem[orig..orig + words.len()].clone_from_slice(words);
+        }
+        self.loaded.extend(ranges);
+        for range in &self.loaded {
+            // Add `HALT` at end of code and data, unless another segment continues from there
+            // Prevents PC running through no-ops to the end of memory
+            let end = range.end;
+            let continued = self.loaded.iter().any(|other| other.contains(&end));
+            if !continued {
+                mem[end] = 0xF025;
+            }
+        }
 
-        mem[orig..orig + raw.len()].clone_from_slice(raw);
-        // Add `HALT` at end of code and data
-        // Prevents PC running through no-ops to the end of memory
-        mem[orig + raw.len()] = 0xF025;
+        // Program starts at the first segment
+        self.state.pc = self.entry.unwrap_or(segments[0].0);
+        for (orig, _) in &segments {
+            self.state.orig = self.state.orig.min(*orig);
+        }
+        Ok(())
+    }
 
-        Ok(RunEnvironment {
-            state: RunState {
-                mem: Box::new(mem),
-                pc: orig as u16,
-                // Stack pointer (R7) initalized to last address in user memory
-                reg: [0, 0, 0, 0, 0, 0, 0, USER_MEMORY_END - 1],
-                flag: RunFlag::Uninit,
-                _psr: 0,
-                orig: orig as u16,
-            },
-            debugger: None,
-        })
+    /// Load the default OS image: a trap routine for each trap which is handled natively, made of
+    /// that trap followed by `RTI`.
+    fn load_os(&mut self) {
+        let state = &mut self.state;
+        for (i, trap_vect) in (0x20..=0x27).enumerate() {
+            let routine = OS_TRAP_ROUTINES + 2 * i as u16;
+            *state.mem_mut(TRAP_VECTOR_TABLE + trap_vect) = routine;
+            *state.mem_mut(routine) = 0xF000 | trap_vect;
+            *state.mem_mut(routine + 1) = 0x8000;
+        }
+        state.orig = TRAP_VECTOR_TABLE;
     }
 
-    /// Run with preset memory
-    pub fn run(&mut self) {
+    /// Run with preset memory, until the program halts, is exited from the debugger, or faults.
+    ///
+    /// With the debugger, a fault pauses execution at the instruction which caused it, instead.
+    pub fn run(&mut self) -> Result<RunOutcome, RuntimeError> {
+        // Keyboard may be polled in raw mode, until the program stops
+        let _raw_mode = term::RawModeGuard;
         loop {
             if let Some(debugger) = &mut self.debugger {
+                // Debugger prompt reads lines in its own raw mode
+                term::release_raw_mode();
                 Output::Debugger(Condition::Always, Default::default()).start_new_line();
 
                 match debugger.next_action(&mut self.state) {
@@ -139,7 +419,7 @@ impl RunEnvironment {
                     }
                     Action::ExitProgram => {
                         dprintln!(Sometimes, Warning, "Exiting program.");
-                        return;
+                        return Ok(RunOutcome::Exited);
                     }
                 }
 
@@ -168,34 +448,77 @@ impl RunEnvironment {
                 break; // Halt was triggered
             }
 
-            // Debugger should have already checked these (if currently active)
-            match self.state.check_pc_bounds() {
-                Ordering::Less => {
-                    exception!("entered protected memory area < 0x{:04x}", self.state.orig)
-                }
-                Ordering::Greater => {
-                    exception!("entered protected memory area >= 0x{:04x}", USER_MEMORY_END)
-                }
-                _ => (),
+            let pc = self.state.pc;
+            let instr = self.state.mem[pc as usize];
+
+            // Debugger should have already checked this (if currently active)
+            let bound = match self.state.check_pc_bounds() {
+                Ordering::Less => Some((false, self.state.orig)),
+                Ordering::Greater => Some((true, USER_MEMORY_END)),
+                Ordering::Equal => None,
+            };
+            if let Some((above, bound)) = bound {
+                return Err(RuntimeError {
+                    fault: Fault::ProtectedMemory { above, bound },
+                    pc,
+                    instr,
+                });
             }
 
-            let instr = self.state.mem[self.state.pc as usize];
             // PC incremented before instruction is performed
             self.state.pc += 1;
-            self.state.execute(instr);
+            if let Err(fault) = self.state.execute(instr) {
+                let error = RuntimeError { fault, pc, instr };
+                let Some(debugger) = &mut self.debugger else {
+                    return Err(error);
+                };
+                // Instruction can be inspected, or skipped with `goto`
+                self.state.pc = pc;
+                debugger.fault(&error);
+                continue;
+            }
+
+            if let Some(interrupt) = self.state.interrupt() {
+                if let Some(debugger) = &mut self.debugger {
+                    debugger.interrupt_taken(interrupt, self.state.pc);
+                }
+            }
         }
 
         Output::Normal.start_new_line();
+        Ok(RunOutcome::Halted)
+    }
+}
+
+/// Split the words of an object file with several segments, after [`SEGMENTS_MAGIC`].
+fn read_segments(mut raw: &[u16]) -> Result<Vec<(u16, &[u16])>> {
+    let mut segments = Vec::new();
+    while let [orig, len, rest @ ..] = raw {
+        let len = *len as usize;
+        if rest.len() < len {
+            return Err(error::load_bad_segments(&format!(
+                "segment at x{orig:04X}, which is truncated"
+            )));
+        }
+        segments.push((*orig, &rest[..len]));
+        raw = &rest[len..];
     }
+    if !raw.is_empty() || segments.is_empty() {
+        return Err(error::load_bad_segments("the end of the segment table"));
+    }
+    Ok(segments)
 }
 
+/// Handler of an opcode, which is given the whole instruction.
+type Op = fn(&mut RunState, u16) -> Result<(), Fault>;
+
 impl RunState {
-    pub fn execute(&mut self, instr: u16) {
+    pub fn execute(&mut self, instr: u16) -> Result<(), Fault> {
         let opcode = (instr >> 12) as usize;
-        RunState::OP_TABLE[opcode](self, instr);
+        RunState::OP_TABLE[opcode](self, instr)
     }
 
-    const OP_TABLE: [fn(&mut RunState, u16); 16] = [
+    const OP_TABLE: [Op; 16] = [
         Self::br,    // 0x0
         Self::add,   // 0x1
         Self::ld,    // 0x2
@@ -238,6 +561,25 @@ impl RunState {
         unsafe { self.mem.get_unchecked_mut(addr as usize) }
     }
 
+    /// Read memory as an instruction does, which may read a device register.
+    fn read(&mut self, addr: u16) -> u16 {
+        if addr >= DEVICE_MEMORY {
+            if let Some(val) = self.devices.read(addr) {
+                return val;
+            }
+        }
+        self.mem(addr)
+    }
+
+    /// Write memory as an instruction does, which may write a device register.
+    fn write(&mut self, addr: u16, val: u16) {
+        if addr < DEVICE_MEMORY || !self.devices.write(addr, val) {
+            *self.mem_mut(addr) = val;
+        } else if !self.devices.clock_enabled() {
+            self.halt();
+        }
+    }
+
     #[inline]
     pub(super) fn orig(&self) -> u16 {
         self.orig
@@ -300,16 +642,77 @@ impl RunState {
         }
     }
 
-    fn stack(&mut self, instr: u16) {
-        if !features::stack() {
-            eprintln!(
-                "\
-                You called a reserved instruction.\n\
-                Note: Run with `-f stack` to enable stack extension feature.\n\
-                Halting...\
-                "
-            );
-            std::process::exit(1);
+    /// Extensions which the program may use.
+    pub(super) fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Processor status register, with the privilege mode, priority level and condition codes.
+    pub(super) fn psr(&self) -> u16 {
+        self.psr | self.flag as u16
+    }
+
+    fn set_psr(&mut self, psr: u16) {
+        self.psr = psr & (PSR_USER | PSR_PRIORITY);
+        self.flag = match psr & 0b111 {
+            0b100 => RunFlag::N,
+            0b010 => RunFlag::Z,
+            0b001 => RunFlag::P,
+            _ => RunFlag::Uninit,
+        };
+    }
+
+    /// Save the PSR and PC on the supervisor stack, switching to it from user mode, then jump to
+    /// `handler` in supervisor mode.
+    fn enter_supervisor(&mut self, handler: u16) {
+        let psr = self.psr();
+        if psr & PSR_USER != 0 {
+            self.saved_usp = self.reg(6);
+            *self.reg_mut(6) = self.saved_ssp;
+        }
+        self.psr &= !PSR_USER;
+        for val in [psr, self.pc] {
+            *self.reg_mut(6) = self.reg(6).wrapping_sub(1);
+            *self.mem_mut(self.reg(6)) = val;
+        }
+        self.pc = handler;
+    }
+
+    /// Take the device interrupt with the highest priority, after an instruction is executed, if
+    /// its priority is higher than the running program.
+    ///
+    /// Interrupts are only taken in OS mode, and are ignored without a handler.
+    fn interrupt(&mut self) -> Option<Interrupt> {
+        self.devices.tick();
+        if !self.os || self.pc == HALT_ADDRESS {
+            return None;
+        }
+        let priority = (self.psr & PSR_PRIORITY) >> 8;
+        let interrupt = self.devices.pending(priority)?;
+        let handler = self.mem(INTERRUPT_VECTOR_TABLE + interrupt.vector());
+        if handler == 0 {
+            return None;
+        }
+        self.enter_supervisor(handler);
+        self.psr = (self.psr & !PSR_PRIORITY) | interrupt.priority() << 8;
+        Some(interrupt)
+    }
+
+    /// Jump to the handler of an exception in OS mode.
+    ///
+    /// Exceptions without a handler are faults.
+    fn raise(&mut self, exception: Exception) -> Result<(), Fault> {
+        let handler = self.mem(INTERRUPT_VECTOR_TABLE + exception as u16);
+        if !self.os || handler == 0 {
+            return Err(exception.into());
+        }
+        self.enter_supervisor(handler);
+        Ok(())
+    }
+
+    fn stack(&mut self, instr: u16) -> Result<(), Fault> {
+        if !self.features.stack() {
+            return self.raise(Exception::IllegalOpcode);
         }
 
         // Bit to determine call/ret or push/pop
@@ -336,32 +739,33 @@ impl RunState {
                 *self.reg_mut(reg) = val;
             }
         }
+        Ok(())
     }
 
     fn push_val(&mut self, val: u16) {
         debug_assert!(
-            features::stack(),
+            self.features.stack(),
             "caller should have ensured stack feature is enabled",
         );
         // Decrement stack
         *self.reg_mut(7) -= 1;
         let sp = self.reg(7);
         // Save onto stack
-        *self.mem_mut(sp) = val;
+        self.write(sp, val);
     }
 
     fn pop_val(&mut self) -> u16 {
         debug_assert!(
-            features::stack(),
+            self.features.stack(),
             "caller should have ensured stack feature is enabled",
         );
         let sp = self.reg(7);
-        let val = self.mem(sp);
+        let val = self.read(sp);
         *self.reg_mut(7) += 1;
         val
     }
 
-    fn add(&mut self, instr: u16) {
+    fn add(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
         let sr = (instr >> 6) & 0b111;
 
@@ -377,9 +781,10 @@ impl RunState {
         let res = val1.wrapping_add(val2);
         self.set_flags(res);
         *self.reg_mut(dr) = res;
+        Ok(())
     }
 
-    fn and(&mut self, instr: u16) {
+    fn and(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
         let sr = (instr >> 6) & 0b111;
 
@@ -395,21 +800,24 @@ impl RunState {
         let res = val1 & val2;
         self.set_flags(res);
         *self.reg_mut(dr) = res;
+        Ok(())
     }
 
-    fn br(&mut self, instr: u16) {
+    fn br(&mut self, instr: u16) -> Result<(), Fault> {
         let flag = (instr >> 9) & 0b111;
         if self.flag as u16 & flag != 0 {
             self.pc = self.pc.wrapping_add(Self::s_ext(instr, 9))
         }
+        Ok(())
     }
 
-    fn jmp(&mut self, instr: u16) {
+    fn jmp(&mut self, instr: u16) -> Result<(), Fault> {
         let br = (instr >> 6) & 0b111;
-        self.pc = self.reg(br)
+        self.pc = self.reg(br);
+        Ok(())
     }
 
-    fn jsr(&mut self, instr: u16) {
+    fn jsr(&mut self, instr: u16) -> Result<(), Fault> {
         *self.reg_mut(7) = self.pc;
         if instr & 0x800 == 0 {
             // reg
@@ -419,78 +827,119 @@ impl RunState {
             // offs
             self.pc = self.pc.wrapping_add(Self::s_ext(instr, 11))
         }
+        Ok(())
     }
 
-    fn ld(&mut self, instr: u16) {
+    fn ld(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
-        let val = self.mem(self.pc.wrapping_add(Self::s_ext(instr, 9)));
+        let val = self.read(self.pc.wrapping_add(Self::s_ext(instr, 9)));
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn ldi(&mut self, instr: u16) {
+    fn ldi(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
-        let ptr = self.mem(self.pc.wrapping_add(Self::s_ext(instr, 9)));
-        let val = self.mem(ptr);
+        let ptr = self.read(self.pc.wrapping_add(Self::s_ext(instr, 9)));
+        let val = self.read(ptr);
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn ldr(&mut self, instr: u16) {
+    fn ldr(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
         let br = (instr >> 6) & 0b111;
         let ptr = self.reg(br);
-        let val = self.mem(ptr.wrapping_add(Self::s_ext(instr, 6)));
+        let val = self.read(ptr.wrapping_add(Self::s_ext(instr, 6)));
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn lea(&mut self, instr: u16) {
+    fn lea(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
         let val = self.pc.wrapping_add(Self::s_ext(instr, 9));
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn not(&mut self, instr: u16) {
+    fn not(&mut self, instr: u16) -> Result<(), Fault> {
         let dr = (instr >> 9) & 0b111;
         let sr = (instr >> 6) & 0b111;
         let val = !self.reg(sr);
         *self.reg_mut(dr) = val;
         self.set_flags(val);
+        Ok(())
     }
 
-    fn rti(&mut self, _instr: u16) {
-        todo!("Please open an issue and I'll get RTI implemented in a jiffy :)")
+    fn rti(&mut self, _instr: u16) -> Result<(), Fault> {
+        if !self.os || self.psr & PSR_USER != 0 {
+            return self.raise(Exception::PrivilegeMode);
+        }
+        let mut pop = || {
+            let val = self.mem(self.reg(6));
+            *self.reg_mut(6) = self.reg(6).wrapping_add(1);
+            val
+        };
+        let pc = pop();
+        let psr = pop();
+        self.pc = pc;
+        self.set_psr(psr);
+        if psr & PSR_USER != 0 {
+            self.saved_ssp = self.reg(6);
+            *self.reg_mut(6) = self.saved_usp;
+        }
+        Ok(())
     }
 
-    fn st(&mut self, instr: u16) {
+    fn st(&mut self, instr: u16) -> Result<(), Fault> {
         let sr = (instr >> 9) & 0b111;
         let val = *self.reg_mut(sr);
-        *self.mem_mut(self.pc.wrapping_add(Self::s_ext(instr, 9))) = val;
+        self.write(self.pc.wrapping_add(Self::s_ext(instr, 9)), val);
+        Ok(())
     }
 
-    fn sti(&mut self, instr: u16) {
+    fn sti(&mut self, instr: u16) -> Result<(), Fault> {
         let sr = (instr >> 9) & 0b111;
         let val = self.reg(sr);
-        let ptr = self.mem(self.pc.wrapping_add(Self::s_ext(instr, 9)));
-        *self.mem_mut(ptr) = val;
+        let ptr = self.read(self.pc.wrapping_add(Self::s_ext(instr, 9)));
+        self.write(ptr, val);
+        Ok(())
     }
 
-    fn str(&mut self, instr: u16) {
+    fn str(&mut self, instr: u16) -> Result<(), Fault> {
         let sr = (instr >> 9) & 0b111;
         let br = (instr >> 6) & 0b111;
         let ptr = self.reg(br);
         let val = self.reg(sr);
-        *self.mem_mut(ptr.wrapping_add(Self::s_ext(instr, 6))) = val;
+        self.write(ptr.wrapping_add(Self::s_ext(instr, 6)), val);
+        Ok(())
     }
 
-    fn trap(&mut self, instr: u16) {
+    fn trap(&mut self, instr: u16) -> Result<(), Fault> {
         let trap_vect = instr & 0xFF;
+        if self.os {
+            let routine = self.mem(TRAP_VECTOR_TABLE + trap_vect);
+            if routine == 0 {
+                return Err(Fault::MissingTrapRoutine(trap_vect as u8));
+            }
+            // A routine which is the trap itself, as in the default OS image, is handled natively
+            if routine != self.pc.wrapping_sub(1) {
+                self.enter_supervisor(routine);
+                return Ok(());
+            }
+        }
+        self.native_trap(trap_vect)
+    }
+
+    /// Perform a trap in Rust, rather than with a trap routine.
+    pub(super) fn native_trap(&mut self, trap_vect: u16) -> Result<(), Fault> {
         match trap_vect {
             // getc
             0x20 => {
-                *self.reg_mut(0) = read_char() as u16;
+                *self.reg_mut(0) = self.read_key()? as u16;
             }
             // out
             0x21 => {
@@ -513,7 +962,7 @@ impl RunState {
             }
             // in
             0x23 => {
-                let ch = read_char();
+                let ch = self.read_key()?;
                 *self.reg_mut(0) = ch as u16;
                 Output::Normal.print(ch);
                 stdout().flush().unwrap();
@@ -522,7 +971,8 @@ impl RunState {
             0x24 => {
                 'string: for addr in self.reg(0).. {
                     let chr_raw = self.mem(addr);
-                    for chr in [chr_raw >> 8, chr_raw & 0xFF] {
+                    // First character is in the low byte
+                    for chr in [chr_raw & 0xFF, chr_raw >> 8] {
                         let chr_ascii = chr as u8 as char;
                         if chr_ascii == '\0' {
                             break 'string;
@@ -533,10 +983,7 @@ impl RunState {
                 stdout().flush().unwrap();
             }
             // halt
-            0x25 => {
-                self.pc = HALT_ADDRESS;
-                println!("\n{:>12}", "Halted".cyan());
-            }
+            0x25 => self.halt(),
             // putn
             0x26 => {
                 let val = self.reg(0);
@@ -553,55 +1000,258 @@ impl RunState {
             // - `src/debugger/command/error.rs`: to suggest `eval` when command name is a mnemonic
 
             // unknown
-            _ => exception!(
-                "called a trap with an unknown vector of 0x{:02x}",
-                trap_vect
-            ),
+            _ => return Err(Fault::UnknownTrap(trap_vect as u8)),
         }
+        Ok(())
     }
 }
 
-// Read one byte from stdin or interactive terminal.
-fn read_char() -> char {
-    /// '�'
-    const REPLACEMENT_CHAR: char = '\u{FFFD}';
+impl RunState {
+    fn halt(&mut self) {
+        self.pc = HALT_ADDRESS;
+        term::release_raw_mode();
+        println!("\n{:>12}", "Halted".cyan());
+    }
+
+    /// Read a character typed on the keyboard, including one which was typed while polling
+    /// `KBSR`.
+    fn read_key(&mut self) -> Result<char, Fault> {
+        match self.devices.take_key() {
+            Some(key) => Ok(key),
+            None => read_char(),
+        }
+    }
+}
+
+/// Read one character from stdin or interactive terminal, if one is available without waiting.
+///
+/// Input which is not from an interactive terminal is always available, until it ends.
+pub(crate) fn poll_char() -> Option<char> {
+    let stdin = stdin();
+    let ch = if stdin.is_terminal() {
+        term::poll_char()?
+    } else {
+        read_byte_stdin(stdin)? as char
+    };
+    Some(if ch.is_ascii() { ch } else { REPLACEMENT_CHAR })
+}
 
+/// '�'
+const REPLACEMENT_CHAR: char = '\u{FFFD}';
+
+// Read one byte from stdin or interactive terminal.
+fn read_char() -> Result<char, Fault> {
     let stdin = stdin();
     let byte = if stdin.is_terminal() {
         term::read_byte()
     } else {
-        Some(read_byte_stdin(stdin))
+        Some(read_byte_stdin(stdin).ok_or(Fault::EndOfInput)?)
     };
     // Replace with marker character if non-ASCII
-    match byte {
+    Ok(match byte {
         Some(byte) if byte.is_ascii() => byte as char,
         _ => REPLACEMENT_CHAR,
-    }
+    })
 }
 
-/// Read one byte from stdin.
+/// Read one byte from stdin, or `None` at the end of input.
 ///
-/// Handles `UnexpectedEof` by printing error minimally and exiting.
 /// Panics on any other error.
-fn read_byte_stdin(mut stdin: io::Stdin) -> u8 {
+fn read_byte_stdin(mut stdin: io::Stdin) -> Option<u8> {
     let mut buf = [0; 1];
     if let Err(err) = stdin.read_exact(&mut buf) {
         if let io::ErrorKind::UnexpectedEof = err.kind() {
-            // This should NOT use `exception!`: it is an error with the
-            // emulator, not the CPU
-            eprintln!("unexpected end of input file stream.");
-            std::process::exit(1);
+            return None;
         } else {
             panic!("failed to read character from stdin: {:?}", err)
         }
     }
-    buf[0]
+    Some(buf[0])
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    /// Execute the instruction at the PC.
+    fn step(state: &mut RunState) {
+        let instr = state.mem(state.pc);
+        state.pc += 1;
+        state.execute(instr).unwrap();
+    }
+
+    #[test]
+    fn os_trap_and_rti() {
+        let mut env = RunEnvironment::new(Features::default(), true);
+        // add r6 r6 #-1, trap x40
+        env.load(&[0x3000, 0x1DBF, 0xF040]).unwrap();
+        let state = &mut env.state;
+        *state.mem_mut(0x0040) = 0x1000;
+        // rti
+        *state.mem_mut(0x1000) = 0x8000;
+
+        step(state);
+        step(state);
+        assert_eq!(state.pc, 0x1000);
+        assert_eq!(state.psr() & PSR_USER, 0);
+        // PSR and PC are pushed onto the supervisor stack
+        assert_eq!(state.reg(6), 0x2FFE);
+        assert_eq!(state.mem(0x2FFF), 0x8004);
+        assert_eq!(state.mem(0x2FFE), 0x3002);
+
+        step(state);
+        assert_eq!(state.pc, 0x3002);
+        assert_eq!(state.psr(), 0x8004);
+        assert_eq!(state.reg(6), 0xFFFF);
+        assert_eq!(state.saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn os_timer_interrupt() {
+        let mut env = RunEnvironment::new(Features::default(), true);
+        env.enable_timer();
+        // Interval of 1 instruction, with interrupts enabled
+        env.load(&[0x3000, 0x0000, 0x0000]).unwrap();
+        let state = &mut env.state;
+        state.write(0xFE0A, 1);
+        state.write(0xFE08, 0x4000);
+
+        step(state);
+        // No handler
+        assert_eq!(state.interrupt(), None);
+        *state.mem_mut(0x0181) = 0x1000;
+        state.set_psr(0x8500 | 0x0002);
+        step(state);
+        // Priority is not higher than the running program
+        assert_eq!(state.interrupt(), None);
+
+        state.set_psr(0x8000);
+        assert_eq!(state.interrupt(), Some(Interrupt::Timer));
+        assert_eq!(state.pc, 0x1000);
+        assert_eq!(state.psr(), 0x0500 | state.flag as u16);
+        assert_eq!(state.reg(6), 0x2FFE);
+        assert_eq!(state.mem(0x2FFE), 0x3002);
+        // Not taken again while it is being handled
+        assert_eq!(state.interrupt(), None);
+    }
+
+    #[test]
+    fn returns_faults() {
+        // rti
+        let mut env = RunEnvironment::from_raw(&[0x3000, 0x8000], Features::default()).unwrap();
+        assert_eq!(
+            env.run(),
+            Err(RuntimeError {
+                fault: Fault::PrivilegeMode,
+                pc: 0x3000,
+                instr: 0x8000,
+            })
+        );
+
+        // add r0 r0 #0; trap x30
+        let mut env =
+            RunEnvironment::from_raw(&[0x3000, 0x1020, 0xF030], Features::default()).unwrap();
+        let error = env.run().unwrap_err();
+        assert_eq!(error.fault, Fault::UnknownTrap(0x30));
+        assert_eq!(error.pc, 0x3001);
+
+        // Without a routine in OS mode
+        let mut env = RunEnvironment::new(Features::default(), true);
+        env.load(&[0x3000, 0xF030]).unwrap();
+        assert_eq!(
+            env.run().unwrap_err().fault,
+            Fault::MissingTrapRoutine(0x30)
+        );
+
+        // ld r0 #1; jmp r0; x2000
+        let mut env =
+            RunEnvironment::from_raw(&[0x3000, 0x2001, 0xC000, 0x2000], Features::default())
+                .unwrap();
+        let error = env.run().unwrap_err();
+        assert_eq!(
+            error.fault,
+            Fault::ProtectedMemory {
+                above: false,
+                bound: 0x3000
+            }
+        );
+        assert_eq!(error.pc, 0x2000);
+    }
+
+    #[test]
+    fn load_images() {
+        let mut env = RunEnvironment::new(Features::default(), false);
+        env.set_entry(0x3001);
+        env.load(&[0x4000, 0x1234]).unwrap();
+        env.load(&[0x3000, 0x0000, 0x0000]).unwrap();
+        // Continues into an earlier image, so no `HALT` is placed between them
+        env.load(&[0x3FFF, 0x5678]).unwrap();
+        assert_eq!(env.state.mem(0x3FFF), 0x5678);
+        assert_eq!(env.state.mem(0x4000), 0x1234);
+        assert_eq!(env.state.mem(0x4001), 0xF025);
+        assert_eq!(env.state.mem(0x3002), 0xF025);
+        assert_eq!(env.state.pc, 0x3001);
+
+        assert!(env.load(&[0x2FFF, 0x0000, 0x0000]).is_err());
+        // Unchanged
+        assert_eq!(env.state.mem(0x2FFF), 0x0000);
+    }
+
+    #[test]
+    fn os_default_trap_routines() {
+        let mut env = RunEnvironment::new(Features::default(), true);
+        // putn
+        env.load(&[0x3000, 0xF026]).unwrap();
+        let state = &mut env.state;
+        let routine = state.mem(0x0026);
+        assert_eq!(state.mem(routine), 0xF026);
+
+        step(state);
+        assert_eq!(state.pc, routine);
+        // Trap is performed natively, rather than through its vector again
+        step(state);
+        assert_eq!(state.pc, routine + 1);
+        step(state);
+        assert_eq!(state.pc, 0x3001);
+    }
+
+    #[test]
+    fn device_registers() {
+        let mut env = RunEnvironment::new(Features::default(), false);
+        // ldi r0 dsr; sti r1 mcr; halt; dsr; mcr
+        env.load(&[0x3000, 0xA002, 0xB202, 0xF025, 0xFE04, 0xFFFE])
+            .unwrap();
+        let state = &mut env.state;
+
+        step(state);
+        assert_eq!(state.reg(0), 0x8000);
+        // Not stored in memory
+        assert_eq!(state.mem(0xFE04), 0);
+        step(state);
+        // Clock was stopped before reaching `halt`
+        assert_eq!(state.pc, HALT_ADDRESS);
+    }
+
+    #[test]
+    fn os_exceptions() {
+        let mut env = RunEnvironment::new(Features::default(), true);
+        // rti, then a stack instruction without the stack feature
+        env.load(&[0x3000, 0x8000, 0xD000]).unwrap();
+        let state = &mut env.state;
+        *state.mem_mut(0x0100) = 0x1000;
+        *state.mem_mut(0x0101) = 0x2000;
+
+        step(state);
+        assert_eq!(state.pc, 0x1000);
+        assert_eq!(state.mem(0x2FFE), 0x3001);
+
+        state.pc = 0x3001;
+        step(state);
+        assert_eq!(state.pc, 0x2000);
+        // Already on the supervisor stack
+        assert_eq!(state.reg(6), 0x2FFC);
+    }
+
     #[test]
     fn s_ext() {
         fn expect(input: u16, bits: u32, expected: u16) {
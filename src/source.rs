@@ -0,0 +1,311 @@
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use fxhash::FxHashMap;
+use miette::{
+    IntoDiagnostic, MietteError, MietteSpanContents, Result, SourceCode, SourceSpan, SpanContents,
+};
+
+use crate::symbol::Span;
+
+/// Source code of a program, which may be made of several files.
+///
+/// Files included with `.include` are appended to the text of the main file, so the offset of a
+/// [`crate::symbol::Span`] identifies the file which it belongs to. Diagnostics show the name of
+/// that file, and line numbers within it.
+#[derive(Clone, Debug)]
+pub struct Source {
+    src: Arc<str>,
+    files: Arc<[SourceFile]>,
+    includes: Arc<[Include]>,
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+    /// Path to the file, or `None` if the source was not read from a file
+    name: Option<String>,
+    /// Range of the file within [`Source`]
+    range: Range<usize>,
+}
+
+/// An `.include` directive, resolved while loading files.
+#[derive(Debug)]
+struct Include {
+    /// Offset of the directive
+    offs: usize,
+    target: Result<usize, IncludeError>,
+}
+
+/// Reason that an included file could not be loaded.
+#[derive(Clone, Debug)]
+pub enum IncludeError {
+    /// File could not be read, with the reason why
+    Read(String),
+    /// File includes itself, directly or indirectly
+    Cycle,
+}
+
+impl Source {
+    /// Source with a single unnamed file, which cannot include other files.
+    pub fn new(src: impl Into<Arc<str>>) -> Self {
+        let src = src.into();
+        Source {
+            files: Arc::new([SourceFile {
+                name: None,
+                range: 0..src.len(),
+            }]),
+            src,
+            includes: Arc::new([]),
+        }
+    }
+
+    /// Read a file, and every file which it includes.
+    ///
+    /// Only failing to read the main file is an error. Problems with included files are reported
+    /// when the `.include` directive is preprocessed.
+    pub fn load(path: &Path) -> Result<Source> {
+        let contents = fs::read_to_string(path).into_diagnostic()?;
+        let mut loader = Loader::default();
+        loader.add_file(path, &contents, &mut Vec::new());
+
+        Ok(Source {
+            src: loader.text.into(),
+            files: loader.files.into(),
+            includes: loader.includes.into(),
+        })
+    }
+
+    /// Text of every file.
+    pub fn text(&self) -> &str {
+        &self.src
+    }
+
+    /// Range of the main file, which is always first.
+    pub fn main_range(&self) -> Range<usize> {
+        self.files[0].range.clone()
+    }
+
+    /// Get range of the file which contains an offset.
+    pub fn file_range(&self, offs: usize) -> Range<usize> {
+        self.file_at(offs).range.clone()
+    }
+
+    /// Get range of the file included by the `.include` directive at `offs`.
+    ///
+    /// Returns `None` if no directive was resolved at that offset.
+    pub fn include(&self, offs: usize) -> Option<Result<Range<usize>, IncludeError>> {
+        let include = self.includes.iter().find(|include| include.offs == offs)?;
+        Some(match &include.target {
+            Ok(file) => Ok(self.files[*file].range.clone()),
+            Err(err) => Err(err.clone()),
+        })
+    }
+
+    /// Name of the file containing a span, with the line and column where it starts.
+    ///
+    /// Lines and columns count from 1, like diagnostics.
+    pub fn location(&self, span: Span) -> String {
+        let (line, column) = self
+            .read_span(&span.into(), 0, 0)
+            .map(|contents| (contents.line() + 1, contents.column() + 1))
+            .unwrap_or((0, 0));
+        let name = self
+            .file_at(span.offs())
+            .name
+            .as_deref()
+            .unwrap_or("<source>");
+        format!("{name}:{line}:{column}")
+    }
+
+    /// Name of the file which contains an offset, or `None` if it was not read from a file.
+    pub fn file_name(&self, offs: usize) -> Option<&str> {
+        self.file_at(offs).name.as_deref()
+    }
+
+    /// Directory of the file which contains an offset, which paths in that file are relative to.
+    pub fn dir_at(&self, offs: usize) -> &Path {
+        self.file_at(offs)
+            .name
+            .as_deref()
+            .and_then(|name| Path::new(name).parent())
+            .unwrap_or(Path::new(""))
+    }
+
+    fn file_at(&self, offs: usize) -> &SourceFile {
+        self.files
+            .iter()
+            .find(|file| file.range.contains(&offs))
+            // Offsets at the very end of a file
+            .or_else(|| self.files.iter().rev().find(|file| file.range.end == offs))
+            .unwrap_or(&self.files[0])
+    }
+}
+
+impl SourceCode for Source {
+    fn read_span<'a>(
+        &'a self,
+        span: &SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> Result<Box<dyn SpanContents<'a> + 'a>, MietteError> {
+        let file = self.file_at(span.offset());
+        let text = &self.src[file.range.clone()];
+
+        // Read span relative to the start of the file, for correct line numbers
+        let local = SourceSpan::new((span.offset() - file.range.start).into(), span.len());
+        let contents = text.read_span(&local, context_lines_before, context_lines_after)?;
+        let global = SourceSpan::new(
+            (contents.span().offset() + file.range.start).into(),
+            contents.span().len(),
+        );
+
+        let (data, line, column, line_count) = (
+            contents.data(),
+            contents.line(),
+            contents.column(),
+            contents.line_count(),
+        );
+        Ok(Box::new(match &file.name {
+            Some(name) => {
+                MietteSpanContents::new_named(name.clone(), data, global, line, column, line_count)
+            }
+            None => MietteSpanContents::new(data, global, line, column, line_count),
+        }))
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    text: String,
+    files: Vec<SourceFile>,
+    includes: Vec<Include>,
+    /// Files which have already been loaded, by canonical path
+    loaded: FxHashMap<PathBuf, usize>,
+}
+
+impl Loader {
+    /// Append file to text, followed by any files which it includes.
+    ///
+    /// `stack` contains the canonical path of each file which is currently being loaded.
+    fn add_file(&mut self, path: &Path, contents: &str, stack: &mut Vec<PathBuf>) -> usize {
+        let start = self.text.len();
+        self.text.push_str(contents);
+        let index = self.files.len();
+        self.files.push(SourceFile {
+            name: Some(path.display().to_string()),
+            range: start..self.text.len(),
+        });
+
+        let canonical = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        self.loaded.insert(canonical.clone(), index);
+        stack.push(canonical);
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for (offs, include) in find_includes(contents) {
+            let target = self.add_include(&dir.join(include), stack);
+            self.includes.push(Include {
+                offs: start + offs,
+                target,
+            });
+        }
+
+        stack.pop();
+        index
+    }
+
+    fn add_include(
+        &mut self,
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<usize, IncludeError> {
+        let canonical =
+            fs::canonicalize(path).map_err(|err| IncludeError::Read(err.to_string()))?;
+        if stack.contains(&canonical) {
+            return Err(IncludeError::Cycle);
+        }
+        if let Some(index) = self.loaded.get(&canonical) {
+            return Ok(*index);
+        }
+        let contents =
+            fs::read_to_string(path).map_err(|err| IncludeError::Read(err.to_string()))?;
+        Ok(self.add_file(path, &contents, stack))
+    }
+}
+
+/// Find `.include "path"` directives at the start of a line.
+///
+/// Returns the offset of each directive, with the path it includes. Malformed directives are
+/// skipped, and reported by the preprocessor instead.
+fn find_includes(contents: &str) -> Vec<(usize, &str)> {
+    const DIRECTIVE: &str = ".include";
+    let mut includes = Vec::new();
+    let mut line_start = 0;
+    for line in contents.split('\n') {
+        let trimmed = line.trim_start();
+        let offs = line_start + line.len() - trimmed.len();
+        line_start += line.len() + 1;
+
+        let Some(rest) = trimmed
+            .get(..DIRECTIVE.len())
+            .filter(|dir| dir.eq_ignore_ascii_case(DIRECTIVE))
+            .map(|_| &trimmed[DIRECTIVE.len()..])
+        else {
+            continue;
+        };
+        let Some(rest) = rest.trim_start().strip_prefix('"') else {
+            continue;
+        };
+        if let Some(end) = rest.find('"') {
+            includes.push((offs, &rest[..end]));
+        }
+    }
+    includes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::SrcOffset;
+
+    #[test]
+    fn finds_includes() {
+        let src = "add r0 r0 r0\n  .INCLUDE \"lib/a.asm\" ; comment\n; .include \"no\"\n.include\n";
+        assert_eq!(find_includes(src), vec![(15, "lib/a.asm")]);
+    }
+
+    #[test]
+    fn reports_file_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main.asm");
+        fs::create_dir(dir.path().join("lib")).unwrap();
+        fs::write(&main, "halt\n.include \"lib/a.asm\"\n").unwrap();
+        fs::write(
+            dir.path().join("lib/a.asm"),
+            "; a\nputs\n.include \"../main.asm\"\n",
+        )
+        .unwrap();
+
+        let source = Source::load(&main).unwrap();
+        let included = source.include(5).unwrap().unwrap();
+        assert_eq!(
+            &source.text()[included.clone()],
+            "; a\nputs\n.include \"../main.asm\"\n"
+        );
+        assert!(matches!(
+            source.include(included.start + 9),
+            Some(Err(IncludeError::Cycle))
+        ));
+
+        // Line numbers are relative to the included file
+        let puts = source.text().find("puts").unwrap();
+        let contents = source.read_span(&(puts, 4).into(), 0, 0).unwrap();
+        assert_eq!(contents.line(), 1);
+        assert!(contents.name().unwrap().ends_with("a.asm"));
+        assert_eq!(contents.span().offset(), puts);
+        assert!(source
+            .location(Span::new(SrcOffset(puts), 4))
+            .ends_with("a.asm:2:1"));
+    }
+}
@@ -1,90 +1,162 @@
-use std::{cell::RefCell, ops::Range, str::FromStr};
+use std::{ops::Range, str::FromStr};
 
 use fxhash::FxHashMap;
 use miette::{miette, Result, SourceSpan};
 
-thread_local! {
-    pub static SYMBOL_TABLE: RefCell<FxHashMap<String, u16>> = RefCell::new(FxHashMap::default());
-}
+use crate::features::Features;
 
-pub fn reset_state() {
-    with_symbol_table(|sym| sym.clear());
+/// Every name defined while assembling a program.
+///
+/// Each program has its own table, so that several programs can be assembled at once.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: FxHashMap<String, Symbol>,
+    /// Each enabled feature is predefined as a constant
+    features: Features,
 }
 
-/// Access to symbol table via closure
-pub fn with_symbol_table<R, F>(f: F) -> R
-where
-    F: FnOnce(&mut FxHashMap<String, u16>) -> R,
-{
-    SYMBOL_TABLE.with_borrow_mut(f)
+/// Value of a name in the symbol table.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symbol {
+    /// Address of a prefix label
+    Label(u16),
+    /// Named constant, defined with `.equ` or `.set`
+    Const {
+        value: i32,
+        /// Constants defined with `.set` may be defined again
+        redefinable: bool,
+    },
+    /// Label in another module, declared with `.extern`
+    Extern,
 }
 
-/// This is not allowed to be cloned to avoid double frees.
-pub struct StaticSource {
-    src: *mut String,
-}
+impl SymbolTable {
+    pub fn new(features: Features) -> Self {
+        SymbolTable {
+            symbols: FxHashMap::default(),
+            features,
+        }
+    }
 
-impl StaticSource {
-    pub fn new(src: String) -> Self {
-        let raw_ptr = Box::into_raw(Box::new(src));
-        Self { src: raw_ptr }
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
     }
 
-    pub fn src(&self) -> &'static str {
-        unsafe { &*self.src }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+        self.symbols
+            .iter()
+            .map(|(name, symbol)| (name.as_str(), symbol))
     }
 
-    pub fn reclaim(&mut self) {
-        unsafe { drop(Box::from_raw(self.src)) }
+    /// Address of a prefix label, if it has been defined.
+    pub fn label(&self, name: &str) -> Option<u16> {
+        match self.symbols.get(name) {
+            Some(Symbol::Label(addr)) => Some(*addr),
+            _ => None,
+        }
     }
-}
 
-/// Line number of referenced label
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub enum Label {
-    Ref(u16),
-    Unfilled(String),
-}
+    /// Name of a label at an address, if there is one.
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.iter()
+            .find(|(_, symbol)| **symbol == Symbol::Label(addr))
+            .map(|(name, _)| name)
+    }
 
-impl Label {
     /// Called on prefix labels. Errors on duplicates.
-    pub fn insert(label: &str, line: u16) -> Result<()> {
-        with_symbol_table(|sym| {
-            // Some is returned if the label already exists
-            if sym.insert(label.to_string(), line).is_some() {
-                Err(miette!("Label exists"))
-            } else {
+    pub fn insert_label(&mut self, label: &str, addr: u16) -> Result<()> {
+        // Labels may not share a name with a constant
+        if self.symbols.contains_key(label) {
+            Err(miette!("Label exists"))
+        } else {
+            self.symbols.insert(label.to_string(), Symbol::Label(addr));
+            Ok(())
+        }
+    }
+
+    /// Address of a prefix label, which may be changed when statements are moved.
+    pub(crate) fn label_mut(&mut self, name: &str) -> Option<&mut u16> {
+        match self.symbols.get_mut(name) {
+            Some(Symbol::Label(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Used on non-prefix labels to give them a discrete address reference
+    pub fn try_fill(&self, label: &str) -> Label {
+        // Fill with existing label value
+        match self.label(label) {
+            Some(addr) => Label::Ref(addr),
+            None => Label::Unfilled(label.to_string()),
+        }
+    }
+
+    /// Define a named constant. Errors if the name is taken, unless both definitions use `.set`.
+    pub fn define_const(&mut self, name: &str, value: i32, redefinable: bool) -> Result<()> {
+        let taken = match self.symbols.get(name) {
+            None => false,
+            Some(Symbol::Const {
+                redefinable: prev, ..
+            }) => !(redefinable && *prev),
+            Some(Symbol::Label(_) | Symbol::Extern) => true,
+        };
+        if taken {
+            Err(miette!("Symbol exists"))
+        } else {
+            self.symbols
+                .insert(name.to_string(), Symbol::Const { value, redefinable });
+            Ok(())
+        }
+    }
+
+    /// Define a constant before assembling, such as with `-D`, replacing any previous definition.
+    pub fn predefine(&mut self, name: &str, value: i32) {
+        self.symbols.insert(
+            name.to_string(),
+            Symbol::Const {
+                value,
+                redefinable: false,
+            },
+        );
+    }
+
+    /// Declare a label which is defined in another module. Errors if the name is taken by a
+    /// constant or a label in this module.
+    pub fn define_extern(&mut self, name: &str) -> Result<()> {
+        match self.symbols.get(name) {
+            None | Some(Symbol::Extern) => {
+                self.symbols.insert(name.to_string(), Symbol::Extern);
                 Ok(())
             }
-        })
+            Some(_) => Err(miette!("Symbol exists")),
+        }
     }
 
-    /// Used on non-prefix labels to give them a discrete line number reference
-    pub fn try_fill(label: &str) -> Self {
-        with_symbol_table(|sym| {
-            // Fill with existing label value
-            if let Some(val) = sym.get(label) {
-                Label::Ref(*val)
-            } else {
-                Label::Unfilled(label.to_string())
-            }
-        })
+    /// Whether a name was declared with `.extern`.
+    pub fn is_extern(&self, name: &str) -> bool {
+        self.symbols.get(name) == Some(&Symbol::Extern)
     }
 
-    /// Used when all prefix labels are guaranteed to exist in table
-    pub fn filled(self) -> Result<Self> {
-        with_symbol_table(|sym| match &self {
-            Self::Unfilled(label) => {
-                if let Some(line) = sym.get(label.as_str()) {
-                    Ok(Self::Ref(*line))
-                } else {
-                    Err(miette!("Label not found"))
-                }
-            }
-            Self::Ref(_) => Ok(self),
-        })
+    /// Get value of a named constant, if it has been defined.
+    ///
+    /// Each enabled feature is predefined as a constant, such as `FEATURE_STACK` for `stack`.
+    pub fn get_const(&self, name: &str) -> Option<i32> {
+        match self.symbols.get(name) {
+            Some(Symbol::Const { value, .. }) => Some(*value),
+            Some(_) => None,
+            None => self.features.constant(name),
+        }
     }
+}
 
+/// Address of referenced label
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Label {
+    Ref(u16),
+    Unfilled(String),
+}
+
+impl Label {
     /// For comparison in tests
     pub fn empty(val: &str) -> Self {
         Label::Unfilled(val.to_string())
@@ -109,21 +181,96 @@ impl Label {
 pub struct Span {
     offs: SrcOffset,
     len: usize,
+    /// Set for tokens which were produced by a macro expansion
+    expansion: Option<Expansion>,
+}
+
+/// Origin of a token which was produced by a macro expansion.
+///
+/// The span of the token itself points into the macro body, or to the argument at the call site.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Expansion {
+    /// Offset of the outermost macro call, as a [`Span`] cannot contain itself
+    call_offs: SrcOffset,
+    call_len: usize,
+    /// Unique for each expanded line, so that statements can be told apart
+    line: u32,
+    /// Unique for each expansion, set for labels which are local to the macro
+    local: Option<u16>,
+}
+
+impl Expansion {
+    pub fn new(call: Span, line: u32, local: Option<u16>) -> Self {
+        Expansion {
+            call_offs: call.offs,
+            call_len: call.len,
+            line,
+            local,
+        }
+    }
 }
 
 impl Span {
     pub fn new(offs: SrcOffset, len: usize) -> Self {
-        Span { offs, len }
+        Span {
+            offs,
+            len,
+            expansion: None,
+        }
     }
 
     /// Non-source span
     pub fn dummy() -> Self {
+        Span::new(SrcOffset(0), 0)
+    }
+
+    /// Copy of this span, marked as being produced by a macro expansion.
+    pub fn expanded(&self, expansion: Expansion) -> Span {
         Span {
-            offs: SrcOffset(0),
-            len: 0,
+            expansion: Some(expansion),
+            ..*self
         }
     }
 
+    pub fn expansion(&self) -> Option<Expansion> {
+        self.expansion
+    }
+
+    /// Span of the outermost macro call which produced this span, if any.
+    pub fn call_site(&self) -> Option<Span> {
+        self.expansion
+            .map(|expansion| Span::new(expansion.call_offs, expansion.call_len))
+    }
+
+    /// Identifier of the macro expansion which a local label belongs to.
+    pub fn local_label(&self) -> Option<u16> {
+        self.expansion.and_then(|expansion| expansion.local)
+    }
+
+    /// Whether two spans belong to the same line of the expanded source.
+    ///
+    /// Lines produced by a macro expansion are distinct from each other, and from any line in the
+    /// source file.
+    pub fn same_line(&self, other: Span, src: &str) -> bool {
+        match (self.expansion, other.expansion) {
+            (Some(a), Some(b)) => a.line == b.line,
+            (None, None) => {
+                let (first, second) = if self.offs <= other.offs {
+                    (self, other)
+                } else {
+                    (&other, *self)
+                };
+                second.offs() <= first.end() || !src[first.end()..second.offs()].contains('\n')
+            }
+            _ => false,
+        }
+    }
+
+    /// Copy of this span with a different length.
+    pub fn with_len(&self, len: usize) -> Span {
+        Span { len, ..*self }
+    }
+
     /// Returns a range that can be used to index the source
     pub fn as_range(&self) -> Range<usize> {
         self.offs()..self.end()
@@ -150,7 +297,11 @@ impl Span {
         let offs = self.offs().min(other.offs());
         let end = self.end().max(other.end());
         let len = end - offs; // Underflow should be impossible
-        Span::new(SrcOffset(offs), len)
+        Span {
+            offs: SrcOffset(offs),
+            len,
+            expansion: self.expansion.or(other.expansion),
+        }
     }
 }
 
@@ -163,10 +314,7 @@ impl From<Span> for SourceSpan {
 
 impl From<Range<usize>> for Span {
     fn from(value: Range<usize>) -> Self {
-        Span {
-            offs: SrcOffset(value.start),
-            len: value.end - value.start,
-        }
+        Span::new(SrcOffset(value.start), value.end - value.start)
     }
 }
 
@@ -264,6 +412,13 @@ pub enum InstrKind {
     Push,
     Call,
     Rets,
+    Mov,
+    Clr,
+    Inc,
+    Dec,
+    Neg,
+    Sub,
+    Ldi16,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -287,6 +442,22 @@ pub enum DirKind {
     Blkw,
     Fill,
     Break,
+    Macro,
+    Endm,
+    Include,
+    Equ,
+    Set,
+    Global,
+    Extern,
+    If,
+    Ifdef,
+    Ifndef,
+    Else,
+    Endif,
+    Pool,
+    Word,
+    Stringp,
+    Incbin,
 }
 
 /// Used to refer to offsets from the start of a source file.
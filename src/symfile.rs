@@ -0,0 +1,97 @@
+//! Symbol files, which are written beside a `.lc3` binary by `lace compile`.
+//!
+//! The layout matches `lc3as`, so that the files can be read by PennSim and lc3tools, and their
+//! files can be read by `lace run` and `lace debug`:
+//!
+//! ```text
+//! // Symbol table
+//! // Scope level 0:
+//! //    Symbol Name       Page Address
+//! //    ----------------  ------------
+//! //    main              3000
+//! ```
+//!
+//! The rows below the header start with `//` followed by a tab, which is shown as spaces here.
+
+use std::fmt::Write;
+
+use miette::Result;
+
+use crate::air::Air;
+use crate::error;
+use crate::symbol::SymbolTable;
+
+/// Write the absolute address of every label in a program.
+pub fn write_symbols(air: &Air) -> String {
+    let mut out = String::from("// Symbol table\n// Scope level 0:\n");
+    writeln!(out, "//\t{:<16}  Page Address", "Symbol Name").unwrap();
+    writeln!(out, "//\t{:-<16}  {:-<12}", "", "").unwrap();
+    for (name, addr) in air.label_addresses() {
+        writeln!(out, "//\t{name:<16}  {addr:04X}").unwrap();
+    }
+    out
+}
+
+/// Read every label in a symbol file into a new symbol table.
+///
+/// Lines before the row of dashes are a header, and are ignored.
+pub fn read_symbols(text: &str) -> Result<SymbolTable> {
+    let mut symbols = SymbolTable::default();
+    let mut in_table = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let entry = line
+            .strip_prefix("//")
+            .ok_or_else(|| error::sym_bad_file(i + 1))?;
+        let fields: Vec<_> = entry.split_whitespace().collect();
+        if !in_table {
+            in_table = !fields.is_empty() && fields.iter().all(|f| f.bytes().all(|b| b == b'-'));
+            continue;
+        }
+        let [name, addr] = fields[..] else {
+            return Err(error::sym_bad_file(i + 1));
+        };
+        let addr = u16::from_str_radix(addr, 16).map_err(|_| error::sym_bad_file(i + 1))?;
+        symbols
+            .insert_label(name, addr)
+            .map_err(|_| error::sym_duplicate(name, i + 1))?;
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AsmParser;
+
+    #[test]
+    fn symbols_round_trip() {
+        let mut air = AsmParser::new("main and r0 r0 #0\nbr main\nend halt\n").parse();
+        air.backpatch();
+        let text = write_symbols(&air);
+        assert_eq!(
+            text,
+            "// Symbol table\n\
+             // Scope level 0:\n\
+             //\tSymbol Name       Page Address\n\
+             //\t----------------  ------------\n\
+             //\tmain              3000\n\
+             //\tend               3002\n"
+        );
+
+        let symbols = read_symbols(&text).unwrap();
+        assert_eq!(symbols.label("main"), Some(0x3000));
+        assert_eq!(symbols.label("end"), Some(0x3002));
+    }
+
+    #[test]
+    fn symbols_malformed() {
+        assert!(read_symbols("//\t----  ----\n//\tmain\n").is_err());
+        assert!(read_symbols("//\t----  ----\n//\tmain  3g00\n").is_err());
+        assert!(read_symbols("main 3000\n").is_err());
+        assert!(read_symbols("//\t----  ----\n//\ta  3000\n//\ta  3001\n").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Lossless syntax tree, used by the formatter.
+//!
+//! Unlike the token stream which is given to the parser, whitespace and comments are kept, so that
+//! the original source can be reproduced exactly.
+
+use std::fmt;
+
+use crate::error::Diagnostics;
+use crate::features::Features;
+use crate::lexer::{cursor::Cursor, Token, TokenKind};
+use crate::symbol::{DirKind, Span, SrcOffset};
+
+/// Every line of a source file, split into tokens.
+pub struct SyntaxTree<'a> {
+    src: &'a str,
+    pub lines: Vec<SyntaxLine>,
+    /// Source following the `.end` directive, which is never lexed.
+    trailing: Option<Span>,
+}
+
+/// A single line of source, not including the newline character.
+#[derive(Debug, Default)]
+pub struct SyntaxLine {
+    /// Every token on the line, including whitespace and comments.
+    pub tokens: Vec<Token>,
+}
+
+impl<'a> SyntaxTree<'a> {
+    /// Lex entire source, keeping all tokens.
+    ///
+    /// Fails if any token could not be lexed, as the source could not be reproduced.
+    pub fn parse(src: &'a str, features: Features) -> Result<Self, Diagnostics> {
+        let mut cur = Cursor::new(src).with_features(features);
+        let mut diagnostics = Diagnostics::new();
+        let mut lines = Vec::new();
+        let mut line = SyntaxLine::default();
+        let mut trailing = None;
+
+        loop {
+            let tok = match cur.advance_token() {
+                Ok(tok) => tok,
+                Err(err) => {
+                    diagnostics.push(err);
+                    cur.skip_line();
+                    continue;
+                }
+            };
+            match tok.kind {
+                TokenKind::Eof => break,
+                // Split at newlines, so that each line owns its whitespace
+                TokenKind::Whitespace => {
+                    let mut offs = tok.span.offs();
+                    let text = &src[tok.span.as_range()];
+                    for (i, part) in text.split('\n').enumerate() {
+                        if i > 0 {
+                            lines.push(std::mem::take(&mut line));
+                            // Newline character
+                            offs += 1;
+                        }
+                        if !part.is_empty() {
+                            let span = Span::new(SrcOffset(offs), part.len());
+                            line.tokens.push(Token::new(TokenKind::Whitespace, span));
+                        }
+                        offs += part.len();
+                    }
+                }
+                TokenKind::Dir(DirKind::End) => {
+                    line.tokens.push(tok);
+                    trailing = Some(Span::from(tok.span.end()..src.len()));
+                    break;
+                }
+                _ => line.tokens.push(tok),
+            }
+        }
+        lines.push(line);
+
+        if diagnostics.has_errors() {
+            return Err(diagnostics);
+        }
+        Ok(Self {
+            src,
+            lines,
+            trailing,
+        })
+    }
+
+    /// Get source text of a token.
+    pub fn text(&self, tok: &Token) -> &'a str {
+        &self.src[tok.span.as_range()]
+    }
+
+    /// Get source text between the end of one token and the start of a later token.
+    pub fn between(&self, prev: &Token, next: &Token) -> &'a str {
+        &self.src[prev.span.end()..next.span.offs()]
+    }
+
+    /// Get source following the `.end` directive, if it exists.
+    pub fn trailing(&self) -> Option<&'a str> {
+        self.trailing.map(|span| &self.src[span.as_range()])
+    }
+}
+
+impl SyntaxLine {
+    /// Tokens which are not whitespace or comments.
+    pub fn significant(&self) -> impl Iterator<Item = &Token> {
+        self.tokens
+            .iter()
+            .filter(|tok| !matches!(tok.kind, TokenKind::Whitespace | TokenKind::Comment))
+    }
+
+    /// Comment at the end of the line, if any.
+    pub fn comment(&self) -> Option<&Token> {
+        self.tokens
+            .iter()
+            .find(|tok| matches!(tok.kind, TokenKind::Comment))
+    }
+
+    /// Whether the line starts with whitespace.
+    pub fn is_indented(&self) -> bool {
+        self.tokens
+            .first()
+            .is_some_and(|tok| tok.kind == TokenKind::Whitespace)
+    }
+}
+
+/// Reproduces the original source.
+impl fmt::Display for SyntaxTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            for tok in &line.tokens {
+                f.write_str(self.text(tok))?;
+            }
+        }
+        if let Some(trailing) = self.trailing() {
+            f.write_str(trailing)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossless() {
+        let src = "\
+; header comment
+main:   LEA R0, hw  ; load
+\tPUTS
+
+  halt ;;
+hw .stringz \"a;b\\\"\"\r
+.end trailing ; text
+not lexed @";
+        let tree = SyntaxTree::parse(src, Features::default()).unwrap();
+        assert_eq!(tree.to_string(), src);
+        assert_eq!(tree.lines.len(), 7);
+        assert_eq!(tree.lines[1].significant().count(), 4);
+        assert!(tree.lines[2].is_indented());
+        assert!(tree.lines[3].tokens.is_empty());
+        assert_eq!(
+            tree.text(tree.lines[4].comment().unwrap()),
+            ";;",
+            "comment should not include newline"
+        );
+    }
+
+    #[test]
+    fn lex_error() {
+        assert!(SyntaxTree::parse("add r0 r0 @\nlea r0 `", Features::default()).is_err());
+    }
+}
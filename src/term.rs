@@ -1,4 +1,5 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 
 use crossterm::{
     event::{self, Event, KeyEvent},
@@ -64,7 +65,7 @@ pub fn read_key() -> Key {
 /// Inputs are read as [`KeyEvent`]s, which read multi-byte characters as `char`. Therefore,
 /// any such characters are encoded in 1-4 bytes as UTF-8, and buffered for the next call.
 ///
-/// Caller must ensure terminal is NOT in raw mode.
+/// Caller must ensure terminal is NOT in raw mode, unless it is held.
 pub fn read_byte() -> Option<u8> {
     // Counter > 0: bytes are still 'buffered'
     if with_counter(|counter| {
@@ -111,13 +112,67 @@ where
     BUFFERED_BYTE_COUNT.with(|counter| func(&mut counter.borrow_mut()))
 }
 
+thread_local! {
+    /// Whether raw mode was enabled by `poll_char`, and is kept until `release_raw_mode`.
+    static RAW_MODE_HELD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether raw mode is held between calls to [`poll_char`], so output must return the cursor to
+/// the start of each new line itself.
+pub fn is_raw_mode_held() -> bool {
+    RAW_MODE_HELD.get()
+}
+
+/// Restore the terminal to its normal state, if raw mode is held by [`poll_char`].
+pub fn release_raw_mode() {
+    if RAW_MODE_HELD.replace(false) {
+        disable_raw_mode();
+    }
+}
+
+/// Releases raw mode which is held by [`poll_char`] when dropped, such as when a program stops
+/// running for any reason.
+pub struct RawModeGuard;
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        release_raw_mode();
+    }
+}
+
+/// Read single character from interactive terminal, if a key has been pressed.
+///
+/// Raw mode is enabled on the first call, and held until [`release_raw_mode`], so that keys are
+/// not echoed or lost between polls.
+///
+/// Caller must ensure terminal is NOT in raw mode, unless it is held.
+pub fn poll_char() -> Option<char> {
+    if !RAW_MODE_HELD.replace(true) {
+        enable_raw_mode();
+    }
+    while event::poll(Duration::ZERO).expect("failed to poll terminal events") {
+        match event::read()
+            .expect("failed to read terminal event")
+            .try_into()
+        {
+            Ok(Key::Char(key)) => return Some(key),
+            Ok(Key::Enter) => return Some('\n'),
+            _ => continue,
+        }
+    }
+    None
+}
+
 /// Read single character from interactive terminal.
 ///
 /// Loops until [`Key::Char`] or [`Key::Enter`] are read.
 ///
-/// Caller must ensure terminal is NOT in raw mode.
+/// Caller must ensure terminal is NOT in raw mode, unless it is held.
 fn read_char() -> char {
-    enable_raw_mode();
+    let held = is_raw_mode_held();
+    if !held {
+        enable_raw_mode();
+    }
     let ch = loop {
         match read_key() {
             Key::Char(ch) => break ch,
@@ -125,7 +180,9 @@ fn read_char() -> char {
             _ => continue,
         };
     };
-    disable_raw_mode();
+    if !held {
+        disable_raw_mode();
+    }
     ch
 }
 
@@ -30,6 +30,23 @@ fn debugs_hello_world() {
         ));
 }
 
+#[test]
+fn catches_faults() {
+    // `rti` without `--os` faults, and is skipped
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg("tests/files/os.asm")
+        .arg("--minimal")
+        .arg("--command")
+        .arg("continue; goto x3003; continue");
+
+    cmd.assert()
+        .success()
+        .stdout(contains("Returned from exception"))
+        .stdout(contains("Halted"))
+        .stderr(contains("Fault\n"));
+}
+
 #[test]
 fn prints_help_message() {
     let mut cmd = Command::cargo_bin("lace").unwrap();
@@ -84,3 +101,18 @@ fn check_every_command() {
             include_str!("expected/check_every_command").replace("\r\n", "\n"),
         ));
 }
+
+#[test]
+fn resolves_local_labels() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg("tests/files/local_labels.asm")
+        .arg("--minimal")
+        .arg("--command")
+        .arg("print main.count\nbreak add main.loop\nbreak list\ncontinue\nprint r1");
+
+    cmd.assert()
+        .success()
+        .stdout(contains("321"))
+        .stderr(contains("x0003\nx3001\nReached::Breakpoint\nx0003"));
+}
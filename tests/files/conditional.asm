@@ -0,0 +1,19 @@
+; Prints a greeting for the student or instructor variant
+.ifndef LEVEL
+LEVEL   .equ #1
+.endif
+
+.if LEVEL - 1
+        lea r0 instructor
+.else
+        lea r0 student
+.endif
+        puts
+.ifdef FEATURE_STACK
+        push r0
+        pop r1
+.endif
+        halt
+
+student     .stringz "Hello, student\n"
+instructor  .stringz "Hello, instructor\n"
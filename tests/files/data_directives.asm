@@ -0,0 +1,21 @@
+; Prints data from each kind of data directive
+        lea r0 packed
+        putsp
+        lea r0 bin
+        puts
+        ld r1 table+2
+        ld r0 table
+        add r0 r0 r1
+        out
+        lea r0 fives
+        ldr r0 r0 #2
+        out
+        lea r0 text
+        puts
+        halt
+packed  .stringp "Packed\n"
+table   .word '0', x10, -2, table
+fives   .blkw #3, '5'
+bin     .incbin "data/hi.bin"
+text    .incbin "data/t.txt" text
+        .fill #0
@@ -0,0 +1,22 @@
+; Echoes input by polling the keyboard and display registers, without traps
+; Stops at the end of a line, by stopping the clock in the machine control register
+        .orig x3000
+.read   ldi r1 kbsr
+        brzp .read
+        ldi r0 kbdr
+.write  ldi r1 dsr
+        brzp .write
+        sti r0 ddr
+        add r0 r0 #-10
+        brnp .read
+        sti r0 mcr
+        lea r0 never
+        puts
+        halt
+
+kbsr    .fill xFE00
+kbdr    .fill xFE02
+dsr     .fill xFE04
+ddr     .fill xFE06
+mcr     .fill xFFFE
+never   .stringz "Not printed\n"
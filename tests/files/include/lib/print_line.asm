@@ -0,0 +1,11 @@
+; Print string at r0, followed by a newline
+print_line
+        st r7 saved_r7
+        puts
+        ld r0 newline
+        out
+        ld r7 saved_r7
+        ret
+
+newline  .fill x0A
+saved_r7 .blkw #1
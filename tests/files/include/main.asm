@@ -0,0 +1,12 @@
+; Subroutines are shared between programs by including them
+.orig x3000
+        lea r0 greeting
+        jsr print_line
+        lea r0 farewell
+        jsr print_line
+        halt
+
+.include "lib/print_line.asm"
+
+greeting .stringz "Hello from main"
+farewell .stringz "Goodbye from main"
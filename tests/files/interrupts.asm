@@ -0,0 +1,37 @@
+; Echoes a line of input from the keyboard interrupt handler, while the program waits
+; Run with `lace run --os tests/files/interrupts.asm`
+        .orig x3000
+        ld r0 ie
+        sti r0 kbsr
+wait    ld r0 done
+        brz wait
+        lea r0 msg
+        puts
+        halt
+ie      .fill x4000
+kbsr    .fill xFE00
+done    .fill #0
+msg     .stringz "Done\n"
+
+; Interrupt vector table, keyboard interrupt
+        .orig x0180
+        .fill keyboard
+
+        .orig x1000
+; Runs in supervisor mode, at priority 4
+keyboard
+        st r0 saved_r0
+        ldi r0 kbdr_ptr
+        out
+        add r0 r0 #-10
+        brnp .return
+; Disable interrupts at the end of the line, and stop waiting
+        sti r0 kbsr_ptr
+        add r0 r0 #1
+        sti r0 done_ptr
+.return ld r0 saved_r0
+        rti
+saved_r0 .blkw #1
+kbsr_ptr .fill xFE00
+kbdr_ptr .fill xFE02
+done_ptr .fill done
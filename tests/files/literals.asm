@@ -0,0 +1,22 @@
+; Prints a string with escapes, and characters from literals
+.macro print ch
+        ld r0 ch
+        out
+.endm
+        lea r0 msg
+        puts
+        add r1 r1 b0101
+        add r1 r1 o2
+        ld r0 zero
+        add r0 r0 r1
+        out
+        print space
+        print semi
+        ld r0 nl
+        out
+        trap x25
+msg     .stringz "\e[1mbold\e[0m\x21\0ignored"
+zero    .fill '0'
+space   .fill ' '
+semi    .fill ';'   ; comment
+nl      .fill '\n'
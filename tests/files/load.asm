@@ -0,0 +1,7 @@
+; Prints a string which is not part of the program, but loaded beside it
+; Run with `lace compile tests/files/load_data.asm` and then
+; `lace run tests/files/load.asm --load tests/files/load_data.lc3`
+        ldi r0 data
+        puts
+        halt
+data    .fill x4000
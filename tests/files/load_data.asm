@@ -0,0 +1,4 @@
+; Data for load.asm, which is loaded at its own origin
+        .orig x4000
+        .fill msg
+msg     .stringz "Hello from another image\n"
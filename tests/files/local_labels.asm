@@ -0,0 +1,12 @@
+; Count down from 3, printing each digit
+main
+    ld r1 .count
+.loop
+    ld r0 .zero
+    add r0 r0 r1
+    out
+    add r1 r1 #-1
+    brp .loop
+    halt
+.count .fill #3
+.zero .fill x30
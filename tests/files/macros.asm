@@ -0,0 +1,25 @@
+; Print a character `count` times, using a local loop label
+.macro repeat char count
+        ld r0 char
+        ld r1 count
+loop    out
+        add r1 r1 #-1
+        brp loop
+.endm
+
+.macro newline
+        ld r0 nl
+        out
+.endm
+
+        repeat star three
+        newline
+        repeat dash five
+        newline
+        halt
+
+star    .fill x2A
+dash    .fill x2D
+nl      .fill x0A
+three   .fill #3
+five    .fill #5
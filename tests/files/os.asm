@@ -0,0 +1,41 @@
+; Replaces the `puts` trap routine, and handles the exception from `rti` in user mode
+; Run with `lace run --os tests/files/os.asm`
+        .orig x3000
+        lea r0 msg
+        puts
+        rti
+        lea r0 after
+        puts
+        halt
+msg     .stringz "Hello from user mode\n"
+after   .stringz "Returned from exception\n"
+
+; Trap vector table
+        .orig x0022
+        .fill my_puts
+; Interrupt vector table, privilege mode exception
+        .orig x0100
+        .fill privilege
+
+        .orig x1000
+; Print each character with `out`, whose routine is in the default OS image
+my_puts st r0 saved_r0
+        st r1 saved_r1
+        add r1 r0 #0
+.loop   ldr r0 r1 #0
+        brz .done
+        out
+        add r1 r1 #1
+        br .loop
+.done   ld r0 saved_r0
+        ld r1 saved_r1
+        rti
+saved_r0 .blkw #1
+saved_r1 .blkw #1
+
+; Returns to the instruction after the `rti`
+privilege
+        lea r0 priv
+        puts
+        rti
+priv    .stringz "Privilege mode violation\n"
@@ -0,0 +1,22 @@
+; Loads and branches to labels beyond the range of their offsets
+        lea r0 msg
+        puts
+        ld r1 value
+        ldi r2 ptr
+        add r0 r1 r2
+        brp start
+        halt
+        .blkw #200
+        halt
+        .blkw #200
+far     lea r0 done
+        puts
+        halt
+value   .fill #5
+ptr     .fill value
+msg     .stringz "Relaxed\n"
+done    .stringz "Done\n"
+        .blkw #200
+start   and r0 r0 #0
+        brz far
+        halt
@@ -0,0 +1,16 @@
+; Prints the digits of a number counting down, using pseudo-instructions
+        ldi16 r1 x33
+        mov r2 #3
+loop    mov r0 r1
+        push r1
+        sub r1 r1 #-12
+        pop r1
+        out
+        dec r1
+        dec r2
+        brp loop
+        clr r0
+        add r0 r0 #10
+        out
+        halt
+.pool
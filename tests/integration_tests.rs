@@ -1,5 +1,5 @@
 use assert_cmd::Command;
-use predicates::str::contains;
+use predicates::{prelude::PredicateBooleanExt, str::contains};
 use tempfile::tempdir;
 
 #[test]
@@ -64,3 +64,694 @@ fn compile_and_run() {
 
     cmd.assert().success().stdout(contains("Hello, world!"));
 }
+
+#[test]
+fn compile_with_listing() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let outfile_path = dir.path().join("hw.lc3");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/hw.asm")
+        .arg(&outfile_path)
+        .arg("--listing");
+
+    cmd.assert().success().stdout(contains("hw.lst"));
+
+    let listing = std::fs::read_to_string(dir.path().join("hw.lst")).unwrap();
+    assert!(listing.contains("x3000  xE002 1110 0000 0000 0010     2                   lea r0 hw"));
+    assert!(listing.contains("hw               hw .stringz \"Hello, world!\""));
+    assert!(listing.contains("hw               x3003"));
+}
+
+#[test]
+fn compile_with_listing_of_included_files() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let outfile_path = dir.path().join("main.lc3");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/include/main.asm")
+        .arg(&outfile_path)
+        .arg("--listing");
+    cmd.assert().success();
+
+    let listing = std::fs::read_to_string(dir.path().join("main.lst")).unwrap();
+    let included = listing
+        .find("--- tests/files/include/lib/print_line.asm\n")
+        .unwrap();
+    let main = listing.find("--- tests/files/include/main.asm\n").unwrap();
+    assert!(included < listing.find("print_line               st r7").unwrap());
+    assert!(main < listing.find("greeting         greeting .stringz").unwrap());
+    assert!(listing.lines().all(|line| line == line.trim_end()));
+}
+
+#[test]
+fn compile_and_debug_with_symbols() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let outfile_path = dir.path().join("hw.lc3");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/hw.asm")
+        .arg(&outfile_path);
+    cmd.assert().success().stdout(contains("hw.sym"));
+
+    let symbols = std::fs::read_to_string(dir.path().join("hw.sym")).unwrap();
+    assert!(symbols.starts_with("// Symbol table\n"));
+    assert!(symbols.contains("//\thw                3003\n"));
+
+    // Labels are read back from the symbol file
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg(&outfile_path)
+        .arg("--minimal")
+        .arg("--command")
+        .arg("print hw");
+    cmd.assert()
+        .success()
+        .stdout(contains("Hello, world!"))
+        .stderr(contains("x0048"));
+}
+
+#[test]
+fn compile_and_run_emit_formats() {
+    let dir = tempdir().expect("Could not make tempdir");
+
+    for (format, extension) in [
+        ("hex", "hex"),
+        ("bin", "bin"),
+        ("ihex", "ihex"),
+        ("lc3tools-obj", "obj"),
+    ] {
+        let outfile_path = dir.path().join("hw").with_extension(extension);
+        let mut cmd = Command::cargo_bin("lace").unwrap();
+        cmd.arg("compile")
+            .arg("tests/files/hw.asm")
+            .arg(&outfile_path)
+            .arg("--emit")
+            .arg(format);
+        cmd.assert().success();
+
+        let mut cmd = Command::cargo_bin("lace").unwrap();
+        cmd.arg("run").arg(&outfile_path);
+        cmd.assert().success().stdout(contains("Hello, world!"));
+    }
+
+    let hex = std::fs::read_to_string(dir.path().join("hw.hex")).unwrap();
+    assert!(hex.starts_with("3000\nE002\n"));
+}
+
+#[test]
+fn disassembles_to_same_binary() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let binary_path = dir.path().join("relax.lc3");
+    let source_path = dir.path().join("disasm.asm");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/relax.asm")
+        .arg(&binary_path)
+        .arg("--relax");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("disasm")
+        .arg(&binary_path)
+        .arg("--output")
+        .arg(&source_path);
+    cmd.assert().success();
+
+    // Labels are read from the symbol file
+    let source = std::fs::read_to_string(&source_path).unwrap();
+    assert!(source.contains("\nmsg     .stringz \"Relaxed\\n\""));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile").arg(&source_path);
+    cmd.current_dir(dir.path());
+    cmd.assert().success();
+
+    let original = std::fs::read(&binary_path).unwrap();
+    let reassembled = std::fs::read(dir.path().join("disasm.lc3")).unwrap();
+    assert_eq!(original, reassembled);
+}
+
+#[test]
+fn disassembles_stack_example_to_same_binary() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let binary_path = dir.path().join("stack.lc3");
+    let source_path = dir.path().join("disasm.asm");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg("tests/files/stack.asm")
+        .arg(&binary_path)
+        .args(["--features", "stack"]);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("disasm")
+        .arg(&binary_path)
+        .arg("--output")
+        .arg(&source_path)
+        .args(["--features", "stack"]);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg(&source_path)
+        .args(["--features", "stack"]);
+    cmd.current_dir(dir.path());
+    cmd.assert().success();
+
+    let original = std::fs::read(&binary_path).unwrap();
+    let reassembled = std::fs::read(dir.path().join("disasm.lc3")).unwrap();
+    assert_eq!(original, reassembled);
+}
+
+#[test]
+fn disassembles_call_outside_image_to_same_binary() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let source_path = dir.path().join("call.asm");
+    let binary_path = dir.path().join("call.lc3");
+    let disasm_path = dir.path().join("disasm.asm");
+    std::fs::write(
+        &source_path,
+        ".orig x3000\ncall sub\nhalt\nsub rets\n.end\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg(&source_path)
+        .arg(&binary_path)
+        .args(["--features", "stack"]);
+    cmd.assert().success();
+
+    // Drop the subroutine, so the call targets the address past the end of the image
+    let mut original = std::fs::read(&binary_path).unwrap();
+    original.truncate(original.len() - 2);
+    std::fs::write(&binary_path, &original).unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("disasm")
+        .arg(&binary_path)
+        .arg("--output")
+        .arg(&disasm_path)
+        .args(["--features", "stack"]);
+    cmd.assert().success();
+    let text = std::fs::read_to_string(&disasm_path).unwrap();
+    assert!(text.contains(".fill xDC01"), "{text}");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile")
+        .arg(&disasm_path)
+        .args(["--features", "stack"]);
+    cmd.current_dir(dir.path());
+    cmd.assert().success();
+
+    let reassembled = std::fs::read(dir.path().join("disasm.lc3")).unwrap();
+    assert_eq!(original, reassembled);
+}
+
+#[test]
+fn compile_and_run_segments() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let path = dir.path().join("segments.asm");
+    std::fs::write(
+        &path,
+        ".orig x3000\nld r0 ptr\nputs\nhalt\nptr .fill msg\n.orig x4000\nmsg .stringz \"Far away\"\n",
+    )
+    .unwrap();
+    let outfile_path = dir.path().join("segments.lc3");
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("compile").arg(&path).arg(&outfile_path);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg(&outfile_path);
+    cmd.assert().success().stdout(contains("Far away"));
+}
+
+#[test]
+fn link_and_run_modules() {
+    let dir = tempdir().expect("Could not make tempdir");
+    std::fs::write(
+        dir.path().join("main.asm"),
+        ".extern print\n.global msg\njsr print\nhalt\nmsg .stringz \"Linked\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("lib.asm"),
+        ".extern msg\n.global print\nprint lea r0 msg\nputs\nret\n",
+    )
+    .unwrap();
+
+    for module in ["main", "lib"] {
+        let mut cmd = Command::cargo_bin("lace").unwrap();
+        cmd.current_dir(dir.path())
+            .arg("compile")
+            .arg("--object")
+            .arg(format!("{module}.asm"));
+        cmd.assert().success();
+    }
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.current_dir(dir.path())
+        .arg("link")
+        .arg("main.lobj")
+        .arg("lib.lobj")
+        .arg("-o")
+        .arg("prog.lc3");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.current_dir(dir.path()).arg("prog.lc3");
+    cmd.assert().success().stdout(contains("Linked"));
+
+    // Modules must be linked to be run
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.current_dir(dir.path()).arg("main.asm");
+    cmd.assert()
+        .failure()
+        .stderr(contains("Label `print` is in another module"));
+}
+
+#[test]
+fn check_reports_every_error() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let path = dir.path().join("bad.asm");
+    std::fs::write(&path, "add r0 r1 #99\nld r0 missing\n@\nhalt\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check").arg(&path);
+
+    cmd.assert()
+        .failure()
+        .stderr(contains("99 is not a 5-bit signed value"))
+        .stderr(contains("from -16 to 15"))
+        .stderr(contains("Label `missing` not found"))
+        .stderr(contains("unknown token"))
+        .stderr(contains("3 errors, 0 warnings"));
+}
+
+#[test]
+fn check_lints() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let path = dir.path().join("lint.asm");
+    std::fs::write(&path, "getc\nbrz done\nunused out\ndone halt\n.blkw #0\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check").arg(&path);
+    cmd.assert().success().stderr(contains("lint::").not());
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check").arg("--lint").arg(&path);
+    cmd.assert()
+        .success()
+        .stderr(contains("lint::stale_flags"))
+        .stderr(contains("Label `unused` is never used"))
+        .stderr(contains("lint::empty_blkw"))
+        .stdout(contains("0 errors, 3 warnings"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check")
+        .args(["--lint", "-A", "all", "-W", "empty-blkw"])
+        .arg(&path);
+    cmd.assert()
+        .success()
+        .stderr(contains("lint::empty_blkw"))
+        .stderr(contains("lint::stale_flags").not());
+}
+
+#[test]
+fn fmt_check_then_format() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let path = dir.path().join("messy.asm");
+    std::fs::write(&path, "LEA R0,HW\nPUTS\nHALT\nHW .STRINGZ \"Hi\"\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("fmt").arg("--check").arg(&path);
+    cmd.assert().failure().stderr(contains("not formatted"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("fmt").arg(&path);
+    cmd.assert().success().stdout(contains("Formatted"));
+
+    let formatted = std::fs::read_to_string(&path).unwrap();
+    assert!(formatted.starts_with("    lea      r0, HW\n"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("fmt").arg("--check").arg(&path);
+    cmd.assert().success();
+
+    // Formatted file must still assemble
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&path);
+    cmd.assert().success().stdout(contains("Hi"));
+}
+
+#[test]
+fn fmt_keeps_assembled_examples() {
+    let dir = tempdir().expect("Could not make tempdir");
+    copy_dir("tests/files".as_ref(), dir.path());
+
+    let examples: &[(&str, &[&str])] = &[
+        ("conditional.asm", &[]),
+        ("data_directives.asm", &[]),
+        ("devices.asm", &[]),
+        ("fibonacci.asm", &["--features", "stack"]),
+        ("hw.asm", &[]),
+        ("include/main.asm", &[]),
+        ("interrupts.asm", &[]),
+        ("literals.asm", &[]),
+        ("load.asm", &[]),
+        ("load_data.asm", &[]),
+        ("local_labels.asm", &[]),
+        ("macros.asm", &[]),
+        ("os.asm", &[]),
+        ("relax.asm", &["--relax"]),
+        ("stack.asm", &["--features", "stack"]),
+        ("sugar.asm", &["--features", "sugar"]),
+    ];
+    for (name, args) in examples {
+        let path = dir.path().join(name);
+        let compile = |dest: &str| {
+            let dest = dir.path().join(dest);
+            let mut cmd = Command::cargo_bin("lace").unwrap();
+            cmd.arg("compile").arg(&path).arg(&dest).args(*args);
+            cmd.assert().success();
+            std::fs::read(dest).unwrap()
+        };
+        let before = compile("before.lc3");
+
+        // Only feature flags apply to formatting
+        let mut cmd = Command::cargo_bin("lace").unwrap();
+        cmd.arg("fmt")
+            .arg(&path)
+            .args(args.iter().take_while(|arg| **arg != "--relax"));
+        cmd.assert().success();
+
+        assert_eq!(compile("after.lc3"), before, "{name} changed by formatting");
+    }
+}
+
+#[test]
+fn runs_macro_example() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/macros.asm");
+
+    cmd.assert().success().stdout(contains("***\n-----\n"));
+}
+
+#[test]
+fn runs_included_files() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/include/main.asm");
+
+    cmd.assert()
+        .success()
+        .stdout(contains("Hello from main\nGoodbye from main\n"));
+}
+
+#[test]
+fn check_reports_errors_in_included_file() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let main = dir.path().join("main.asm");
+    std::fs::write(
+        &main,
+        ".include \"lib.asm\"\n.include \"missing.asm\"\nhalt\n",
+    )
+    .unwrap();
+    std::fs::write(dir.path().join("lib.asm"), "; lib\nadd r0 r0 #99\n").unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check").arg(&main);
+
+    cmd.assert()
+        .failure()
+        .stderr(contains("lib.asm:2:11"))
+        .stderr(contains("Failed to include missing.asm"));
+}
+
+#[test]
+fn runs_conditional_variants() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/conditional.asm");
+    cmd.assert().success().stdout(contains("Hello, student"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/conditional.asm")
+        .arg("-D")
+        .arg("LEVEL=2")
+        .arg("--features")
+        .arg("stack");
+    cmd.assert().success().stdout(contains("Hello, instructor"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check")
+        .arg("tests/files/conditional.asm")
+        .arg("-D")
+        .arg("LEVEL=oops");
+    cmd.assert()
+        .failure()
+        .stderr(contains("Invalid value 'oops' for constant 'LEVEL'"));
+}
+
+#[test]
+fn runs_sugar() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/sugar.asm")
+        .arg("--features")
+        .arg("sugar");
+    cmd.assert().success().stdout(contains("321"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check").arg("tests/files/sugar.asm");
+    cmd.assert().failure();
+}
+
+#[test]
+fn runs_sugar_branch_on_difference() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let path = dir.path().join("sub.asm");
+    // Flags are set from the difference, whichever registers are used
+    std::fs::write(
+        &path,
+        "mov r1 #9\nmov r2 #9\nsub r1 r1 r2\nbrnp fail\nsub r3 r1 r2\nbrzp fail\n\
+         sub r2 r2 #10\nbrzp fail\nlea r0 ok\nputs\nhalt\nfail lea r0 bad\nputs\nhalt\n\
+         ok .stringz \"Equal\"\nbad .stringz \"Wrong flags\"\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&path).args(["--features", "sugar"]);
+    cmd.assert()
+        .success()
+        .stdout(contains("Equal"))
+        .stdout(contains("Wrong flags").not());
+}
+
+#[test]
+fn runs_relaxed_references() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("check").arg("tests/files/relax.asm");
+    cmd.assert().failure().stderr(contains(
+        "Difference between label and label reference is too large",
+    ));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/relax.asm").arg("--relax");
+    cmd.assert()
+        .success()
+        .stdout(contains("Relaxed"))
+        .stdout(contains("Done"))
+        .stderr(contains("Rewrote reference to a distant label"));
+}
+
+#[test]
+fn runs_with_devices() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/devices.asm");
+    cmd.write_stdin("echo\nignored\n");
+    cmd.assert()
+        .success()
+        .stdout(contains("echo\n"))
+        .stdout(contains("Halted"))
+        .stdout(contains("ignored").not())
+        .stdout(contains("Not printed").not());
+}
+
+#[test]
+fn runs_with_interrupts() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("--os").arg("tests/files/interrupts.asm");
+    cmd.write_stdin("echo\nignored\n");
+    cmd.assert()
+        .success()
+        .stdout(contains("echo\nDone\n"))
+        .stdout(contains("ignored").not());
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("debug")
+        .arg("--os")
+        .arg("tests/files/interrupts.asm")
+        .arg("--minimal")
+        .arg("--command")
+        .arg("continue");
+    cmd.write_stdin("a\n");
+    cmd.assert()
+        .success()
+        .stdout(contains("a\nDone\n"))
+        .stderr(contains("Interrupt::Keyboard"));
+}
+
+#[test]
+fn runs_with_loaded_images() {
+    let dir = tempdir().expect("Could not make tempdir");
+    let data_path = dir.path().join("load_data.lc3");
+    let os_path = dir.path().join("os.lc3");
+    for (name, path) in [
+        ("tests/files/load_data.asm", &data_path),
+        ("tests/files/os.asm", &os_path),
+    ] {
+        let mut cmd = Command::cargo_bin("lace").unwrap();
+        cmd.arg("compile").arg(name).arg(path);
+        cmd.assert().success();
+    }
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/load.asm")
+        .arg("--load")
+        .arg(&data_path);
+    cmd.assert()
+        .success()
+        .stdout(contains("Hello from another image"));
+
+    // Start at `halt`
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/load.asm")
+        .arg("--load")
+        .arg(&data_path)
+        .arg("--entry")
+        .arg("x3002");
+    cmd.assert().success().stdout(contains("Hello").not());
+
+    // Program of the OS image is also at x3000
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/load.asm")
+        .arg("--load")
+        .arg(&data_path)
+        .arg("--load")
+        .arg(&os_path);
+    cmd.assert()
+        .failure()
+        .stderr(contains("load::overlap"))
+        .stderr(contains("Failed to load tests/files/load.asm"))
+        .stderr(contains("Image overlaps memory at x3000"));
+
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run")
+        .arg("tests/files/load.asm")
+        .arg("--load")
+        .arg(&data_path)
+        .arg("--load")
+        .arg(&data_path);
+    cmd.assert()
+        .failure()
+        .stderr(contains("load::overlap"))
+        .stderr(contains("load_data.lc3"))
+        .stderr(contains("Image overlaps memory at x"));
+}
+
+#[test]
+fn runs_with_os() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("--os").arg("tests/files/os.asm");
+    cmd.assert()
+        .success()
+        .stdout(contains("Hello from user mode"))
+        .stdout(contains(
+            "Privilege mode violation\nReturned from exception",
+        ));
+
+    // Without a vector table, `rti` is fatal
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/os.asm");
+    cmd.assert()
+        .code(0xEE)
+        .stderr(contains("returned from interrupt in user mode"));
+}
+
+#[test]
+fn runs_faults_with_exit_codes() {
+    let dir = tempdir().expect("Could not make tempdir");
+
+    // Stack instructions are reserved without the feature
+    let path = dir.path().join("reserved.asm");
+    std::fs::write(&path, ".orig x3000\n.fill xD000\nhalt\n.end\n").unwrap();
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&path);
+    cmd.assert()
+        .code(1)
+        .stderr(contains("You called a reserved instruction."))
+        .stderr(contains("Run with `-f stack`"));
+
+    let path = dir.path().join("protected.asm");
+    std::fs::write(
+        &path,
+        ".orig x3000\nld r0 addr\njmp r0\naddr .fill x2000\n.end\n",
+    )
+    .unwrap();
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&path);
+    cmd.assert().code(0xEE).stderr(contains(
+        "exception: entered protected memory area < 0x3000, exiting",
+    ));
+
+    let path = dir.path().join("trap.asm");
+    std::fs::write(&path, ".orig x3000\ntrap x7f\n.end\n").unwrap();
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg(&path);
+    cmd.assert().code(0xEE).stderr(contains(
+        "exception: called a trap with an unknown vector of 0x7f, exiting",
+    ));
+}
+
+#[test]
+fn runs_char_and_radix_literals() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/literals.asm");
+    cmd.assert()
+        .success()
+        .stdout(contains("\x1B[1mbold\x1B[0m!7 ;\n"))
+        .stdout(contains("ignored").not());
+}
+
+#[test]
+fn runs_data_directives() {
+    let mut cmd = Command::cargo_bin("lace").unwrap();
+    cmd.arg("run").arg("tests/files/data_directives.asm");
+    cmd.assert()
+        .success()
+        .stdout(contains("Packed\nHi\n.5Text!"));
+}
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let dest = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &dest);
+        } else {
+            std::fs::copy(entry.path(), dest).unwrap();
+        }
+    }
+}